@@ -1,2 +1,5 @@
 pub mod types;
 pub mod protocol;
+pub mod names;
+#[cfg(feature = "grpc")]
+pub mod proto;
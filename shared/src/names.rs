@@ -0,0 +1,143 @@
+use std::fmt;
+use crate::protocol::AUTHORITY_SERVICE_TYPE;
+
+/// Instance-name prefix the authority advertises itself under, followed by its hostname
+pub const AUTHORITY_INSTANCE_PREFIX: &str = "subnet-authority-";
+
+/// A DNS-SD service instance name, split into its parts.
+///
+/// Parts are stored without leading or trailing dots; `Display` renders the
+/// canonical fully-qualified form with a trailing dot, e.g.
+/// `"fileserver._http._tcp.local."`, which is how the cache keys services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceName {
+    /// The instance label, e.g. "fileserver". May itself contain dots.
+    pub short_name: String,
+    /// Service type without the domain, e.g. "_http._tcp"
+    pub service_type: String,
+    /// Domain, e.g. "local"
+    pub domain: String,
+}
+
+impl InstanceName {
+    /// Build from parts, tolerating stray leading/trailing dots on each
+    pub fn new(short_name: &str, service_type: &str, domain: &str) -> Self {
+        Self {
+            short_name: short_name.trim_end_matches('.').to_string(),
+            service_type: service_type.trim_matches('.').to_string(),
+            domain: domain.trim_matches('.').to_string(),
+        }
+    }
+
+    /// Build from a short name and a fully-qualified service type such as
+    /// `"_http._tcp.local."`. Returns None if the type has no `_tcp`/`_udp` label.
+    pub fn with_type(short_name: &str, full_type: &str) -> Option<Self> {
+        let (service_type, domain) = split_type(full_type)?;
+        Some(Self::new(short_name, &service_type, &domain))
+    }
+
+    /// The authority's own advertisement for `hostname`
+    pub fn authority(hostname: &str) -> Self {
+        Self::with_type(&format!("{}{}", AUTHORITY_INSTANCE_PREFIX, hostname), AUTHORITY_SERVICE_TYPE)
+            .expect("AUTHORITY_SERVICE_TYPE is a valid service type")
+    }
+
+    /// Parse a fully-qualified instance name, with or without the trailing
+    /// dot. The service type is the rightmost `_name._tcp` or `_name._udp`
+    /// pair, so dots in the instance label are kept in `short_name`.
+    pub fn parse(full: &str) -> Option<Self> {
+        let labels: Vec<&str> = full.trim_end_matches('.').split('.').collect();
+        let proto = labels
+            .iter()
+            .rposition(|l| *l == "_tcp" || *l == "_udp")
+            .filter(|&i| i >= 2 && labels[i - 1].starts_with('_'))?;
+
+        let short_name = labels[..proto - 1].join(".");
+        let domain = labels[proto + 1..].join(".");
+        if short_name.is_empty() || domain.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            short_name,
+            service_type: labels[proto - 1..=proto].join("."),
+            domain,
+        })
+    }
+
+    /// Fully-qualified service type, e.g. `"_http._tcp.local."`, as stored
+    /// in `ServiceEntry::service_type`
+    pub fn full_type(&self) -> String {
+        format!("{}.{}.", self.service_type, self.domain)
+    }
+
+    /// The hostname in an authority advertisement, if this is one
+    pub fn authority_host(&self) -> Option<&str> {
+        (self.full_type() == AUTHORITY_SERVICE_TYPE)
+            .then(|| self.short_name.strip_prefix(AUTHORITY_INSTANCE_PREFIX))
+            .flatten()
+            .filter(|host| !host.is_empty())
+    }
+}
+
+impl fmt::Display for InstanceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}.", self.short_name, self.service_type, self.domain)
+    }
+}
+
+/// Split `"_http._tcp.local."` into `("_http._tcp", "local")`
+fn split_type(full_type: &str) -> Option<(String, String)> {
+    let parsed = InstanceName::parse(&format!("x.{}", full_type))?;
+    Some((parsed.service_type, parsed.domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for full in ["fileserver._http._tcp.local.", "My Printer._ipp._tcp.example.com.", "a.b._ssh._tcp.local."] {
+            let name = InstanceName::parse(full).unwrap();
+            assert_eq!(name.to_string(), full);
+            assert_eq!(InstanceName::parse(&name.to_string()), Some(name));
+        }
+    }
+
+    #[test]
+    fn test_parse_parts() {
+        let name = InstanceName::parse("web.v2._http._tcp.local").unwrap();
+        assert_eq!(name.short_name, "web.v2");
+        assert_eq!(name.service_type, "_http._tcp");
+        assert_eq!(name.domain, "local");
+        assert_eq!(name.full_type(), "_http._tcp.local.");
+        assert_eq!(name.to_string(), "web.v2._http._tcp.local.");
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        assert_eq!(InstanceName::parse("_http._tcp.local."), None);
+        assert_eq!(InstanceName::parse("web._tcp.local."), None);
+        assert_eq!(InstanceName::parse("web._http._tcp."), None);
+        assert_eq!(InstanceName::parse("plain.example.com."), None);
+    }
+
+    #[test]
+    fn test_with_type_normalizes_dots() {
+        let name = InstanceName::with_type("web.", "_http._tcp.local").unwrap();
+        assert_eq!(name.to_string(), "web._http._tcp.local.");
+        assert_eq!(InstanceName::with_type("web", "local."), None);
+    }
+
+    #[test]
+    fn test_authority_name() {
+        let name = InstanceName::authority("gateway");
+        assert_eq!(name.to_string(), "subnet-authority-gateway._subnet-authority._tcp.local.");
+        assert_eq!(name.authority_host(), Some("gateway"));
+
+        let parsed = InstanceName::parse("subnet-authority-gateway._subnet-authority._tcp.local.").unwrap();
+        assert_eq!(parsed.authority_host(), Some("gateway"));
+        assert_eq!(InstanceName::parse("subnet-authority-x._http._tcp.local.").unwrap().authority_host(), None);
+    }
+}
@@ -0,0 +1,352 @@
+//! Protobuf messages of the gRPC API, mirroring `proto/subnet_authority.proto`.
+//! Kept by hand rather than generated so building needs no `protoc`; a test
+//! compiles the `.proto` and checks every message against it.
+
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use crate::types::ServiceEntry;
+
+/// gRPC package of the `Authority` service
+pub const PACKAGE: &str = "subnet_authority.v1";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Service {
+    #[prost(string, tag = "1")]
+    pub service_type: String,
+    #[prost(string, tag = "2")]
+    pub instance_name: String,
+    #[prost(string, tag = "3")]
+    pub hostname: String,
+    #[prost(string, repeated, tag = "4")]
+    pub addresses: Vec<String>,
+    #[prost(uint32, tag = "5")]
+    pub port: u32,
+    #[prost(map = "string, string", tag = "6")]
+    pub txt: HashMap<String, String>,
+    #[prost(int64, tag = "7")]
+    pub first_seen_ms: i64,
+    #[prost(int64, tag = "8")]
+    pub last_seen_ms: i64,
+    #[prost(uint32, tag = "9")]
+    pub ttl: u32,
+    #[prost(bool, tag = "10")]
+    pub alive: bool,
+    #[prost(string, tag = "11")]
+    pub source: String,
+    #[prost(bool, optional, tag = "12")]
+    pub reachable: Option<bool>,
+    #[prost(uint32, optional, tag = "13")]
+    pub latency_ms: Option<u32>,
+    #[prost(string, repeated, tag = "14")]
+    pub subtypes: Vec<String>,
+    #[prost(string, optional, tag = "15")]
+    pub iface: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListServicesRequest {
+    #[prost(string, tag = "1")]
+    pub service_type: String,
+    #[prost(string, tag = "2")]
+    pub subtype: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListServicesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub services: Vec<Service>,
+    #[prost(uint64, tag = "2")]
+    pub generation: u64,
+    #[prost(string, tag = "3")]
+    pub hash: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetServiceRequest {
+    #[prost(string, tag = "1")]
+    pub instance_name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WatchChangesRequest {
+    #[prost(uint64, tag = "1")]
+    pub since_generation: u64,
+    #[prost(string, tag = "2")]
+    pub service_type: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChangeEvent {
+    #[prost(uint64, tag = "1")]
+    pub generation: u64,
+    #[prost(bool, tag = "2")]
+    pub resync: bool,
+    #[prost(message, repeated, tag = "3")]
+    pub services: Vec<Service>,
+    #[prost(string, repeated, tag = "4")]
+    pub removed: Vec<String>,
+}
+
+impl From<&ServiceEntry> for Service {
+    fn from(entry: &ServiceEntry) -> Self {
+        Self {
+            service_type: entry.service_type.clone(),
+            instance_name: entry.instance_name.clone(),
+            hostname: entry.hostname.clone(),
+            addresses: entry.addresses.iter().map(|a| a.to_string()).collect(),
+            port: entry.port.into(),
+            txt: entry.txt.clone(),
+            first_seen_ms: entry.first_seen.timestamp_millis(),
+            last_seen_ms: entry.last_seen.timestamp_millis(),
+            ttl: entry.ttl,
+            alive: entry.alive,
+            source: entry.source.as_str().to_string(),
+            reachable: entry.reachable,
+            latency_ms: entry.latency_ms,
+            subtypes: entry.subtypes.clone(),
+            iface: entry.iface.clone(),
+        }
+    }
+}
+
+impl TryFrom<Service> for ServiceEntry {
+    type Error = String;
+
+    fn try_from(service: Service) -> Result<Self, Self::Error> {
+        let timestamp = |ms: i64| {
+            DateTime::<Utc>::from_timestamp_millis(ms).ok_or_else(|| format!("Invalid timestamp: {}", ms))
+        };
+        Ok(Self {
+            addresses: service
+                .addresses
+                .iter()
+                .map(|a| a.parse().map_err(|_| format!("Invalid IP address: {}", a)))
+                .collect::<Result<_, _>>()?,
+            port: service.port.try_into().map_err(|_| format!("Invalid port: {}", service.port))?,
+            first_seen: timestamp(service.first_seen_ms)?,
+            last_seen: timestamp(service.last_seen_ms)?,
+            source: service.source.parse()?,
+            service_type: service.service_type,
+            instance_name: service.instance_name,
+            hostname: service.hostname,
+            txt: service.txt,
+            ttl: service.ttl,
+            alive: service.alive,
+            reachable: service.reachable,
+            latency_ms: service.latency_ms,
+            subtypes: service.subtypes,
+            iface: service.iface,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use prost_reflect::{DescriptorPool, DynamicMessage, FieldDescriptor, MapKey, Value};
+    use crate::types::ServiceSource;
+
+    #[test]
+    fn test_service_round_trip() {
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let entry = ServiceEntry {
+            service_type: "_http._tcp.local.".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: vec!["fd00::10".parse().unwrap()],
+            port: 8080,
+            txt: HashMap::from([("path".to_string(), "/".to_string())]),
+            first_seen: now,
+            last_seen: now,
+            ttl: 120,
+            alive: true,
+            source: ServiceSource::Static,
+            reachable: Some(true),
+            latency_ms: Some(3),
+            subtypes: vec!["_printer".to_string()],
+            iface: Some("eth1".to_string()),
+        };
+
+        let bytes = Service::from(&entry).encode_to_vec();
+        let decoded = ServiceEntry::try_from(Service::decode(bytes.as_slice()).unwrap()).unwrap();
+        assert_eq!(decoded.instance_name, entry.instance_name);
+        assert_eq!(decoded.addresses, entry.addresses);
+        assert_eq!(decoded.port, entry.port);
+        assert_eq!(decoded.txt, entry.txt);
+        assert_eq!(decoded.last_seen, entry.last_seen);
+        assert_eq!(decoded.source, entry.source);
+        assert_eq!((decoded.reachable, decoded.latency_ms), (Some(true), Some(3)));
+        assert_eq!(decoded.subtypes, entry.subtypes);
+        assert_eq!(decoded.iface, entry.iface);
+    }
+
+    fn descriptor_pool() -> DescriptorPool {
+        protox::Compiler::new([concat!(env!("CARGO_MANIFEST_DIR"), "/proto")])
+            .unwrap()
+            .open_file("subnet_authority.proto")
+            .unwrap()
+            .descriptor_pool()
+    }
+
+    /// A Rust field's value as the `.proto` field `field` decodes it
+    trait ProtoValue {
+        const OPTIONAL: bool = false;
+        fn proto_value(&self, field: &FieldDescriptor) -> Value;
+    }
+
+    impl ProtoValue for String {
+        fn proto_value(&self, _: &FieldDescriptor) -> Value {
+            Value::String(self.clone())
+        }
+    }
+
+    impl ProtoValue for bool {
+        fn proto_value(&self, _: &FieldDescriptor) -> Value {
+            Value::Bool(*self)
+        }
+    }
+
+    impl ProtoValue for u32 {
+        fn proto_value(&self, _: &FieldDescriptor) -> Value {
+            Value::U32(*self)
+        }
+    }
+
+    impl ProtoValue for u64 {
+        fn proto_value(&self, _: &FieldDescriptor) -> Value {
+            Value::U64(*self)
+        }
+    }
+
+    impl ProtoValue for i64 {
+        fn proto_value(&self, _: &FieldDescriptor) -> Value {
+            Value::I64(*self)
+        }
+    }
+
+    impl<T: ProtoValue> ProtoValue for Option<T> {
+        const OPTIONAL: bool = true;
+        fn proto_value(&self, field: &FieldDescriptor) -> Value {
+            self.as_ref().expect("test messages set every field").proto_value(field)
+        }
+    }
+
+    impl<T: ProtoValue> ProtoValue for Vec<T> {
+        fn proto_value(&self, field: &FieldDescriptor) -> Value {
+            Value::List(self.iter().map(|item| item.proto_value(field)).collect())
+        }
+    }
+
+    impl ProtoValue for HashMap<String, String> {
+        fn proto_value(&self, _: &FieldDescriptor) -> Value {
+            Value::Map(self.iter().map(|(k, v)| (MapKey::String(k.clone()), Value::String(v.clone()))).collect())
+        }
+    }
+
+    impl ProtoValue for Service {
+        fn proto_value(&self, field: &FieldDescriptor) -> Value {
+            let descriptor = field.kind().as_message().cloned().expect("Service field should be a message");
+            Value::Message(DynamicMessage::decode(descriptor, self.encode_to_vec().as_slice()).unwrap())
+        }
+    }
+
+    fn optional<T: ProtoValue>(_: &T) -> bool {
+        T::OPTIONAL
+    }
+
+    /// Build the message from every one of its fields (the struct literal
+    /// won't compile with one missing), decode its encoding against the
+    /// `.proto`, and check each field comes back under the same name with
+    /// the same value. A wrong tag, type or name fails.
+    macro_rules! assert_matches_proto {
+        ($pool:expr, $message:ident { $($field:ident: $value:expr),* $(,)? }) => {{
+            let message = $message { $($field: $value),* };
+            let name = format!("{}.{}", PACKAGE, stringify!($message));
+            let descriptor = $pool.get_message_by_name(&name).unwrap_or_else(|| panic!("{} is not in the .proto", name));
+            let decoded = DynamicMessage::decode(descriptor.clone(), message.encode_to_vec().as_slice()).unwrap();
+
+            let mut proto_fields: Vec<_> = descriptor.fields().map(|f| f.name().to_string()).collect();
+            let mut rust_fields = vec![$(stringify!($field).to_string()),*];
+            proto_fields.sort();
+            rust_fields.sort();
+            assert_eq!(rust_fields, proto_fields, "fields of {}", name);
+            $(
+                let field = descriptor.get_field_by_name(stringify!($field)).unwrap();
+                assert_eq!(
+                    *decoded.get_field(&field),
+                    message.$field.proto_value(&field),
+                    "{}.{}",
+                    name,
+                    stringify!($field)
+                );
+                assert_eq!(field.supports_presence() && !field.is_list(), optional(&message.$field), "{}.{} presence", name, stringify!($field));
+            )*
+        }};
+    }
+
+    #[test]
+    fn test_messages_match_proto_file() {
+        let pool = descriptor_pool();
+        assert!(pool.get_service_by_name(&format!("{}.Authority", PACKAGE)).is_some());
+
+        // Values differ per field, and the timestamps are negative, so
+        // swapped tags or a sint64/int64 mix-up show up as a mismatch
+        let service = Service {
+            service_type: "_http._tcp.local.".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: vec!["fd00::10".to_string(), "fd00::11".to_string()],
+            port: 8080,
+            txt: HashMap::from([("path".to_string(), "/".to_string())]),
+            first_seen_ms: -2,
+            last_seen_ms: -1,
+            ttl: 120,
+            alive: true,
+            source: "static".to_string(),
+            reachable: Some(true),
+            latency_ms: Some(3),
+            subtypes: vec!["_printer".to_string()],
+            iface: Some("eth1".to_string()),
+        };
+        assert_matches_proto!(pool, Service {
+            service_type: service.service_type.clone(),
+            instance_name: service.instance_name.clone(),
+            hostname: service.hostname.clone(),
+            addresses: service.addresses.clone(),
+            port: service.port,
+            txt: service.txt.clone(),
+            first_seen_ms: service.first_seen_ms,
+            last_seen_ms: service.last_seen_ms,
+            ttl: service.ttl,
+            alive: service.alive,
+            source: service.source.clone(),
+            reachable: service.reachable,
+            latency_ms: service.latency_ms,
+            subtypes: service.subtypes.clone(),
+            iface: service.iface.clone(),
+        });
+        assert_matches_proto!(pool, ListServicesRequest {
+            service_type: "_http._tcp".to_string(),
+            subtype: "_printer".to_string(),
+        });
+        assert_matches_proto!(pool, ListServicesResponse {
+            services: vec![service.clone()],
+            generation: 7,
+            hash: "abc".to_string(),
+        });
+        assert_matches_proto!(pool, GetServiceRequest {
+            instance_name: "web._http._tcp.local.".to_string(),
+        });
+        assert_matches_proto!(pool, WatchChangesRequest {
+            since_generation: 7,
+            service_type: "_http._tcp".to_string(),
+        });
+        assert_matches_proto!(pool, ChangeEvent {
+            generation: 8,
+            resync: true,
+            services: vec![service],
+            removed: vec!["old._http._tcp.local.".to_string()],
+        });
+    }
+}
@@ -6,6 +6,15 @@ pub const TXT_ZONE: &str = "zone";
 pub const TXT_PREFIX: &str = "prefix";
 pub const TXT_COAP_PORT: &str = "coap";
 pub const TXT_DNS_PORT: &str = "dns";
+/// Current cache hash, as served in `ETag`, so clients can tell from the
+/// announcement alone whether they need to re-sync
+pub const TXT_HASH: &str = "hash";
+/// Latest persisted change sequence number, for `/v1/changes?since=`
+pub const TXT_SEQ: &str = "seq";
 
 /// API path prefix
 pub const API_PREFIX: &str = "/v1";
+
+/// Media type to put in `Accept` to get `/v1/services` and `/v1/snapshot`
+/// as CBOR instead of JSON. Bodies decode into the same types.
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
@@ -1,11 +1,12 @@
 use std::collections::HashMap;
-use std::net::Ipv6Addr;
+use std::net::IpAddr;
 use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 
 /// A discovered service on the network.
 /// This is the canonical data model used by the authority daemon, API, and client.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct ServiceEntry {
     /// Service type, e.g. "_http._tcp"
     pub service_type: String,
@@ -16,8 +17,10 @@ pub struct ServiceEntry {
     /// Hostname, e.g. "nas.local."
     pub hostname: String,
 
-    /// IPv6 addresses (ULA subnet only)
-    pub addresses: Vec<Ipv6Addr>,
+    /// IPv6 addresses, and IPv4 ones when the authority records them
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    #[serde(with = "ip_addrs")]
+    pub addresses: Vec<IpAddr>,
 
     /// Service port
     pub port: u16,
@@ -36,4 +39,235 @@ pub struct ServiceEntry {
 
     /// Whether the service is currently alive
     pub alive: bool,
+
+    /// Where this entry came from
+    #[serde(default)]
+    pub source: ServiceSource,
+
+    /// Whether the last TCP connect to `port` succeeded; absent until probed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reachable: Option<bool>,
+
+    /// How long the last successful connect took, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u32>,
+
+    /// DNS-SD subtype labels the service is announced under, e.g. `_printer`
+    /// for `_printer._sub._http._tcp`, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subtypes: Vec<String>,
+
+    /// Interface the service was last heard announcing on, e.g. "eth0";
+    /// absent for services not learned from mDNS announcements
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iface: Option<String>,
+}
+
+/// Addresses encoded as they were when only IPv6 was kept, so readers of
+/// the old `Vec<Ipv6Addr>` still understand IPv6 entries: strings in
+/// human-readable formats, and in binary ones (CBOR) bare octet sequences,
+/// 16 long for IPv6 and 4 for IPv4, rather than `IpAddr`'s tagged enum.
+mod ip_addrs {
+    use std::fmt;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use serde::de::{self, Deserializer, SeqAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+
+    struct AddressRef<'a>(&'a IpAddr);
+
+    impl Serialize for AddressRef<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.0 {
+                IpAddr::V4(addr) => addr.serialize(serializer),
+                IpAddr::V6(addr) => addr.serialize(serializer),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(addresses: &[IpAddr], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(addresses.iter().map(AddressRef))
+    }
+
+    struct AddressVisitor;
+
+    impl<'de> Visitor<'de> for AddressVisitor {
+        type Value = IpAddr;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an IP address string or 4 or 16 octets")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<IpAddr, E> {
+            v.parse().map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<IpAddr, E> {
+            octets(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<IpAddr, A::Error> {
+            let mut bytes = Vec::with_capacity(16);
+            while let Some(b) = seq.next_element::<u8>()? {
+                bytes.push(b);
+            }
+            octets(&bytes).ok_or_else(|| de::Error::invalid_length(bytes.len(), &self))
+        }
+    }
+
+    fn octets(bytes: &[u8]) -> Option<IpAddr> {
+        if let Ok(v4) = <[u8; 4]>::try_from(bytes) {
+            Some(Ipv4Addr::from(v4).into())
+        } else {
+            <[u8; 16]>::try_from(bytes).ok().map(|v6| Ipv6Addr::from(v6).into())
+        }
+    }
+
+    struct Address(IpAddr);
+
+    impl<'de> de::Deserialize<'de> for Address {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(AddressVisitor).map(Address)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpAddr>, D::Error> {
+        let addresses: Vec<Address> = de::Deserialize::deserialize(deserializer)?;
+        Ok(addresses.into_iter().map(|a| a.0).collect())
+    }
+}
+
+/// Origin of a cache entry. Only mDNS-discovered entries expire through
+/// staleness and pruning; the others are kept until explicitly removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceSource {
+    /// Discovered by browsing mDNS
+    #[default]
+    Mdns,
+    /// Loaded from an imported service definition (e.g. an Avahi `.service` file)
+    Import,
+    /// Registered through `POST /v1/services`, for hosts that don't speak mDNS
+    Static,
+    /// Registered with a signed DNS UPDATE (RFC 2136), e.g. from `nsupdate`
+    Dns,
+    /// Defined in a drop-in file under `[import] services_dir`
+    File,
+}
+
+impl ServiceSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServiceSource::Mdns => "mdns",
+            ServiceSource::Import => "import",
+            ServiceSource::Static => "static",
+            ServiceSource::Dns => "dns",
+            ServiceSource::File => "file",
+        }
+    }
+}
+
+impl std::str::FromStr for ServiceSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mdns" => Ok(ServiceSource::Mdns),
+            "import" => Ok(ServiceSource::Import),
+            "static" => Ok(ServiceSource::Static),
+            "dns" => Ok(ServiceSource::Dns),
+            "file" => Ok(ServiceSource::File),
+            other => Err(format!("Unknown service source: {}", other)),
+        }
+    }
+}
+
+/// Body of `/v1/snapshot`: the whole cache at a single generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Snapshot {
+    pub generation: u64,
+    pub hash: String,
+    pub services: Vec<ServiceEntry>,
+}
+
+/// Body of `/v1/export/json` and `/v1/admin/import`: every cached service,
+/// for moving the cache to another host or seeding a new deployment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CacheExport {
+    pub exported_at: DateTime<Utc>,
+    pub services: Vec<ServiceEntry>,
+}
+
+/// Body of `/v1/changes`: what changed after a sequence number. Sequence
+/// numbers are persisted, so unlike generations they survive restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ChangeSet {
+    /// Latest sequence number; pass it as `since` next time
+    pub seq: u64,
+    /// The requested sequence is too old (or unknown); fetch a full snapshot
+    /// and continue from `seq`. The lists below are empty when this is set.
+    pub resync: bool,
+    /// Services first cached after `since`
+    pub added: Vec<ServiceEntry>,
+    /// Services cached before `since` and changed after it
+    pub updated: Vec<ServiceEntry>,
+    /// Instance names deleted from the cache after `since`
+    pub removed: Vec<String>,
+}
+
+/// A state transition in a service's history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceEventKind {
+    /// First cached, or seen alive again after being dead
+    Resolved,
+    /// Records changed; `detail` lists the fields
+    Changed,
+    /// Marked dead by a goodbye, failed confirmation, or staleness
+    Dead,
+    /// Deleted from the cache by expiry pruning
+    Pruned,
+    /// Deleted from the cache any other way, e.g. purged or dropped by its source
+    Removed,
+}
+
+impl ServiceEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServiceEventKind::Resolved => "resolved",
+            ServiceEventKind::Changed => "changed",
+            ServiceEventKind::Dead => "dead",
+            ServiceEventKind::Pruned => "pruned",
+            ServiceEventKind::Removed => "removed",
+        }
+    }
+}
+
+impl std::str::FromStr for ServiceEventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolved" => Ok(ServiceEventKind::Resolved),
+            "changed" => Ok(ServiceEventKind::Changed),
+            "dead" => Ok(ServiceEventKind::Dead),
+            "pruned" => Ok(ServiceEventKind::Pruned),
+            "removed" => Ok(ServiceEventKind::Removed),
+            other => Err(format!("Unknown service event: {}", other)),
+        }
+    }
+}
+
+/// One entry of `/v1/services/{instance}/history`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ServiceEvent {
+    pub event: ServiceEventKind,
+    pub at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
 }
@@ -0,0 +1,33 @@
+//! Generates the gRPC server for `shared/proto/subnet_authority.proto`. The
+//! messages are hand-written in `shared::proto`, so no `protoc` is needed.
+
+use tonic_build::manual::{Builder, Method, Service};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("shared::proto::{}", input))
+            .output_type(format!("shared::proto::{}", output))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+
+    let authority = Service::builder()
+        .name("Authority")
+        .package("subnet_authority.v1")
+        .method(method("list_services", "ListServices", "ListServicesRequest", "ListServicesResponse").build())
+        .method(method("get_service", "GetService", "GetServiceRequest", "Service").build())
+        .method(
+            method("watch_changes", "WatchChanges", "WatchChangesRequest", "ChangeEvent")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+
+    Builder::new()
+        .build_client(false)
+        .build_transport(false)
+        .compile(&[authority]);
+}
@@ -0,0 +1,168 @@
+use std::net::{IpAddr, Ipv6Addr};
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+use crate::config::AddressConfig;
+
+/// Kinds of address a service may advertise, for choosing between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressClass {
+    /// Inside the authority's subnet prefix
+    InPrefix,
+    /// Unique local, fc00::/7
+    Ula,
+    /// Anything routable that isn't ULA or link-local
+    Global,
+    /// fe80::/10, only usable with a zone index
+    LinkLocal,
+}
+
+impl AddressClass {
+    fn matches(self, addr: &Ipv6Addr, prefix: &Ipv6Net) -> bool {
+        let is_ula = addr.segments()[0] & 0xfe00 == 0xfc00;
+        let is_link_local = is_link_local(addr);
+        match self {
+            AddressClass::InPrefix => prefix.contains(addr),
+            AddressClass::Ula => is_ula,
+            AddressClass::LinkLocal => is_link_local,
+            AddressClass::Global => !is_ula && !is_link_local && !addr.is_loopback() && !addr.is_unspecified(),
+        }
+    }
+}
+
+pub(crate) fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+pub fn default_preference() -> Vec<AddressClass> {
+    vec![
+        AddressClass::InPrefix,
+        AddressClass::Ula,
+        AddressClass::Global,
+        AddressClass::LinkLocal,
+    ]
+}
+
+/// Orders a service's addresses by configured class preference, so callers
+/// that can only hand out one address give the most useful one
+#[derive(Debug, Clone)]
+pub struct AddressPreference {
+    order: Vec<AddressClass>,
+    prefix: Ipv6Net,
+}
+
+impl AddressPreference {
+    pub fn new(order: Vec<AddressClass>, prefix: Ipv6Net) -> Self {
+        Self { order, prefix }
+    }
+
+    /// Position of the first configured class `addr` belongs to; unlisted
+    /// classes rank last, and IPv4 after them
+    fn rank(&self, addr: &IpAddr) -> usize {
+        match addr {
+            IpAddr::V6(addr) => self
+                .order
+                .iter()
+                .position(|class| class.matches(addr, &self.prefix))
+                .unwrap_or(self.order.len()),
+            IpAddr::V4(_) => self.order.len() + 1,
+        }
+    }
+
+    /// The most preferred address, the first advertised one among equals
+    pub fn preferred(&self, addresses: &[IpAddr]) -> Option<IpAddr> {
+        addresses.iter().min_by_key(|addr| self.rank(addr)).copied()
+    }
+
+    /// Order `addresses` most preferred first, in advertised order among
+    /// equals, so the first is `preferred`
+    pub fn sort(&self, addresses: &mut [IpAddr]) {
+        addresses.sort_by_key(|addr| self.rank(addr));
+    }
+}
+
+/// Which of a service's advertised addresses the browser stores. IPv4
+/// addresses, when recorded at all, aren't filtered.
+#[derive(Debug, Clone, Default)]
+pub struct AddressFilter {
+    /// Keep only IPv6 addresses inside this prefix
+    prefix: Option<Ipv6Net>,
+    exclude_link_local: bool,
+}
+
+impl AddressFilter {
+    pub fn new(config: &AddressConfig, prefix: Ipv6Net) -> Self {
+        Self {
+            prefix: config.in_prefix_only.then_some(prefix),
+            exclude_link_local: config.exclude_link_local,
+        }
+    }
+
+    pub fn keeps(&self, addr: &IpAddr) -> bool {
+        match addr {
+            IpAddr::V6(addr) => {
+                self.prefix.is_none_or(|prefix| prefix.contains(addr))
+                    && !(self.exclude_link_local && is_link_local(addr))
+            }
+            IpAddr::V4(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    fn preference(order: Vec<AddressClass>) -> AddressPreference {
+        AddressPreference::new(order, "fd00:1234:5678:1::/64".parse().unwrap())
+    }
+
+    fn sorted(pref: &AddressPreference, mut addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+        pref.sort(&mut addresses);
+        addresses
+    }
+
+    #[test]
+    fn test_default_order() {
+        let mixed = addrs(&["192.168.1.7", "fe80::1", "2001:db8::1", "fd99::1", "fd00:1234:5678:1::7"]);
+        assert_eq!(
+            sorted(&preference(default_preference()), mixed),
+            addrs(&["fd00:1234:5678:1::7", "fd99::1", "2001:db8::1", "fe80::1", "192.168.1.7"])
+        );
+    }
+
+    #[test]
+    fn test_global_first() {
+        let pref = preference(vec![AddressClass::Global, AddressClass::Ula]);
+        let mixed = addrs(&["fd00:1234:5678:1::7", "fe80::1", "2001:db8::1"]);
+        assert_eq!(pref.preferred(&mixed), Some("2001:db8::1".parse().unwrap()));
+
+        // Unlisted classes come last, in advertised order
+        assert_eq!(sorted(&pref, mixed), addrs(&["2001:db8::1", "fd00:1234:5678:1::7", "fe80::1"]));
+    }
+
+    #[test]
+    fn test_filter() {
+        let config = AddressConfig { in_prefix_only: true, ..AddressConfig::default() };
+        let filter = AddressFilter::new(&config, "fd00:1234:5678:1::/64".parse().unwrap());
+        let kept: Vec<IpAddr> = addrs(&["fd00:1234:5678:1::7", "fe80::1", "2001:db8::1", "192.168.1.7"])
+            .into_iter()
+            .filter(|addr| filter.keeps(addr))
+            .collect();
+        assert_eq!(kept, addrs(&["fd00:1234:5678:1::7", "192.168.1.7"]));
+
+        let config = AddressConfig { exclude_link_local: true, ..AddressConfig::default() };
+        let filter = AddressFilter::new(&config, "fd00:1234:5678:1::/64".parse().unwrap());
+        assert!(!filter.keeps(&"fe80::1".parse().unwrap()));
+        assert!(filter.keeps(&"2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_preferred_of_empty() {
+        assert_eq!(preference(default_preference()).preferred(&[]), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use crate::api::grpc;
+use crate::api::openapi;
+use crate::api::routes::AppState;
+
+/// What a token may do. `Admin` includes everything `Read` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// GET/HEAD requests outside `/v1/admin`
+    Read,
+    /// Everything, including registration, deletion, and `/v1/admin`
+    Admin,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ApiToken {
+    pub token: String,
+    pub scope: TokenScope,
+}
+
+// Keep token values out of logs
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("token", &"<redacted>")
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    /// Bearer tokens accepted by the API. Empty leaves the API open.
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+}
+
+impl AuthConfig {
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Scope of the token presented in `Authorization: Bearer`, if it's one of ours
+    fn scope_of(&self, headers: &HeaderMap) -> Option<TokenScope> {
+        let presented = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?
+            .trim();
+        self.tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token.as_bytes(), presented.as_bytes()))
+            .map(|t| t.scope)
+    }
+
+    /// Check a request against the configured tokens
+    pub fn authorize(&self, method: &Method, path: &str, headers: &HeaderMap) -> Result<(), StatusCode> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let Some(required) = required_scope(method, path) else {
+            return Ok(());
+        };
+        match self.scope_of(headers) {
+            Some(scope) if scope >= required => Ok(()),
+            Some(_) => Err(StatusCode::FORBIDDEN),
+            None => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Scope a request needs, or None for endpoints left open: the probes, so
+/// they work without credentials, and the API docs, which hold no data
+fn required_scope(method: &Method, path: &str) -> Option<TokenScope> {
+    if matches!(path, "/healthz" | "/readyz" | "/v1/readyz" | openapi::SPEC_PATH)
+        || path.starts_with(openapi::DOCS_PATH)
+    {
+        return None;
+    }
+    // Every gRPC method is a POST, but none of them changes anything
+    if path.starts_with(grpc::GRPC_PREFIX) {
+        return Some(TokenScope::Read);
+    }
+    if path.starts_with("/v1/admin/") || !matches!(*method, Method::GET | Method::HEAD) {
+        return Some(TokenScope::Admin);
+    }
+    Some(TokenScope::Read)
+}
+
+/// Compare without bailing out at the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests without a token of sufficient scope
+pub async fn require_token(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    match state.api_config.auth.authorize(request.method(), request.uri().path(), request.headers()) {
+        Ok(()) => next.run(request).await,
+        Err(StatusCode::UNAUTHORIZED) => {
+            (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response()
+        }
+        Err(status) => status.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> AuthConfig {
+        AuthConfig {
+            tokens: vec![
+                ApiToken { token: "reader".to_string(), scope: TokenScope::Read },
+                ApiToken { token: "operator".to_string(), scope: TokenScope::Admin },
+            ],
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_scopes_enforced() {
+        let auth = auth();
+        assert_eq!(auth.authorize(&Method::GET, "/v1/services", &HeaderMap::new()), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(auth.authorize(&Method::GET, "/v1/services", &bearer("wrong")), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(auth.authorize(&Method::GET, "/v1/services", &bearer("reader")), Ok(()));
+        assert_eq!(auth.authorize(&Method::POST, "/v1/services", &bearer("reader")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(auth.authorize(&Method::GET, "/v1/admin/config-source", &bearer("reader")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(auth.authorize(&Method::DELETE, "/v1/services/x", &bearer("operator")), Ok(()));
+        assert_eq!(auth.authorize(&Method::POST, "/subnet_authority.v1.Authority/ListServices", &bearer("reader")), Ok(()));
+        assert_eq!(auth.authorize(&Method::GET, "/v1/readyz", &HeaderMap::new()), Ok(()));
+        assert_eq!(auth.authorize(&Method::GET, "/healthz", &HeaderMap::new()), Ok(()));
+        assert_eq!(auth.authorize(&Method::GET, "/v1/openapi.json", &HeaderMap::new()), Ok(()));
+    }
+
+    #[test]
+    fn test_no_tokens_leaves_api_open() {
+        let auth = AuthConfig::default();
+        assert_eq!(auth.authorize(&Method::POST, "/v1/services", &HeaderMap::new()), Ok(()));
+    }
+}
@@ -0,0 +1,136 @@
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use shared::protocol::CBOR_CONTENT_TYPE;
+
+/// Body format for API responses, chosen from the request's `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    /// Compact binary encoding for low-bandwidth links
+    Cbor,
+}
+
+impl Encoding {
+    /// CBOR if the client accepts it, otherwise JSON
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        let accepts_cbor = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media| media.split(';').next().unwrap_or("").trim() == CBOR_CONTENT_TYPE);
+
+        if accepts_cbor {
+            Encoding::Cbor
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    pub fn serialize(self, value: &impl Serialize) -> Result<Bytes, StatusCode> {
+        let result = match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(value, &mut body)
+                    .map(|()| body)
+                    .map_err(|e| e.to_string())
+            }
+        };
+
+        result.map(Bytes::from).map_err(|e| {
+            tracing::error!("Failed to serialize services: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+
+    /// `value` serialized as a complete response with the matching content type
+    pub fn respond(self, value: &impl Serialize) -> Result<Response, StatusCode> {
+        let body = self.serialize(value)?;
+        Ok(([(header::CONTENT_TYPE, self.content_type()), (header::VARY, "accept")], body).into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use axum::http::HeaderValue;
+    use chrono::Utc;
+    use shared::types::{ServiceEntry, ServiceSource};
+
+    #[test]
+    fn test_negotiate() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Encoding::negotiate(&headers), Encoding::Json);
+
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+        assert_eq!(Encoding::negotiate(&headers), Encoding::Json);
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/cbor;q=1.0, application/json;q=0.5"),
+        );
+        assert_eq!(Encoding::negotiate(&headers), Encoding::Cbor);
+    }
+
+    #[test]
+    fn test_cbor_round_trip() {
+        let services = vec![ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: vec!["fd00::10".parse().unwrap()],
+            port: 80,
+            txt: HashMap::from([("path".to_string(), "/".to_string())]),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }];
+
+        let cbor = Encoding::Cbor.serialize(&services).unwrap();
+        let json = Encoding::Json.serialize(&services).unwrap();
+        assert!(cbor.len() < json.len(), "CBOR should be smaller than JSON");
+
+        let decoded: Vec<ServiceEntry> = ciborium::from_reader(cbor.as_ref()).unwrap();
+        assert_eq!(decoded[0].instance_name, services[0].instance_name);
+        assert_eq!(decoded[0].addresses, services[0].addresses);
+        assert_eq!(decoded[0].last_seen, services[0].last_seen);
+
+        // IPv6 addresses are encoded as they were before IPv4 was recorded
+        #[derive(serde::Deserialize)]
+        struct Ipv6Only {
+            addresses: Vec<std::net::Ipv6Addr>,
+        }
+        let old: Vec<Ipv6Only> = ciborium::from_reader(cbor.as_ref()).unwrap();
+        assert_eq!(old[0].addresses, ["fd00::10".parse::<std::net::Ipv6Addr>().unwrap()]);
+
+        let mut services = services;
+        services[0].addresses.push("192.0.2.10".parse().unwrap());
+        let cbor = Encoding::Cbor.serialize(&services).unwrap();
+        let decoded: Vec<ServiceEntry> = ciborium::from_reader(cbor.as_ref()).unwrap();
+        assert_eq!(decoded[0].addresses, services[0].addresses);
+    }
+
+    #[test]
+    fn test_respond_labels_encoding() {
+        let response = Encoding::Cbor.respond(&vec!["a", "b"]).unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CBOR_CONTENT_TYPE);
+        assert_eq!(response.headers()[header::VARY], "accept");
+    }
+}
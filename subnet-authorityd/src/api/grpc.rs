@@ -0,0 +1,178 @@
+use std::pin::Pin;
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use shared::proto::{
+    ChangeEvent, GetServiceRequest, ListServicesRequest, ListServicesResponse, Service, WatchChangesRequest,
+};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status};
+use crate::api::sse::{self, ChangesUpdate};
+use crate::cache::query::{Page, ServiceFilter};
+use crate::cache_manager::{CacheHandle, CacheSnapshot};
+
+include!(concat!(env!("OUT_DIR"), "/subnet_authority.v1.Authority.rs"));
+
+pub use authority_server::AuthorityServer;
+
+/// Path prefix of every gRPC method, e.g. `/subnet_authority.v1.Authority/ListServices`
+pub const GRPC_PREFIX: &str = "/subnet_authority.v1.Authority/";
+
+/// The `Authority` gRPC service, answering from the same cache as the REST API
+pub struct AuthorityService {
+    pub cache: CacheHandle,
+    pub snapshot_rx: watch::Receiver<CacheSnapshot>,
+    /// Shortest gap between `WatchChanges` events, as `sse_min_interval_ms`
+    pub min_interval: Duration,
+    pub shutdown: CancellationToken,
+}
+
+/// Proto3 strings can't be absent; empty means "not set"
+fn non_empty(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    tracing::error!("gRPC request failed: {}", e);
+    Status::internal("cache query failed")
+}
+
+#[tonic::async_trait]
+impl authority_server::Authority for AuthorityService {
+    async fn list_services(
+        &self,
+        request: Request<ListServicesRequest>,
+    ) -> Result<Response<ListServicesResponse>, Status> {
+        let request = request.into_inner();
+        let filter = ServiceFilter {
+            service_type: non_empty(request.service_type),
+            subtype: non_empty(request.subtype),
+            ..Default::default()
+        };
+        // Read the generation first, as the REST listing does, so it never
+        // claims to be newer than the services returned
+        let (generation, hash) = {
+            let snapshot = self.snapshot_rx.borrow();
+            (snapshot.generation, snapshot.hash.clone())
+        };
+        let page = self.cache.query(filter, Page::default()).await.map_err(internal)?;
+        Ok(Response::new(ListServicesResponse {
+            services: page.services.iter().map(Service::from).collect(),
+            generation,
+            hash,
+        }))
+    }
+
+    async fn get_service(&self, request: Request<GetServiceRequest>) -> Result<Response<Service>, Status> {
+        let instance_name = request.into_inner().instance_name;
+        match self.cache.get_one(instance_name.clone()).await.map_err(internal)? {
+            Some(entry) => Ok(Response::new(Service::from(&entry))),
+            None => Err(Status::not_found(format!("{} is not cached", instance_name))),
+        }
+    }
+
+    type WatchChangesStream = Pin<Box<dyn Stream<Item = Result<ChangeEvent, Status>> + Send>>;
+
+    async fn watch_changes(
+        &self,
+        request: Request<WatchChangesRequest>,
+    ) -> Result<Response<Self::WatchChangesStream>, Status> {
+        let request = request.into_inner();
+        let events = sse::changes(
+            self.snapshot_rx.clone(),
+            self.min_interval,
+            self.shutdown.clone(),
+            request.since_generation,
+            non_empty(request.service_type),
+            |update| {
+                Some(match update {
+                    ChangesUpdate::Resync { generation } => {
+                        ChangeEvent { generation, resync: true, services: vec![], removed: vec![] }
+                    }
+                    ChangesUpdate::Changes { generation, services, removed } => ChangeEvent {
+                        generation,
+                        resync: false,
+                        services: services.into_iter().map(Service::from).collect(),
+                        removed: removed.into_iter().map(str::to_string).collect(),
+                    },
+                })
+            },
+        );
+        Ok(Response::new(Box::pin(events.map(Ok))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use authority_server::Authority;
+    use chrono::Utc;
+    use shared::types::ServiceEntry;
+    use crate::cache::db::CacheDb;
+    use crate::cache::hash::HashFields;
+    use crate::config::CacheConfig;
+
+    fn entry(instance_name: &str, service_type: &str) -> ServiceEntry {
+        ServiceEntry {
+            service_type: service_type.to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: "host.local.".to_string(),
+            addresses: vec!["fd00::1".parse().unwrap()],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_and_watch() {
+        let (snapshot_tx, snapshot_rx) = watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let cache = CacheHandle::spawn(CacheDb::open(":memory:").unwrap(), snapshot_tx, &CacheConfig::default());
+        cache.upsert(entry("web._http._tcp.local.", "_http._tcp")).await.unwrap();
+        cache.upsert(entry("box._ssh._tcp.local.", "_ssh._tcp")).await.unwrap();
+
+        let shutdown = CancellationToken::new();
+        let service = AuthorityService {
+            cache: cache.clone(),
+            snapshot_rx,
+            min_interval: Duration::ZERO,
+            shutdown: shutdown.clone(),
+        };
+
+        let request = ListServicesRequest { service_type: "_ssh._tcp".to_string(), subtype: String::new() };
+        let listed = service.list_services(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(listed.services.len(), 1);
+        assert_eq!(listed.services[0].instance_name, "box._ssh._tcp.local.");
+        assert_eq!(listed.generation, 2);
+
+        let missing = GetServiceRequest { instance_name: "gone._http._tcp.local.".to_string() };
+        let err = service.get_service(Request::new(missing)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        // The first event replays what changed after `since`, filtered by type
+        let request = WatchChangesRequest { since_generation: 0, service_type: "_http._tcp".to_string() };
+        let mut events = service.watch_changes(Request::new(request)).await.unwrap().into_inner();
+        let first = events.next().await.unwrap().unwrap();
+        assert_eq!(first.generation, 2);
+        assert_eq!(first.services.len(), 1);
+        assert_eq!(first.services[0].instance_name, "web._http._tcp.local.");
+
+        // A purged service is reported by name
+        cache.purge("web._http._tcp.local.".to_string()).await.unwrap();
+        let purged = events.next().await.unwrap().unwrap();
+        assert!(purged.services.is_empty());
+        assert_eq!(purged.removed, ["web._http._tcp.local."]);
+
+        shutdown.cancel();
+        assert!(events.next().await.is_none());
+        cache.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::cache_manager::{CacheSnapshot, DbHealth};
+use crate::config::MetricsConfig;
+
+/// Renders `/metrics` in the Prometheus text exposition format
+pub struct Metrics {
+    config: MetricsConfig,
+    /// Set once the per-service cap has been hit, so it's only warned about once
+    cap_warned: AtomicBool,
+}
+
+impl Metrics {
+    pub fn new(config: MetricsConfig) -> Self {
+        Self {
+            config,
+            cap_warned: AtomicBool::new(false),
+        }
+    }
+
+    pub fn render(&self, snapshot: &CacheSnapshot, health: &DbHealth) -> String {
+        let alive = snapshot.services.iter().filter(|s| s.alive).count();
+        let mut out = String::new();
+
+        gauge(&mut out, "subnet_authority_services", "Services in the cache", snapshot.services.len() as u64);
+        gauge(&mut out, "subnet_authority_services_alive", "Alive services in the cache", alive as u64);
+        gauge(&mut out, "subnet_authority_cache_generation", "Current cache generation", snapshot.generation);
+        gauge(
+            &mut out,
+            "subnet_authority_degraded",
+            "1 while the cache is served from memory",
+            health.is_degraded() as u64,
+        );
+        gauge(
+            &mut out,
+            "subnet_authority_db_write_failures",
+            "Database writes that have failed in a row",
+            health.consecutive_failures() as u64,
+        );
+
+        if self.config.per_service {
+            self.render_per_service(&mut out, snapshot);
+        }
+        out
+    }
+
+    fn render_per_service(&self, out: &mut String, snapshot: &CacheSnapshot) {
+        let total = snapshot.services.len();
+        let dropped = total.saturating_sub(self.config.max_series);
+        if dropped > 0 && !self.cap_warned.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                services = total,
+                max_series = self.config.max_series,
+                "More services than metrics.max_series; per-service series beyond the cap are not exported"
+            );
+        }
+
+        out.push_str("# HELP subnet_authority_service_up 1 if the service is alive, 0 if dead\n");
+        out.push_str("# TYPE subnet_authority_service_up gauge\n");
+        for service in snapshot.services.iter().take(self.config.max_series) {
+            let _ = writeln!(
+                out,
+                "subnet_authority_service_up{{instance=\"{}\",type=\"{}\"}} {}",
+                escape_label(&service.instance_name),
+                escape_label(&service.service_type),
+                service.alive as u8,
+            );
+        }
+        gauge(
+            out,
+            "subnet_authority_service_series_dropped",
+            "Services left out of subnet_authority_service_up by metrics.max_series",
+            dropped as u64,
+        );
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+/// Escape a label value per the text exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use shared::types::{ServiceEntry, ServiceSource};
+    use crate::cache::hash::HashFields;
+
+    fn service(name: &str, alive: bool) -> ServiceEntry {
+        ServiceEntry {
+            instance_name: name.to_string(),
+            service_type: "_http._tcp.local.".to_string(),
+            hostname: "host.local.".to_string(),
+            addresses: vec![],
+            port: 80,
+            txt: Default::default(),
+            ttl: 120,
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            alive,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    fn snapshot(services: Vec<ServiceEntry>) -> CacheSnapshot {
+        CacheSnapshot::new(services, HashFields::default())
+    }
+
+    #[test]
+    fn test_per_service_off_by_default() {
+        let metrics = Metrics::new(MetricsConfig::default());
+        let out = metrics.render(&snapshot(vec![service("a", true)]), &DbHealth::default());
+        assert!(out.contains("subnet_authority_services 1\n"));
+        assert!(!out.contains("subnet_authority_service_up"));
+    }
+
+    #[test]
+    fn test_per_service_capped() {
+        let metrics = Metrics::new(MetricsConfig { per_service: true, max_series: 2 });
+        let services = vec![service("a", true), service("b \"quoted\"", false), service("c", true)];
+        let out = metrics.render(&snapshot(services), &DbHealth::default());
+
+        assert!(out.contains("subnet_authority_service_up{instance=\"a\",type=\"_http._tcp.local.\"} 1\n"));
+        assert!(out.contains("instance=\"b \\\"quoted\\\"\""));
+        assert!(!out.contains("instance=\"c\""));
+        assert!(out.contains("subnet_authority_service_series_dropped 1\n"));
+        assert!(metrics.cap_warned.load(Ordering::Relaxed));
+    }
+}
@@ -1 +1,9 @@
+pub mod auth;
+pub mod encoding;
+pub mod grpc;
+pub mod metrics;
+pub mod openapi;
+pub mod response_cache;
 pub mod routes;
+pub mod server;
+pub mod sse;
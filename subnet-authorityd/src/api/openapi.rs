@@ -0,0 +1,99 @@
+use axum::Router;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::api::routes;
+
+/// Where the generated spec is served
+pub const SPEC_PATH: &str = "/v1/openapi.json";
+/// Where the Swagger UI is served
+pub const DOCS_PATH: &str = "/v1/docs";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "subnet-authorityd", description = "Service cache of the subnet authority daemon"),
+    paths(
+        routes::get_config,
+        routes::get_config_source,
+        routes::reload_config,
+        routes::reload_filters,
+        routes::browse_type,
+        routes::backup_database,
+        routes::import_services,
+        routes::get_prefix,
+        routes::get_warmup,
+        routes::get_rejected,
+        routes::get_healthz,
+        routes::get_readyz,
+        routes::get_stats,
+        routes::get_metrics,
+        routes::get_services,
+        routes::register_service,
+        routes::get_service,
+        routes::head_service,
+        routes::delete_service,
+        routes::get_service_history,
+        routes::get_interface_services,
+        routes::get_hash,
+        routes::stream_hash,
+        routes::get_summary,
+        routes::get_merkle_node,
+        routes::get_changes,
+        routes::stream_changes,
+        routes::stream_events,
+        routes::subscribe_ws,
+        routes::search_services,
+        routes::get_snapshot,
+        routes::export_zone,
+        routes::export_dnsmasq,
+        routes::export_unbound,
+        routes::export_hosts,
+        routes::export_json,
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    tags(
+        (name = "services", description = "Querying and registering services"),
+        (name = "sync", description = "Change detection and incremental sync"),
+        (name = "status", description = "Daemon status and probes"),
+        (name = "export", description = "The cache in other DNS software's formats"),
+        (name = "admin", description = "Requires an admin token"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares the `[api.auth]` bearer tokens. They're only enforced when configured.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// Routes serving the spec and the Swagger UI
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    SwaggerUi::new(DOCS_PATH).url(SPEC_PATH, ApiDoc::openapi()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_documents_routes() {
+        let spec = ApiDoc::openapi();
+        for path in ["/v1/services", "/v1/services/{instance}", "/v1/changes", "/healthz", "/v1/admin/config-source", "/v1/admin/browse"] {
+            assert!(spec.paths.paths.contains_key(path), "{} is undocumented", path);
+        }
+
+        let schemas = &spec.components.as_ref().unwrap().schemas;
+        for schema in ["ServiceEntry", "ChangeSet", "Snapshot", "HashFields"] {
+            assert!(schemas.contains_key(schema), "{} schema missing", schema);
+        }
+    }
+}
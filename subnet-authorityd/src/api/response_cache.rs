@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+
+/// Short-lived cache of serialized `/v1/services` responses, keyed by query string.
+///
+/// Entries are tagged with the cache generation they were built from and are
+/// only served while that generation is current, so the TTL bounds reuse of
+/// identical polls but never lets a client see data older than the last change.
+pub struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedResponse>>,
+}
+
+struct CachedResponse {
+    generation: u64,
+    stored_at: Instant,
+    body: Bytes,
+}
+
+impl ResponseCache {
+    /// A zero TTL disables caching entirely
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Look up a response built from `generation` that hasn't expired
+    pub fn get(&self, key: &str, generation: u64) -> Option<Bytes> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|cached| cached.generation == generation && cached.stored_at.elapsed() < self.ttl)
+            .map(|cached| cached.body.clone())
+    }
+
+    pub fn insert(&self, key: String, generation: u64, body: Bytes) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        // Evict anything that can no longer be served so arbitrary query
+        // strings can't grow the map without bound
+        entries.retain(|_, cached| cached.generation == generation && cached.stored_at.elapsed() < self.ttl);
+        entries.insert(key, CachedResponse {
+            generation,
+            stored_at: Instant::now(),
+            body,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_within_generation() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        cache.insert("type=_http._tcp".to_string(), 3, Bytes::from_static(b"[]"));
+
+        assert_eq!(cache.get("type=_http._tcp", 3), Some(Bytes::from_static(b"[]")));
+        assert_eq!(cache.get("type=_ssh._tcp", 3), None);
+    }
+
+    #[test]
+    fn test_generation_bump_invalidates() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        cache.insert(String::new(), 3, Bytes::from_static(b"[]"));
+
+        assert_eq!(cache.get("", 4), None, "A newer generation must not see the old body");
+    }
+
+    #[test]
+    fn test_disabled_when_ttl_zero() {
+        let cache = ResponseCache::new(Duration::ZERO);
+        cache.insert(String::new(), 1, Bytes::from_static(b"[]"));
+
+        assert_eq!(cache.get("", 1), None);
+    }
+}
@@ -1,83 +1,1333 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use axum::body::Bytes;
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    routing::get,
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{Path, Query, RawQuery, State},
+    Extension,
+    http::{header, HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    middleware,
+    routing::{get, post},
     Json, Router,
 };
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use ipnet::Ipv6Net;
+use mdns_sd::ServiceDaemon;
 use serde::{Deserialize, Serialize};
-use tokio::sync::watch;
-use crate::cache_manager::CacheHandle;
-use crate::config::AuthorityConfig;
-use shared::types::ServiceEntry;
+use utoipa::{IntoParams, ToSchema};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_util::sync::CancellationToken;
+use tower_http::compression::CompressionLayer;
+use crate::addresses::AddressPreference;
+use crate::api::auth;
+use crate::api::encoding::Encoding;
+use crate::api::grpc;
+use crate::api::metrics::Metrics;
+use crate::api::openapi;
+use crate::api::response_cache::ResponseCache;
+use crate::api::server::ClientIdentity;
+use crate::api::sse;
+use crate::cache::db::TypeConflictError;
+use crate::cache::hash::{HashFields, HASH_VERSION};
+use crate::cache::merkle::{self, MerkleNode};
+use crate::cache::query::{query_in_memory, Page, ServiceFilter, ServicePage, MIN_SEARCH_LEN};
+use crate::cache_manager::{CacheEvent, CacheHandle, CacheSnapshot, DatabaseDegraded};
+use crate::config::{ApiConfig, AuthorityConfig, ConfigSource};
+use crate::reload::{Reloader, RestartRequired};
+use crate::dns::zone::ZoneInfo;
+use crate::export;
+use crate::mdns;
+use crate::mdns::browser::BrowserCommand;
+use crate::mdns::rejected::{RejectedRing, RejectedService};
+use shared::names::InstanceName;
+use shared::types::{CacheExport, ChangeSet, ServiceEntry, ServiceEvent, ServiceSource, Snapshot};
 
 #[derive(Clone)]
 pub struct AppState {
     pub cache: CacheHandle,
-    pub hash_rx: watch::Receiver<String>,
+    pub snapshot_rx: watch::Receiver<CacheSnapshot>,
+    /// Becomes true once the browser has completed its first discovery cycle
+    pub warm_rx: watch::Receiver<bool>,
+    /// Services the browser recently dropped, and why
+    pub rejected: Arc<RejectedRing>,
+    pub response_cache: Arc<ResponseCache>,
     pub config: Arc<AuthorityConfig>,
+    pub api_config: Arc<ApiConfig>,
+    /// Applies config file changes; also knows where the config came from
+    pub reloader: Arc<Reloader>,
+    /// `[cache] backup_dir`
+    pub backup_dir: Arc<PathBuf>,
+    pub address_preference: Arc<AddressPreference>,
+    /// What the DNS zone is built from, also for exports
+    pub zone: Arc<ZoneInfo>,
+    pub metrics: Arc<Metrics>,
+    /// Checked by the health probes
+    pub mdns_daemon: ServiceDaemon,
+    /// Takes on-demand browse requests
+    pub browser: mpsc::Sender<BrowserCommand>,
+    /// Interfaces mDNS is browsed on
+    pub browsed_rx: watch::Receiver<BTreeSet<String>>,
+    /// Ends long-lived streams so graceful shutdown isn't held open
+    pub shutdown: CancellationToken,
     /// Fix #1: store api_port directly instead of parsing it from config.zone
     pub api_port: u16,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct ConfigResponse {
     pub zone: String,
     pub prefix: String,
     pub api_port: u16,
+    /// Serialization format version of `/v1/services/hash`
+    pub hash_version: u32,
+    /// Service fields that contribute to the hash
+    pub hash_fields: HashFields,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, ToSchema)]
+pub struct PrefixResponse {
+    pub prefix: String,
+    /// First address in the prefix
+    #[schema(value_type = String, format = Ipv6)]
+    pub network: Ipv6Addr,
+    /// Last address in the prefix
+    #[schema(value_type = String, format = Ipv6)]
+    pub last_address: Ipv6Addr,
+    pub prefix_len: u8,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct ServiceQuery {
     #[serde(rename = "type")]
     pub service_type: Option<String>,
+    /// Only services changed after this generation. Generations restart from 0
+    /// when the daemon restarts, so a client seeing `X-Cache-Generation` go
+    /// backwards must do a full resync.
+    pub since_generation: Option<u64>,
+    /// Only services advertising this TXT key, whatever its value
+    pub has_txt: Option<String>,
+    /// Only services announced with this subtype, e.g. `_printer`
+    pub subtype: Option<String>,
+    /// Only services advertising this address (all of them, whatever their port)
+    #[param(value_type = Option<String>)]
+    pub address: Option<IpAddr>,
+    /// Include dead services, with `alive: false`, until they are pruned.
+    /// Defaults to true with `since_generation`, so deaths reach delta
+    /// clients, and to false otherwise.
+    pub include_dead: Option<bool>,
+    /// Reduce each service to its most preferred address
+    #[serde(default)]
+    pub single_address: bool,
+    /// Page size; paged listings are ordered by instance name
+    pub limit: Option<usize>,
+    /// Services to skip before the page starts
+    pub offset: Option<usize>,
+    /// `X-Next-Cursor` from the previous page
+    pub cursor: Option<String>,
+    /// `txt.<key>=<value>` parameters, see `txt_filters`
+    #[serde(skip)]
+    pub txt: Vec<(String, String)>,
+}
+
+impl ServiceQuery {
+    fn filter(&self) -> ServiceFilter {
+        ServiceFilter {
+            service_type: self.service_type.clone(),
+            address: self.address,
+            has_txt: self.has_txt.clone(),
+            txt: self.txt.clone(),
+            subtype: self.subtype.clone(),
+            iface: None,
+            alive_only: !self.include_dead.unwrap_or(self.since_generation.is_some()),
+        }
+    }
+
+    /// True if `service` passes every filter in the query
+    fn matches(&self, service: &ServiceEntry) -> bool {
+        self.filter().matches(service)
+    }
+
+    /// The requested page, or None for an unpaged listing
+    fn page(&self) -> Result<Option<Page>, StatusCode> {
+        if self.limit.is_none() && self.offset.is_none() && self.cursor.is_none() {
+            return Ok(None);
+        }
+        let after = self
+            .cursor
+            .as_deref()
+            .map(decode_cursor)
+            .transpose()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        Ok(Some(Page {
+            limit: self.limit,
+            offset: self.offset.unwrap_or(0),
+            after,
+        }))
+    }
+}
+
+/// Collect `txt.<key>=<value>` pairs from a query string. Keys are dynamic,
+/// so they can't be fields of `ServiceQuery`.
+fn txt_filters(raw_query: Option<&str>) -> Result<Vec<(String, String)>, StatusCode> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(raw_query.unwrap_or_default())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(pairs
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix("txt.")?.to_string(), value)))
+        .collect())
+}
+
+/// Cursors are hex-encoded instance names, so they're always header-safe
+fn encode_cursor(instance_name: &str) -> String {
+    hex::encode(instance_name)
+}
+
+fn decode_cursor(cursor: &str) -> anyhow::Result<String> {
+    Ok(String::from_utf8(hex::decode(cursor)?)?)
+}
+
+/// Response header with the number of services matching across all pages
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+/// Response header with the cursor for the next page, absent on the last one
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HashQuery {
+    /// Long-poll: wait up to this many seconds for the hash to differ from `current`
+    pub wait: Option<u64>,
+    /// The hash the client already has
+    pub current: Option<String>,
+    /// Hash only the services of this type, e.g. "_http._tcp"
+    #[serde(rename = "type")]
+    pub service_type: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MerkleQuery {
+    /// Hex digits leading to the node; the root if absent
+    pub node: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MerkleResponse {
+    /// Generation the node was read at. Compare nodes of one generation;
+    /// if it moves mid-walk, start again from the root.
+    pub generation: u64,
+    #[serde(flatten)]
+    pub node: MerkleNode,
+}
+
+/// Upper bound on `?wait=`, so a client can't hold a connection indefinitely
+const MAX_HASH_WAIT_SECS: u64 = 300;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SeqQuery {
+    /// Sequence number from the previous `/v1/changes` response; 0 for everything
+    #[serde(default)]
+    pub since: u64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangesQuery {
+    /// Replay changes after this generation before following live changes.
+    /// A `Last-Event-ID` header takes precedence, for reconnects.
+    pub since: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscribeQuery {
+    /// Only send changes to services of this type
+    #[serde(rename = "type")]
+    pub service_type: Option<String>,
+    /// Replay changes after this generation first, as for `/v1/changes/stream`
+    pub since: Option<u64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeleteQuery {
+    /// Remove the entry instead of marking it dead
+    #[serde(default)]
+    pub purge: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    /// Text to find in instance names, hostnames, and TXT values (3+ characters)
+    pub q: String,
+    /// Most results to return
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    50
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExistsQuery {
+    /// Whether a dead service counts as existing, as it does for GET
+    #[serde(default = "default_include_dead")]
+    pub include_dead: bool,
+}
+
+fn default_include_dead() -> bool {
+    true
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WarmupQuery {
+    /// How long to wait for warmup before giving up
+    #[serde(default = "default_warmup_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_warmup_timeout() -> u64 {
+    30
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WarmupResponse {
+    /// Alive services in the cache when warmup completed
+    pub services: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReadyResponse {
+    /// The first discovery cycle has completed and the daemon is healthy
+    pub ready: bool,
+    /// The cache is being served from memory because the database is unwritable
+    pub degraded: bool,
+    #[serde(flatten)]
+    pub health: HealthResponse,
 }
 
+#[derive(Serialize, ToSchema)]
+pub struct HealthResponse {
+    /// The cache thread answered a ping
+    pub cache: bool,
+    /// The mDNS daemon reports itself running
+    pub mdns: bool,
+}
+
+impl HealthResponse {
+    fn healthy(&self) -> bool {
+        self.cache && self.mdns
+    }
+}
+
+/// How long each health probe check may take before counting as failed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, ToSchema)]
+pub struct StatsResponse {
+    pub services: usize,
+    pub alive_services: usize,
+    pub generation: u64,
+    pub degraded: bool,
+    /// Database writes that have failed in a row
+    pub db_write_failures: u32,
+    pub maintenance_running: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SummaryResponse {
+    pub generation: u64,
+    pub hash: String,
+    pub hash_version: u32,
+    /// Root of the tree served by `/v1/services/merkle`
+    pub merkle_root: String,
+    /// Each cached service type's hash and counts
+    pub types: BTreeMap<String, TypeSummary>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TypeSummary {
+    /// What `/v1/services/hash?type=` returns for the type
+    pub hash: String,
+    pub services: usize,
+    pub alive_services: usize,
+}
+
+/// Borrowing twin of `shared::types::Snapshot`, which clients decode it into
+#[derive(Serialize)]
+pub struct SnapshotResponse<'a> {
+    pub generation: u64,
+    pub hash: &'a str,
+    pub services: &'a [ServiceEntry],
+}
+
+/// Response header carrying the cache generation a listing was read at
+pub const GENERATION_HEADER: &str = "x-cache-generation";
+
 pub fn router(state: AppState) -> Router {
-    Router::new()
+    let router = Router::new()
         .route("/v1/config", get(get_config))
-        .route("/v1/services", get(get_services))
+        .route("/v1/prefix", get(get_prefix))
+        .route("/v1/warmup", get(get_warmup))
+        .route("/v1/rejected", get(get_rejected))
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .route("/v1/readyz", get(get_readyz))
+        .route("/v1/stats", get(get_stats))
+        .route("/metrics", get(get_metrics))
+        .route("/v1/admin/config-source", get(get_config_source))
+        .route("/v1/admin/reload", post(reload_config))
+        .route("/v1/admin/reload-filters", post(reload_filters))
+        .route("/v1/admin/browse", post(browse_type))
+        .route("/v1/admin/backup", post(backup_database))
+        .route("/v1/admin/import", post(import_services))
+        .route("/v1/services", get(get_services).post(register_service))
         .route("/v1/services/hash", get(get_hash))
-        .route("/v1/services/:instance", get(get_service))
+        .route("/v1/services/hash/stream", get(stream_hash))
+        .route("/v1/services/summary", get(get_summary))
+        .route("/v1/services/merkle", get(get_merkle_node))
+        .route("/v1/changes", get(get_changes))
+        .route("/v1/changes/stream", get(stream_changes))
+        .route("/v1/events/stream", get(stream_events))
+        .route("/v1/ws", get(subscribe_ws))
+        .route("/v1/search", get(search_services))
+        .route("/v1/snapshot", get(get_snapshot))
+        .route("/v1/export/zone", get(export_zone))
+        .route("/v1/export/dnsmasq", get(export_dnsmasq))
+        .route("/v1/export/unbound", get(export_unbound))
+        .route("/v1/export/hosts", get(export_hosts))
+        .route("/v1/export/json", get(export_json))
+        .route(
+            "/v1/services/:instance",
+            get(get_service).head(head_service).delete(delete_service),
+        )
+        .route("/v1/services/:instance/history", get(get_service_history))
+        .route("/v1/interfaces/:iface/services", get(get_interface_services))
+        .merge(openapi::router());
+    let router = if state.api_config.grpc {
+        router.route_service(&format!("{}*rpc", grpc::GRPC_PREFIX), grpc_service(&state))
+    } else {
+        router
+    };
+    let router = if state.api_config.compression {
+        router.layer(CompressionLayer::new())
+    } else {
+        router
+    };
+    router
+        .layer(middleware::from_fn_with_state(state.clone(), auth::require_token))
         .with_state(state)
 }
 
+fn grpc_service(state: &AppState) -> grpc::AuthorityServer<grpc::AuthorityService> {
+    grpc::AuthorityServer::new(grpc::AuthorityService {
+        cache: state.cache.clone(),
+        snapshot_rx: state.snapshot_rx.clone(),
+        min_interval: Duration::from_millis(state.api_config.sse_min_interval_ms),
+        shutdown: state.shutdown.clone(),
+    })
+}
+
+#[utoipa::path(get, path = "/v1/config", tag = "status",
+    responses((status = 200, description = "Authority metadata", body = ConfigResponse)))]
 async fn get_config(State(state): State<AppState>) -> Json<ConfigResponse> {
     // Fix #1: use pre-computed api_port from AppState
     Json(ConfigResponse {
         zone: state.config.zone.clone(),
         prefix: state.config.prefix.clone(),
         api_port: state.api_port,
+        hash_version: HASH_VERSION,
+        hash_fields: (*state.snapshot_rx.borrow().hash_fields).clone(),
+    })
+}
+
+/// Which config file was loaded and which keys it set. There are no other
+/// config sources (environment, drop-ins) yet, so anything not listed is a default.
+#[utoipa::path(get, path = "/v1/admin/config-source", tag = "admin",
+    responses((status = 200, description = "Loaded config file and its explicit keys", body = ConfigSource)))]
+async fn get_config_source(State(state): State<AppState>) -> Json<ConfigSource> {
+    Json(state.reloader.source())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReloadResponse {
+    /// Dotted keys whose new values took effect; empty if nothing changed
+    pub applied: Vec<String>,
+}
+
+/// Re-read the config file, as SIGHUP does. Changes to cache retention and
+/// timings, `[browser]` patterns, and `logging.level` take effect; a change
+/// to any other key needs a restart, and rejects the reload as a whole.
+#[utoipa::path(post, path = "/v1/admin/reload", tag = "admin",
+    responses(
+        (status = 200, description = "Config reloaded", body = ReloadResponse),
+        (status = 409, description = "Keys changed that need a restart; nothing was reloaded", body = String),
+        (status = 422, description = "The config file is invalid; nothing was reloaded", body = String),
+    ))]
+async fn reload_config(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientIdentity>>,
+) -> Result<Json<ReloadResponse>, (StatusCode, String)> {
+    tracing::info!("Config reload requested{}", requested_by(&client));
+    let applied = state.reloader.reload().await.map_err(|e| {
+        tracing::warn!("Keeping current config: {:#}", e);
+        let status = if e.is::<RestartRequired>() {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::UNPROCESSABLE_ENTITY
+        };
+        (status, format!("{:#}", e))
+    })?;
+    Ok(Json(ReloadResponse { applied }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReloadFiltersResponse {
+    /// Fully-qualified types browsed once the patterns were applied
+    pub active_types: Vec<String>,
+}
+
+/// Re-read `[browser]` alone from the config file and apply its type allow
+/// and deny lists, without the rest of a reload. Types asked for by name
+/// stay browsed.
+#[utoipa::path(post, path = "/v1/admin/reload-filters", tag = "admin",
+    responses(
+        (status = 200, description = "Patterns applied", body = ReloadFiltersResponse),
+        (status = 422, description = "The config file or a pattern is invalid; nothing was reloaded", body = String),
+        (status = 503, description = "The browser is not running", body = String),
+    ))]
+async fn reload_filters(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientIdentity>>,
+) -> Result<Json<ReloadFiltersResponse>, (StatusCode, String)> {
+    tracing::info!("Type filter reload requested{}", requested_by(&client));
+    let types = state.reloader.reload_browser().await.map_err(|e| {
+        tracing::warn!("Keeping current type filters: {:#}", e);
+        (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e))
+    })?;
+
+    let unavailable = || (StatusCode::SERVICE_UNAVAILABLE, "The browser is not running".to_string());
+    let (reply, rx) = oneshot::channel();
+    state
+        .browser
+        .send(BrowserCommand::SetTypes(types, Some(reply)))
+        .await
+        .map_err(|_| unavailable())?;
+    let active_types = rx.await.map_err(|_| unavailable())?;
+    Ok(Json(ReloadFiltersResponse { active_types }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BrowseRequest {
+    /// e.g. "_ipp._tcp"; ".local." is implied
+    pub service_type: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BrowseResponse {
+    /// The type as browsed, fully qualified
+    pub service_type: String,
+    /// False if the type was already browsed; it has been queried again
+    pub started: bool,
+}
+
+/// Browse a service type now, for devices that answer the DNS-SD meta-query
+/// poorly. `[browser]` patterns don't apply to types asked for by name.
+#[utoipa::path(post, path = "/v1/admin/browse", tag = "admin", request_body = BrowseRequest,
+    responses(
+        (status = 202, description = "Browsing", body = BrowseResponse),
+        (status = 400, description = "Not a service type", body = String),
+    ))]
+async fn browse_type(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientIdentity>>,
+    Json(request): Json<BrowseRequest>,
+) -> Result<(StatusCode, Json<BrowseResponse>), (StatusCode, String)> {
+    let service_type = full_service_type(&request.service_type)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("{} is not a service type", request.service_type)))?;
+
+    tracing::info!("Browse of {} requested{}", service_type, requested_by(&client));
+    let unavailable = || (StatusCode::SERVICE_UNAVAILABLE, "The browser is not running".to_string());
+    let (reply, rx) = oneshot::channel();
+    state
+        .browser
+        .send(BrowserCommand::Browse(service_type.clone(), reply))
+        .await
+        .map_err(|_| unavailable())?;
+    let started = rx.await.map_err(|_| unavailable())?;
+    Ok((StatusCode::ACCEPTED, Json(BrowseResponse { service_type, started })))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BackupResponse {
+    /// The backup file, on the daemon's host
+    pub path: String,
+    /// Change sequence number the backup is at
+    pub seq: u64,
+}
+
+/// Snapshot the database into `[cache] backup_dir` without stopping the
+/// daemon. Restore with `--restore <path>` at startup.
+#[utoipa::path(post, path = "/v1/admin/backup", tag = "admin",
+    responses(
+        (status = 201, description = "Backup written", body = BackupResponse),
+        (status = 503, description = "Cache is served from memory; the database is behind", body = String),
+    ))]
+async fn backup_database(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientIdentity>>,
+) -> Result<(StatusCode, Json<BackupResponse>), (StatusCode, String)> {
+    let path = state
+        .backup_dir
+        .join(format!("services-{}.db", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    let seq = state.cache.backup(path.clone()).await.map_err(|e| {
+        if e.is::<DatabaseDegraded>() {
+            return (StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+        }
+        tracing::error!("Backup failed: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    })?;
+    tracing::info!("Backed up the database to {}{}", path.display(), requested_by(&client));
+    Ok((StatusCode::CREATED, Json(BackupResponse { path: path.display().to_string(), seq })))
+}
+
+/// `"_ipp._tcp"` or `"_ipp._tcp.local."` as mdns-sd browses it
+fn full_service_type(service_type: &str) -> Option<String> {
+    let trimmed = service_type.trim_end_matches('.');
+    let full = match trimmed.strip_suffix(".local") {
+        Some(_) => format!("{}.", trimmed),
+        None => format!("{}.local.", trimmed),
+    };
+    let name = InstanceName::with_type("x", &full)?;
+    (name.full_type() == full && name.domain == "local").then_some(full)
+}
+
+#[utoipa::path(get, path = "/v1/prefix", tag = "status",
+    responses((status = 200, description = "The subnet prefix", body = PrefixResponse)))]
+async fn get_prefix(
+    State(state): State<AppState>,
+) -> Result<Json<PrefixResponse>, (StatusCode, String)> {
+    let prefix = state.config.prefix_net().map_err(|e| {
+        tracing::error!("Cannot describe prefix: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    })?;
+
+    Ok(Json(PrefixResponse {
+        prefix: prefix.trunc().to_string(),
+        network: prefix.network(),
+        last_address: prefix.broadcast(),
+        prefix_len: prefix.prefix_len(),
+    }))
+}
+
+/// Block until the first discovery cycle completes, or 503 after the timeout
+#[utoipa::path(get, path = "/v1/warmup", tag = "status", params(WarmupQuery),
+    responses(
+        (status = 200, description = "Warmed up", body = WarmupResponse),
+        (status = 503, description = "Not warmed up within the timeout"),
+    ))]
+async fn get_warmup(
+    State(state): State<AppState>,
+    Query(params): Query<WarmupQuery>,
+) -> Result<Json<WarmupResponse>, StatusCode> {
+    let mut warm_rx = state.warm_rx.clone();
+    let timeout = Duration::from_secs(params.timeout_secs);
+
+    match tokio::time::timeout(timeout, warm_rx.wait_for(|warm| *warm)).await {
+        Ok(Ok(_)) => {}
+        // The browser exited before warming up
+        Ok(Err(_)) | Err(_) => return Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+
+    let services = state.snapshot_rx.borrow().services.iter().filter(|s| s.alive).count();
+    Ok(Json(WarmupResponse { services }))
+}
+
+#[utoipa::path(get, path = "/v1/rejected", tag = "status",
+    responses((status = 200, description = "Recently rejected services, newest first", body = Vec<RejectedService>)))]
+async fn get_rejected(State(state): State<AppState>) -> Json<Vec<RejectedService>> {
+    Json(state.rejected.recent())
+}
+
+async fn check_health(state: &AppState) -> HealthResponse {
+    let (cache, mdns) = tokio::join!(
+        state.cache.ping(PROBE_TIMEOUT),
+        mdns::interfaces::daemon_alive(&state.mdns_daemon, PROBE_TIMEOUT),
+    );
+    HealthResponse { cache, mdns }
+}
+
+/// 503 if the cache thread or mDNS daemon has stopped responding
+#[utoipa::path(get, path = "/healthz", tag = "status", security(()),
+    responses(
+        (status = 200, description = "Healthy", body = HealthResponse),
+        (status = 503, description = "A component stopped responding", body = HealthResponse),
+    ))]
+async fn get_healthz(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    let health = check_health(&state).await;
+    let status = if health.healthy() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+/// 503 until warmed up, or while unhealthy. A degraded cache still serves
+/// reads, so it stays ready.
+#[utoipa::path(get, path = "/readyz", tag = "status", security(()),
+    responses(
+        (status = 200, description = "Ready", body = ReadyResponse),
+        (status = 503, description = "Warming up or unhealthy", body = ReadyResponse),
+    ))]
+async fn get_readyz(State(state): State<AppState>) -> (StatusCode, Json<ReadyResponse>) {
+    let health = check_health(&state).await;
+    let ready = *state.warm_rx.borrow() && health.healthy();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let degraded = state.cache.health().is_degraded();
+    (status, Json(ReadyResponse { ready, degraded, health }))
+}
+
+#[utoipa::path(get, path = "/v1/stats", tag = "status",
+    responses((status = 200, description = "Cache statistics", body = StatsResponse)))]
+async fn get_stats(State(state): State<AppState>) -> Json<StatsResponse> {
+    let snapshot = state.snapshot_rx.borrow().clone();
+    let health = state.cache.health();
+    Json(StatsResponse {
+        services: snapshot.services.len(),
+        alive_services: snapshot.services.iter().filter(|s| s.alive).count(),
+        generation: snapshot.generation,
+        degraded: health.is_degraded(),
+        db_write_failures: health.consecutive_failures(),
+        maintenance_running: state.cache.is_maintenance_running(),
     })
 }
 
+#[utoipa::path(get, path = "/metrics", tag = "status",
+    responses((status = 200, description = "Prometheus text exposition", body = String, content_type = "text/plain")))]
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let snapshot = state.snapshot_rx.borrow().clone();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&snapshot, state.cache.health()),
+    )
+}
+
+#[utoipa::path(get, path = "/v1/services", tag = "services", params(ServiceQuery),
+    responses(
+        (status = 200, description = "Matching services. `txt.<key>=<value>` parameters filter by TXT value.",
+            content((Vec<ServiceEntry> = "application/json"), (Vec<ServiceEntry> = "application/cbor")),
+            headers(
+                ("x-cache-generation" = u64, description = "Generation the listing was read at"),
+                ("x-total-count" = usize, description = "Matches across all pages (paged requests only)"),
+                ("x-next-cursor" = String, description = "Cursor for the next page, absent on the last"),
+            )),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Malformed query or cursor"),
+    ))]
 async fn get_services(
     State(state): State<AppState>,
-    Query(params): Query<ServiceQuery>,
-) -> Result<Json<Vec<ServiceEntry>>, StatusCode> {
-    let services = if let Some(service_type) = params.service_type {
-        state.cache.get_by_type(service_type).await
-    } else {
-        state.cache.get_all().await
+    Query(mut params): Query<ServiceQuery>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    params.txt = txt_filters(raw_query.as_deref())?;
+    let encoding = Encoding::negotiate(&headers);
+    // Read the generation before querying so a concurrent change can only
+    // make the cached body newer than its tag, never older
+    let (generation, etag) = {
+        let snapshot = state.snapshot_rx.borrow();
+        (snapshot.generation, entity_tag(encoding, &snapshot.content_hash))
     };
+    if if_none_match(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    // Paged responses carry per-page headers, so they bypass the response cache
+    if let Some(page) = params.page()? {
+        return get_services_page(&state, &params, page, encoding, &etag).await;
+    }
+    let cache_key = format!("{}?{}", encoding.content_type(), raw_query.unwrap_or_default());
 
-    services
-        .map(Json)
+    if let Some(body) = state.response_cache.get(&cache_key, generation) {
+        return Ok(with_etag(encoded_response(encoding, generation, body), &etag));
+    }
+
+    if let Some(since) = params.since_generation {
+        // Incremental listings come from the snapshot so the generation and
+        // the entries are consistent with each other
+        let snapshot = state.snapshot_rx.borrow().clone();
+        let mut services: Vec<ServiceEntry> = snapshot
+            .changed_since(since)
+            .filter(|s| params.matches(s))
+            .cloned()
+            .collect();
+        if params.single_address {
+            keep_preferred_address(&state.address_preference, &mut services);
+        }
+        let body = encoding.serialize(&services)?;
+        state.response_cache.insert(cache_key, snapshot.generation, body.clone());
+        let etag = entity_tag(encoding, &snapshot.content_hash);
+        return Ok(with_etag(encoded_response(encoding, snapshot.generation, body), &etag));
+    }
+
+    // Every filter is applied by the store, in SQL when the database is up
+    let mut services = state
+        .cache
+        .query(params.filter(), Page::default())
+        .await
         .map_err(|e| {
             tracing::error!("Failed to query services: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .services;
+    if params.single_address {
+        keep_preferred_address(&state.address_preference, &mut services);
+    }
+
+    let body = encoding.serialize(&services)?;
+    state.response_cache.insert(cache_key, generation, body.clone());
+    Ok(with_etag(encoded_response(encoding, generation, body), &etag))
+}
+
+/// ETag for a listing: the snapshot's content hash, qualified by encoding
+/// since JSON and CBOR bodies differ. Any filtered view is unchanged while
+/// the content hash is. The client-facing hash won't do, as it may leave out
+/// fields the listing shows.
+fn entity_tag(encoding: Encoding, hash: &str) -> String {
+    match encoding {
+        Encoding::Json => format!("\"{}\"", hash),
+        Encoding::Cbor => format!("\"{}-cbor\"", hash),
+    }
+}
+
+/// Whether `If-None-Match` lists `etag` (weakly compared) or is `*`
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+fn with_etag(mut response: Response, etag: &str) -> Response {
+    if let Ok(value) = header::HeaderValue::from_str(etag) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    response
+}
+
+async fn get_services_page(
+    state: &AppState,
+    params: &ServiceQuery,
+    page: Page,
+    encoding: Encoding,
+    etag: &str,
+) -> Result<Response, StatusCode> {
+    let (generation, mut result) = match params.since_generation {
+        Some(since) => {
+            let snapshot = state.snapshot_rx.borrow().clone();
+            let result = query_in_memory(snapshot.changed_since(since), &params.filter(), &page);
+            (snapshot.generation, result)
+        }
+        None => {
+            let generation = state.snapshot_rx.borrow().generation;
+            let result = state.cache.query(params.filter(), page).await.map_err(|e| {
+                tracing::error!("Failed to query services: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            (generation, result)
+        }
+    };
+    if params.single_address {
+        keep_preferred_address(&state.address_preference, &mut result.services);
+    }
+
+    let ServicePage { services, total, next } = result;
+    let mut response = with_etag(encoded_response(encoding, generation, encoding.serialize(&services)?), etag);
+    let headers = response.headers_mut();
+    headers.insert(header::HeaderName::from_static(TOTAL_COUNT_HEADER), total.into());
+    if let Some(next) = next {
+        if let Ok(value) = header::HeaderValue::from_str(&encode_cursor(&next)) {
+            headers.insert(header::HeaderName::from_static(NEXT_CURSOR_HEADER), value);
+        }
+    }
+    Ok(response)
+}
+
+fn keep_preferred_address(preference: &AddressPreference, services: &mut [ServiceEntry]) {
+    for service in services {
+        service.addresses = preference.preferred(&service.addresses).into_iter().collect();
+    }
+}
+
+fn encoded_response(encoding: Encoding, generation: u64, body: Bytes) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, encoding.content_type().to_string()),
+            (header::VARY, "accept".to_string()),
+            (header::HeaderName::from_static(GENERATION_HEADER), generation.to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// The whole cache at a single generation, for clients starting an
+/// incremental sync with `?since_generation=`
+#[utoipa::path(get, path = "/v1/snapshot", tag = "sync",
+    responses((status = 200, description = "The whole cache at one generation",
+        content((Snapshot = "application/json"), (Snapshot = "application/cbor")))))]
+async fn get_snapshot(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let encoding = Encoding::negotiate(&headers);
+    let snapshot = state.snapshot_rx.borrow().clone();
+    let body = encoding.serialize(&SnapshotResponse {
+        generation: snapshot.generation,
+        hash: &snapshot.hash,
+        services: &snapshot.services,
+    })?;
+    Ok(encoded_response(encoding, snapshot.generation, body))
+}
+
+/// The zone the embedded DNS server serves, as a BIND zone file, whether or
+/// not the server runs. The serial is the export time.
+#[utoipa::path(get, path = "/v1/export/zone", tag = "export",
+    responses((status = 200, description = "RFC 1035 master file of the alive services",
+        content_type = "text/dns", body = String)))]
+async fn export_zone(State(state): State<AppState>) -> impl IntoResponse {
+    let services = state.snapshot_rx.borrow().services.clone();
+    let serial = Utc::now().timestamp() as u32;
+    (
+        [(header::CONTENT_TYPE, "text/dns")],
+        export::zone_file(&services, &state.zone, serial),
+    )
+}
+
+/// The zone's hosts as dnsmasq `host-record` lines, for an
+/// `/etc/dnsmasq.d/` file
+#[utoipa::path(get, path = "/v1/export/dnsmasq", tag = "export",
+    responses((status = 200, description = "dnsmasq configuration for the alive services' hosts",
+        content_type = "text/plain", body = String)))]
+async fn export_dnsmasq(State(state): State<AppState>) -> impl IntoResponse {
+    let services = state.snapshot_rx.borrow().services.clone();
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], export::dnsmasq(&services, &state.zone))
+}
+
+/// The zone's hosts as an unbound `server:` clause of `local-data`, for an
+/// `include:` file
+#[utoipa::path(get, path = "/v1/export/unbound", tag = "export",
+    responses((status = 200, description = "unbound configuration for the alive services' hosts",
+        content_type = "text/plain", body = String)))]
+async fn export_unbound(State(state): State<AppState>) -> impl IntoResponse {
+    let services = state.snapshot_rx.borrow().services.clone();
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], export::unbound(&services, &state.zone))
+}
+
+/// The zone's hosts in `/etc/hosts` format, for scripts and containers
+/// without a resolver to point at
+#[utoipa::path(get, path = "/v1/export/hosts", tag = "export",
+    responses((status = 200, description = "Hosts file lines for the alive services' hosts",
+        content_type = "text/plain", body = String)))]
+async fn export_hosts(State(state): State<AppState>) -> impl IntoResponse {
+    let services = state.snapshot_rx.borrow().services.clone();
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], export::hosts_file(&services, &state.zone))
+}
+
+/// Every cached service, dead ones included, for `/v1/admin/import` or
+/// `--import-json` on another host
+#[utoipa::path(get, path = "/v1/export/json", tag = "export",
+    responses((status = 200, description = "The whole cache", body = CacheExport)))]
+async fn export_json(State(state): State<AppState>) -> Json<CacheExport> {
+    let services = state.snapshot_rx.borrow().services.clone();
+    Json(CacheExport { exported_at: Utc::now(), services: services.to_vec() })
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ImportQuery {
+    /// Delete cached services missing from the import
+    #[serde(default)]
+    pub replace: bool,
+}
+
+/// Load a `/v1/export/json` document. Entries keep their source and
+/// timestamps, so imported mDNS services still expire unless heard again.
+#[utoipa::path(post, path = "/v1/admin/import", tag = "admin", params(ImportQuery), request_body = CacheExport,
+    responses(
+        (status = 204, description = "Imported"),
+        (status = 409, description = "An instance is cached under another service type", body = String),
+    ))]
+async fn import_services(
+    State(state): State<AppState>,
+    Query(params): Query<ImportQuery>,
+    client: Option<Extension<ClientIdentity>>,
+    Json(export): Json<CacheExport>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    tracing::info!(
+        "Importing {} service(s){}{}",
+        export.services.len(),
+        if params.replace { ", replacing the cache" } else { "" },
+        requested_by(&client)
+    );
+    state.cache.import(export.services, params.replace).await.map_err(|e| {
+        if let Some(conflict) = e.downcast_ref::<TypeConflictError>() {
+            return (StatusCode::CONFLICT, conflict.to_string());
+        }
+        tracing::error!("Failed to import services: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to import services".to_string())
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Services added, updated, and removed after a persisted sequence number.
+/// 503 while the cache is served from memory, since changes then aren't logged.
+#[utoipa::path(get, path = "/v1/changes", tag = "sync", params(SeqQuery),
+    responses(
+        (status = 200, description = "Changes after `since`",
+            content((ChangeSet = "application/json"), (ChangeSet = "application/cbor"))),
+        (status = 503, description = "Cache is served from memory; no change log"),
+    ))]
+async fn get_changes(
+    State(state): State<AppState>,
+    Query(params): Query<SeqQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let changes = state.cache.changes_since(params.since).await.map_err(|e| {
+        if e.is::<DatabaseDegraded>() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        tracing::error!("Failed to query changes: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Encoding::negotiate(&headers).respond(&changes)
+}
+
+/// Manually register a service that isn't advertised via mDNS
+#[utoipa::path(post, path = "/v1/services", tag = "services", request_body = ServiceEntry,
+    responses(
+        (status = 201, description = "Registered"),
+        (status = 400, description = "Malformed instance name, or address outside the prefix", body = String),
+        (status = 403, description = "Registration is disabled", body = String),
+        (status = 409, description = "Instance is cached under another service type", body = String),
+    ))]
+async fn register_service(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientIdentity>>,
+    Json(mut entry): Json<ServiceEntry>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !state.api_config.allow_registration {
+        return Err((StatusCode::FORBIDDEN, "Service registration is disabled".to_string()));
+    }
+    canonicalize_names(&mut entry).map_err(|msg| (StatusCode::BAD_REQUEST, msg))?;
+
+    let prefix = state.config.prefix_net().map_err(|e| {
+        tracing::error!("Cannot validate registration: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    })?;
+
+    if let Err(msg) = check_addresses_in_prefix(&prefix, &entry.addresses) {
+        if state.api_config.strict_prefix {
+            return Err((StatusCode::BAD_REQUEST, msg));
+        }
+        tracing::warn!("Registering {} anyway: {}", entry.instance_name, msg);
+    }
+
+    // Registration counts as a sighting; the client's timestamps aren't trusted
+    let now = Utc::now();
+    entry.first_seen = now;
+    entry.last_seen = now;
+    entry.alive = true;
+    // Nothing re-announces a registered service, so keep it out of staleness
+    // and pruning until it is explicitly removed
+    entry.source = ServiceSource::Static;
+
+    tracing::info!("Registering service {}{}", entry.instance_name, requested_by(&client));
+    state.cache.upsert(entry).await.map_err(|e| {
+        if let Some(conflict) = e.downcast_ref::<TypeConflictError>() {
+            return (StatusCode::CONFLICT, conflict.to_string());
+        }
+        tracing::error!("Failed to register service: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to register service".to_string())
+    })?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Rewrite a registration's names into the form browsed services are cached
+/// under, so the same instance can't be stored twice under different
+/// spellings. `service_type` may omit the domain but must match the name.
+fn canonicalize_names(entry: &mut ServiceEntry) -> Result<(), String> {
+    let name = InstanceName::parse(&entry.instance_name)
+        .ok_or_else(|| format!("{:?} is not a DNS-SD instance name", entry.instance_name))?;
+    let full_type = name.full_type();
+    let given = entry.service_type.trim_end_matches('.');
+    if given != name.service_type && given != full_type.trim_end_matches('.') {
+        return Err(format!("Service type {:?} does not match instance {}", entry.service_type, name));
+    }
+    entry.instance_name = name.to_string();
+    entry.service_type = full_type;
+    Ok(())
+}
+
+/// Check that every address falls within the subnet prefix, naming the first
+/// one that doesn't.
+fn check_addresses_in_prefix(prefix: &Ipv6Net, addresses: &[IpAddr]) -> Result<(), String> {
+    match addresses.iter().find(|addr| !matches!(addr, IpAddr::V6(v6) if prefix.contains(v6))) {
+        Some(addr) => Err(format!("Address {} is outside prefix {}", addr, prefix)),
+        None => Ok(()),
+    }
+}
+
+/// The cache hash, or with `?type=` the hash of that type's services alone.
+/// With `?wait=N&current=H`, returns as soon as the hash differs from H, or
+/// after N seconds with the (unchanged) current hash.
+#[utoipa::path(get, path = "/v1/services/hash", tag = "sync", params(HashQuery),
+    responses((status = 200, description = "Current cache hash", body = String, content_type = "text/plain")))]
+async fn get_hash(State(state): State<AppState>, Query(params): Query<HashQuery>) -> String {
+    let hash_of = |snapshot: &CacheSnapshot| match &params.service_type {
+        Some(service_type) => snapshot.type_hash(service_type),
+        None => snapshot.hash.clone(),
+    };
+    if let (Some(wait), Some(current)) = (params.wait, &params.current) {
+        let timeout = Duration::from_secs(wait.min(MAX_HASH_WAIT_SECS));
+        let mut rx = state.snapshot_rx.clone();
+        tokio::select! {
+            _ = state.shutdown.cancelled() => {}
+            _ = tokio::time::timeout(timeout, rx.wait_for(|s| hash_of(s) != *current)) => {}
+        }
+    }
+    hash_of(&state.snapshot_rx.borrow().clone())
+}
+
+/// The cache hash alongside each service type's, so a client following a
+/// few types can tell which of them changed in one request
+#[utoipa::path(get, path = "/v1/services/summary", tag = "sync",
+    responses((status = 200, description = "Cache and per-type hashes", body = SummaryResponse)))]
+async fn get_summary(State(state): State<AppState>) -> Json<SummaryResponse> {
+    Json(summarize(&state.snapshot_rx.borrow().clone()))
+}
+
+/// One node of the Merkle tree over the entry digests. Walking down from the
+/// root through children whose hashes differ from another copy's finds the
+/// instances that differ, listed with their digests at the leaves.
+#[utoipa::path(get, path = "/v1/services/merkle", tag = "sync", params(MerkleQuery),
+    responses(
+        (status = 200, description = "The node, its children's hashes or a leaf's entries", body = MerkleResponse),
+        (status = 400, description = "`node` isn't a path in the tree"),
+    ))]
+async fn get_merkle_node(
+    State(state): State<AppState>,
+    Query(params): Query<MerkleQuery>,
+) -> Result<Json<MerkleResponse>, (StatusCode, String)> {
+    let snapshot = state.snapshot_rx.borrow().clone();
+    let path = params.node.unwrap_or_default();
+    let node = snapshot.merkle().node(&path).ok_or_else(|| {
+        (StatusCode::BAD_REQUEST, format!("{} is not a node: expected up to {} hex digits", path, merkle::DEPTH))
+    })?;
+    Ok(Json(MerkleResponse { generation: snapshot.generation, node }))
+}
+
+fn summarize(snapshot: &CacheSnapshot) -> SummaryResponse {
+    let mut types: BTreeMap<String, TypeSummary> = snapshot
+        .type_hashes()
+        .iter()
+        .map(|(service_type, hash)| {
+            (service_type.clone(), TypeSummary { hash: hash.clone(), services: 0, alive_services: 0 })
         })
+        .collect();
+    for service in snapshot.services.iter() {
+        if let Some(summary) = types.get_mut(&service.service_type) {
+            summary.services += 1;
+            summary.alive_services += usize::from(service.alive);
+        }
+    }
+    SummaryResponse {
+        generation: snapshot.generation,
+        hash: snapshot.hash.clone(),
+        hash_version: HASH_VERSION,
+        merkle_root: snapshot.merkle().root(),
+        types,
+    }
+}
+
+/// Server-sent `hash` events carrying the cache hash, with the generation as
+/// the event id. Throttled per subscriber; intermediate hashes may be skipped.
+#[utoipa::path(get, path = "/v1/services/hash/stream", tag = "sync",
+    responses((status = 200, description = "Server-sent `hash` events", content_type = "text/event-stream")))]
+async fn stream_hash(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let min_interval = Duration::from_millis(state.api_config.sse_min_interval_ms);
+    let events = sse::throttled(
+        state.snapshot_rx.clone(),
+        min_interval,
+        state.shutdown.clone(),
+        |snapshot| {
+            Event::default()
+                .event("hash")
+                .id(snapshot.generation.to_string())
+                .data(&snapshot.hash)
+        },
+    );
+    Sse::new(events.map(Ok)).keep_alive(KeepAlive::default())
+}
+
+/// Server-sent `changes` events, each a JSON list of the services changed
+/// since the previous event, with the generation as the event id, after a
+/// `removed` event carrying the instance name of each service removed since.
+/// Starts by replaying everything after `since`; a cursor ahead of the cache,
+/// or too far behind, gets a `resync` event instead, meaning fetch
+/// `/v1/snapshot` and carry on.
+#[utoipa::path(get, path = "/v1/changes/stream", tag = "sync", params(ChangesQuery),
+    responses((status = 200, description = "Server-sent `changes`, `removed` and `resync` events", content_type = "text/event-stream")))]
+async fn stream_changes(
+    State(state): State<AppState>,
+    Query(params): Query<ChangesQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let mut cursor = sse::ChangesCursor::new(last_event_id.or(params.since).unwrap_or(0));
+
+    let min_interval = Duration::from_millis(state.api_config.sse_min_interval_ms);
+    let events = sse::throttled(
+        state.snapshot_rx.clone(),
+        min_interval,
+        state.shutdown.clone(),
+        move |snapshot| match cursor.next(snapshot) {
+            sse::ChangesUpdate::Resync { generation } => vec![Ok(Event::default()
+                .event("resync")
+                .id(generation.to_string())
+                .data(generation.to_string()))],
+            sse::ChangesUpdate::Changes { generation, services, removed } => removed
+                .into_iter()
+                .map(|instance_name| Ok(Event::default().event("removed").id(generation.to_string()).data(instance_name)))
+                .chain([Event::default().event("changes").id(generation.to_string()).json_data(services)])
+                .collect(),
+        },
+    );
+    Sse::new(events.flat_map(futures::stream::iter)).keep_alive(KeepAlive::default())
 }
 
-async fn get_hash(State(state): State<AppState>) -> String {
-    state.hash_rx.borrow().clone()
+/// Server-sent cache events as they happen: `added` and `updated` carrying
+/// the service, `removed` and `pruned` carrying its instance name. Nothing is
+/// replayed or collapsed. A client that falls behind gets a `lagged` event
+/// with the number of events it missed, and should resync from `/v1/snapshot`.
+#[utoipa::path(get, path = "/v1/events/stream", tag = "sync",
+    responses((status = 200, description = "Server-sent `added`, `updated`, `removed`, `pruned` and `lagged` events",
+        content_type = "text/event-stream")))]
+async fn stream_events(State(state): State<AppState>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = sse::cache_events(state.cache.subscribe(), state.shutdown.clone(), |event| match event {
+        Ok(CacheEvent::Added(service)) => Event::default().event("added").json_data(service),
+        Ok(CacheEvent::Updated(service)) => Event::default().event("updated").json_data(service),
+        Ok(CacheEvent::Removed(instance_name)) => Ok(Event::default().event("removed").data(instance_name)),
+        Ok(CacheEvent::Pruned(instance_name)) => Ok(Event::default().event("pruned").data(instance_name)),
+        Err(missed) => Ok(Event::default().event("lagged").data(missed.to_string())),
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
 }
 
+#[utoipa::path(get, path = "/v1/ws", tag = "sync", params(SubscribeQuery),
+    responses((status = 101, description = "WebSocket of JSON change messages tagged by `event`")))]
+async fn subscribe_ws(
+    State(state): State<AppState>,
+    Query(params): Query<SubscribeQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| run_subscription(socket, state, params))
+}
+
+/// Push JSON `ChangesUpdate` messages over a WebSocket until either side
+/// closes. Updates are throttled like the SSE streams; messages from the
+/// client other than close are ignored.
+async fn run_subscription(mut socket: WebSocket, state: AppState, params: SubscribeQuery) {
+    let min_interval = Duration::from_millis(state.api_config.sse_min_interval_ms);
+    let updates = sse::changes(
+        state.snapshot_rx.clone(),
+        min_interval,
+        state.shutdown.clone(),
+        params.since.unwrap_or(0),
+        params.service_type,
+        |update| serde_json::to_string(&update).ok(),
+    );
+    futures::pin_mut!(updates);
+
+    loop {
+        tokio::select! {
+            update = updates.next() => match update {
+                Some(text) => {
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Find services by partial instance name, hostname, or TXT value
+#[utoipa::path(get, path = "/v1/search", tag = "services", params(SearchQuery),
+    responses(
+        (status = 200, description = "Matching services",
+            content((Vec<ServiceEntry> = "application/json"), (Vec<ServiceEntry> = "application/cbor"))),
+        (status = 400, description = "Query shorter than 3 characters", body = String),
+    ))]
+async fn search_services(
+    State(state): State<AppState>,
+    Query(params): Query<SearchQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let q = params.q.trim();
+    if q.chars().count() < MIN_SEARCH_LEN {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Search needs at least {} characters", MIN_SEARCH_LEN),
+        ));
+    }
+
+    let services = state.cache.search(q.to_string(), params.limit).await.map_err(|e| {
+        tracing::error!("Failed to search services: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to search services".to_string())
+    })?;
+    Encoding::negotiate(&headers)
+        .respond(&services)
+        .map_err(|status| (status, "Failed to encode services".to_string()))
+}
+
+#[utoipa::path(get, path = "/v1/services/{instance}", tag = "services",
+    params(("instance" = String, Path, description = "Full instance name")),
+    responses(
+        (status = 200, description = "The service",
+            content((ServiceEntry = "application/json"), (ServiceEntry = "application/cbor"))),
+        (status = 404, description = "Not cached"),
+    ))]
 async fn get_service(
     State(state): State<AppState>,
     Path(instance): Path<String>,
-) -> Result<Json<ServiceEntry>, StatusCode> {
-    state
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let service = state
         .cache
         .get_one(instance)
         .await
@@ -85,6 +1335,459 @@ async fn get_service(
             tracing::error!("Failed to query service: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?
-        .map(Json)
-        .ok_or(StatusCode::NOT_FOUND)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Encoding::negotiate(&headers).respond(&service)
+}
+
+/// State transitions recorded for an instance, oldest first. Kept after the
+/// service leaves the cache, for `[cache] history_retention_secs`.
+#[utoipa::path(get, path = "/v1/services/{instance}/history", tag = "services",
+    params(("instance" = String, Path, description = "Full instance name")),
+    responses(
+        (status = 200, description = "The instance's history",
+            content((Vec<ServiceEvent> = "application/json"), (Vec<ServiceEvent> = "application/cbor"))),
+        (status = 404, description = "No history recorded"),
+        (status = 503, description = "Cache is served from memory; no history"),
+    ))]
+async fn get_service_history(
+    State(state): State<AppState>,
+    Path(instance): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let events = state.cache.history(instance).await.map_err(|e| {
+        if e.is::<DatabaseDegraded>() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        tracing::error!("Failed to query service history: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if events.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Encoding::negotiate(&headers).respond(&events)
+}
+
+/// Live services last heard announcing on one browsed interface, to tell
+/// which segment a service is on. Services not learned from mDNS
+/// announcements belong to no interface.
+#[utoipa::path(get, path = "/v1/interfaces/{iface}/services", tag = "services",
+    params(("iface" = String, Path, description = "Interface name, e.g. eth0")),
+    responses(
+        (status = 200, description = "Services heard on the interface",
+            content((Vec<ServiceEntry> = "application/json"), (Vec<ServiceEntry> = "application/cbor"))),
+        (status = 404, description = "mDNS is not browsed on the interface", body = String),
+    ))]
+async fn get_interface_services(
+    State(state): State<AppState>,
+    Path(iface): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    if !state.browsed_rx.borrow().contains(&iface) {
+        return Err((StatusCode::NOT_FOUND, format!("mDNS is not browsed on {}", iface)));
+    }
+    let filter = ServiceFilter { iface: Some(iface), alive_only: true, ..Default::default() };
+    let services = state.cache.query(filter, Page::default()).await.map_err(|e| {
+        tracing::error!("Failed to query services: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to query services".to_string())
+    })?;
+    Encoding::negotiate(&headers)
+        .respond(&services.services)
+        .map_err(|status| (status, "Failed to encode services".to_string()))
+}
+
+/// Existence check: 200 with headers only if the instance is cached, else 404
+#[utoipa::path(head, path = "/v1/services/{instance}", tag = "services",
+    params(("instance" = String, Path, description = "Full instance name"), ExistsQuery),
+    responses(
+        (status = 200, description = "Cached", headers(("x-cache-generation" = u64))),
+        (status = 404, description = "Not cached"),
+    ))]
+async fn head_service(
+    State(state): State<AppState>,
+    Path(instance): Path<String>,
+    Query(params): Query<ExistsQuery>,
+) -> Result<Response, StatusCode> {
+    let generation = state.snapshot_rx.borrow().generation;
+    let exists = state
+        .cache
+        .exists(instance, params.include_dead)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check service existence: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    if !exists {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok((
+        [(header::HeaderName::from_static(GENERATION_HEADER), generation.to_string())],
+        StatusCode::OK,
+    )
+        .into_response())
+}
+
+/// Mark a service dead now, or with `?purge=true` drop it from the cache.
+/// A service still advertising on mDNS comes back when it next announces.
+#[utoipa::path(delete, path = "/v1/services/{instance}", tag = "services",
+    params(("instance" = String, Path, description = "Full instance name"), DeleteQuery),
+    responses(
+        (status = 204, description = "Marked dead, or purged"),
+        (status = 403, description = "Registration is disabled", body = String),
+        (status = 404, description = "Not cached", body = String),
+    ))]
+async fn delete_service(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientIdentity>>,
+    Path(instance): Path<String>,
+    Query(params): Query<DeleteQuery>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !state.api_config.allow_registration {
+        return Err((StatusCode::FORBIDDEN, "Service registration is disabled".to_string()));
+    }
+
+    let internal = |e: anyhow::Error| {
+        tracing::error!("Failed to delete service: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to delete service".to_string())
+    };
+    let found = if params.purge {
+        state.cache.purge(instance.clone()).await.map_err(internal)?
+    } else {
+        let found = state.cache.exists(instance.clone(), true).await.map_err(internal)?;
+        if found {
+            state.cache.mark_dead(instance.clone()).await.map_err(internal)?;
+        }
+        found
+    };
+
+    if !found {
+        return Err((StatusCode::NOT_FOUND, format!("{} is not cached", instance)));
+    }
+    if params.purge {
+        tracing::info!("Purged service {}{}", instance, requested_by(&client));
+    } else {
+        tracing::info!("Marked service {} dead{}", instance, requested_by(&client));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// " by <identity>" for audit logs when the request came over mutual TLS
+fn requested_by(client: &Option<Extension<ClientIdentity>>) -> String {
+    client
+        .as_ref()
+        .map(|Extension(identity)| format!(" by {}", identity))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix() -> Ipv6Net {
+        "fd00:1234:5678:1::/64".parse().unwrap()
+    }
+
+    #[test]
+    fn test_addresses_in_prefix_accepted() {
+        let addresses = vec![
+            "fd00:1234:5678:1::10".parse().unwrap(),
+            "fd00:1234:5678:1:abcd::1".parse().unwrap(),
+        ];
+        assert!(check_addresses_in_prefix(&prefix(), &addresses).is_ok());
+    }
+
+    #[test]
+    fn test_address_outside_prefix_rejected() {
+        let addresses = vec![
+            "fd00:1234:5678:1::10".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ];
+        let err = check_addresses_in_prefix(&prefix(), &addresses).unwrap_err();
+        assert!(err.contains("2001:db8::1"), "Error should name the offending address: {}", err);
+    }
+
+    #[test]
+    fn test_registration_names_canonicalized() {
+        let entry = |instance_name: &str, service_type: &str| ServiceEntry {
+            service_type: service_type.to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: Vec::new(),
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+
+        for (instance_name, service_type) in [
+            ("web._http._tcp.local", "_http._tcp"),
+            ("web._http._tcp.local.", "_http._tcp.local"),
+            ("web._http._tcp.local.", "_http._tcp.local."),
+        ] {
+            let mut registered = entry(instance_name, service_type);
+            canonicalize_names(&mut registered).unwrap();
+            assert_eq!(registered.instance_name, "web._http._tcp.local.");
+            assert_eq!(registered.service_type, "_http._tcp.local.");
+        }
+
+        let err = canonicalize_names(&mut entry("web", "_http._tcp")).unwrap_err();
+        assert!(err.contains("not a DNS-SD instance name"), "{}", err);
+        let err = canonicalize_names(&mut entry("web._http._tcp.local.", "_ipp._tcp")).unwrap_err();
+        assert!(err.contains("does not match"), "{}", err);
+        assert!(canonicalize_names(&mut entry("web._http._tcp.local.", "_http._tcp.example")).is_err());
+    }
+
+    #[test]
+    fn test_full_service_type() {
+        assert_eq!(full_service_type("_ipp._tcp").as_deref(), Some("_ipp._tcp.local."));
+        assert_eq!(full_service_type("_ipp._tcp.local.").as_deref(), Some("_ipp._tcp.local."));
+        assert_eq!(full_service_type("_ipp._tcp.local").as_deref(), Some("_ipp._tcp.local."));
+        assert_eq!(full_service_type("printer._ipp._tcp"), None);
+        assert_eq!(full_service_type("_ipp"), None);
+        assert_eq!(full_service_type("_ipp._tcp.example.com"), None);
+    }
+
+    #[test]
+    fn test_has_txt_filter_combines_with_type() {
+        let mut service = ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: vec!["fd00:1234:5678:1::10".parse().unwrap()],
+            port: 80,
+            txt: std::collections::HashMap::from([("Path".to_string(), "/metrics".to_string())]),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+        let query = |service_type: Option<&str>, has_txt: &str| ServiceQuery {
+            service_type: service_type.map(str::to_string),
+            since_generation: None,
+            has_txt: Some(has_txt.to_string()),
+            subtype: None,
+            address: None,
+            include_dead: None,
+            single_address: false,
+            limit: None,
+            offset: None,
+            cursor: None,
+            txt: Vec::new(),
+        };
+
+        assert!(query(None, "path").matches(&service));
+        assert!(query(Some("_http._tcp"), "path").matches(&service));
+        assert!(!query(Some("_ssh._tcp"), "path").matches(&service));
+        assert!(!query(None, "version").matches(&service));
+
+        // Presence is what counts, not the value
+        service.txt.insert("path".to_string(), String::new());
+        assert!(query(None, "path").matches(&service));
+
+        // Dead services are listed only on request, or to delta clients
+        service.alive = false;
+        assert!(!query(None, "path").matches(&service));
+        assert!(ServiceQuery { include_dead: Some(true), ..query(None, "path") }.matches(&service));
+        assert!(ServiceQuery { since_generation: Some(3), ..query(None, "path") }.matches(&service));
+    }
+
+    #[test]
+    fn test_cbor_snapshot_decodes_into_shared_type() {
+        let body = Encoding::Cbor
+            .serialize(&SnapshotResponse { generation: 7, hash: "abc", services: &[] })
+            .unwrap();
+        let snapshot: shared::types::Snapshot = ciborium::from_reader(body.as_ref()).unwrap();
+        assert_eq!(snapshot.generation, 7);
+        assert_eq!(snapshot.hash, "abc");
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = entity_tag(Encoding::Json, "abc");
+        let matches = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+            if_none_match(&headers, &etag)
+        };
+
+        assert!(matches("\"abc\""));
+        assert!(matches("\"old\", W/\"abc\""));
+        assert!(matches("*"));
+        assert!(!matches("\"abc-cbor\""));
+        assert!(!if_none_match(&HeaderMap::new(), &etag));
+    }
+
+    #[test]
+    fn test_etag_follows_unhashed_fields() {
+        let service = ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: vec!["fd00:1234:5678:1::10".parse().unwrap()],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+        let fields: HashFields = serde_json::from_str(r#"["addresses"]"#).unwrap();
+        let mut snapshot = CacheSnapshot::new(vec![service.clone()], fields);
+        let etag = entity_tag(Encoding::Json, &snapshot.content_hash);
+
+        assert!(snapshot.update(vec![ServiceEntry { port: 8080, ..service }]));
+        assert_eq!(snapshot.generation, 1);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.parse().unwrap());
+        assert!(
+            !if_none_match(&headers, &entity_tag(Encoding::Json, &snapshot.content_hash)),
+            "a port change must not be answered with 304"
+        );
+    }
+
+    /// State for calling handlers directly, with the config loaded from
+    /// `config_path` and browser commands sent to `browser`
+    fn test_state(config_path: &std::path::Path, browser: mpsc::Sender<BrowserCommand>) -> AppState {
+        let config = crate::config::Config::load(config_path).unwrap();
+        let (snapshot_tx, snapshot_rx) = watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = crate::cache::db::CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &config.cache);
+        let reloader = Reloader::new(
+            config.clone(),
+            Default::default(),
+            cache.clone(),
+            watch::channel(config.cache.clone()).0,
+            browser.clone(),
+            Box::new(|_| Ok(())),
+        );
+        AppState {
+            cache,
+            snapshot_rx,
+            warm_rx: watch::channel(true).1,
+            rejected: Arc::new(RejectedRing::new(16)),
+            response_cache: Arc::new(ResponseCache::new(Duration::ZERO)),
+            config: Arc::new(config.authority.clone()),
+            api_config: Arc::new(config.api.clone()),
+            reloader: Arc::new(reloader),
+            backup_dir: Arc::new(config.cache.backup_dir.clone()),
+            address_preference: Arc::new(AddressPreference::new(
+                crate::addresses::default_preference(),
+                prefix(),
+            )),
+            zone: Arc::new(ZoneInfo::new(&config.authority.zone, "authority", &config.authority.address, 60, None).unwrap()),
+            metrics: Arc::new(Metrics::new(config.metrics.clone())),
+            mdns_daemon: ServiceDaemon::new().unwrap(),
+            browser,
+            browsed_rx: watch::channel(BTreeSet::from([config.authority.interface.clone()])).1,
+            shutdown: CancellationToken::new(),
+            api_port: 8080,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_filters_applies_browser_section() {
+        let path = std::env::temp_dir().join(format!("zerocomfy-reload-filters-{}.toml", std::process::id()));
+        let authority = "[authority]\ninterface = \"eth0\"\nprefix = \"fd00:1234:5678:1::/64\"\naddress = \"fd00:1234:5678:1::1\"\nzone = \"subnet.example\"\n";
+        std::fs::write(&path, authority).unwrap();
+        let (browser_tx, mut browser_rx) = mpsc::channel(4);
+        let state = test_state(&path, browser_tx);
+
+        // Only [browser] is taken up; the API port change waits for a restart
+        std::fs::write(
+            &path,
+            format!("{}[api]\nlisten = \"[::]:9999\"\n[browser]\ninclude_types = [\"_ipp*._tcp\"]\n", authority),
+        )
+        .unwrap();
+        let browser = tokio::spawn(async move {
+            let Some(BrowserCommand::SetTypes(types, Some(reply))) = browser_rx.recv().await else {
+                panic!("expected SetTypes with a reply");
+            };
+            assert!(types.allows("_ipps._tcp.local."));
+            assert!(!types.allows("_http._tcp.local."));
+            reply.send(vec!["_ipps._tcp.local.".to_string()]).unwrap();
+        });
+        let Json(response) = reload_filters(State(state.clone()), None).await.unwrap();
+        browser.await.unwrap();
+        assert_eq!(response.active_types, vec!["_ipps._tcp.local."]);
+        let err = state.reloader.reload().await.unwrap_err();
+        assert!(err.is::<RestartRequired>(), "only api.listen should be left to apply: {:#}", err);
+        assert_eq!(err.downcast_ref::<RestartRequired>().unwrap().0, vec!["api.listen"]);
+
+        std::fs::write(&path, format!("{}[browser]\ninclude_types = [\"[\"]\n", authority)).unwrap();
+        let result = reload_filters(State(state), None).await;
+        assert!(matches!(result, Err((StatusCode::UNPROCESSABLE_ENTITY, _))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interface_services() {
+        let path = std::env::temp_dir().join(format!("zerocomfy-iface-services-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[authority]\ninterface = \"eth0\"\nprefix = \"fd00:1234:5678:1::/64\"\naddress = \"fd00:1234:5678:1::1\"\nzone = \"subnet.example\"\n",
+        )
+        .unwrap();
+        let state = test_state(&path, mpsc::channel(1).0);
+        std::fs::remove_file(&path).unwrap();
+        let service = |name: &str, iface: Option<&str>| ServiceEntry {
+            service_type: "_http._tcp.local.".to_string(),
+            instance_name: format!("{}._http._tcp.local.", name),
+            hostname: format!("{}.local.", name),
+            addresses: vec!["fd00:1234:5678:1::10".parse().unwrap()],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: iface.map(str::to_string),
+        };
+        state.cache.upsert(service("web", Some("eth0"))).await.unwrap();
+        state.cache.upsert(service("nas", Some("eth1"))).await.unwrap();
+        state.cache.upsert(service("vm", None)).await.unwrap();
+
+        let list = |iface: &str| get_interface_services(State(state.clone()), Path(iface.to_string()), HeaderMap::new());
+        let response = list("eth0").await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let services: Vec<ServiceEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].instance_name, "web._http._tcp.local.");
+
+        // eth1 has services tagged with it, but isn't browsed
+        let Err((status, _)) = list("eth1").await else {
+            panic!("an interface that isn't browsed must be 404");
+        };
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_txt_filters_from_query_string() {
+        let pairs = txt_filters(Some("type=_http._tcp&txt.path=%2Fapi&txt.version=2")).unwrap();
+        assert_eq!(
+            pairs,
+            vec![("path".to_string(), "/api".to_string()), ("version".to_string(), "2".to_string())]
+        );
+        assert!(txt_filters(None).unwrap().is_empty());
+    }
 }
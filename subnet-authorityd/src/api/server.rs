@@ -0,0 +1,287 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use hyper_util::service::TowerToHyperService;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::rustls::{self, pki_types::CertificateDer, server::WebPkiClientVerifier, RootCertStore};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use crate::config::TlsFiles;
+
+/// The certificate a client presented over mutual TLS. Handlers can take
+/// `Option<Extension<ClientIdentity>>`; it is absent on plain connections.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    /// Certificate subject, e.g. "CN=resolver-1,O=Example"
+    pub subject: String,
+    /// Hex SHA-256 of the DER certificate
+    pub fingerprint: String,
+}
+
+impl ClientIdentity {
+    fn from_cert(cert: &CertificateDer<'_>) -> Self {
+        let subject = x509_parser::parse_x509_certificate(cert)
+            .map(|(_, parsed)| parsed.subject().to_string())
+            .unwrap_or_default();
+        Self {
+            subject,
+            fingerprint: hex::encode(Sha256::digest(cert)),
+        }
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (sha256:{})", self.subject, self.fingerprint)
+    }
+}
+
+/// Build a TLS acceptor from a PEM certificate chain and private key,
+/// offering the `alpn` protocols. With `client_ca` set, clients must present
+/// a certificate signed by it.
+pub fn tls_acceptor(files: &TlsFiles, alpn: &[&[u8]]) -> Result<TlsAcceptor> {
+    let certs = load_certs(files.cert)?;
+    let key = rustls_pemfile::private_key(&mut open(files.key)?)
+        .with_context(|| format!("Failed to read private key: {}", files.key.display()))?
+        .with_context(|| format!("No private key found in {}", files.key.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to select TLS protocol versions")?;
+    let builder = match files.client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(ca_path)? {
+                roots
+                    .add(ca)
+                    .with_context(|| format!("Invalid CA certificate in {}", ca_path.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("Failed to build client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key don't match")?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates: {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "No certificates found in {}", path.display());
+    Ok(certs)
+}
+
+fn open(path: &Path) -> Result<std::io::BufReader<std::fs::File>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(std::io::BufReader::new(file))
+}
+
+/// `api.listen` prefix selecting a unix domain socket instead of a TCP address
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Where the API accepts connections: a TCP address, or a local socket for
+/// `listen = "unix:/path"`. A socket's file is removed when it is dropped.
+pub enum ApiListener {
+    Tcp(TcpListener),
+    Unix { listener: UnixListener, path: PathBuf },
+}
+
+impl ApiListener {
+    pub async fn bind(listen: &str) -> Result<Self> {
+        let Some(path) = listen.strip_prefix(UNIX_PREFIX) else {
+            let listener = TcpListener::bind(listen)
+                .await
+                .with_context(|| format!("Failed to bind to {}", listen))?;
+            return Ok(Self::Tcp(listener));
+        };
+
+        let path = PathBuf::from(path);
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind to unix socket {}", path.display()))?;
+        Ok(Self::Unix { listener, path })
+    }
+}
+
+impl Drop for ApiListener {
+    fn drop(&mut self) {
+        if let ApiListener::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Remove a socket left behind by a previous run; refuse to remove anything
+/// else, or a socket something still accepts connections on
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => match std::os::unix::net::UnixStream::connect(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display())),
+            Ok(_) => anyhow::bail!("Unix socket {} is in use by another process", path.display()),
+            Err(e) => Err(e).with_context(|| format!("Unix socket {} may be in use", path.display())),
+        },
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Serve `app` until `shutdown` is cancelled, then wait for open connections
+/// to finish. With `tls`, a failed handshake only drops that connection.
+pub async fn serve(listener: ApiListener, tls: Option<TlsAcceptor>, app: Router, shutdown: CancellationToken) {
+    let graceful = GracefulShutdown::new();
+
+    loop {
+        let accepted = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = accept(&listener) => accepted,
+        };
+        let watcher = graceful.watcher();
+        match accepted {
+            Ok(Accepted::Tcp(stream, peer)) => {
+                let _ = stream.set_nodelay(true);
+                spawn_connection(stream, peer.to_string(), tls.clone(), app.clone(), watcher);
+            }
+            Ok(Accepted::Unix(stream)) => {
+                spawn_connection(stream, "unix socket".to_string(), tls.clone(), app.clone(), watcher);
+            }
+            Err(e) => {
+                // Typically out of file descriptors; back off rather than spin
+                tracing::warn!("Failed to accept API connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    graceful.shutdown().await;
+}
+
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    Unix(UnixStream),
+}
+
+async fn accept(listener: &ApiListener) -> std::io::Result<Accepted> {
+    match listener {
+        ApiListener::Tcp(listener) => listener.accept().await.map(|(stream, peer)| Accepted::Tcp(stream, peer)),
+        ApiListener::Unix { listener, .. } => listener.accept().await.map(|(stream, _)| Accepted::Unix(stream)),
+    }
+}
+
+fn spawn_connection<S>(stream: S, peer: String, tls: Option<TlsAcceptor>, app: Router, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let Some(acceptor) = tls else {
+            return serve_http(stream, app, watcher, &peer).await;
+        };
+        let stream = match acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::debug!("TLS handshake with {} failed: {}", peer, e);
+                return;
+            }
+        };
+        let identity = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(ClientIdentity::from_cert);
+        let app = match identity {
+            Some(identity) => {
+                tracing::debug!("API client {} connected as {}", peer, identity);
+                app.layer(Extension(identity))
+            }
+            None => app,
+        };
+        serve_http(stream, app, watcher, &peer).await;
+    });
+}
+
+async fn serve_http<S>(stream: S, app: Router, watcher: Watcher, peer: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app));
+    if let Err(e) = watcher.watch(conn.into_owned()).await {
+        tracing::debug!("API connection from {} ended with error: {}", peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_unix_socket_serves_and_replaces_stale_socket() {
+        let path = std::env::temp_dir().join(format!("subnet-authority-test-{}.sock", std::process::id()));
+        let listen = format!("{}{}", UNIX_PREFIX, path.display());
+
+        // A socket left by an earlier run is replaced rather than failing the bind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = ApiListener::bind(&listen).await.unwrap();
+
+        let app = Router::new().route("/v1/readyz", get(|| async { "ok" }));
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve(listener, None, app, shutdown.clone()));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /v1/readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("ok"), "{}", response);
+
+        shutdown.cancel();
+        server.await.unwrap();
+        assert!(!path.exists(), "socket should be removed on shutdown");
+    }
+
+    #[tokio::test]
+    async fn test_refuses_to_replace_live_socket() {
+        let path = std::env::temp_dir().join(format!("subnet-authority-test-{}-live.sock", std::process::id()));
+        let live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let err = ApiListener::bind(&format!("{}{}", UNIX_PREFIX, path.display())).await.err().unwrap();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok(), "the live socket should be left alone");
+        drop(live);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_to_replace_regular_file() {
+        let path = std::env::temp_dir().join(format!("subnet-authority-test-{}.file", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::time::Duration;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use shared::types::ServiceEntry;
+use tokio::sync::{broadcast, watch};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use crate::cache_manager::{CacheEvent, CacheSnapshot};
+
+/// Stream the value of `rx` now and then on every change, at most once per
+/// `min_interval`, until `cancel` fires or the sender goes away.
+///
+/// Follows `watch` semantics: each item reflects the latest state when it is
+/// sent, and any states in between are skipped. Nothing queues up per
+/// subscriber however fast the value changes or however slow the client is.
+pub fn throttled<T, U>(
+    rx: watch::Receiver<T>,
+    min_interval: Duration,
+    cancel: CancellationToken,
+    map: impl FnMut(&T) -> U,
+) -> impl Stream<Item = U> {
+    futures::stream::unfold((rx, None, map), move |(mut rx, last_sent, mut map)| {
+        let cancel = cancel.clone();
+        async move {
+            if let Some(last_sent) = last_sent {
+                tokio::select! {
+                    _ = cancel.cancelled() => return None,
+                    _ = tokio::time::sleep_until(last_sent + min_interval) => {}
+                }
+                tokio::select! {
+                    _ = cancel.cancelled() => return None,
+                    changed = rx.changed() => changed.ok()?,
+                }
+            }
+
+            let item = map(&rx.borrow_and_update());
+            Some((item, (rx, Some(Instant::now()), map)))
+        }
+    })
+}
+
+/// What a changes stream should send for a snapshot. Serializes tagged by
+/// `event` ("resync" or "changes") for transports without named events.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChangesUpdate<'a> {
+    /// The cursor is ahead of the cache (the daemon restarted), or so far
+    /// behind that removals since were forgotten, so the client must fetch a
+    /// full snapshot. Streaming resumes from `generation`.
+    Resync { generation: u64 },
+    /// Services changed since the previous update, and instance names of
+    /// those removed, as of `generation`
+    Changes { generation: u64, services: Vec<&'a ServiceEntry>, removed: Vec<&'a str> },
+}
+
+/// Position of one changes stream in the cache's generations.
+///
+/// Each update covers everything after the last generation sent, so a
+/// throttled stream that skips intermediate snapshots still misses nothing.
+pub struct ChangesCursor {
+    generation: u64,
+    service_type: Option<String>,
+}
+
+impl ChangesCursor {
+    pub fn new(since: u64) -> Self {
+        Self { generation: since, service_type: None }
+    }
+
+    /// Only report services of `service_type`, if given
+    pub fn of_type(self, service_type: Option<String>) -> Self {
+        Self { service_type, ..self }
+    }
+
+    pub fn next<'a>(&mut self, snapshot: &'a CacheSnapshot) -> ChangesUpdate<'a> {
+        let since = std::mem::replace(&mut self.generation, snapshot.generation);
+        let removed = match snapshot.removed_since(since) {
+            Some(removed) if since <= snapshot.generation => removed,
+            _ => return ChangesUpdate::Resync { generation: snapshot.generation },
+        };
+        let wanted = |service_type: &str| self.service_type.as_deref().is_none_or(|wanted| wanted == service_type);
+        ChangesUpdate::Changes {
+            generation: snapshot.generation,
+            services: snapshot.changed_since(since).filter(|s| wanted(&s.service_type)).collect(),
+            removed: removed.filter(|(_, service_type)| wanted(service_type)).map(|(name, _)| name).collect(),
+        }
+    }
+}
+
+/// Throttled changes after `since`, optionally limited to one service type,
+/// each turned into a message by `map` (None drops it). Updates touching no
+/// service of the type are skipped, except the first, which always tells
+/// the client the generation.
+pub fn changes<U>(
+    rx: watch::Receiver<CacheSnapshot>,
+    min_interval: Duration,
+    cancel: CancellationToken,
+    since: u64,
+    service_type: Option<String>,
+    mut map: impl FnMut(ChangesUpdate<'_>) -> Option<U>,
+) -> impl Stream<Item = U> {
+    let mut cursor = ChangesCursor::new(since).of_type(service_type);
+    let mut first = true;
+    throttled(rx, min_interval, cancel, move |snapshot| {
+        let update = cursor.next(snapshot);
+        let skip = match &update {
+            ChangesUpdate::Changes { services, removed, .. } => services.is_empty() && removed.is_empty() && !first,
+            ChangesUpdate::Resync { .. } => false,
+        };
+        first = false;
+        if skip { None } else { map(update) }
+    })
+    .filter_map(futures::future::ready)
+}
+
+/// Every `CacheEvent` received on `rx`, each turned into a message by `map`,
+/// until `cancel` fires. Unlike the snapshot streams nothing is collapsed,
+/// so a subscriber that falls too far behind misses events: `map` gets the
+/// number missed as `Err`, and the stream carries on from the oldest held.
+pub fn cache_events<U>(
+    rx: broadcast::Receiver<CacheEvent>,
+    cancel: CancellationToken,
+    map: impl FnMut(Result<CacheEvent, u64>) -> U,
+) -> impl Stream<Item = U> {
+    futures::stream::unfold((rx, map), move |(mut rx, mut map)| {
+        let cancel = cancel.clone();
+        async move {
+            let received = tokio::select! {
+                _ = cancel.cancelled() => return None,
+                received = rx.recv() => received,
+            };
+            let item = match received {
+                Ok(event) => map(Ok(event)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => map(Err(missed)),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            Some((item, (rx, map)))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_collapses_intermediate_states() {
+        let (tx, rx) = watch::channel(0);
+        let cancel = CancellationToken::new();
+        let stream = throttled(rx, Duration::from_millis(20), cancel.clone(), |v: &i32| *v);
+        futures::pin_mut!(stream);
+
+        assert_eq!(stream.next().await, Some(0));
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        assert_eq!(stream.next().await, Some(3));
+
+        cancel.cancel();
+        assert_eq!(stream.next().await, None);
+    }
+
+    fn entry(instance_name: &str, port: u16) -> ServiceEntry {
+        ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: "test.local.".to_string(),
+            addresses: vec!["fd00::1".parse().unwrap()],
+            port,
+            txt: Default::default(),
+            first_seen: chrono::Utc::now(),
+            last_seen: chrono::Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[test]
+    fn test_changes_cursor_replays_then_follows() {
+        let (tx, rx) = watch::channel(CacheSnapshot::new(
+            vec![entry("a", 80), entry("b", 80)],
+            Default::default(),
+        ));
+        tx.send_modify(|s| assert!(s.update(vec![entry("a", 81), entry("b", 80)])));
+
+        // Replaying from 0 covers only what changed after it
+        let mut cursor = ChangesCursor::new(0);
+        let snapshot = rx.borrow().clone();
+        match cursor.next(&snapshot) {
+            ChangesUpdate::Changes { generation, services, .. } => {
+                assert_eq!(generation, 1);
+                assert_eq!(services.len(), 1);
+                assert_eq!(services[0].instance_name, "a");
+            }
+            other => panic!("Unexpected update: {:?}", other),
+        }
+
+        // Two changes collapsed into one update still include both
+        tx.send_modify(|s| assert!(s.update(vec![entry("a", 81), entry("b", 81)])));
+        tx.send_modify(|s| assert!(s.update(vec![entry("a", 82), entry("b", 81)])));
+        let snapshot = rx.borrow().clone();
+        match cursor.next(&snapshot) {
+            ChangesUpdate::Changes { generation, services, .. } => {
+                assert_eq!(generation, 3);
+                assert_eq!(services.len(), 2);
+            }
+            other => panic!("Unexpected update: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_changes_cursor_ahead_requires_resync() {
+        let snapshot = CacheSnapshot::new(vec![entry("a", 80)], Default::default());
+        let mut cursor = ChangesCursor::new(42);
+        assert!(matches!(cursor.next(&snapshot), ChangesUpdate::Resync { generation: 0 }));
+        assert!(matches!(cursor.next(&snapshot), ChangesUpdate::Changes { generation: 0, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_cache_events_report_lag() {
+        let (tx, rx) = broadcast::channel(2);
+        let cancel = CancellationToken::new();
+        let stream = cache_events(rx, cancel.clone(), |event| match event {
+            Ok(event) => format!("{:?}", event),
+            Err(missed) => format!("missed {}", missed),
+        });
+        futures::pin_mut!(stream);
+
+        for name in ["a", "b", "c"] {
+            tx.send(CacheEvent::Removed(name.to_string())).unwrap();
+        }
+        assert_eq!(stream.next().await.unwrap(), "missed 1");
+        assert_eq!(stream.next().await.unwrap(), r#"Removed("b")"#);
+        assert_eq!(stream.next().await.unwrap(), r#"Removed("c")"#);
+
+        cancel.cancel();
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[test]
+    fn test_update_json_tagged_by_event() {
+        let resync = serde_json::to_value(ChangesUpdate::Resync { generation: 3 }).unwrap();
+        assert_eq!(resync, serde_json::json!({"event": "resync", "generation": 3}));
+
+        let service = entry("a", 80);
+        let changes = ChangesUpdate::Changes { generation: 4, services: vec![&service], removed: vec!["b"] };
+        let json = serde_json::to_value(changes).unwrap();
+        assert_eq!(json["event"], "changes");
+        assert_eq!(json["services"][0]["instance_name"], "a");
+        assert_eq!(json["removed"][0], "b");
+    }
+
+    #[test]
+    fn test_changes_cursor_reports_removals() {
+        let (tx, rx) = watch::channel(CacheSnapshot::new(
+            vec![entry("a", 80), entry("b", 80)],
+            Default::default(),
+        ));
+        let mut cursor = ChangesCursor::new(0);
+        let mut printers = ChangesCursor::new(0).of_type(Some("_ipp._tcp".to_string()));
+        tx.send_modify(|s| assert!(s.update(vec![entry("b", 80)])));
+
+        let snapshot = rx.borrow().clone();
+        match cursor.next(&snapshot) {
+            ChangesUpdate::Changes { services, removed, .. } => {
+                assert!(services.is_empty());
+                assert_eq!(removed, ["a"]);
+            }
+            other => panic!("Unexpected update: {:?}", other),
+        }
+        // Removals of other types are left out
+        assert!(matches!(printers.next(&snapshot), ChangesUpdate::Changes { removed, .. } if removed.is_empty()));
+
+        // Back again: no longer removed
+        tx.send_modify(|s| assert!(s.update(vec![entry("a", 80), entry("b", 80)])));
+        let snapshot = rx.borrow().clone();
+        assert!(matches!(ChangesCursor::new(0).next(&snapshot), ChangesUpdate::Changes { removed, .. } if removed.is_empty()));
+    }
+}
@@ -1,16 +1,226 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::sync::Arc;
 use anyhow::{Context, Result};
-use rusqlite::{Connection, params, OptionalExtension};
-use shared::types::ServiceEntry;
-use chrono::Utc;
+use rusqlite::{Connection, DatabaseName, OpenFlags, params, OptionalExtension};
+use rusqlite::backup::{Backup, StepResult};
+use serde::Deserialize;
+use shared::types::{ChangeSet, ServiceEntry, ServiceEvent, ServiceEventKind, ServiceSource};
+use chrono::{DateTime, Utc};
+use crate::cache::clock::{system_clock, Clock};
+use crate::cache::hash::{self, EntryDigest, HashFields};
+use crate::cache::query::{next_cursor, Page, ServiceFilter, ServicePage};
+
+/// Columns read by `row_to_entry`, in order
+const SERVICE_COLUMNS: &str = "instance_name, service_type, hostname, addresses, port, txt,
+                        first_seen, last_seen, ttl, alive, source, reachable, latency_ms, subtypes, iface";
+
+/// Longest time anything is kept for: as long as a `chrono::Duration` can hold
+pub const MAX_RETENTION_SECS: u64 = (i64::MAX / 1000) as u64;
+
+/// What to do when an instance already cached under one service type is
+/// upserted under another. The instance name is the primary key, so both
+/// can't be kept; replacing lets a misbehaving device flap between types.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypeConflictPolicy {
+    /// Keep the existing entry and fail the conflicting write
+    #[default]
+    Reject,
+    /// Overwrite the existing entry, type included
+    Replace,
+}
+
+/// How long an alive mDNS-discovered service may go unseen before it is
+/// marked stale. Service types with a `TypeRetention` override use that
+/// instead, less the same `early_secs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleAfter {
+    /// The same number of seconds for every service, less `early_secs`
+    Secs { after: u64, early_secs: u64 },
+    /// Each service's own TTL, less `early_secs`
+    Ttl { early_secs: u64 },
+}
+
+impl StaleAfter {
+    pub fn secs(after: u64) -> Self {
+        StaleAfter::Secs { after, early_secs: 0 }
+    }
+
+    /// The same deadline `secs` sooner, for acting before it passes
+    pub fn earlier(self, secs: u64) -> Self {
+        match self {
+            StaleAfter::Secs { after, early_secs } => StaleAfter::Secs { after, early_secs: early_secs + secs },
+            StaleAfter::Ttl { early_secs } => StaleAfter::Ttl { early_secs: early_secs + secs },
+        }
+    }
+
+    fn early_secs(self) -> u64 {
+        match self {
+            StaleAfter::Secs { early_secs, .. } | StaleAfter::Ttl { early_secs } => early_secs,
+        }
+    }
+
+    /// Whether `service`'s deadline has passed at `now`
+    pub fn passed(self, service: &ServiceEntry, now: DateTime<Utc>, retention: &TypeRetention) -> bool {
+        let after = match (retention.stale_after_secs.get(&service.service_type), self) {
+            (Some(&after), _) | (None, StaleAfter::Secs { after, .. }) => after as i64,
+            (None, StaleAfter::Ttl { .. }) => i64::from(service.ttl),
+        };
+        service.last_seen + chrono::Duration::seconds(after - self.early_secs() as i64) < now
+    }
+
+    /// The same test as SQL over `services`, with its parameters as `?1`
+    /// and `?2`
+    fn condition(self, now: DateTime<Utc>, retention: &TypeRetention) -> (String, String, String) {
+        let default = match self {
+            StaleAfter::Secs { after, .. } => after.to_string(),
+            StaleAfter::Ttl { .. } => "ttl".to_string(),
+        };
+        (
+            format!("unixepoch(last_seen) + {} < unixepoch(?1)", retention.lookup("?2", &default)),
+            (now + chrono::Duration::seconds(self.early_secs() as i64)).to_rfc3339(),
+            json_map(&retention.stale_after_secs),
+        )
+    }
+}
+
+/// Per-service-type overrides of `[cache]` staleness and pruning, keyed by
+/// service type as cached (e.g. `_ipp._tcp`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypeRetention {
+    pub stale_after_secs: HashMap<String, u64>,
+    pub prune_after_secs: HashMap<String, u64>,
+}
+
+impl TypeRetention {
+    /// SQL for the override of the row's service type in the JSON map bound
+    /// to `param`, or `default`
+    fn lookup(&self, param: &str, default: &str) -> String {
+        format!(
+            "COALESCE((SELECT value FROM json_each({}) WHERE key = services.service_type), {})",
+            param, default
+        )
+    }
+
+    /// Whether `service` is due for pruning at `now`
+    pub fn prune_due(&self, service: &ServiceEntry, now: DateTime<Utc>, prune_after_secs: u64) -> bool {
+        let after = self.prune_after_secs.get(&service.service_type).copied().unwrap_or(prune_after_secs);
+        service.last_seen + chrono::Duration::seconds(after as i64) < now
+    }
+}
+
+fn json_map(map: &HashMap<String, u64>) -> String {
+    serde_json::to_string(map).unwrap_or_else(|_| "{}".to_string())
+}
+
+/// When maintenance gives disk space back. Neither step changes the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Checkpoint and truncate the WAL once its file is larger than this
+    /// (0 = never; SQLite still checkpoints, but keeps the file's size)
+    pub wal_bytes: u64,
+    /// Release free pages once they are at least this percent of the file (0 = never)
+    pub free_percent: u8,
+}
+
+/// What `CacheDb::compact` reclaimed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Compacted {
+    /// Size of the WAL that was truncated
+    pub wal_bytes: Option<u64>,
+    /// Pages returned to the filesystem
+    pub free_pages: u64,
+}
+
+/// Kinds of entry in the change journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChangeOp {
+    Add,
+    Update,
+    Remove,
+}
+
+impl ChangeOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChangeOp::Add => "add",
+            ChangeOp::Update => "update",
+            ChangeOp::Remove => "remove",
+        }
+    }
+}
+
+/// Returned (inside `anyhow::Error`) when an upsert is rejected by `TypeConflictPolicy::Reject`
+#[derive(Debug)]
+pub struct TypeConflictError {
+    pub instance_name: String,
+    pub existing_type: String,
+    pub new_type: String,
+}
+
+impl std::fmt::Display for TypeConflictError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is already cached as {}, refusing to change it to {}",
+            self.instance_name, self.existing_type, self.new_type
+        )
+    }
+}
+
+impl std::error::Error for TypeConflictError {}
+
+/// Fail with `TypeConflictError` if `policy` forbids replacing `existing` with `entry`
+pub(crate) fn check_type_conflict(
+    policy: TypeConflictPolicy,
+    existing: Option<&ServiceEntry>,
+    entry: &ServiceEntry,
+) -> Result<()> {
+    match existing {
+        Some(old) if policy == TypeConflictPolicy::Reject && old.service_type != entry.service_type => {
+            Err(TypeConflictError {
+                instance_name: entry.instance_name.clone(),
+                existing_type: old.service_type.clone(),
+                new_type: entry.service_type.clone(),
+            }
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
 
 pub struct CacheDb {
     conn: Connection,
+    type_conflict: TypeConflictPolicy,
+    clock: Arc<dyn Clock>,
+    type_retention: TypeRetention,
+    /// Seconds service history is kept; None records no history
+    history_retention: Option<u64>,
+    /// History and journal entries are kept at least this many times the
+    /// longest TTL cached, so they outlast a slow announcer's cycle
+    retention_ttl_factor: u64,
+    /// Seconds a dead mDNS service is kept after it died; None prunes it
+    /// with the alive ones
+    tombstone_secs: Option<u64>,
+    /// SQLCipher key the database is encrypted with, which backups share
+    key: Option<String>,
+    /// Fields the stored per-row digests cover
+    hash_fields: HashFields,
 }
 
 impl CacheDb {
-    /// Open or create the SQLite database with WAL mode enabled
+    /// Open or create an unencrypted database
+    #[cfg(test)]
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_key(path, None)
+    }
+
+    /// Open or create the SQLite database with WAL mode enabled, bringing
+    /// its schema up to date. It is encrypted with SQLCipher if `key` is
+    /// given; an unencrypted database already at `path` is encrypted first.
+    pub fn open_with_key(path: impl AsRef<Path>, key: Option<&str>) -> Result<Self> {
         let path = path.as_ref();
 
         // Create parent directory if it doesn't exist
@@ -19,52 +229,149 @@ impl CacheDb {
                 .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
         }
 
-        let conn = Connection::open(path)
+        if let Some(key) = key {
+            require_sqlcipher()?;
+            if is_plaintext(path)? {
+                encrypt_database(path, key)?;
+                tracing::info!("Encrypted database at {}", path.display());
+            }
+        }
+
+        let mut conn = Connection::open(path)
             .with_context(|| format!("Failed to open database: {}", path.display()))?;
+        apply_key(&conn, key).with_context(|| format!("Failed to open database: {}", path.display()))?;
 
         // Enable WAL mode for better concurrency and crash recovery
         conn.execute_batch("PRAGMA journal_mode=WAL;")
             .context("Failed to enable WAL mode")?;
 
-        // Create tables if they don't exist
-        conn.execute_batch(
-            r#"
-            CREATE TABLE IF NOT EXISTS services (
-                instance_name TEXT PRIMARY KEY,
-                service_type  TEXT NOT NULL,
-                hostname      TEXT NOT NULL,
-                addresses     TEXT NOT NULL,
-                port          INTEGER NOT NULL,
-                txt           TEXT NOT NULL,
-                first_seen    TEXT NOT NULL,
-                last_seen     TEXT NOT NULL,
-                ttl           INTEGER NOT NULL,
-                alive         INTEGER NOT NULL DEFAULT 1
-            );
-
-            CREATE INDEX IF NOT EXISTS idx_service_type ON services(service_type);
-            "#,
+        // Without auto_vacuum free pages can only be released by a full
+        // VACUUM, which rewrites the file; an existing database needs one
+        // VACUUM to switch over
+        let auto_vacuum: i64 = conn
+            .pragma_query_value(None, "auto_vacuum", |row| row.get(0))
+            .context("Failed to read auto_vacuum")?;
+        if auto_vacuum != 2 {
+            conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")
+                .context("Failed to enable incremental vacuum")?;
+        }
+
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn,
+            type_conflict: TypeConflictPolicy::default(),
+            clock: system_clock(),
+            type_retention: TypeRetention::default(),
+            history_retention: None,
+            retention_ttl_factor: 0,
+            tombstone_secs: None,
+            key: key.map(str::to_string),
+            hash_fields: HashFields::default(),
+        })
+    }
+
+    /// Open a read-only connection to the database at `path`, which `open`
+    /// has already brought up to date. WAL lets it read while another
+    /// connection writes.
+    pub fn open_reader(path: impl AsRef<Path>, key: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )
-        .context("Failed to create database schema")?;
+        .with_context(|| format!("Failed to open database: {}", path.display()))?;
+        apply_key(&conn, key).with_context(|| format!("Failed to open database: {}", path.display()))?;
+
+        Ok(Self {
+            conn,
+            type_conflict: TypeConflictPolicy::default(),
+            clock: system_clock(),
+            type_retention: TypeRetention::default(),
+            history_retention: None,
+            retention_ttl_factor: 0,
+            tombstone_secs: None,
+            key: key.map(str::to_string),
+            hash_fields: HashFields::default(),
+        })
+    }
+
+    /// Set how upserts that change an instance's service type are handled
+    pub fn set_type_conflict_policy(&mut self, policy: TypeConflictPolicy) {
+        self.type_conflict = policy;
+    }
+
+    pub fn type_conflict_policy(&self) -> TypeConflictPolicy {
+        self.type_conflict
+    }
+
+    /// Set per-service-type staleness and pruning overrides
+    pub fn set_type_retention(&mut self, retention: TypeRetention) {
+        self.type_retention = retention;
+    }
+
+    pub fn type_retention(&self) -> &TypeRetention {
+        &self.type_retention
+    }
+
+    /// Keep dead mDNS services for `secs` after they die, whatever
+    /// `prune_after_secs` is; None prunes them by when they were last seen
+    pub fn set_tombstone_retention(&mut self, secs: Option<u64>) {
+        self.tombstone_secs = secs;
+    }
+
+    pub fn tombstone_retention(&self) -> Option<u64> {
+        self.tombstone_secs
+    }
+
+    /// Set the fields the per-row digests cover. Digests stored under other
+    /// fields are cleared, to be recomputed when next read.
+    pub fn set_hash_fields(&mut self, fields: HashFields) -> Result<()> {
+        self.conn
+            .execute("UPDATE services SET digest = NULL", [])
+            .context("Failed to clear service digests")?;
+        self.hash_fields = fields;
+        Ok(())
+    }
+
+    /// Record service history, kept for `secs`; None stops recording
+    pub fn set_history_retention(&mut self, secs: Option<u64>) {
+        self.history_retention = secs;
+    }
 
-        Ok(Self { conn })
+    /// Keep history and journal entries at least `factor` times the longest
+    /// TTL cached (0 = no minimum)
+    pub fn set_retention_ttl_factor(&mut self, factor: u64) {
+        self.retention_ttl_factor = factor;
+    }
+
+    /// The clock staleness and pruning cutoffs are measured against
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
     }
 
     /// Insert or update a service entry. Returns true if data changed.
+    /// Fails with `TypeConflictError` if the policy rejects a type change.
+    /// An entry that was never probed keeps the stored probe result.
     pub fn upsert_service(&self, entry: &ServiceEntry) -> Result<bool> {
         // Fix #7: compare meaningful fields in Rust instead of fragile SQL concatenation
         let existing = self
             .conn
             .query_row(
-                "SELECT instance_name, service_type, hostname, addresses, port, txt,
-                        first_seen, last_seen, ttl, alive
-                 FROM services WHERE instance_name = ?1",
+                &format!("SELECT {} FROM services WHERE instance_name = ?1", SERVICE_COLUMNS),
                 params![&entry.instance_name],
-                |row| Ok(Self::row_to_entry(row)?),
+                Self::row_to_entry,
             )
             .optional()
             .context("Failed to query existing service")?;
 
+        check_type_conflict(self.type_conflict, existing.as_ref(), entry)?;
+
         let changed = match &existing {
             Some(old) => service_data_changed(old, entry),
             None => true,
@@ -74,14 +381,17 @@ impl CacheDb {
             .context("Failed to serialize addresses")?;
         let txt_json = serde_json::to_string(&entry.txt)
             .context("Failed to serialize txt records")?;
+        let subtypes_json = serde_json::to_string(&entry.subtypes)
+            .context("Failed to serialize subtypes")?;
+        let digest = hash::entry_digest(&stored_entry(existing.as_ref(), entry), &self.hash_fields);
 
         // Insert or replace
         self.conn.execute(
             r#"
             INSERT INTO services (
                 instance_name, service_type, hostname, addresses, port, txt,
-                first_seen, last_seen, ttl, alive
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                first_seen, last_seen, ttl, alive, source, reachable, latency_ms, subtypes, dead_since, digest, iface
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?16, ?17, ?18)
             ON CONFLICT(instance_name) DO UPDATE SET
                 service_type = excluded.service_type,
                 hostname = excluded.hostname,
@@ -90,7 +400,14 @@ impl CacheDb {
                 txt = excluded.txt,
                 last_seen = excluded.last_seen,
                 ttl = excluded.ttl,
-                alive = excluded.alive
+                alive = excluded.alive,
+                dead_since = CASE WHEN excluded.alive THEN NULL ELSE COALESCE(dead_since, excluded.dead_since) END,
+                source = excluded.source,
+                latency_ms = CASE WHEN excluded.reachable IS NULL THEN latency_ms ELSE excluded.latency_ms END,
+                reachable = COALESCE(excluded.reachable, reachable),
+                subtypes = CASE WHEN ?15 THEN subtypes ELSE excluded.subtypes END,
+                iface = CASE WHEN ?19 THEN iface ELSE excluded.iface END,
+                digest = excluded.digest
             "#,
             params![
                 &entry.instance_name,
@@ -103,39 +420,371 @@ impl CacheDb {
                 entry.last_seen.to_rfc3339(),
                 entry.ttl,
                 entry.alive as i32,
+                entry.source.as_str(),
+                entry.reachable,
+                entry.latency_ms,
+                &subtypes_json,
+                keeps_subtypes(entry),
+                (!entry.alive).then(|| self.clock.now().to_rfc3339()),
+                &digest[..],
+                &entry.iface,
+                keeps_iface(entry),
             ],
         )
         .context("Failed to upsert service")?;
 
+        if let Some((event, detail)) = transition(existing.as_ref(), entry) {
+            self.record_event(&entry.instance_name, event, detail.as_deref())?;
+        }
+
+        if changed {
+            let op = if existing.is_none() { ChangeOp::Add } else { ChangeOp::Update };
+            let seq = self.log_change(&entry.instance_name, op)?;
+            self.conn.execute(
+                "UPDATE services SET seq = ?1, created_seq = CASE WHEN ?2 THEN ?1 ELSE created_seq END
+                 WHERE instance_name = ?3",
+                params![seq, existing.is_none(), &entry.instance_name],
+            )
+            .context("Failed to record service change")?;
+        }
+
         Ok(changed)
     }
 
+    /// Make `entries` the complete set of services from `source`: upsert each
+    /// one and delete any other rows from that source.
+    pub fn replace_source(&self, source: ServiceSource, entries: &[ServiceEntry]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()
+            .context("Failed to begin transaction")?;
+
+        let keep: HashSet<&str> = entries.iter().map(|e| e.instance_name.as_str()).collect();
+        let existing = self
+            .conn
+            .prepare("SELECT instance_name FROM services WHERE source = ?1")?
+            .query_map([source.as_str()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to query existing services by source")?;
+
+        for instance_name in existing.iter().filter(|name| !keep.contains(name.as_str())) {
+            self.remove_service(instance_name)?;
+        }
+
+        for entry in entries {
+            self.upsert_service(entry)?;
+        }
+
+        tx.commit().context("Failed to commit replaced services")?;
+        Ok(())
+    }
+
+    /// Replace the whole table with `entries`, e.g. to write back services
+    /// that were only held in memory while the database was unavailable
+    pub fn restore(&self, entries: &[ServiceEntry]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()
+            .context("Failed to begin transaction")?;
+
+        // Diff rather than clear, so only real changes get new sequence numbers
+        let keep: HashSet<&str> = entries.iter().map(|e| e.instance_name.as_str()).collect();
+        let existing = self
+            .conn
+            .prepare("SELECT instance_name FROM services")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to query existing services")?;
+        for instance_name in existing.iter().filter(|name| !keep.contains(name.as_str())) {
+            self.remove_service(instance_name)?;
+        }
+        for entry in entries {
+            self.upsert_service(entry)?;
+        }
+
+        tx.commit().context("Failed to commit restored services")?;
+        Ok(())
+    }
+
+    /// Upsert a batch of entries in one transaction, returning each one's
+    /// result. An entry the type conflict policy rejects fails alone; any
+    /// other failure rolls back the whole batch.
+    pub fn upsert_services(&self, entries: &[ServiceEntry]) -> Result<Vec<Result<bool>>> {
+        let tx = self.conn.unchecked_transaction()
+            .context("Failed to begin transaction")?;
+        let mut results = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match self.upsert_service(entry) {
+                Err(e) if !e.is::<TypeConflictError>() => return Err(e),
+                result => results.push(result),
+            }
+        }
+        tx.commit().context("Failed to commit upserts")?;
+        Ok(results)
+    }
+
+    /// Upsert every entry in one transaction, e.g. from a JSON export
+    pub fn import(&self, entries: &[ServiceEntry]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()
+            .context("Failed to begin transaction")?;
+        for entry in entries {
+            self.upsert_service(entry)?;
+        }
+        tx.commit().context("Failed to commit imported services")?;
+        Ok(())
+    }
+
+    /// Hand out the next change sequence number
+    fn next_seq(&self) -> Result<u64> {
+        self.conn
+            .query_row(
+                "INSERT INTO sync_state (key, value) VALUES ('seq', 1)
+                 ON CONFLICT(key) DO UPDATE SET value = value + 1
+                 RETURNING value",
+                [],
+                |row| row.get(0),
+            )
+            .context("Failed to allocate change sequence number")
+    }
+
+    /// Append a change to the journal under a new sequence number
+    fn log_change(&self, instance_name: &str, op: ChangeOp) -> Result<u64> {
+        let seq = self.next_seq()?;
+        self.conn.execute(
+            "INSERT INTO changes (seq, instance_name, op, at) VALUES (?1, ?2, ?3, ?4)",
+            params![seq, instance_name, op.as_str(), self.clock.now().to_rfc3339()],
+        )
+        .context("Failed to journal change")?;
+        Ok(seq)
+    }
+
+    fn sync_value(&self, key: &str) -> Result<u64> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM sync_state WHERE key = ?1", [key], |row| row.get(0))
+            .optional()
+            .context("Failed to read sync state")?
+            .unwrap_or(0))
+    }
+
+    /// Delete one service, journaling the removal
+    fn remove_service(&self, instance_name: &str) -> Result<()> {
+        self.record_event(instance_name, ServiceEventKind::Removed, None)?;
+        self.log_change(instance_name, ChangeOp::Remove)?;
+        self.conn.execute("DELETE FROM services WHERE instance_name = ?1", params![instance_name])
+            .context("Failed to delete service")?;
+        Ok(())
+    }
+
+    /// Everything added, updated, or removed after sequence number `since`.
+    /// Asks for a resync if the journal after `since` has been pruned, or if
+    /// `since` is from a database this one isn't.
+    pub fn changes_since(&self, since: u64) -> Result<ChangeSet> {
+        let seq = self.sync_value("seq")?;
+        let mut changes = ChangeSet {
+            seq,
+            resync: since > seq || since < self.sync_value("floor")?,
+            added: Vec::new(),
+            updated: Vec::new(),
+            removed: Vec::new(),
+        };
+        if changes.resync {
+            return Ok(changes);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {}, created_seq FROM services WHERE seq > ?1 ORDER BY seq", SERVICE_COLUMNS))
+            .context("Failed to prepare query")?;
+        let rows = stmt
+            .query_map([since], |row| Ok((Self::row_to_entry(row)?, row.get::<_, u64>(15)?)))
+            .context("Failed to query changed services")?;
+        for row in rows {
+            let (entry, created_seq) = row.context("Failed to collect changed services")?;
+            if created_seq > since {
+                changes.added.push(entry);
+            } else {
+                changes.updated.push(entry);
+            }
+        }
+
+        changes.removed = self
+            .conn
+            .prepare(
+                "SELECT instance_name FROM changes
+                 WHERE seq > ?1 AND op = 'remove' AND instance_name NOT IN (SELECT instance_name FROM services)
+                 GROUP BY instance_name ORDER BY MAX(seq)",
+            )?
+            .query_map([since], |row| row.get(0))
+            .context("Failed to query removed services")?
+            .collect::<Result<Vec<String>, _>>()
+            .context("Failed to collect removed services")?;
+
+        Ok(changes)
+    }
+
+    /// Make every write fail, to simulate a read-only or full filesystem
+    #[cfg(test)]
+    pub fn set_read_only(&self, read_only: bool) {
+        self.conn
+            .pragma_update(None, "query_only", read_only)
+            .unwrap();
+    }
+
+    /// Copy the database to `path` with SQLite's online backup API. The copy
+    /// is written beside `path` and renamed into place once complete.
+    pub fn backup(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        let partial = path.with_extension("partial");
+        let _ = std::fs::remove_file(&partial);
+        {
+            let mut dst = Connection::open(&partial)
+                .with_context(|| format!("Failed to create backup: {}", partial.display()))?;
+            apply_key(&dst, self.key.as_deref())?;
+            copy_database(&self.conn, &mut dst).context("Failed to back up database")?;
+        }
+        std::fs::rename(&partial, path)
+            .with_context(|| format!("Failed to move backup to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Replace the database at `path` with the backup at `from`, before the
+    /// daemon opens it. The backup is checked first, so a wrong file leaves
+    /// the database untouched; it is migrated when next opened. Both are
+    /// encrypted with `key`, if given, as backups of an encrypted database are.
+    pub fn restore_backup(path: impl AsRef<Path>, from: impl AsRef<Path>, key: Option<&str>) -> Result<()> {
+        let (path, from) = (path.as_ref(), from.as_ref());
+        let src = Connection::open_with_flags(from, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open backup: {}", from.display()))?;
+        apply_key(&src, key).with_context(|| format!("Failed to open backup: {}", from.display()))?;
+        let version: usize = src
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .with_context(|| format!("{} is not an SQLite database", from.display()))?;
+        let has_services = src
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'services'")?
+            .exists([])?;
+        if !has_services || version > MIGRATIONS.len() {
+            anyhow::bail!("{} is not a cache backup this build can restore", from.display());
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        // An unencrypted database can't be keyed, only replaced
+        if key.is_some() && is_plaintext(path)? {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to replace unencrypted database: {}", path.display()))?;
+            remove_wal(path);
+        }
+        let mut dst = Connection::open(path)
+            .with_context(|| format!("Failed to open database: {}", path.display()))?;
+        apply_key(&dst, key).with_context(|| format!("Failed to open database: {}", path.display()))?;
+        copy_database(&src, &mut dst).context("Failed to restore database")?;
+        Ok(())
+    }
+
+    /// Truncate the WAL and release free pages where `compaction`'s
+    /// thresholds are passed
+    pub fn compact(&self, compaction: Compaction) -> Result<Compacted> {
+        let mut compacted = Compacted::default();
+
+        let wal_size = self.conn.path()
+            .filter(|path| !path.is_empty())
+            .and_then(|path| std::fs::metadata(format!("{}-wal", path)).ok())
+            .map_or(0, |meta| meta.len());
+        if compaction.wal_bytes > 0 && wal_size > compaction.wal_bytes {
+            // Blocked by a reader mid-transaction; the next cycle tries again
+            let busy: bool = self.conn
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))
+                .context("Failed to checkpoint WAL")?;
+            if !busy {
+                compacted.wal_bytes = Some(wal_size);
+            }
+        }
+
+        if compaction.free_percent > 0 {
+            let (free, total): (u64, u64) = self.conn
+                .query_row("SELECT * FROM pragma_freelist_count, pragma_page_count", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .context("Failed to count free pages")?;
+            if free > 0 && free * 100 >= total * u64::from(compaction.free_percent) {
+                // Frees a page per step, so it has to be run to the end
+                let mut stmt = self.conn.prepare("PRAGMA incremental_vacuum")?;
+                let mut rows = stmt.query([]).context("Failed to vacuum free pages")?;
+                while rows.next().context("Failed to vacuum free pages")?.is_some() {}
+                compacted.free_pages = free;
+            }
+        }
+        Ok(compacted)
+    }
+
     /// Mark a service as dead (not alive)
     pub fn mark_dead(&self, instance_name: &str) -> Result<()> {
-        let now = Utc::now().to_rfc3339();
+        let seq = if self.service_exists(instance_name, false)? {
+            self.record_event(instance_name, ServiceEventKind::Dead, None)?;
+            Some(self.log_change(instance_name, ChangeOp::Update)?)
+        } else {
+            None
+        };
+        let now = self.clock.now().to_rfc3339();
         self.conn.execute(
-            "UPDATE services SET alive = 0, last_seen = ?1 WHERE instance_name = ?2",
-            params![now, instance_name],
+            "UPDATE services SET alive = 0, last_seen = ?1, seq = COALESCE(?2, seq),
+                                 dead_since = COALESCE(dead_since, ?1), digest = NULL
+             WHERE instance_name = ?3",
+            params![now, seq, instance_name],
         )
         .context("Failed to mark service as dead")?;
         Ok(())
     }
 
+    /// Delete a service outright, recording the removal. Returns false if it
+    /// wasn't cached.
+    pub fn purge_service(&self, instance_name: &str) -> Result<bool> {
+        if !self.service_exists(instance_name, true)? {
+            return Ok(false);
+        }
+        self.remove_service(instance_name)?;
+        Ok(true)
+    }
+
+    /// Record a liveness probe of `instance_name`. Returns true if its
+    /// reachability changed, which counts as a change to the service; a new
+    /// latency alone doesn't.
+    pub fn set_reachability(&self, instance_name: &str, reachable: bool, latency_ms: Option<u32>) -> Result<bool> {
+        let previous: Option<Option<bool>> = self
+            .conn
+            .query_row(
+                "SELECT reachable FROM services WHERE instance_name = ?1",
+                params![instance_name],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query service reachability")?;
+        let Some(previous) = previous else {
+            return Ok(false);
+        };
+        let changed = previous != Some(reachable);
+        let seq = if changed { Some(self.log_change(instance_name, ChangeOp::Update)?) } else { None };
+        self.conn.execute(
+            "UPDATE services SET reachable = ?2, latency_ms = ?3, seq = COALESCE(?4, seq),
+                                 digest = CASE WHEN ?4 IS NULL THEN digest END
+             WHERE instance_name = ?1",
+            params![instance_name, reachable, latency_ms, seq],
+        )
+        .context("Failed to record service reachability")?;
+        Ok(changed)
+    }
+
     /// Get all services
     pub fn get_all_services(&self) -> Result<Vec<ServiceEntry>> {
         let mut stmt = self
             .conn
-            .prepare(
-                "SELECT instance_name, service_type, hostname, addresses, port, txt,
-                        first_seen, last_seen, ttl, alive
-                 FROM services"
-            )
+            .prepare(&format!("SELECT {} FROM services", SERVICE_COLUMNS))
             .context("Failed to prepare query")?;
 
         let services = stmt
-            .query_map([], |row| {
-                Ok(Self::row_to_entry(row)?)
-            })
+            .query_map([], Self::row_to_entry)
             .context("Failed to query services")?
             .collect::<Result<Vec<_>, _>>()
             .context("Failed to collect services")?;
@@ -143,24 +792,165 @@ impl CacheDb {
         Ok(services)
     }
 
-    /// Get services filtered by type
-    pub fn get_services_by_type(&self, service_type: &str) -> Result<Vec<ServiceEntry>> {
+    /// Every service with its digest, ordered by instance name. Rows changed
+    /// in place since their digest was stored get it recomputed and written
+    /// back, so only those are serialized.
+    pub fn digested_services(&self) -> Result<(Vec<ServiceEntry>, Vec<EntryDigest>)> {
         let mut stmt = self
             .conn
-            .prepare(
-                "SELECT instance_name, service_type, hostname, addresses, port, txt,
-                        first_seen, last_seen, ttl, alive
-                 FROM services WHERE service_type = ?1"
+            .prepare(&format!("SELECT {}, digest FROM services ORDER BY instance_name", SERVICE_COLUMNS))
+            .context("Failed to prepare query")?;
+        let rows = stmt
+            .query_map([], |row| Ok((Self::row_to_entry(row)?, row.get::<_, Option<Vec<u8>>>(15)?)))
+            .context("Failed to query services")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect services")?;
+
+        let mut services = Vec::with_capacity(rows.len());
+        let mut digests = Vec::with_capacity(rows.len());
+        let mut recomputed = Vec::new();
+        for (entry, stored) in rows {
+            let digest = match stored.as_deref().map(EntryDigest::try_from) {
+                Some(Ok(digest)) => digest,
+                _ => {
+                    let digest = hash::entry_digest(&entry, &self.hash_fields);
+                    recomputed.push((entry.instance_name.clone(), digest));
+                    digest
+                }
+            };
+            services.push(entry);
+            digests.push(digest);
+        }
+
+        if !recomputed.is_empty() {
+            // Only saves work next time, so a failed write is no reason to fail
+            if let Err(e) = self.store_digests(&recomputed) {
+                tracing::debug!("Failed to store recomputed digests: {:#}", e);
+            }
+        }
+        Ok((services, digests))
+    }
+
+    fn store_digests(&self, digests: &[(String, EntryDigest)]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()
+            .context("Failed to begin transaction")?;
+        let mut stmt = self.conn.prepare("UPDATE services SET digest = ?2 WHERE instance_name = ?1")?;
+        for (instance_name, digest) in digests {
+            stmt.execute(params![instance_name, &digest[..]])?;
+        }
+        drop(stmt);
+        tx.commit().context("Failed to commit digests")?;
+        Ok(())
+    }
+
+    /// One page of the services matching `filter`, ordered by instance name,
+    /// with filtering and paging done in SQL
+    pub fn query_services(&self, filter: &ServiceFilter, page: &Page) -> Result<ServicePage> {
+        use rusqlite::types::Value;
+
+        let mut conditions = vec!["1".to_string()];
+        let mut values: Vec<Value> = Vec::new();
+        if filter.alive_only {
+            conditions.push("alive = 1".to_string());
+        }
+        if let Some(service_type) = &filter.service_type {
+            values.push(service_type.clone().into());
+            conditions.push(format!("service_type = ?{}", values.len()));
+        }
+        if let Some(address) = &filter.address {
+            // Addresses are stored in their canonical text form, see upsert_service
+            values.push(address.to_string().into());
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM json_each(services.addresses) WHERE value = ?{})",
+                values.len()
+            ));
+        }
+        if let Some(key) = &filter.has_txt {
+            values.push(key.clone().into());
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM json_each(services.txt) WHERE lower(key) = lower(?{}))",
+                values.len()
+            ));
+        }
+        if let Some(subtype) = &filter.subtype {
+            values.push(subtype.clone().into());
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM json_each(services.subtypes) WHERE value = ?{})",
+                values.len()
+            ));
+        }
+        if let Some(iface) = &filter.iface {
+            values.push(iface.clone().into());
+            conditions.push(format!("iface = ?{}", values.len()));
+        }
+        for (key, value) in &filter.txt {
+            values.push(key.clone().into());
+            values.push(value.clone().into());
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM json_each(services.txt) WHERE lower(key) = lower(?{}) AND value = ?{})",
+                values.len() - 1,
+                values.len()
+            ));
+        }
+        let filter_sql = conditions.join(" AND ");
+
+        let total: usize = self
+            .conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM services WHERE {}", filter_sql),
+                rusqlite::params_from_iter(&values),
+                |row| row.get(0),
             )
+            .context("Failed to count services")?;
+
+        let mut page_sql = filter_sql;
+        if let Some(after) = &page.after {
+            values.push(after.clone().into());
+            page_sql.push_str(&format!(" AND instance_name > ?{}", values.len()));
+        }
+        // One extra row tells whether there's a next page; -1 means no limit
+        values.push(page.limit.map_or(-1, |limit| sql_count(limit.saturating_add(1))).into());
+        values.push(sql_count(page.offset).into());
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM services WHERE {} ORDER BY instance_name LIMIT ?{} OFFSET ?{}",
+                SERVICE_COLUMNS,
+                page_sql,
+                values.len() - 1,
+                values.len()
+            ))
             .context("Failed to prepare query")?;
+        let mut services = stmt
+            .query_map(rusqlite::params_from_iter(&values), Self::row_to_entry)
+            .context("Failed to query services")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect services")?;
+
+        let next = next_cursor(&mut services, page.limit);
+        Ok(ServicePage { services, total, next })
+    }
+
+    /// Services whose instance name, hostname, or a TXT value contains `query`
+    /// (case-insensitively), best matches first
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<ServiceEntry>> {
+        // Quote as a single FTS5 string so operators in the query are literal
+        let phrase = format!("\"{}\"", query.replace('"', "\"\""));
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM services_fts JOIN services ON services.instance_name = services_fts.key
+                 WHERE services_fts MATCH ?1 ORDER BY services_fts.rank LIMIT ?2",
+                SERVICE_COLUMNS
+            ))
+            .context("Failed to prepare search")?;
 
         let services = stmt
-            .query_map([service_type], |row| {
-                Ok(Self::row_to_entry(row)?)
-            })
-            .context("Failed to query services by type")?
+            .query_map(params![phrase, sql_count(limit)], Self::row_to_entry)
+            .context("Failed to search services")?
             .collect::<Result<Vec<_>, _>>()
-            .context("Failed to collect services")?;
+            .context("Failed to collect search results")?;
 
         Ok(services)
     }
@@ -170,11 +960,9 @@ impl CacheDb {
         let result = self
             .conn
             .query_row(
-                "SELECT instance_name, service_type, hostname, addresses, port, txt,
-                        first_seen, last_seen, ttl, alive
-                 FROM services WHERE instance_name = ?1",
+                &format!("SELECT {} FROM services WHERE instance_name = ?1", SERVICE_COLUMNS),
                 params![instance_name],
-                |row| Ok(Self::row_to_entry(row)?),
+                Self::row_to_entry,
             )
             .optional()
             .context("Failed to query service")?;
@@ -182,32 +970,247 @@ impl CacheDb {
         Ok(result)
     }
 
-    /// Mark services as stale if not seen recently
-    pub fn mark_stale(&self, stale_after_secs: u64) -> Result<u64> {
-        let cutoff = Utc::now() - chrono::Duration::seconds(stale_after_secs as i64);
-        let cutoff_str = cutoff.to_rfc3339();
-
-        let count = self.conn.execute(
-            "UPDATE services SET alive = 0 WHERE last_seen < ?1 AND alive = 1",
-            params![cutoff_str],
-        )
-        .context("Failed to mark stale services")?;
+    /// Whether an instance is cached, without loading it. Dead services only
+    /// count when `include_dead` is set.
+    pub fn service_exists(&self, instance_name: &str, include_dead: bool) -> Result<bool> {
+        let exists = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM services WHERE instance_name = ?1 AND (alive = 1 OR ?2) LIMIT 1",
+                params![instance_name, include_dead],
+                |_| Ok(()),
+            )
+            .optional()
+            .context("Failed to check service existence")?
+            .is_some();
 
-        Ok(count as u64)
+        Ok(exists)
     }
 
-    /// Prune old services from the database
-    pub fn prune_stale(&self, prune_after_secs: u64) -> Result<u64> {
-        let cutoff = Utc::now() - chrono::Duration::seconds(prune_after_secs as i64);
-        let cutoff_str = cutoff.to_rfc3339();
+    /// Types of alive mDNS-discovered services past `unseen`
+    pub fn unseen_service_types(&self, unseen: StaleAfter) -> Result<Vec<String>> {
+        let (condition, now, overrides) = unseen.condition(self.clock.now(), &self.type_retention);
 
-        let count = self.conn.execute(
-            "DELETE FROM services WHERE last_seen < ?1",
-            params![cutoff_str],
-        )
-        .context("Failed to prune old services")?;
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT DISTINCT service_type FROM services
+                 WHERE {} AND alive = 1 AND source = 'mdns'",
+                condition
+            ))
+            .context("Failed to prepare query")?;
 
-        Ok(count as u64)
+        let types = stmt
+            .query_map([now, overrides], |row| row.get(0))
+            .context("Failed to query unseen service types")?
+            .collect::<Result<Vec<String>, _>>()
+            .context("Failed to collect service types")?;
+
+        Ok(types)
+    }
+
+    /// Alive mDNS-discovered services past `unseen`
+    pub fn unseen_services(&self, unseen: StaleAfter) -> Result<Vec<ServiceEntry>> {
+        let (condition, now, overrides) = unseen.condition(self.clock.now(), &self.type_retention);
+
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM services WHERE {} AND alive = 1 AND source = 'mdns'",
+                SERVICE_COLUMNS, condition
+            ))
+            .context("Failed to prepare query")?;
+
+        let services = stmt
+            .query_map([now, overrides], Self::row_to_entry)
+            .context("Failed to query unseen services")?
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to collect services")?;
+
+        Ok(services)
+    }
+
+    /// Mark mDNS-discovered services as stale if not seen recently. A
+    /// sequence number is only taken when some service goes stale.
+    pub fn mark_stale(&self, stale: StaleAfter) -> Result<u64> {
+        let (condition, deadline, overrides) = stale.condition(self.clock.now(), &self.type_retention);
+        let stale_where = format!("{} AND alive = 1 AND source = 'mdns'", condition);
+        if !self.any_service(&stale_where, params![deadline, overrides])? {
+            return Ok(0);
+        }
+
+        let now = self.clock.now().to_rfc3339();
+        if self.history_retention.is_some() {
+            self.conn.execute(
+                &format!(
+                    "INSERT INTO service_events (instance_name, event, at, detail)
+                     SELECT instance_name, 'dead', ?3, 'stale' FROM services
+                     WHERE {}",
+                    stale_where
+                ),
+                params![deadline, overrides, now],
+            )
+            .context("Failed to record stale services")?;
+        }
+
+        let seq = self.next_seq()?;
+        self.conn.execute(
+            &format!(
+                "INSERT INTO changes (seq, instance_name, op, at)
+                 SELECT ?3, instance_name, 'update', ?4 FROM services
+                 WHERE {}",
+                stale_where
+            ),
+            params![deadline, overrides, seq, now],
+        )
+        .context("Failed to journal stale services")?;
+        let count = self.conn.execute(
+            &format!(
+                "UPDATE services SET alive = 0, seq = ?3, dead_since = ?4, digest = NULL
+                 WHERE {}",
+                stale_where
+            ),
+            params![deadline, overrides, seq, now],
+        )
+        .context("Failed to mark stale services")?;
+
+        Ok(count as u64)
+    }
+
+    /// Whether any service matches `condition`
+    fn any_service(&self, condition: &str, params: impl rusqlite::Params) -> Result<bool> {
+        self.conn
+            .query_row(&format!("SELECT EXISTS(SELECT 1 FROM services WHERE {})", condition), params, |row| row.get(0))
+            .context("Failed to query services")
+    }
+
+    /// Prune old mDNS-discovered services from the database, and journal
+    /// entries older than `prune_after_secs` or the TTL-based minimum. With
+    /// a tombstone retention, dead services are pruned by when they died.
+    /// Returns the instance names pruned. A sequence number is only taken
+    /// when some service is pruned.
+    pub fn prune_stale(&self, prune_after_secs: u64) -> Result<Vec<String>> {
+        let now = self.clock.now().to_rfc3339();
+        let overrides = json_map(&self.type_retention.prune_after_secs);
+        let due = format!(
+            "source = 'mdns' AND CASE WHEN alive = 0 AND ?3 IS NOT NULL
+                 THEN unixepoch(dead_since) + ?3 < unixepoch(?1)
+                 ELSE unixepoch(last_seen) + {} < unixepoch(?1) END",
+            self.type_retention.lookup("?2", &prune_after_secs.to_string())
+        );
+        let journal_cutoff = self.retention_cutoff(prune_after_secs)?;
+
+        let tx = self.conn.unchecked_transaction()
+            .context("Failed to begin transaction")?;
+
+        // Only a prune that removes something takes a sequence number
+        let mut pruned = Vec::new();
+        if self.any_service(&due, params![now, overrides, self.tombstone_secs])? {
+            let seq = self.next_seq()?;
+            self.conn.execute(
+                &format!(
+                    "INSERT INTO changes (seq, instance_name, op, at)
+                     SELECT ?4, instance_name, 'remove', ?1 FROM services WHERE {}",
+                    due
+                ),
+                params![now, overrides, self.tombstone_secs, seq],
+            )
+            .context("Failed to journal pruned services")?;
+            if self.history_retention.is_some() {
+                self.conn.execute(
+                    &format!(
+                        "INSERT INTO service_events (instance_name, event, at)
+                         SELECT instance_name, 'pruned', ?1 FROM services WHERE {}",
+                        due
+                    ),
+                    params![now, overrides, self.tombstone_secs],
+                )
+                .context("Failed to record pruned services")?;
+            }
+            pruned = self
+                .conn
+                .prepare(&format!("DELETE FROM services WHERE {} RETURNING instance_name", due))?
+                .query_map(params![now, overrides, self.tombstone_secs], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()
+                .context("Failed to prune old services")?;
+        }
+
+        // The journal ages out too; clients behind the newest entry dropped
+        // can no longer be told about it, so they must resync
+        self.conn.execute(
+            "INSERT INTO sync_state (key, value)
+             SELECT 'floor', MAX(seq) FROM changes WHERE at < ?1 HAVING COUNT(*) > 0
+             ON CONFLICT(key) DO UPDATE SET value = MAX(value, excluded.value)",
+            params![journal_cutoff],
+        )
+        .context("Failed to advance sync floor")?;
+        self.conn.execute("DELETE FROM changes WHERE at < ?1", params![journal_cutoff])
+            .context("Failed to prune change journal")?;
+
+        tx.commit().context("Failed to commit pruning")?;
+        Ok(pruned)
+    }
+
+    /// Append an event to `instance_name`'s history, if history is kept
+    fn record_event(&self, instance_name: &str, event: ServiceEventKind, detail: Option<&str>) -> Result<()> {
+        if self.history_retention.is_none() {
+            return Ok(());
+        }
+        self.conn.execute(
+            "INSERT INTO service_events (instance_name, event, at, detail) VALUES (?1, ?2, ?3, ?4)",
+            params![instance_name, event.as_str(), self.clock.now().to_rfc3339(), detail],
+        )
+        .context("Failed to record service event")?;
+        Ok(())
+    }
+
+    /// `instance_name`'s recorded history, oldest first
+    pub fn history(&self, instance_name: &str) -> Result<Vec<ServiceEvent>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT event, at, detail FROM service_events WHERE instance_name = ?1 ORDER BY id")
+            .context("Failed to prepare query")?;
+        let rows = stmt
+            .query_map([instance_name], |row| {
+                let event: String = row.get(0)?;
+                let at: String = row.get(1)?;
+                Ok((event, at, row.get::<_, Option<String>>(2)?))
+            })
+            .context("Failed to query service history")?;
+        let mut events = Vec::new();
+        for row in rows {
+            let (event, at, detail) = row.context("Failed to collect service history")?;
+            events.push(ServiceEvent {
+                event: event.parse().map_err(anyhow::Error::msg)?,
+                at: DateTime::parse_from_rfc3339(&at)
+                    .context("Invalid event timestamp")?
+                    .with_timezone(&Utc),
+                detail,
+            });
+        }
+        Ok(events)
+    }
+
+    /// Drop history events older than the retention allows
+    pub fn prune_history(&self) -> Result<u64> {
+        let Some(secs) = self.history_retention else {
+            return Ok(0);
+        };
+        let cutoff = self.retention_cutoff(secs)?;
+        let count = self.conn.execute("DELETE FROM service_events WHERE at < ?1", [cutoff])
+            .context("Failed to prune service history")?;
+        Ok(count as u64)
+    }
+
+    /// The time before which entries kept for `secs` may go, as stored,
+    /// with `secs` raised to the TTL floor (see `retention_secs`)
+    fn retention_cutoff(&self, secs: u64) -> Result<String> {
+        let max_ttl: u64 = self.conn
+            .query_row("SELECT COALESCE(MAX(ttl), 0) FROM services", [], |row| row.get(0))
+            .context("Failed to query longest TTL")?;
+        let secs = retention_secs(secs, max_ttl, self.retention_ttl_factor);
+        let cutoff = self.clock.now().checked_sub_signed(chrono::Duration::seconds(secs as i64));
+        Ok(cutoff.unwrap_or(DateTime::<Utc>::MIN_UTC).to_rfc3339())
     }
 
     /// Helper to convert a database row to ServiceEntry
@@ -217,6 +1220,8 @@ impl CacheDb {
         let first_seen_str: String = row.get(6)?;
         let last_seen_str: String = row.get(7)?;
         let alive_int: i32 = row.get(9)?;
+        let source_str: String = row.get(10)?;
+        let subtypes_json: String = row.get(13)?;
 
         let addresses = serde_json::from_str(&addresses_json)
             .map_err(|e| rusqlite::Error::FromSqlConversionFailure(
@@ -232,6 +1237,13 @@ impl CacheDb {
                 Box::new(e),
             ))?;
 
+        let subtypes = serde_json::from_str(&subtypes_json)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(
+                13,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            ))?;
+
         let first_seen = chrono::DateTime::parse_from_rfc3339(&first_seen_str)
             .map_err(|e| rusqlite::Error::FromSqlConversionFailure(
                 6,
@@ -248,6 +1260,13 @@ impl CacheDb {
             ))?
             .with_timezone(&Utc);
 
+        let source = source_str.parse::<ServiceSource>()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(
+                10,
+                rusqlite::types::Type::Text,
+                e.into(),
+            ))?;
+
         Ok(ServiceEntry {
             instance_name: row.get(0)?,
             service_type: row.get(1)?,
@@ -259,13 +1278,315 @@ impl CacheDb {
             last_seen,
             ttl: row.get::<_, u32>(8)?,
             alive: alive_int != 0,
+            source,
+            reachable: row.get(11)?,
+            latency_ms: row.get(12)?,
+            subtypes,
+            iface: row.get(14)?,
         })
     }
 }
 
+/// How long history and journal entries are kept: the explicit retention
+/// `secs`, or `factor` times the longest cached TTL if that is longer, so
+/// a long-TTL service's entries outlast its announcement cycles. The
+/// explicit retention wins when larger; a factor of 0 leaves it alone.
+fn retention_secs(secs: u64, max_ttl: u64, factor: u64) -> u64 {
+    secs.max(max_ttl.saturating_mul(factor)).min(MAX_RETENTION_SECS)
+}
+
+/// A row count or offset as SQLite takes it, however large
+fn sql_count(n: usize) -> i64 {
+    i64::try_from(n).unwrap_or(i64::MAX)
+}
+
+/// Schema migrations in order. A database's `user_version` counts the steps
+/// already applied to it. Append new steps; never change released ones.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[baseline, dead_since, digest, sorted_txt_digest, iface];
+
+/// Apply the migrations `conn` hasn't had yet, each in its own transaction
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .context("Failed to read schema version")?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {} is newer than this build supports ({})",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    for (applied, step) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().context("Failed to begin migration")?;
+        step(&tx).with_context(|| format!("Failed to migrate schema to version {}", applied + 1))?;
+        tx.pragma_update(None, "user_version", applied + 1)
+            .context("Failed to record schema version")?;
+        tx.commit().context("Failed to commit migration")?;
+    }
+    Ok(())
+}
+
+/// Version 1: the schema as it stood before migrations were versioned.
+/// Databases from then may be at any earlier layout, so every part of this
+/// step checks before it changes anything.
+fn baseline(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS services (
+            instance_name TEXT PRIMARY KEY,
+            service_type  TEXT NOT NULL,
+            hostname      TEXT NOT NULL,
+            addresses     TEXT NOT NULL,
+            port          INTEGER NOT NULL,
+            txt           TEXT NOT NULL,
+            first_seen    TEXT NOT NULL,
+            last_seen     TEXT NOT NULL,
+            ttl           INTEGER NOT NULL,
+            alive         INTEGER NOT NULL DEFAULT 1,
+            source        TEXT NOT NULL DEFAULT 'mdns'
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_service_type ON services(service_type);
+
+        -- Append-only journal of every change, for /v1/changes. Changes
+        -- made together (e.g. a maintenance pass) share a sequence number.
+        CREATE TABLE IF NOT EXISTS changes (
+            seq           INTEGER NOT NULL,
+            instance_name TEXT NOT NULL,
+            op            TEXT NOT NULL,
+            at            TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_changes_seq ON changes(seq);
+
+        -- Full-text index for /v1/search. Trigrams match any substring of
+        -- three or more characters, case-insensitively.
+        CREATE VIRTUAL TABLE IF NOT EXISTS services_fts USING fts5(
+            key UNINDEXED, name, host, txt_values, tokenize = 'trigram'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS services_fts_insert AFTER INSERT ON services BEGIN
+            INSERT INTO services_fts (key, name, host, txt_values)
+            VALUES (new.instance_name, new.instance_name, new.hostname,
+                    (SELECT group_concat(value, ' ') FROM json_each(new.txt)));
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS services_fts_update AFTER UPDATE OF hostname, txt ON services BEGIN
+            UPDATE services_fts
+            SET host = new.hostname,
+                txt_values = (SELECT group_concat(value, ' ') FROM json_each(new.txt))
+            WHERE key = new.instance_name;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS services_fts_delete AFTER DELETE ON services BEGIN
+            DELETE FROM services_fts WHERE key = old.instance_name;
+        END;
+
+        -- State transitions of each service, for /v1/services/{instance}/history.
+        -- Kept after the service itself is deleted.
+        CREATE TABLE IF NOT EXISTS service_events (
+            id            INTEGER PRIMARY KEY AUTOINCREMENT,
+            instance_name TEXT NOT NULL,
+            event         TEXT NOT NULL,
+            at            TEXT NOT NULL,
+            detail        TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_service_events_instance ON service_events(instance_name, id);
+
+        -- 'seq': last change sequence number handed out
+        -- 'floor': changes at or below this may be missing (journal pruned)
+        CREATE TABLE IF NOT EXISTS sync_state (
+            key   TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        );
+        "#,
+    )
+    .context("Failed to create database schema")?;
+
+    // Databases created before the source column existed
+    add_column_if_missing(conn, "services", "source", "TEXT NOT NULL DEFAULT 'mdns'")?;
+    // Change sequence numbers: of the last change, and of the first insert
+    add_column_if_missing(conn, "services", "seq", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "services", "created_seq", "INTEGER NOT NULL DEFAULT 0")?;
+    // Liveness probe results, NULL until probed
+    add_column_if_missing(conn, "services", "reachable", "INTEGER")?;
+    add_column_if_missing(conn, "services", "latency_ms", "INTEGER")?;
+    add_column_if_missing(conn, "services", "subtypes", "TEXT NOT NULL DEFAULT '[]'")?;
+
+    // Removal records from before the journal existed
+    let tombstones = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'removed_services'")?
+        .exists([])
+        .context("Failed to check for removal records")?;
+    if tombstones {
+        conn.execute_batch(
+            "INSERT INTO changes (seq, instance_name, op, at)
+             SELECT seq, instance_name, 'remove', removed_at FROM removed_services ORDER BY seq;
+             DROP TABLE removed_services;",
+        )
+        .context("Failed to move removal records into the journal")?;
+    }
+
+    // Index rows from before the search index existed
+    let indexed: bool = conn
+        .query_row(
+            "SELECT (SELECT COUNT(*) FROM services_fts) = (SELECT COUNT(*) FROM services)",
+            [],
+            |row| row.get(0),
+        )
+        .context("Failed to check search index")?;
+    if !indexed {
+        conn.execute_batch(
+            "DELETE FROM services_fts;
+             INSERT INTO services_fts (key, name, host, txt_values)
+             SELECT instance_name, instance_name, hostname,
+                    (SELECT group_concat(value, ' ') FROM json_each(services.txt))
+             FROM services;",
+        )
+        .context("Failed to rebuild search index")?;
+    }
+
+    // Rows from before sequence numbers existed count as one batch of adds
+    conn.execute_batch(
+        "INSERT INTO sync_state (key, value)
+         SELECT 'seq', 1 WHERE EXISTS (SELECT 1 FROM services WHERE seq = 0)
+         ON CONFLICT(key) DO UPDATE SET value = value + 1;
+         UPDATE services SET seq = (SELECT value FROM sync_state WHERE key = 'seq'),
+                             created_seq = (SELECT value FROM sync_state WHERE key = 'seq')
+         WHERE seq = 0;",
+    )
+    .context("Failed to number existing services")?;
+    Ok(())
+}
+
+/// Version 2: when each dead service died, so tombstones can be kept for a
+/// fixed time. Services already dead count from when they were last seen.
+fn dead_since(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE services ADD COLUMN dead_since TEXT;
+         UPDATE services SET dead_since = last_seen WHERE alive = 0;",
+    )
+    .context("Failed to add dead_since column")
+}
+
+/// Version 3: each service's hash digest, so the cache hash needn't
+/// serialize every row. Existing rows get theirs when first read.
+fn digest(conn: &Connection) -> Result<()> {
+    conn.execute_batch("ALTER TABLE services ADD COLUMN digest BLOB;")
+        .context("Failed to add digest column")
+}
+
+/// Version 4: digests hash TXT keys in sorted order. Stored ones may not,
+/// so they're cleared to be recomputed when next read.
+fn sorted_txt_digest(conn: &Connection) -> Result<()> {
+    conn.execute("UPDATE services SET digest = NULL", [])
+        .context("Failed to clear service digests")?;
+    Ok(())
+}
+
+/// Version 5: the interface each service was last heard on, for
+/// `/v1/interfaces/{iface}/services`
+fn iface(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE services ADD COLUMN iface TEXT;
+         CREATE INDEX IF NOT EXISTS idx_services_iface ON services(iface);",
+    )
+    .context("Failed to add iface column")
+}
+
+/// Key a newly opened connection, if `key` is given, and check the key by
+/// reading the schema: a wrong key only shows when the first page is read
+fn apply_key(conn: &Connection, key: Option<&str>) -> Result<()> {
+    if let Some(key) = key {
+        require_sqlcipher()?;
+        conn.pragma_update(None, "key", key).context("Failed to set encryption key")?;
+    }
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))
+        .map_err(|e| match key {
+            Some(_) => anyhow::Error::new(e).context("Wrong encryption key, or not an encrypted database"),
+            None => anyhow::Error::new(e).context("Not a database, or an encrypted one"),
+        })?;
+    Ok(())
+}
+
+/// Plain SQLite ignores `PRAGMA key`, which would leave a database meant
+/// to be encrypted in the clear
+fn require_sqlcipher() -> Result<()> {
+    anyhow::ensure!(cfg!(feature = "sqlcipher"), "Database encryption needs the `sqlcipher` feature");
+    Ok(())
+}
+
+/// Whether the file at `path` is an unencrypted SQLite database. SQLCipher
+/// encrypts the header too, so only plaintext files start with the magic.
+fn is_plaintext(path: &Path) -> Result<bool> {
+    use std::io::Read;
+
+    let mut header = [0u8; 16];
+    match std::fs::File::open(path) {
+        Ok(mut file) => Ok(file.read_exact(&mut header).is_ok() && &header == b"SQLite format 3\0"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to read database: {}", path.display())),
+    }
+}
+
+/// Replace the unencrypted database at `path` with an encrypted copy.
+/// `sqlcipher_export` copies the schema and rows but not `user_version`,
+/// which is carried over so migrations aren't applied twice.
+fn encrypt_database(path: &Path, key: &str) -> Result<()> {
+    let encrypted = path.with_extension("encrypting");
+    let _ = std::fs::remove_file(&encrypted);
+    {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open database: {}", path.display()))?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        conn.execute("ATTACH DATABASE ?1 AS encrypted KEY ?2", params![encrypted.to_string_lossy(), key])
+            .context("Failed to create encrypted copy")?;
+        conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))
+            .context("Failed to copy database into encrypted copy")?;
+        conn.pragma_update(Some(DatabaseName::Attached("encrypted")), "user_version", version)?;
+        conn.execute_batch("DETACH DATABASE encrypted;")?;
+    }
+    // The plaintext WAL belongs to the file being replaced
+    remove_wal(path);
+    std::fs::rename(&encrypted, path)
+        .with_context(|| format!("Failed to move encrypted database to {}", path.display()))?;
+    Ok(())
+}
+
+/// Remove the WAL and shared-memory files of a database no longer in use
+fn remove_wal(path: &Path) {
+    for suffix in ["-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
+
+/// Copy all of `src` over `dst` in one backup step, so the copy is a single
+/// consistent snapshot
+fn copy_database(src: &Connection, dst: &mut Connection) -> Result<()> {
+    match Backup::new(src, dst)?.step(-1)? {
+        StepResult::Done => Ok(()),
+        _ => anyhow::bail!("database busy"),
+    }
+}
+
+/// Add a column to an existing table unless it is already present
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", table))?
+        .exists([column])
+        .with_context(|| format!("Failed to inspect table {}", table))?;
+
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))
+            .with_context(|| format!("Failed to add column {}.{}", table, column))?;
+    }
+    Ok(())
+}
+
 /// Fix #7: compare meaningful service fields in Rust — avoids fragile SQL
 /// concatenation that duplicated field order and serialization across two languages.
-fn service_data_changed(old: &ServiceEntry, new: &ServiceEntry) -> bool {
+pub(crate) fn service_data_changed(old: &ServiceEntry, new: &ServiceEntry) -> bool {
     old.hostname != new.hostname
         || old.addresses != new.addresses
         || old.port != new.port
@@ -273,26 +1594,101 @@ fn service_data_changed(old: &ServiceEntry, new: &ServiceEntry) -> bool {
         || old.ttl != new.ttl
         || old.alive != new.alive
         || old.service_type != new.service_type
+        || (old.subtypes != new.subtypes && !keeps_subtypes(new))
+        || (old.iface != new.iface && !keeps_iface(new))
+}
+
+/// `entry` as `upsert_service` leaves it over `existing`: an unprobed entry
+/// keeps the stored probe result, and perhaps the stored subtypes and
+/// interface
+fn stored_entry<'a>(existing: Option<&ServiceEntry>, entry: &'a ServiceEntry) -> Cow<'a, ServiceEntry> {
+    let Some(old) = existing else {
+        return Cow::Borrowed(entry);
+    };
+    let keeps_reachable = entry.reachable.is_none() && old.reachable.is_some();
+    let keeps_subtypes = keeps_subtypes(entry) && !old.subtypes.is_empty();
+    let keeps_iface = keeps_iface(entry) && old.iface.is_some();
+    if !keeps_reachable && !keeps_subtypes && !keeps_iface {
+        return Cow::Borrowed(entry);
+    }
+    let mut stored = entry.clone();
+    if keeps_reachable {
+        stored.reachable = old.reachable;
+        stored.latency_ms = old.latency_ms;
+    }
+    if keeps_subtypes {
+        stored.subtypes = old.subtypes.clone();
+    }
+    if keeps_iface {
+        stored.iface = old.iface.clone();
+    }
+    Cow::Owned(stored)
+}
+
+/// The history event for replacing `old` with `new`, if any
+fn transition(old: Option<&ServiceEntry>, new: &ServiceEntry) -> Option<(ServiceEventKind, Option<String>)> {
+    let Some(old) = old else {
+        return Some((ServiceEventKind::Resolved, None));
+    };
+    match (old.alive, new.alive) {
+        (false, true) => return Some((ServiceEventKind::Resolved, None)),
+        (true, false) => return Some((ServiceEventKind::Dead, None)),
+        _ => {}
+    }
+    let fields: Vec<&str> = [
+        ("service_type", old.service_type != new.service_type),
+        ("hostname", old.hostname != new.hostname),
+        ("addresses", old.addresses != new.addresses),
+        ("port", old.port != new.port),
+        ("txt", old.txt != new.txt),
+        ("subtypes", old.subtypes != new.subtypes && !keeps_subtypes(new)),
+        ("iface", old.iface != new.iface && !keeps_iface(new)),
+    ]
+    .into_iter()
+    .filter_map(|(field, changed)| changed.then_some(field))
+    .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    Some((ServiceEventKind::Changed, Some(fields.join(", "))))
+}
+
+/// Subtypes are only heard in announcements, so an mDNS entry resolved
+/// without any keeps the ones already stored
+pub(crate) fn keeps_subtypes(entry: &ServiceEntry) -> bool {
+    entry.subtypes.is_empty() && entry.source == ServiceSource::Mdns
+}
+
+/// Likewise the interface: an mDNS entry resolved before its announcement
+/// was heard keeps the interface it was last heard on
+pub(crate) fn keeps_iface(entry: &ServiceEntry) -> bool {
+    entry.iface.is_none() && entry.source == ServiceSource::Mdns
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+    use crate::cache::clock::MockClock;
     use std::collections::HashMap;
-    use std::net::Ipv6Addr;
 
     fn test_entry() -> ServiceEntry {
         ServiceEntry {
             service_type: "_http._tcp".to_string(),
             instance_name: "test._http._tcp.local.".to_string(),
             hostname: "test.local.".to_string(),
-            addresses: vec![Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 1)],
+            addresses: vec![Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 1).into()],
             port: 8080,
             txt: HashMap::from([("path".to_string(), "/api".to_string())]),
             first_seen: Utc::now(),
             last_seen: Utc::now(),
             ttl: 4500,
             alive: true,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
         }
     }
 
@@ -338,6 +1734,73 @@ mod tests {
         assert!(!retrieved.alive);
     }
 
+    #[test]
+    fn test_type_conflict_rejected_by_default() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+        db.upsert_service(&entry).unwrap();
+
+        let mut conflicting = test_entry();
+        conflicting.service_type = "_ipp._tcp".to_string();
+        let err = db.upsert_service(&conflicting).unwrap_err();
+        assert!(err.is::<TypeConflictError>(), "Unexpected error: {:#}", err);
+
+        let stored = db.get_service(&entry.instance_name).unwrap().unwrap();
+        assert_eq!(stored.service_type, "_http._tcp");
+    }
+
+    #[test]
+    fn test_type_conflict_replace() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        db.set_type_conflict_policy(TypeConflictPolicy::Replace);
+        let entry = test_entry();
+        db.upsert_service(&entry).unwrap();
+
+        let mut conflicting = test_entry();
+        conflicting.service_type = "_ipp._tcp".to_string();
+        assert!(db.upsert_service(&conflicting).unwrap());
+
+        let stored = db.get_service(&entry.instance_name).unwrap().unwrap();
+        assert_eq!(stored.service_type, "_ipp._tcp");
+    }
+
+    #[test]
+    fn test_service_exists() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+
+        assert!(!db.service_exists(&entry.instance_name, true).unwrap());
+
+        db.upsert_service(&entry).unwrap();
+        assert!(db.service_exists(&entry.instance_name, false).unwrap());
+
+        db.mark_dead(&entry.instance_name).unwrap();
+        assert!(db.service_exists(&entry.instance_name, true).unwrap());
+        assert!(!db.service_exists(&entry.instance_name, false).unwrap());
+    }
+
+    #[test]
+    fn test_unseen_service_types() {
+        let db = CacheDb::open(":memory:").unwrap();
+
+        let mut quiet = test_entry();
+        quiet.instance_name = "quiet._http._tcp.local.".to_string();
+        quiet.last_seen = Utc::now() - chrono::Duration::seconds(600);
+
+        let mut fresh = test_entry();
+        fresh.service_type = "_ssh._tcp".to_string();
+        fresh.instance_name = "fresh._ssh._tcp.local.".to_string();
+
+        db.upsert_service(&quiet).unwrap();
+        db.upsert_service(&fresh).unwrap();
+
+        assert_eq!(db.unseen_service_types(StaleAfter::secs(300)).unwrap(), vec!["_http._tcp".to_string()]);
+
+        // Already-dead services don't need probing
+        db.mark_dead(&quiet.instance_name).unwrap();
+        assert!(db.unseen_service_types(StaleAfter::secs(300)).unwrap().is_empty());
+    }
+
     #[test]
     fn test_get_services_by_type() {
         let db = CacheDb::open(":memory:").unwrap();
@@ -356,10 +1819,683 @@ mod tests {
         db.upsert_service(&entry2).unwrap();
         db.upsert_service(&entry3).unwrap();
 
-        let http_services = db.get_services_by_type("_http._tcp").unwrap();
-        assert_eq!(http_services.len(), 2);
+        let by_type = |service_type: &str| {
+            let filter = ServiceFilter { service_type: Some(service_type.to_string()), ..Default::default() };
+            db.query_services(&filter, &Page::default()).unwrap().services
+        };
+        assert_eq!(by_type("_http._tcp").len(), 2);
+        assert_eq!(by_type("_ssh._tcp").len(), 1);
+    }
+
+    #[test]
+    fn test_get_services_by_address_keeps_shared_address() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let shared: IpAddr = Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 1).into();
+
+        let mut web = test_entry();
+        web.instance_name = "web._http._tcp.local.".to_string();
+        web.port = 80;
+
+        let mut api = test_entry();
+        api.instance_name = "api._http._tcp.local.".to_string();
+        api.port = 8080;
+
+        let mut other = test_entry();
+        other.instance_name = "other._http._tcp.local.".to_string();
+        other.addresses = vec![Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 2).into()];
+
+        db.upsert_service(&web).unwrap();
+        db.upsert_service(&api).unwrap();
+        db.upsert_service(&other).unwrap();
+
+        let filter = ServiceFilter { address: Some(shared), ..Default::default() };
+        let mut ports: Vec<u16> = db
+            .query_services(&filter, &Page::default())
+            .unwrap()
+            .services
+            .iter()
+            .map(|s| s.port)
+            .collect();
+        ports.sort();
+        assert_eq!(ports, vec![80, 8080]);
+    }
+
+    #[test]
+    fn test_imported_services_not_pruned() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let old = Utc::now() - chrono::Duration::seconds(7200);
+
+        let mut discovered = test_entry();
+        discovered.last_seen = old;
+
+        let mut imported = test_entry();
+        imported.instance_name = "imported._http._tcp.local.".to_string();
+        imported.last_seen = old;
+        imported.source = ServiceSource::Import;
+
+        let mut registered = test_entry();
+        registered.instance_name = "vm._ssh._tcp.local.".to_string();
+        registered.last_seen = old;
+        registered.source = ServiceSource::Static;
+
+        db.upsert_service(&discovered).unwrap();
+        db.upsert_service(&imported).unwrap();
+        db.upsert_service(&registered).unwrap();
+
+        db.mark_stale(StaleAfter::secs(300)).unwrap();
+        db.prune_stale(3600).unwrap();
+
+        let mut remaining = db.get_all_services().unwrap();
+        remaining.sort_by(|a, b| a.instance_name.cmp(&b.instance_name));
+        assert_eq!(remaining.len(), 2);
+        assert_eq!(remaining[0].source, ServiceSource::Import);
+        assert!(remaining[0].alive);
+        assert_eq!(remaining[1].source, ServiceSource::Static);
+        assert!(remaining[1].alive);
+    }
+
+    #[test]
+    fn test_stale_and_prune_follow_clock() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+        let clock = MockClock::new(entry.last_seen);
+        db.set_clock(clock.clone());
+        db.upsert_service(&entry).unwrap();
+
+        clock.advance(chrono::Duration::seconds(299));
+        assert_eq!(db.mark_stale(StaleAfter::secs(300)).unwrap(), 0);
+
+        clock.advance(chrono::Duration::seconds(2));
+        assert_eq!(db.mark_stale(StaleAfter::secs(300)).unwrap(), 1);
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 0);
+
+        clock.advance(chrono::Duration::seconds(3600));
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reachability_survives_reresolve() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+        db.upsert_service(&entry).unwrap();
+
+        assert!(db.set_reachability(&entry.instance_name, true, Some(4)).unwrap());
+        assert!(!db.set_reachability(&entry.instance_name, true, Some(6)).unwrap(), "latency alone is no change");
+        assert!(!db.set_reachability("missing._http._tcp.local.", true, Some(6)).unwrap());
+
+        // The browser doesn't probe, so its entries leave the result alone
+        db.upsert_service(&entry).unwrap();
+        let stored = db.get_service(&entry.instance_name).unwrap().unwrap();
+        assert_eq!((stored.reachable, stored.latency_ms), (Some(true), Some(6)));
+
+        assert!(db.set_reachability(&entry.instance_name, false, None).unwrap());
+        let stored = db.get_service(&entry.instance_name).unwrap().unwrap();
+        assert_eq!((stored.reachable, stored.latency_ms), (Some(false), None));
+    }
+
+    #[test]
+    fn test_digests_follow_changes() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let fields: HashFields = serde_json::from_str(r#"["alive", "reachable", "subtypes"]"#).unwrap();
+        db.set_hash_fields(fields.clone()).unwrap();
+        let assert_digests = |db: &CacheDb| {
+            let (services, digests) = db.digested_services().unwrap();
+            let expected: Vec<_> = services.iter().map(|s| hash::entry_digest(s, &fields)).collect();
+            assert_eq!(digests, expected);
+            let missing: i64 = db.conn
+                .query_row("SELECT COUNT(*) FROM services WHERE digest IS NULL", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(missing, 0, "recomputed digests are stored");
+        };
+
+        let entry = ServiceEntry { subtypes: vec!["_printer".to_string()], ..test_entry() };
+        db.upsert_service(&entry).unwrap();
+        assert_digests(&db);
+
+        // Re-resolves keep the stored probe result and subtypes
+        db.set_reachability(&entry.instance_name, true, Some(4)).unwrap();
+        assert_digests(&db);
+        db.upsert_service(&ServiceEntry { subtypes: Vec::new(), ..entry.clone() }).unwrap();
+        assert_digests(&db);
+
+        db.mark_dead(&entry.instance_name).unwrap();
+        assert_digests(&db);
+    }
+
+    #[test]
+    fn test_subtypes_kept_and_queried() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let entry = ServiceEntry { subtypes: vec!["_printer".to_string()], ..test_entry() };
+        db.upsert_service(&entry).unwrap();
+
+        // Resolved again before the next announcement is heard
+        let resolved = ServiceEntry { subtypes: Vec::new(), ..entry.clone() };
+        assert!(!db.upsert_service(&resolved).unwrap());
+        assert_eq!(db.get_service(&entry.instance_name).unwrap().unwrap().subtypes, ["_printer"]);
+
+        let query = |subtype: &str| {
+            let filter = ServiceFilter { subtype: Some(subtype.to_string()), ..Default::default() };
+            let page = db.query_services(&filter, &Page::default()).unwrap();
+            assert!(page.services.iter().all(|s| filter.matches(s)));
+            page.total
+        };
+        assert_eq!(query("_printer"), 1);
+        assert_eq!(query("_scanner"), 0);
+
+        // Registrations say exactly which subtypes they have
+        let registered = ServiceEntry { source: ServiceSource::Static, ..resolved };
+        db.upsert_service(&registered).unwrap();
+        assert_eq!(query("_printer"), 0);
+    }
+
+    #[test]
+    fn test_iface_kept_and_queried() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        db.set_history_retention(Some(60));
+        let entry = ServiceEntry { iface: Some("eth1".to_string()), ..test_entry() };
+        assert!(db.upsert_service(&entry).unwrap());
+
+        // Resolved again before the next announcement is heard
+        let resolved = ServiceEntry { iface: None, ..entry.clone() };
+        assert!(!db.upsert_service(&resolved).unwrap());
+        assert_eq!(db.get_service(&entry.instance_name).unwrap().unwrap().iface.as_deref(), Some("eth1"));
+
+        let query = |iface: &str| {
+            let filter = ServiceFilter { iface: Some(iface.to_string()), ..Default::default() };
+            let page = db.query_services(&filter, &Page::default()).unwrap();
+            assert!(page.services.iter().all(|s| filter.matches(s)));
+            page.total
+        };
+        assert_eq!(query("eth1"), 1);
+        assert_eq!(query("eth0"), 0);
+
+        // Heard on another segment: a change, and recorded as one
+        assert!(db.upsert_service(&ServiceEntry { iface: Some("eth0".to_string()), ..entry.clone() }).unwrap());
+        assert_eq!((query("eth0"), query("eth1")), (1, 0));
+        let history = db.history(&entry.instance_name).unwrap();
+        assert_eq!(history.last().unwrap().detail.as_deref(), Some("iface"));
+
+        // Registrations belong to no interface
+        db.upsert_service(&ServiceEntry { source: ServiceSource::Static, ..resolved }).unwrap();
+        assert_eq!(query("eth0"), 0);
+    }
+
+    #[test]
+    fn test_stale_by_ttl() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let short = ServiceEntry { ttl: 120, ..test_entry() };
+        let long = ServiceEntry {
+            service_type: "_ipp._tcp".to_string(),
+            instance_name: "long._ipp._tcp.local.".to_string(),
+            ..test_entry()
+        };
+        let clock = MockClock::new(short.last_seen);
+        db.set_clock(clock.clone());
+        db.upsert_service(&short).unwrap();
+        db.upsert_service(&long).unwrap();
+
+        let stale = StaleAfter::Ttl { early_secs: 0 };
+        clock.advance(chrono::Duration::seconds(61));
+        assert!(db.unseen_service_types(stale).unwrap().is_empty());
+        assert_eq!(db.unseen_service_types(stale.earlier(60)).unwrap(), ["_http._tcp"]);
+
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(db.mark_stale(stale).unwrap(), 1);
+        assert!(!db.get_service(&short.instance_name).unwrap().unwrap().alive);
+        assert!(db.get_service(&long.instance_name).unwrap().unwrap().alive);
+    }
+
+    #[test]
+    fn test_type_retention_overrides() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let web = test_entry();
+        let printer = ServiceEntry {
+            service_type: "_ipp._tcp".to_string(),
+            instance_name: "printer._ipp._tcp.local.".to_string(),
+            ..test_entry()
+        };
+        let clock = MockClock::new(web.last_seen);
+        db.set_clock(clock.clone());
+        db.set_type_retention(TypeRetention {
+            stale_after_secs: HashMap::from([("_ipp._tcp".to_string(), 7200)]),
+            prune_after_secs: HashMap::from([("_ipp._tcp".to_string(), 86400)]),
+        });
+        db.upsert_service(&web).unwrap();
+        db.upsert_service(&printer).unwrap();
+
+        clock.advance(chrono::Duration::seconds(301));
+        assert_eq!(db.unseen_service_types(StaleAfter::secs(300)).unwrap(), ["_http._tcp"]);
+        assert_eq!(db.mark_stale(StaleAfter::secs(300)).unwrap(), 1);
+        assert!(db.get_service(&printer.instance_name).unwrap().unwrap().alive);
+
+        clock.advance(chrono::Duration::seconds(7200));
+        assert_eq!(db.mark_stale(StaleAfter::secs(300)).unwrap(), 1);
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 1);
+        assert!(db.get_service(&printer.instance_name).unwrap().is_some());
+
+        clock.advance(chrono::Duration::seconds(86400));
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 1);
+        assert!(db.get_service(&printer.instance_name).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tombstones_kept_from_death() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+        let clock = MockClock::new(entry.last_seen);
+        db.set_clock(clock.clone());
+        db.set_tombstone_retention(Some(86400));
+        db.upsert_service(&entry).unwrap();
+
+        clock.advance(chrono::Duration::seconds(3000));
+        db.mark_dead(&entry.instance_name).unwrap();
+        clock.advance(chrono::Duration::seconds(3601));
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 0, "prune_after_secs doesn't apply to the dead");
+
+        let alive_only = ServiceFilter { alive_only: true, ..Default::default() };
+        assert_eq!(db.query_services(&alive_only, &Page::default()).unwrap().total, 0);
+        let tombstone = db.query_services(&ServiceFilter::default(), &Page::default()).unwrap().services;
+        assert!(!tombstone[0].alive);
+
+        // Coming back alive and dying again restarts the window
+        db.upsert_service(&entry).unwrap();
+        db.mark_dead(&entry.instance_name).unwrap();
+        clock.advance(chrono::Duration::seconds(86400));
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 0);
+        clock.advance(chrono::Duration::seconds(1));
+        assert_eq!(db.prune_stale(3600).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_changes_since_tracks_adds_updates_removals() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let a = test_entry();
+        let clock = MockClock::new(a.last_seen);
+        db.set_clock(clock.clone());
+        let mut b = test_entry();
+        b.instance_name = "b._http._tcp.local.".to_string();
+        db.upsert_service(&a).unwrap();
+        db.upsert_service(&b).unwrap();
+
+        let all = db.changes_since(0).unwrap();
+        assert!(!all.resync);
+        assert_eq!(all.added.len(), 2);
+
+        // Re-announcing unchanged data is not a change
+        db.upsert_service(&a).unwrap();
+        assert!(db.changes_since(all.seq).unwrap().added.is_empty());
+
+        let mut moved = a.clone();
+        moved.port = 9090;
+        db.upsert_service(&moved).unwrap();
+        let changes = db.changes_since(all.seq).unwrap();
+        assert!(changes.added.is_empty());
+        assert_eq!(changes.updated.len(), 1);
+        assert_eq!(changes.updated[0].port, 9090);
+
+        clock.advance(chrono::Duration::seconds(7200));
+        db.prune_stale(3600).unwrap();
+        let pruned = db.changes_since(changes.seq).unwrap();
+        assert_eq!(pruned.removed.len(), 2);
+        assert!(pruned.updated.is_empty());
+
+        // Once the removal records age out, older cursors must resync
+        clock.advance(chrono::Duration::seconds(7200));
+        db.prune_stale(3600).unwrap();
+        assert!(db.changes_since(changes.seq).unwrap().resync);
+        assert!(!db.changes_since(db.changes_since(0).unwrap().seq).unwrap().resync);
+        assert!(db.changes_since(u64::MAX).unwrap().resync);
+    }
+
+    #[test]
+    fn test_idle_maintenance_keeps_seq() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+        let clock = MockClock::new(entry.last_seen);
+        db.set_clock(clock.clone());
+        db.upsert_service(&entry).unwrap();
+        let seq = db.changes_since(0).unwrap().seq;
+
+        // Nothing is due: no sequence number is used up
+        db.mark_stale(StaleAfter::secs(3600)).unwrap();
+        db.prune_stale(7200).unwrap();
+        assert_eq!(db.changes_since(0).unwrap().seq, seq);
+
+        clock.advance(chrono::Duration::seconds(3601));
+        assert_eq!(db.mark_stale(StaleAfter::secs(3600)).unwrap(), 1);
+        assert_eq!(db.changes_since(0).unwrap().seq, seq + 1);
+    }
+
+    #[test]
+    fn test_journal_outlasts_longest_ttl() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let mut pinned = test_entry();
+        pinned.source = ServiceSource::Static;
+        let clock = MockClock::new(pinned.last_seen);
+        db.set_clock(clock.clone());
+        db.set_retention_ttl_factor(2);
+        let mut gone = test_entry();
+        gone.instance_name = "gone._http._tcp.local.".to_string();
+        db.upsert_service(&pinned).unwrap();
+        db.upsert_service(&gone).unwrap();
+        db.purge_service(&gone.instance_name).unwrap();
+
+        // The cached 4500s TTL keeps the journal for 9000s, not 3600s
+        clock.advance(chrono::Duration::seconds(3601));
+        db.prune_stale(3600).unwrap();
+        let changes = db.changes_since(0).unwrap();
+        assert!(!changes.resync);
+        assert_eq!(changes.removed, vec![gone.instance_name.clone()]);
+
+        clock.advance(chrono::Duration::seconds(5400));
+        db.prune_stale(3600).unwrap();
+        assert!(db.changes_since(0).unwrap().resync);
+    }
+
+    #[test]
+    fn test_ttl_floor_against_explicit_retention() {
+        // A longer explicit retention wins
+        assert_eq!(retention_secs(86400, 4500, 2), 86400);
+        // A shorter one is raised to the floor
+        assert_eq!(retention_secs(3600, 4500, 2), 9000);
+        // No floor, or nothing cached
+        assert_eq!(retention_secs(3600, 4500, 0), 3600);
+        assert_eq!(retention_secs(3600, 0, 2), 3600);
+    }
+
+    #[test]
+    fn test_retention_cutoff_saturates() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let mut entry = test_entry();
+        entry.ttl = u32::MAX;
+        db.set_retention_ttl_factor(u64::MAX);
+        db.set_history_retention(Some(u64::MAX));
+        db.upsert_service(&entry).unwrap();
+        db.mark_dead(&entry.instance_name).unwrap();
+
+        // Kept as long as can be, rather than overflowing into the future
+        assert_eq!(db.prune_history().unwrap(), 0);
+        db.prune_stale(3600).unwrap();
+        assert!(!db.changes_since(0).unwrap().resync);
+        assert!(!db.history(&entry.instance_name).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_change_seq_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("zerocomfy-seq-{}.db", std::process::id()));
+        let seq = {
+            let db = CacheDb::open(&path).unwrap();
+            db.upsert_service(&test_entry()).unwrap();
+            db.changes_since(0).unwrap().seq
+        };
+
+        let db = CacheDb::open(&path).unwrap();
+        let changes = db.changes_since(seq).unwrap();
+        assert_eq!(changes.seq, seq);
+        assert!(!changes.resync);
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_migrates_unversioned_database() {
+        let path = std::env::temp_dir().join(format!("zerocomfy-migrate-{}.db", std::process::id()));
+        {
+            // The original layout: no source, sequence, or probe columns
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE services (
+                    instance_name TEXT PRIMARY KEY, service_type TEXT NOT NULL, hostname TEXT NOT NULL,
+                    addresses TEXT NOT NULL, port INTEGER NOT NULL, txt TEXT NOT NULL,
+                    first_seen TEXT NOT NULL, last_seen TEXT NOT NULL, ttl INTEGER NOT NULL,
+                    alive INTEGER NOT NULL DEFAULT 1
+                );
+                INSERT INTO services VALUES ('old._http._tcp.local.', '_http._tcp', 'old.local.', '[]', 80,
+                    '{}', '2024-01-01T00:00:00+00:00', '2024-01-01T00:00:00+00:00', 120, 1);",
+            )
+            .unwrap();
+        }
+
+        let db = CacheDb::open(&path).unwrap();
+        let old = db.get_service("old._http._tcp.local.").unwrap().unwrap();
+        assert_eq!(old.source, ServiceSource::Mdns);
+        assert_eq!(db.changes_since(0).unwrap().added.len(), 1);
+        assert_eq!(db.search("old", 10).unwrap().len(), 1);
+
+        // A database from a newer build is left alone
+        db.conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(db);
+        assert!(CacheDb::open(&path).is_err());
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = std::env::temp_dir().join(format!("zerocomfy-backup-{}", std::process::id()));
+        let backup = dir.join("backups/services.db");
+        let restored = dir.join("restored.db");
+        let db = CacheDb::open(dir.join("services.db")).unwrap();
+        db.upsert_service(&test_entry()).unwrap();
+        db.backup(&backup).unwrap();
+
+        // Not a backup: nothing is overwritten
+        std::fs::write(dir.join("junk"), b"not a database").unwrap();
+        assert!(CacheDb::restore_backup(&restored, dir.join("junk"), None).is_err());
+        assert!(!restored.exists());
+
+        CacheDb::restore_backup(&restored, &backup, None).unwrap();
+        let copy = CacheDb::open(&restored).unwrap();
+        assert_eq!(copy.get_all_services().unwrap().len(), 1);
+        assert_eq!(copy.changes_since(0).unwrap().seq, db.changes_since(0).unwrap().seq);
+        drop((db, copy));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_encrypted_database() {
+        let dir = std::env::temp_dir().join(format!("zerocomfy-encrypted-{}", std::process::id()));
+        let path = dir.join("services.db");
+        let backup = dir.join("backup.db");
+        CacheDb::open(&path).unwrap().upsert_service(&test_entry()).unwrap();
+        assert!(is_plaintext(&path).unwrap());
+
+        // Encrypted in place on the first keyed open
+        let db = CacheDb::open_with_key(&path, Some("secret")).unwrap();
+        assert_eq!(db.get_all_services().unwrap().len(), 1);
+        db.backup(&backup).unwrap();
+        drop(db);
+        assert!(!is_plaintext(&path).unwrap());
+        assert!(CacheDb::open(&path).is_err());
+        assert!(CacheDb::open_with_key(&path, Some("wrong")).is_err());
+
+        // Backups share the key
+        assert!(CacheDb::restore_backup(dir.join("restored.db"), &backup, None).is_err());
+        CacheDb::restore_backup(dir.join("restored.db"), &backup, Some("secret")).unwrap();
+        let copy = CacheDb::open_with_key(dir.join("restored.db"), Some("secret")).unwrap();
+        assert_eq!(copy.get_all_services().unwrap().len(), 1);
+        drop(copy);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_key_needs_sqlcipher() {
+        let Err(err) = CacheDb::open_with_key(":memory:", Some("secret")) else {
+            panic!("a key must not be ignored by plain SQLite");
+        };
+        assert!(err.to_string().contains("`sqlcipher` feature"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_compact_releases_space() {
+        let path = std::env::temp_dir().join(format!("zerocomfy-compact-{}.db", std::process::id()));
+        let db = CacheDb::open(&path).unwrap();
+        for i in 0..500 {
+            let mut entry = test_entry();
+            entry.instance_name = format!("{}._http._tcp.local.", i);
+            entry.txt.insert("padding".to_string(), "x".repeat(500));
+            db.upsert_service(&entry).unwrap();
+        }
+        db.conn.execute_batch("DELETE FROM services; PRAGMA wal_checkpoint;").unwrap();
+
+        let compacted = db.compact(Compaction { wal_bytes: 1, free_percent: 10 }).unwrap();
+        assert!(compacted.wal_bytes.is_some());
+        assert!(compacted.free_pages > 0);
+        let free: u64 = db.conn.query_row("PRAGMA freelist_count", [], |row| row.get(0)).unwrap();
+        assert_eq!(free, 0);
+
+        // Nothing left to reclaim
+        assert_eq!(db.compact(Compaction { wal_bytes: 0, free_percent: 10 }).unwrap(), Compacted::default());
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_query_services_pages_in_sql() {
+        let db = CacheDb::open(":memory:").unwrap();
+        for name in ["d", "b", "a", "c"] {
+            let mut entry = test_entry();
+            entry.instance_name = format!("{}._http._tcp.local.", name);
+            if name == "c" {
+                entry.txt = HashMap::from([("Version".to_string(), "2".to_string())]);
+            }
+            db.upsert_service(&entry).unwrap();
+        }
+
+        let page = Page { limit: Some(2), ..Default::default() };
+        let first = db.query_services(&ServiceFilter::default(), &page).unwrap();
+        assert_eq!(first.total, 4);
+        assert_eq!(first.services[1].instance_name, "b._http._tcp.local.");
+        assert_eq!(first.next.as_deref(), Some("b._http._tcp.local."));
+
+        let page = Page { limit: Some(2), offset: 0, after: first.next };
+        let second = db.query_services(&ServiceFilter::default(), &page).unwrap();
+        assert_eq!(second.services.len(), 2);
+        assert_eq!(second.next, None);
+
+        for limit in [usize::MAX, i64::MAX as usize] {
+            let huge = db.query_services(&ServiceFilter::default(), &Page { limit: Some(limit), ..Default::default() }).unwrap();
+            assert_eq!(huge.services.len(), 4);
+            assert_eq!(huge.next, None);
+        }
+
+        let filter = ServiceFilter { has_txt: Some("version".to_string()), ..Default::default() };
+        let filtered = db.query_services(&filter, &Page::default()).unwrap();
+        assert_eq!(filtered.total, 1);
+        assert_eq!(filtered.services[0].instance_name, "c._http._tcp.local.");
+    }
+
+    #[test]
+    fn test_query_services_txt_values() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let mut v1 = test_entry();
+        v1.instance_name = "v1._http._tcp.local.".to_string();
+        v1.txt = HashMap::from([("Path".to_string(), "/api".to_string()), ("version".to_string(), "1".to_string())]);
+        let mut v2 = v1.clone();
+        v2.instance_name = "v2._http._tcp.local.".to_string();
+        v2.txt.insert("version".to_string(), "2".to_string());
+        db.upsert_service(&v1).unwrap();
+        db.upsert_service(&v2).unwrap();
+
+        let query = |pairs: &[(&str, &str)]| {
+            let filter = ServiceFilter {
+                txt: pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                ..Default::default()
+            };
+            let names: Vec<String> = db.query_services(&filter, &Page::default()).unwrap()
+                .services.into_iter().map(|s| s.instance_name).collect();
+            // The in-memory filter must agree with the SQL one
+            assert_eq!(names.len(), [&v1, &v2].iter().filter(|s| filter.matches(s)).count());
+            names
+        };
+
+        assert_eq!(query(&[("path", "/api")]).len(), 2);
+        assert_eq!(query(&[("path", "/api"), ("version", "2")]), vec!["v2._http._tcp.local."]);
+        assert!(query(&[("path", "/API")]).is_empty());
+    }
+
+    #[test]
+    fn test_search_follows_changes() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let mut printer = test_entry();
+        printer.instance_name = "Office LaserPrinter._ipp._tcp.local.".to_string();
+        printer.hostname = "hp-m404.local.".to_string();
+        db.upsert_service(&printer).unwrap();
+        db.upsert_service(&test_entry()).unwrap();
+
+        let names = |q: &str| -> Vec<String> {
+            db.search(q, 10).unwrap().into_iter().map(|s| s.instance_name).collect()
+        };
+        assert_eq!(names("printer"), vec![printer.instance_name.clone()]);
+        assert_eq!(names("M404").len(), 1);
+        // Query syntax is taken literally
+        assert!(names("printer OR test").is_empty());
+
+        // Upserts re-index TXT values; deletes drop the row
+        printer.txt = HashMap::from([("note".to_string(), "second floor".to_string())]);
+        db.upsert_service(&printer).unwrap();
+        assert_eq!(names("floor").len(), 1);
+        assert!(names("/api").iter().all(|n| n != &printer.instance_name));
+
+        db.replace_source(ServiceSource::Mdns, &[test_entry()]).unwrap();
+        assert!(names("printer").is_empty());
+    }
+
+    #[test]
+    fn test_purge_service() {
+        let db = CacheDb::open(":memory:").unwrap();
+        let entry = test_entry();
+        db.upsert_service(&entry).unwrap();
+        let seq = db.changes_since(0).unwrap().seq;
+
+        assert!(db.purge_service(&entry.instance_name).unwrap());
+        assert!(db.get_service(&entry.instance_name).unwrap().is_none());
+        assert_eq!(db.changes_since(seq).unwrap().removed, vec![entry.instance_name.clone()]);
+        assert!(!db.purge_service(&entry.instance_name).unwrap());
+    }
+
+    #[test]
+    fn test_history_recorded_and_pruned() {
+        let mut db = CacheDb::open(":memory:").unwrap();
+        let mut entry = test_entry();
+        let clock = MockClock::new(entry.last_seen);
+        db.set_clock(clock.clone());
+        db.set_history_retention(Some(60));
+        db.set_retention_ttl_factor(2);
+
+        db.upsert_service(&entry).unwrap();
+        db.upsert_service(&entry).unwrap();
+        entry.port = 8081;
+        db.upsert_service(&entry).unwrap();
+        clock.advance(chrono::Duration::seconds(301));
+        db.mark_stale(StaleAfter::secs(300)).unwrap();
+        clock.advance(chrono::Duration::seconds(3600));
+        db.prune_stale(3600).unwrap();
+
+        let events: Vec<_> = db.history(&entry.instance_name).unwrap().into_iter().map(|e| (e.event, e.detail)).collect();
+        assert_eq!(events, vec![
+            (ServiceEventKind::Resolved, None),
+            (ServiceEventKind::Changed, Some("port".to_string())),
+            (ServiceEventKind::Dead, Some("stale".to_string())),
+            (ServiceEventKind::Pruned, None),
+        ]);
+
+        // A cached 4500s TTL keeps history for 9000s, past the 60s retention
+        let mut other = test_entry();
+        other.instance_name = "other._http._tcp.local.".to_string();
+        other.source = ServiceSource::Static;
+        db.upsert_service(&other).unwrap();
+        assert_eq!(db.prune_history().unwrap(), 0);
 
-        let ssh_services = db.get_services_by_type("_ssh._tcp").unwrap();
-        assert_eq!(ssh_services.len(), 1);
+        db.purge_service(&other.instance_name).unwrap();
+        assert_eq!(db.prune_history().unwrap(), 3);
+        assert_eq!(db.history(&entry.instance_name).unwrap().len(), 1);
     }
 }
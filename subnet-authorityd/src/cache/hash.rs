@@ -1,50 +1,176 @@
-use std::collections::HashMap;
-use std::net::Ipv6Addr;
-use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use shared::types::ServiceEntry;
+use utoipa::ToSchema;
+
+/// Version of the hash serialization format. Bump whenever the way entries
+/// are serialized for hashing changes, so clients know old hashes are void.
+/// Version 2 hashes the entries' digests rather than one JSON array.
+/// Version 3 serializes TXT keys in sorted order.
+pub const HASH_VERSION: u32 = 3;
+
+/// SHA-256 of one entry's hashed fields, see `entry_digest`
+pub type EntryDigest = [u8; 32];
+
+/// A `ServiceEntry` field that may contribute to the cache hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HashField {
+    ServiceType,
+    /// Always hashed: it identifies the entry
+    InstanceName,
+    Hostname,
+    Addresses,
+    Port,
+    Txt,
+    Alive,
+    /// Off by default, so liveness probing doesn't churn the hash
+    Reachable,
+    Subtypes,
+    /// Off by default: the segment a service is heard on isn't part of it
+    Iface,
+}
+
+/// The set of fields included in the hash. Defaults to every stable field.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(transparent)]
+pub struct HashFields(BTreeSet<HashField>);
+
+impl Default for HashFields {
+    fn default() -> Self {
+        Self(BTreeSet::from([
+            HashField::ServiceType,
+            HashField::InstanceName,
+            HashField::Hostname,
+            HashField::Addresses,
+            HashField::Port,
+            HashField::Txt,
+            HashField::Alive,
+            HashField::Subtypes,
+        ]))
+    }
+}
+
+impl HashFields {
+    /// Every field, whatever the configured hash leaves out. Digests over
+    /// these tell whether an entry changed at all.
+    pub fn all() -> Self {
+        Self(BTreeSet::from([
+            HashField::ServiceType,
+            HashField::InstanceName,
+            HashField::Hostname,
+            HashField::Addresses,
+            HashField::Port,
+            HashField::Txt,
+            HashField::Alive,
+            HashField::Reachable,
+            HashField::Subtypes,
+            HashField::Iface,
+        ]))
+    }
+
+    fn contains(&self, field: HashField) -> bool {
+        field == HashField::InstanceName || self.0.contains(&field)
+    }
+}
 
 /// Fix #3: hash only stable fields — last_seen/first_seen/ttl change on every
 /// re-resolve but don't represent meaningful service data changes.
+/// Fields excluded by the configured `HashFields` are left out entirely.
 #[derive(Serialize)]
 struct HashView<'a> {
-    service_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_type: Option<&'a str>,
     instance_name: &'a str,
-    hostname: &'a str,
-    addresses: &'a [Ipv6Addr],
-    port: u16,
-    txt: &'a HashMap<String, String>,
-    alive: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    addresses: Option<&'a [IpAddr]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Sorted, since a `HashMap` serializes in a different order per instance
+    txt: Option<BTreeMap<&'a str, &'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alive: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reachable: Option<Option<bool>>,
+    /// Left out when empty, so hashes of services without subtypes are as
+    /// they were before subtypes were recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtypes: Option<&'a [String]>,
+    /// Left out when unknown, like subtypes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iface: Option<&'a str>,
 }
 
-/// Computes a SHA-256 hash of the service list.
-/// Services are sorted by instance_name for deterministic output.
-/// Fix #8: sort indices instead of cloning the entire service list.
-pub fn compute_hash(services: &[ServiceEntry]) -> String {
-    let mut indices: Vec<usize> = (0..services.len()).collect();
-    indices.sort_by(|&a, &b| services[a].instance_name.cmp(&services[b].instance_name));
-
-    let views: Vec<HashView<'_>> = indices
-        .iter()
-        .map(|&i| {
-            let s = &services[i];
-            HashView {
-                service_type: &s.service_type,
-                instance_name: &s.instance_name,
-                hostname: &s.hostname,
-                addresses: &s.addresses,
-                port: s.port,
-                txt: &s.txt,
-                alive: s.alive,
-            }
-        })
-        .collect();
+impl<'a> HashView<'a> {
+    fn new(s: &'a ServiceEntry, fields: &HashFields) -> Self {
+        let pick = |field, value| fields.contains(field).then_some(value);
+        HashView {
+            service_type: pick(HashField::ServiceType, s.service_type.as_str()),
+            instance_name: &s.instance_name,
+            hostname: pick(HashField::Hostname, s.hostname.as_str()),
+            addresses: fields.contains(HashField::Addresses).then_some(s.addresses.as_slice()),
+            port: fields.contains(HashField::Port).then_some(s.port),
+            txt: fields
+                .contains(HashField::Txt)
+                .then(|| s.txt.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()),
+            alive: fields.contains(HashField::Alive).then_some(s.alive),
+            reachable: fields.contains(HashField::Reachable).then_some(s.reachable),
+            subtypes: (fields.contains(HashField::Subtypes) && !s.subtypes.is_empty())
+                .then_some(s.subtypes.as_slice()),
+            iface: fields.contains(HashField::Iface).then_some(s.iface.as_deref()).flatten(),
+        }
+    }
+}
 
-    let json = serde_json::to_string(&views)
-        .expect("Failed to serialize services for hashing");
+/// SHA-256 of the JSON of one entry's hashed fields. Two entries have the
+/// same digest exactly when they're identical in every hashed field.
+pub fn entry_digest(service: &ServiceEntry, fields: &HashFields) -> EntryDigest {
+    let json = serde_json::to_vec(&HashView::new(service, fields))
+        .expect("Failed to serialize service for hashing");
+    Sha256::digest(&json).into()
+}
+
+/// The cache hash from each entry's digest: SHA-256 over the digests in
+/// instance name order, so it costs one pass over the rows however large
+/// they are
+pub fn fold_digests<'a>(digests: impl IntoIterator<Item = (&'a str, EntryDigest)>) -> String {
+    let mut digests: Vec<_> = digests.into_iter().collect();
+    digests.sort_unstable_by_key(|&(instance_name, _)| instance_name);
 
-    let hash = Sha256::digest(json.as_bytes());
-    hex::encode(hash)
+    let mut hasher = Sha256::new();
+    for (_, digest) in &digests {
+        hasher.update(digest);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// The hash of each service type's entries, folded like the cache hash from
+/// that type's digests alone, so churn in one type leaves the others' as
+/// they were
+pub fn type_hashes<'a>(entries: impl IntoIterator<Item = (&'a ServiceEntry, EntryDigest)>) -> BTreeMap<String, String> {
+    let mut by_type: BTreeMap<&str, Vec<(&str, EntryDigest)>> = BTreeMap::new();
+    for (service, digest) in entries {
+        by_type
+            .entry(service.service_type.as_str())
+            .or_default()
+            .push((service.instance_name.as_str(), digest));
+    }
+    by_type
+        .into_iter()
+        .map(|(service_type, digests)| (service_type.to_string(), fold_digests(digests)))
+        .collect()
+}
+
+/// Computes a SHA-256 hash of the service list over the given fields.
+/// Services are sorted by instance_name for deterministic output.
+#[cfg(test)]
+pub fn compute_hash(services: &[ServiceEntry], fields: &HashFields) -> String {
+    fold_digests(services.iter().map(|s| (s.instance_name.as_str(), entry_digest(s, fields))))
 }
 
 #[cfg(test)]
@@ -53,19 +179,25 @@ mod tests {
     use std::collections::HashMap;
     use std::net::Ipv6Addr;
     use chrono::Utc;
+    use shared::types::ServiceSource;
 
     fn test_entry(instance_name: &str) -> ServiceEntry {
         ServiceEntry {
             service_type: "_http._tcp".to_string(),
             instance_name: instance_name.to_string(),
             hostname: "test.local.".to_string(),
-            addresses: vec![Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 1)],
+            addresses: vec![Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 1).into()],
             port: 8080,
             txt: HashMap::new(),
             first_seen: Utc::now(),
             last_seen: Utc::now(),
             ttl: 4500,
             alive: true,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
         }
     }
 
@@ -74,8 +206,8 @@ mod tests {
         let entry1 = test_entry("a._http._tcp.local.");
         let entry2 = test_entry("b._http._tcp.local.");
 
-        let hash1 = compute_hash(&[entry1.clone(), entry2.clone()]);
-        let hash2 = compute_hash(&[entry2.clone(), entry1.clone()]);
+        let hash1 = compute_hash(&[entry1.clone(), entry2.clone()], &HashFields::default());
+        let hash2 = compute_hash(&[entry2.clone(), entry1.clone()], &HashFields::default());
 
         assert_eq!(hash1, hash2, "Hash should be same regardless of input order");
     }
@@ -85,10 +217,10 @@ mod tests {
         let entry1 = test_entry("a._http._tcp.local.");
         let mut entry2 = test_entry("a._http._tcp.local.");
 
-        let hash1 = compute_hash(&[entry1.clone()]);
+        let hash1 = compute_hash(&[entry1], &HashFields::default());
 
         entry2.port = 9090;
-        let hash2 = compute_hash(&[entry2]);
+        let hash2 = compute_hash(&[entry2], &HashFields::default());
 
         assert_ne!(hash1, hash2, "Hash should change when entry changes");
     }
@@ -102,9 +234,72 @@ mod tests {
         entry2.first_seen = Utc::now() - chrono::Duration::seconds(60);
         entry2.ttl = 9999;
 
-        let hash1 = compute_hash(&[entry1]);
-        let hash2 = compute_hash(&[entry2]);
+        let hash1 = compute_hash(&[entry1], &HashFields::default());
+        let hash2 = compute_hash(&[entry2], &HashFields::default());
 
         assert_eq!(hash1, hash2, "Hash should not change when only timestamps/ttl change");
     }
+
+    #[test]
+    fn test_default_fields_match_previous_format() {
+        // The default field set must serialize exactly like the original
+        // fixed HashView so existing clients don't see a spurious change
+        let entry = test_entry("a._http._tcp.local.");
+        let json = serde_json::to_string(&[HashView::new(&entry, &HashFields::default())]).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"service_type":"_http._tcp","instance_name":"a._http._tcp.local.","hostname":"test.local.","addresses":["fd00:0:0:1::1"],"port":8080,"txt":{},"alive":true}]"#
+        );
+    }
+
+    #[test]
+    fn test_txt_order_ignored() {
+        let keys = ["path", "model", "vers", "rp", "note", "ty"];
+        let with_keys = |order: &mut dyn Iterator<Item = &&str>| {
+            let mut entry = test_entry("a._http._tcp.local.");
+            entry.txt = HashMap::new();
+            for key in order {
+                entry.txt.insert(key.to_string(), format!("{}-value", key));
+            }
+            entry_digest(&entry, &HashFields::default())
+        };
+        let forward = with_keys(&mut keys.iter());
+        for _ in 0..20 {
+            assert_eq!(with_keys(&mut keys.iter().rev()), forward);
+            assert_eq!(with_keys(&mut keys.iter()), forward);
+        }
+    }
+
+    #[test]
+    fn test_excluded_fields_ignored() {
+        let fields: HashFields = serde_json::from_str(r#"["addresses", "hostname"]"#).unwrap();
+        let entry1 = test_entry("a._http._tcp.local.");
+        let mut entry2 = test_entry("a._http._tcp.local.");
+        entry2.port = 9090;
+        entry2.txt.insert("path".to_string(), "/api".to_string());
+        entry2.alive = false;
+
+        assert_eq!(
+            compute_hash(std::slice::from_ref(&entry1), &fields),
+            compute_hash(&[entry2.clone()], &fields),
+            "Port, TXT and alive changes should not affect an address-only hash"
+        );
+
+        entry2.addresses.push(Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 2).into());
+        assert_ne!(compute_hash(&[entry1], &fields), compute_hash(&[entry2], &fields));
+    }
+
+    #[test]
+    fn test_instance_name_always_hashed() {
+        let fields: HashFields = serde_json::from_str(r#"["port"]"#).unwrap();
+        let entry1 = test_entry("a._http._tcp.local.");
+        let entry2 = test_entry("b._http._tcp.local.");
+
+        assert_ne!(compute_hash(&[entry1], &fields), compute_hash(&[entry2], &fields));
+    }
+
+    #[test]
+    fn test_unknown_field_rejected() {
+        assert!(serde_json::from_str::<HashFields>(r#"["last_seen"]"#).is_err());
+    }
 }
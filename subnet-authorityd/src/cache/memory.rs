@@ -0,0 +1,216 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use shared::types::{ServiceEntry, ServiceSource};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use crate::cache::clock::Clock;
+use crate::cache::query::{query_in_memory, search_matches, Page, ServiceFilter, ServicePage};
+use crate::cache::db::{check_type_conflict, keeps_iface, keeps_subtypes, service_data_changed, StaleAfter, TypeConflictPolicy, TypeRetention};
+
+/// In-memory stand-in for `CacheDb`, used while the database is unwritable.
+///
+/// Mirrors the semantics of the corresponding `CacheDb` methods so the cache
+/// behaves the same whichever store is serving it.
+#[derive(Debug)]
+pub struct MemoryStore {
+    services: Vec<ServiceEntry>,
+    type_conflict: TypeConflictPolicy,
+    type_retention: TypeRetention,
+    tombstone_secs: Option<u64>,
+    /// When each service died, for those that died while in memory; others
+    /// count from when they were last seen
+    dead_since: HashMap<String, DateTime<Utc>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl MemoryStore {
+    pub fn new(
+        services: Vec<ServiceEntry>,
+        type_conflict: TypeConflictPolicy,
+        type_retention: TypeRetention,
+        tombstone_secs: Option<u64>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self { services, type_conflict, type_retention, tombstone_secs, dead_since: HashMap::new(), clock }
+    }
+
+    /// Replace the retention settings given to `new`
+    pub fn set_retention(&mut self, type_retention: TypeRetention, tombstone_secs: Option<u64>) {
+        self.type_retention = type_retention;
+        self.tombstone_secs = tombstone_secs;
+    }
+
+    /// Insert or update a service entry. Returns true if data changed.
+    pub fn upsert_service(&mut self, entry: &ServiceEntry) -> Result<bool> {
+        let existing = self.services.iter_mut().find(|s| s.instance_name == entry.instance_name);
+        check_type_conflict(self.type_conflict, existing.as_deref(), entry)?;
+        if entry.alive {
+            self.dead_since.remove(&entry.instance_name);
+        } else {
+            self.dead_since.entry(entry.instance_name.clone()).or_insert_with(|| self.clock.now());
+        }
+
+        Ok(match existing {
+            Some(existing) => {
+                let changed = service_data_changed(existing, entry);
+                let first_seen = existing.first_seen;
+                let probed = (existing.reachable, existing.latency_ms);
+                let subtypes = std::mem::take(&mut existing.subtypes);
+                let iface = existing.iface.take();
+                *existing = entry.clone();
+                existing.first_seen = first_seen;
+                if entry.reachable.is_none() {
+                    (existing.reachable, existing.latency_ms) = probed;
+                }
+                if keeps_subtypes(entry) {
+                    existing.subtypes = subtypes;
+                }
+                if keeps_iface(entry) {
+                    existing.iface = iface;
+                }
+                changed
+            }
+            None => {
+                self.services.push(entry.clone());
+                true
+            }
+        })
+    }
+
+    /// Upsert each entry, returning each one's result as `CacheDb` does
+    pub fn upsert_services(&mut self, entries: &[ServiceEntry]) -> Vec<Result<bool>> {
+        entries.iter().map(|entry| self.upsert_service(entry)).collect()
+    }
+
+    pub fn mark_dead(&mut self, instance_name: &str) {
+        if let Some(service) = self.services.iter_mut().find(|s| s.instance_name == instance_name) {
+            service.alive = false;
+            service.last_seen = self.clock.now();
+            self.dead_since.entry(instance_name.to_string()).or_insert(service.last_seen);
+        }
+    }
+
+    /// Record a liveness probe. Returns true if reachability changed.
+    pub fn set_reachability(&mut self, instance_name: &str, reachable: bool, latency_ms: Option<u32>) -> bool {
+        let Some(service) = self.services.iter_mut().find(|s| s.instance_name == instance_name) else {
+            return false;
+        };
+        let changed = service.reachable != Some(reachable);
+        service.reachable = Some(reachable);
+        service.latency_ms = latency_ms;
+        changed
+    }
+
+    /// Make `entries` the complete set of services from `source`
+    pub fn replace_source(&mut self, source: ServiceSource, entries: &[ServiceEntry]) -> Result<()> {
+        self.services.retain(|s| s.source != source || entries.iter().any(|e| e.instance_name == s.instance_name));
+        for entry in entries {
+            self.upsert_service(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Make `entries` the whole cache
+    pub fn restore(&mut self, entries: &[ServiceEntry]) -> Result<()> {
+        self.services.retain(|s| entries.iter().any(|e| e.instance_name == s.instance_name));
+        for entry in entries {
+            self.upsert_service(entry)?;
+        }
+        Ok(())
+    }
+
+    pub fn purge_service(&mut self, instance_name: &str) -> bool {
+        let before = self.services.len();
+        self.services.retain(|s| s.instance_name != instance_name);
+        self.services.len() != before
+    }
+
+    pub fn services(&self) -> &[ServiceEntry] {
+        &self.services
+    }
+
+    pub fn get_all_services(&self) -> Vec<ServiceEntry> {
+        self.services.clone()
+    }
+
+    pub fn query_services(&self, filter: &ServiceFilter, page: &Page) -> ServicePage {
+        query_in_memory(&self.services, filter, page)
+    }
+
+    pub fn search(&self, query: &str, limit: usize) -> Vec<ServiceEntry> {
+        self.services
+            .iter()
+            .filter(|s| search_matches(s, query))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_service(&self, instance_name: &str) -> Option<ServiceEntry> {
+        self.services.iter().find(|s| s.instance_name == instance_name).cloned()
+    }
+
+    pub fn service_exists(&self, instance_name: &str, include_dead: bool) -> bool {
+        self.services
+            .iter()
+            .any(|s| s.instance_name == instance_name && (s.alive || include_dead))
+    }
+
+    /// Types of alive mDNS-discovered services past `unseen`
+    pub fn unseen_service_types(&self, unseen: StaleAfter) -> Vec<String> {
+        let now = self.clock.now();
+        let types: BTreeSet<&str> = self
+            .services
+            .iter()
+            .filter(|s| s.alive && s.source == ServiceSource::Mdns && unseen.passed(s, now, &self.type_retention))
+            .map(|s| s.service_type.as_str())
+            .collect();
+        types.into_iter().map(str::to_string).collect()
+    }
+
+    /// Alive mDNS-discovered services past `unseen`
+    pub fn unseen_services(&self, unseen: StaleAfter) -> Vec<ServiceEntry> {
+        let now = self.clock.now();
+        self.services
+            .iter()
+            .filter(|s| s.alive && s.source == ServiceSource::Mdns && unseen.passed(s, now, &self.type_retention))
+            .cloned()
+            .collect()
+    }
+
+    /// Mark mDNS-discovered services as stale (not alive) if not seen recently
+    pub fn mark_stale(&mut self, stale: StaleAfter) {
+        let now = self.clock.now();
+        for service in &mut self.services {
+            if service.alive && service.source == ServiceSource::Mdns && stale.passed(service, now, &self.type_retention) {
+                service.alive = false;
+                self.dead_since.insert(service.instance_name.clone(), now);
+            }
+        }
+    }
+
+    /// Drop old mDNS-discovered services, and dead ones past the tombstone
+    /// retention if there is one. Returns the instance names dropped.
+    pub fn prune_stale(&mut self, prune_after_secs: u64) -> Vec<String> {
+        let now = self.clock.now();
+        let (retention, dead_since) = (&self.type_retention, &self.dead_since);
+        let due = |s: &ServiceEntry| match self.tombstone_secs {
+            Some(secs) if !s.alive => {
+                let died = dead_since.get(&s.instance_name).unwrap_or(&s.last_seen);
+                *died + chrono::Duration::seconds(secs as i64) < now
+            }
+            _ => retention.prune_due(s, now, prune_after_secs),
+        };
+        let mut pruned = Vec::new();
+        self.services.retain(|s| {
+            let keep = s.source != ServiceSource::Mdns || !due(s);
+            if !keep {
+                pruned.push(s.instance_name.clone());
+            }
+            keep
+        });
+        let services = &self.services;
+        self.dead_since.retain(|name, _| services.iter().any(|s| &s.instance_name == name));
+        pruned
+    }
+}
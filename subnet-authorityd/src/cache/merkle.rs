@@ -0,0 +1,181 @@
+//! Merkle tree over the entry digests, for finding where two copies of the
+//! cache differ without comparing every entry. Each entry is placed by the
+//! SHA-256 of its instance name, one hex digit per level, so an instance
+//! lands in the same leaf whatever else either copy holds. Comparing node
+//! hashes from the root down reaches the differing leaves in `DEPTH` steps.
+
+use std::collections::{BTreeMap, HashMap};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use crate::cache::hash::EntryDigest;
+
+/// Levels below the root. Leaves are the 4096 three-digit prefixes.
+pub const DEPTH: usize = 3;
+
+const NIBBLES: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Debug, Default)]
+pub struct MerkleTree {
+    /// Hash of every node with entries under it, by path
+    nodes: HashMap<String, EntryDigest>,
+    /// Entries of each non-empty leaf, ordered by instance name
+    leaves: HashMap<String, Vec<(String, EntryDigest)>>,
+}
+
+/// One node as served by the API
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MerkleNode {
+    /// Hex digits of the instance name hash leading to the node; the root's is empty
+    pub node: String,
+    pub hash: String,
+    /// All sixteen children, empty ones included; none for a leaf
+    pub children: Vec<MerkleChild>,
+    /// Entries of a leaf; none for an inner node
+    pub entries: Vec<MerkleEntry>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MerkleChild {
+    pub node: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MerkleEntry {
+    pub instance_name: String,
+    /// See `cache::hash::entry_digest`
+    pub digest: String,
+}
+
+impl MerkleTree {
+    pub fn build<'a>(digests: impl IntoIterator<Item = (&'a str, EntryDigest)>) -> Self {
+        let mut leaves: HashMap<String, Vec<(String, EntryDigest)>> = HashMap::new();
+        for (instance_name, digest) in digests {
+            leaves
+                .entry(leaf_path(instance_name))
+                .or_default()
+                .push((instance_name.to_string(), digest));
+        }
+
+        // A leaf hashes its digests; an inner node hashes each non-empty
+        // child's digit and hash, in digit order
+        let mut level: BTreeMap<String, EntryDigest> = BTreeMap::new();
+        for (path, entries) in &mut leaves {
+            entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            let mut hasher = Sha256::new();
+            for (_, digest) in entries.iter() {
+                hasher.update(digest);
+            }
+            level.insert(path.clone(), hasher.finalize().into());
+        }
+        let mut nodes: HashMap<String, EntryDigest> = level.clone().into_iter().collect();
+        for _ in 0..DEPTH {
+            let mut parents: BTreeMap<String, Sha256> = BTreeMap::new();
+            for (path, hash) in &level {
+                let (parent, digit) = path.split_at(path.len() - 1);
+                let hasher = parents.entry(parent.to_string()).or_default();
+                hasher.update(digit.as_bytes());
+                hasher.update(hash);
+            }
+            level = parents.into_iter().map(|(path, hasher)| (path, hasher.finalize().into())).collect();
+            nodes.extend(level.iter().map(|(path, hash)| (path.clone(), *hash)));
+        }
+
+        Self { nodes, leaves }
+    }
+
+    /// Hash of the whole tree
+    pub fn root(&self) -> String {
+        self.hash("")
+    }
+
+    /// The node at `path`, or None if it isn't hex digits at most `DEPTH` long
+    pub fn node(&self, path: &str) -> Option<MerkleNode> {
+        let path = path.to_ascii_lowercase();
+        if path.len() > DEPTH || !path.bytes().all(|b| NIBBLES.contains(&b)) {
+            return None;
+        }
+
+        let (children, entries) = if path.len() == DEPTH {
+            let entries = self.leaves.get(&path).map_or(&[][..], Vec::as_slice);
+            let entries = entries
+                .iter()
+                .map(|(instance_name, digest)| MerkleEntry {
+                    instance_name: instance_name.clone(),
+                    digest: hex::encode(digest),
+                })
+                .collect();
+            (Vec::new(), entries)
+        } else {
+            let children = NIBBLES
+                .iter()
+                .map(|&digit| {
+                    let node = format!("{}{}", path, digit as char);
+                    MerkleChild { hash: self.hash(&node), node }
+                })
+                .collect();
+            (children, Vec::new())
+        };
+        Some(MerkleNode { hash: self.hash(&path), node: path, children, entries })
+    }
+
+    /// Hash of the node at `path`. An empty node hashes like no input at all.
+    fn hash(&self, path: &str) -> String {
+        match self.nodes.get(path) {
+            Some(hash) => hex::encode(hash),
+            None => hex::encode(Sha256::digest([])),
+        }
+    }
+}
+
+/// The leaf `instance_name` belongs in
+fn leaf_path(instance_name: &str) -> String {
+    let mut path = hex::encode(Sha256::digest(instance_name.as_bytes()));
+    path.truncate(DEPTH);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(byte: u8) -> EntryDigest {
+        [byte; 32]
+    }
+
+    #[test]
+    fn test_differing_leaf_found_from_root() {
+        let names: Vec<String> = (0..200).map(|i| format!("host-{}._http._tcp.local.", i)).collect();
+        let ours = MerkleTree::build(names.iter().map(|name| (name.as_str(), digest(1))));
+        let reordered = MerkleTree::build(names.iter().rev().map(|name| (name.as_str(), digest(1))));
+        assert_eq!(ours.root(), reordered.root(), "insertion order doesn't matter");
+
+        let changed = "host-42._http._tcp.local.";
+        let theirs = MerkleTree::build(names.iter().map(|name| {
+            (name.as_str(), if name == changed { digest(2) } else { digest(1) })
+        }));
+        assert_ne!(ours.root(), theirs.root());
+
+        // Follow the one differing child at each level
+        let mut path = String::new();
+        while path.len() < DEPTH {
+            let (a, b) = (ours.node(&path).unwrap(), theirs.node(&path).unwrap());
+            let differing: Vec<_> = a.children.iter().zip(&b.children).filter(|(x, y)| x.hash != y.hash).collect();
+            assert_eq!(differing.len(), 1);
+            path = differing[0].0.node.clone();
+        }
+        let leaf = theirs.node(&path).unwrap();
+        assert!(leaf.entries.iter().any(|e| e.instance_name == changed && e.digest == hex::encode(digest(2))));
+    }
+
+    #[test]
+    fn test_node_paths_checked() {
+        let tree = MerkleTree::build([]);
+        assert_eq!(tree.root(), hex::encode(Sha256::digest([])));
+        assert_eq!(tree.node("").unwrap().children.len(), 16);
+        assert!(tree.node("A0f").unwrap().entries.is_empty());
+        assert!(tree.node("a0f0").is_none());
+        assert!(tree.node("xy").is_none());
+    }
+}
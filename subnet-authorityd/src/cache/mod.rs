@@ -1,2 +1,7 @@
+pub mod clock;
 pub mod db;
 pub mod hash;
+pub mod memory;
+pub mod merkle;
+pub mod pool;
+pub mod query;
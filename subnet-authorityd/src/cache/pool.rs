@@ -0,0 +1,98 @@
+//! Read-only connections for queries. The cache thread serializes every
+//! command, so without these a listing waits behind each queued upsert;
+//! with WAL, readers see the last committed state while the thread writes.
+
+use std::path::Path;
+use anyhow::{Context, Result};
+use crate::cache::db::CacheDb;
+
+pub struct ReadPool {
+    idle: flume::Receiver<CacheDb>,
+    returned: flume::Sender<CacheDb>,
+}
+
+/// A connection taken from the pool, put back when dropped, even if the
+/// read using it panicked
+struct Lease {
+    db: Option<CacheDb>,
+    returned: flume::Sender<CacheDb>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            let _ = self.returned.send(db);
+        }
+    }
+}
+
+impl ReadPool {
+    /// Open `size` read-only connections to the database at `path`
+    pub fn open(path: impl AsRef<Path>, key: Option<&str>, size: usize) -> Result<Self> {
+        let (returned, idle) = flume::bounded(size);
+        for _ in 0..size {
+            let db = CacheDb::open_reader(path.as_ref(), key).context("Failed to open read connection")?;
+            // There's room for every connection and the receiver is held
+            let _ = returned.send(db);
+        }
+        Ok(Self { idle, returned })
+    }
+
+    /// Run `read` on an idle connection, on a blocking thread, waiting for
+    /// one to come free if all are in use
+    pub async fn read<T, F>(&self, read: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&CacheDb) -> Result<T> + Send + 'static,
+    {
+        let db = self.idle.recv_async().await.context("Read pool closed")?;
+        let lease = Lease { db: Some(db), returned: self.returned.clone() };
+        tokio::task::spawn_blocking(move || read(lease.db.as_ref().expect("leased connection")))
+            .await
+            .context("Read panicked")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use shared::types::{ServiceEntry, ServiceSource};
+
+    #[tokio::test]
+    async fn test_readers_see_commits_and_are_reused() {
+        let path = std::env::temp_dir().join(format!("zerocomfy-pool-{}.db", std::process::id()));
+        let db = CacheDb::open(&path).unwrap();
+        let pool = ReadPool::open(&path, None, 1).unwrap();
+
+        let entry = ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "web.local.".to_string(),
+            addresses: vec!["fd00::10".parse().unwrap()],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+        db.upsert_service(&entry).unwrap();
+
+        // One connection serves both reads, and a failed read gives it back too
+        let name = entry.instance_name.clone();
+        assert!(pool.read(move |db| db.get_service(&name)).await.unwrap().is_some());
+        assert!(pool.read(move |db| db.upsert_service(&entry)).await.is_err(), "readers can't write");
+        assert_eq!(pool.read(|db| db.get_all_services()).await.unwrap().len(), 1);
+
+        drop((db, pool));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::net::IpAddr;
+use shared::types::ServiceEntry;
+
+/// Which services a listing includes. Every set field must match.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceFilter {
+    pub service_type: Option<String>,
+    /// Services advertising this address, whatever their port
+    pub address: Option<IpAddr>,
+    /// Services advertising this TXT key, compared case-insensitively as
+    /// RFC 6763 section 6.4 requires
+    pub has_txt: Option<String>,
+    /// TXT key/value pairs that must all be present; keys compare
+    /// case-insensitively, values exactly
+    pub txt: Vec<(String, String)>,
+    /// Services announced with this subtype label, e.g. `_printer`
+    pub subtype: Option<String>,
+    /// Services last heard on this interface
+    pub iface: Option<String>,
+    /// Leave out dead services
+    pub alive_only: bool,
+}
+
+impl ServiceFilter {
+    pub fn matches(&self, service: &ServiceEntry) -> bool {
+        self.service_type.as_ref().is_none_or(|t| &service.service_type == t)
+            && self.address.is_none_or(|a| service.addresses.contains(&a))
+            && self.has_txt.as_ref().is_none_or(|key| {
+                service.txt.keys().any(|k| k.eq_ignore_ascii_case(key))
+            })
+            && self.txt.iter().all(|(key, value)| {
+                service.txt.iter().any(|(k, v)| k.eq_ignore_ascii_case(key) && v == value)
+            })
+            && self.subtype.as_ref().is_none_or(|subtype| service.subtypes.contains(subtype))
+            && self.iface.as_ref().is_none_or(|iface| service.iface.as_ref() == Some(iface))
+            && (service.alive || !self.alive_only)
+    }
+}
+
+/// Shortest query `/v1/search` accepts; the index is built from trigrams
+pub const MIN_SEARCH_LEN: usize = 3;
+
+/// Whether `query` occurs in the fields `/v1/search` covers, ignoring case,
+/// as the SQLite full-text index matches it
+pub fn search_matches(service: &ServiceEntry, query: &str) -> bool {
+    let query = query.to_lowercase();
+    std::iter::once(&service.instance_name)
+        .chain(std::iter::once(&service.hostname))
+        .chain(service.txt.values())
+        .any(|field| field.to_lowercase().contains(&query))
+}
+
+/// A window of a listing ordered by instance name
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    /// Most services to return; None for all
+    pub limit: Option<usize>,
+    /// Services to skip, counted after `after`
+    pub offset: usize,
+    /// Start after this instance name (the cursor from the previous page)
+    pub after: Option<String>,
+}
+
+/// One page of a listing
+#[derive(Debug, Clone)]
+pub struct ServicePage {
+    pub services: Vec<ServiceEntry>,
+    /// Services matching the filter across all pages
+    pub total: usize,
+    /// Instance name to pass as `after` for the next page, if there is one
+    pub next: Option<String>,
+}
+
+/// Filter and page services held in memory, the same way `CacheDb::query_services` does
+pub fn query_in_memory<'a>(
+    services: impl IntoIterator<Item = &'a ServiceEntry>,
+    filter: &ServiceFilter,
+    page: &Page,
+) -> ServicePage {
+    let mut matching: Vec<&ServiceEntry> = services.into_iter().filter(|s| filter.matches(s)).collect();
+    matching.sort_by(|a, b| a.instance_name.cmp(&b.instance_name));
+    let total = matching.len();
+
+    let remaining = matching
+        .into_iter()
+        .filter(|s| page.after.as_ref().is_none_or(|after| &s.instance_name > after))
+        .skip(page.offset);
+    let mut services: Vec<ServiceEntry> = match page.limit {
+        // One extra row tells whether there's a next page
+        Some(limit) => remaining.take(limit.saturating_add(1)).cloned().collect(),
+        None => remaining.cloned().collect(),
+    };
+
+    let next = next_cursor(&mut services, page.limit);
+    ServicePage { services, total, next }
+}
+
+/// Drop the look-ahead row fetched beyond `limit`, returning the cursor for
+/// the next page if there was one
+pub(crate) fn next_cursor(services: &mut Vec<ServiceEntry>, limit: Option<usize>) -> Option<String> {
+    let limit = limit?;
+    if services.len() <= limit {
+        return None;
+    }
+    services.truncate(limit);
+    services.last().map(|s| s.instance_name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn entry(name: &str, service_type: &str) -> ServiceEntry {
+        ServiceEntry {
+            service_type: service_type.to_string(),
+            instance_name: name.to_string(),
+            hostname: "host.local.".to_string(),
+            addresses: vec![],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[test]
+    fn test_pages_follow_cursor() {
+        let services = vec![entry("c", "_http._tcp"), entry("a", "_http._tcp"), entry("b", "_ssh._tcp"), entry("d", "_http._tcp")];
+        let filter = ServiceFilter { service_type: Some("_http._tcp".to_string()), ..Default::default() };
+
+        let first = query_in_memory(&services, &filter, &Page { limit: Some(2), ..Default::default() });
+        let names: Vec<&str> = first.services.iter().map(|s| s.instance_name.as_str()).collect();
+        assert_eq!(names, vec!["a", "c"]);
+        assert_eq!(first.total, 3);
+        assert_eq!(first.next.as_deref(), Some("c"));
+
+        let second = query_in_memory(&services, &filter, &Page { limit: Some(2), offset: 0, after: first.next });
+        assert_eq!(second.services.len(), 1);
+        assert_eq!(second.services[0].instance_name, "d");
+        assert_eq!(second.next, None);
+
+        let offset = query_in_memory(&services, &filter, &Page { limit: None, offset: 1, after: None });
+        assert_eq!(offset.services.len(), 2);
+
+        let huge = query_in_memory(&services, &filter, &Page { limit: Some(usize::MAX), ..Default::default() });
+        assert_eq!(huge.services.len(), 3);
+        assert_eq!(huge.next, None);
+    }
+}
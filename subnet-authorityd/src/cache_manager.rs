@@ -1,97 +1,470 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::thread;
-use tokio::sync::{mpsc, oneshot, watch};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use anyhow::Result;
-use shared::types::ServiceEntry;
+use futures::StreamExt;
+use shared::types::{ChangeSet, ServiceEntry, ServiceEvent, ServiceSource};
 use crate::cache::{db::CacheDb, hash};
+use crate::cache::clock::Clock;
+use crate::cache::db::{Compaction, StaleAfter, TypeConflictError};
+use crate::cache::hash::{EntryDigest, HashFields};
+use crate::cache::memory::MemoryStore;
+use crate::cache::merkle::MerkleTree;
+use crate::cache::pool::ReadPool;
+use crate::cache::query::{Page, ServiceFilter, ServicePage};
 use crate::config::CacheConfig;
+use crate::mdns::browser::BrowserCommand;
+use crate::mdns::confirm::{Confirmer, Outcome};
 // Fix #5: import BrowserEvent from its owning module
 pub use crate::mdns::browser::BrowserEvent;
 
 /// Commands sent to the cache thread
 pub enum CacheCommand {
     Upsert(ServiceEntry, oneshot::Sender<Result<bool>>),
+    /// Upsert several entries in one transaction, replying with each result
+    UpsertBatch(Vec<ServiceEntry>, oneshot::Sender<Result<Vec<Result<bool>>>>),
     MarkDead(String, oneshot::Sender<Result<()>>),
-    GetAll(oneshot::Sender<Result<Vec<ServiceEntry>>>),
-    GetByType(String, oneshot::Sender<Result<Vec<ServiceEntry>>>),
+    /// Record a liveness probe; replies true if reachability changed
+    SetReachability {
+        instance_name: String,
+        reachable: bool,
+        latency_ms: Option<u32>,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    /// Delete the entry entirely; replies false if it wasn't cached
+    Purge(String, oneshot::Sender<Result<bool>>),
+    /// Make these the only services from a source
+    ReplaceSource(ServiceSource, Vec<ServiceEntry>, oneshot::Sender<Result<()>>),
+    /// Upsert every entry, first deleting everything else if `replace`
+    Import {
+        services: Vec<ServiceEntry>,
+        replace: bool,
+        reply: oneshot::Sender<Result<()>>,
+    },
     GetOne(String, oneshot::Sender<Result<Option<ServiceEntry>>>),
+    Search {
+        query: String,
+        limit: usize,
+        reply: oneshot::Sender<Result<Vec<ServiceEntry>>>,
+    },
+    Query {
+        filter: ServiceFilter,
+        page: Page,
+        reply: oneshot::Sender<Result<ServicePage>>,
+    },
+    Exists {
+        instance_name: String,
+        include_dead: bool,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    UnseenTypes(StaleAfter, oneshot::Sender<Result<Vec<String>>>),
+    UnseenServices(StaleAfter, oneshot::Sender<Result<Vec<ServiceEntry>>>),
+    ChangesSince(u64, oneshot::Sender<Result<ChangeSet>>),
+    History(String, oneshot::Sender<Result<Vec<ServiceEvent>>>),
+    /// Copy the database to a file, replying with its change sequence number
+    Backup(PathBuf, oneshot::Sender<Result<u64>>),
     Maintenance {
-        stale_after_secs: u64,
+        stale: StaleAfter,
         prune_after_secs: u64,
         reply: oneshot::Sender<Result<()>>,
     },
-    Shutdown,
+    /// Publish a hash notification held back by `hash_notify_interval_ms`
+    FlushHash,
+    /// Apply the retention settings of a reloaded `[cache]`
+    Reconfigure(CacheConfig, oneshot::Sender<()>),
+    /// Reply immediately, to show the cache thread is still taking commands
+    Ping(oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<ShutdownReport>),
+}
+
+/// What changed in the cache, broadcast to subscribers of `CacheHandle::subscribe` each
+/// time a new snapshot is published. Changes are net over the publication:
+/// an entry changed twice before it is one `Updated` with its final state.
+#[derive(Debug, Clone)]
+pub enum CacheEvent {
+    Added(ServiceEntry),
+    /// Includes a service going dead, which stays cached with `alive` false
+    Updated(ServiceEntry),
+    /// Deleted outright: purged, withdrawn from its source, or replaced
+    Removed(String),
+    /// Dropped by maintenance after going unseen too long
+    Pruned(String),
+}
+
+/// Events a subscriber may fall behind by before it misses some and gets
+/// `RecvError::Lagged`
+const EVENT_CAPACITY: usize = 1024;
+
+/// Returned by `changes_since`, `history`, and `backup`, which need the
+/// database, while the cache is served from memory
+#[derive(Debug)]
+pub struct DatabaseDegraded;
+
+impl std::fmt::Display for DatabaseDegraded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unavailable while the database is degraded")
+    }
+}
+
+impl std::error::Error for DatabaseDegraded {}
+
+/// Cache state published by the cache thread whenever its contents change.
+///
+/// Cloning is cheap; the service list is shared. Timestamps in `services`
+/// reflect the last content change, not every re-announcement.
+///
+/// Whether the contents changed is decided over every field, so a change to
+/// a field `hash_fields` leaves out still reaches snapshot consumers. Only
+/// `hash`, `type_hashes` and `merkle` follow `hash_fields`; the last two are
+/// only worked out once something asks for them.
+#[derive(Debug, Clone)]
+pub struct CacheSnapshot {
+    /// Incremented on every content change. Starts from 0 on each daemon start.
+    pub generation: u64,
+    /// Hash of the service list, see `cache::hash::fold_digests`
+    pub hash: String,
+    /// Hash of the service list over every field, which moves with
+    /// `generation` and tags `/v1/services` listings
+    pub content_hash: String,
+    pub services: Arc<Vec<ServiceEntry>>,
+    /// Generation at which each instance last changed
+    pub changed_at: Arc<HashMap<String, u64>>,
+    /// Generation at which each instance no longer cached was removed, and
+    /// its service type; only the latest `REMOVALS_KEPT`
+    removed_at: Arc<HashMap<String, (u64, String)>>,
+    /// Removals up to this generation may have been forgotten
+    removals_forgotten: u64,
+    /// Each instance's digest, which `hash` is folded from
+    pub digests: Arc<HashMap<String, EntryDigest>>,
+    /// Each instance's digest over every field, which `content_hash` is
+    /// folded from
+    content_digests: Arc<HashMap<String, EntryDigest>>,
+    /// See `type_hashes()`
+    type_hashes: Arc<OnceLock<BTreeMap<String, String>>>,
+    /// See `merkle()`
+    merkle: Arc<OnceLock<MerkleTree>>,
+    /// Fields that contribute to `hash`
+    pub hash_fields: Arc<HashFields>,
+    /// The database's change sequence number when this was read, which DNS
+    /// zone serials follow. Stands still while the cache is held in memory.
+    pub seq: u64,
+}
+
+impl CacheSnapshot {
+    /// Snapshot of the cache as loaded at startup, at generation 0
+    pub fn new(services: Vec<ServiceEntry>, hash_fields: HashFields) -> Self {
+        let changed_at = services
+            .iter()
+            .map(|s| (s.instance_name.clone(), 0))
+            .collect();
+        let digests: HashMap<String, EntryDigest> = services
+            .iter()
+            .map(|s| (s.instance_name.clone(), hash::entry_digest(s, &hash_fields)))
+            .collect();
+        let content_digests = content_digests(&services);
+
+        Self {
+            generation: 0,
+            hash: hash::fold_digests(digests.iter().map(|(name, &digest)| (name.as_str(), digest))),
+            content_hash: hash::fold_digests(content_digests.iter().map(|(name, &digest)| (name.as_str(), digest))),
+            type_hashes: Arc::default(),
+            merkle: Arc::default(),
+            services: Arc::new(services),
+            changed_at: Arc::new(changed_at),
+            removed_at: Arc::default(),
+            removals_forgotten: 0,
+            digests: Arc::new(digests),
+            content_digests: Arc::new(content_digests),
+            hash_fields: Arc::new(hash_fields),
+            seq: 0,
+        }
+    }
+
+    /// Replace the service list with a fresh read of the cache. If any field
+    /// of any service changed, bump the generation, stamp changed instances
+    /// with it, and return true.
+    pub(crate) fn update(&mut self, services: Vec<ServiceEntry>) -> bool {
+        let digests = services.iter().map(|s| hash::entry_digest(s, &self.hash_fields)).collect();
+        self.update_digested(services, digests)
+    }
+
+    /// `update` with each service's digest already known, as the database
+    /// stores them
+    pub(crate) fn update_digested(&mut self, services: Vec<ServiceEntry>, digests: Vec<EntryDigest>) -> bool {
+        let content_digests = content_digests(&services);
+        let content_hash = hash::fold_digests(content_digests.iter().map(|(name, &digest)| (name.as_str(), digest)));
+        if content_hash == self.content_hash {
+            return false;
+        }
+
+        let digests: HashMap<String, EntryDigest> = services
+            .iter()
+            .zip(digests)
+            .map(|(s, digest)| (s.instance_name.clone(), digest))
+            .collect();
+        let generation = self.generation + 1;
+        let changed_at = services
+            .iter()
+            .map(|s| {
+                let unchanged = self.content_digests.get(&s.instance_name) == content_digests.get(&s.instance_name);
+                let at = match self.changed_at.get(&s.instance_name) {
+                    Some(&at) if unchanged => at,
+                    _ => generation,
+                };
+                (s.instance_name.clone(), at)
+            })
+            .collect();
+
+        let mut removed = self
+            .services
+            .iter()
+            .filter(|s| !content_digests.contains_key(&s.instance_name))
+            .peekable();
+        if removed.peek().is_some() || self.removed_at.keys().any(|name| content_digests.contains_key(name)) {
+            let mut removed_at: HashMap<String, (u64, String)> = removed
+                .map(|s| (s.instance_name.clone(), (generation, s.service_type.clone())))
+                .collect();
+            removed_at.extend(
+                self.removed_at
+                    .iter()
+                    .filter(|(name, _)| !content_digests.contains_key(*name) && !removed_at.contains_key(*name))
+                    .map(|(name, removal)| (name.clone(), removal.clone()))
+                    .collect::<Vec<_>>(),
+            );
+            if removed_at.len() > REMOVALS_KEPT {
+                let mut ages: Vec<u64> = removed_at.values().map(|(at, _)| *at).collect();
+                let (_, &mut forget_through, _) = ages.select_nth_unstable(removed_at.len() - REMOVALS_KEPT - 1);
+                removed_at.retain(|_, (at, _)| *at > forget_through);
+                self.removals_forgotten = self.removals_forgotten.max(forget_through);
+            }
+            self.removed_at = Arc::new(removed_at);
+        }
+
+        let hash = hash::fold_digests(digests.iter().map(|(name, &digest)| (name.as_str(), digest)));
+        if hash != self.hash {
+            self.type_hashes = Arc::default();
+            self.merkle = Arc::default();
+        }
+        self.generation = generation;
+        self.hash = hash;
+        self.content_hash = content_hash;
+        self.services = Arc::new(services);
+        self.changed_at = Arc::new(changed_at);
+        self.digests = Arc::new(digests);
+        self.content_digests = Arc::new(content_digests);
+        true
+    }
+
+    /// Hash of each service type's entries, see `cache::hash::type_hashes`.
+    /// Worked out on first use and shared by clones of this snapshot.
+    pub fn type_hashes(&self) -> &BTreeMap<String, String> {
+        self.type_hashes
+            .get_or_init(|| hash::type_hashes(self.services.iter().map(|s| (s, self.digests[&s.instance_name]))))
+    }
+
+    /// Tree over `digests` for finding where another copy differs. Built on
+    /// first use and shared by clones of this snapshot.
+    pub fn merkle(&self) -> &MerkleTree {
+        self.merkle
+            .get_or_init(|| MerkleTree::build(self.digests.iter().map(|(name, &digest)| (name.as_str(), digest))))
+    }
+
+    /// Hash of the services of `service_type`. A type with none cached
+    /// hashes like an empty cache.
+    pub fn type_hash(&self, service_type: &str) -> String {
+        match self.type_hashes().get(service_type) {
+            Some(hash) => hash.clone(),
+            None => hash::fold_digests([]),
+        }
+    }
+
+    /// The events that turn `previous` into this snapshot. Removals of the
+    /// `pruned` instances are `Pruned`, others `Removed`.
+    fn events_since(&self, previous: &CacheSnapshot, pruned: &HashSet<String>) -> Vec<CacheEvent> {
+        let mut events: Vec<CacheEvent> = previous
+            .services
+            .iter()
+            .filter(|s| !self.content_digests.contains_key(&s.instance_name))
+            .map(|s| match pruned.contains(&s.instance_name) {
+                true => CacheEvent::Pruned(s.instance_name.clone()),
+                false => CacheEvent::Removed(s.instance_name.clone()),
+            })
+            .collect();
+        for service in self.services.iter() {
+            match previous.content_digests.get(&service.instance_name) {
+                None => events.push(CacheEvent::Added(service.clone())),
+                Some(digest) if self.content_digests.get(&service.instance_name) != Some(digest) => {
+                    events.push(CacheEvent::Updated(service.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        events
+    }
+
+    /// Instance names and service types of the services removed after
+    /// `generation`, or None if some of them may have been forgotten
+    pub fn removed_since(&self, generation: u64) -> Option<impl Iterator<Item = (&str, &str)>> {
+        (generation >= self.removals_forgotten).then(|| {
+            self.removed_at
+                .iter()
+                .filter(move |(_, (at, _))| *at > generation)
+                .map(|(name, (_, service_type))| (name.as_str(), service_type.as_str()))
+        })
+    }
+
+    /// Services that changed after `generation`
+    pub fn changed_since(&self, generation: u64) -> impl Iterator<Item = &ServiceEntry> {
+        self.services.iter().filter(move |s| {
+            self.changed_at
+                .get(&s.instance_name)
+                .is_some_and(|&at| at > generation)
+        })
+    }
+}
+
+/// Removed instances a snapshot remembers, for telling changes streams
+/// what went; a stream further behind has to resync
+const REMOVALS_KEPT: usize = 4096;
+
+/// Each service's digest over every field
+fn content_digests(services: &[ServiceEntry]) -> HashMap<String, EntryDigest> {
+    let fields = HashFields::all();
+    services
+        .iter()
+        .map(|s| (s.instance_name.clone(), hash::entry_digest(s, &fields)))
+        .collect()
+}
+
+/// Final state of the cache thread, reported once it stops
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownReport {
+    /// Services in the cache at shutdown
+    pub services: usize,
+    /// Commands still queued when shutdown was requested
+    pub drained_commands: usize,
+}
+
+/// Health of the database behind the cache, shared with the API
+#[derive(Debug, Default)]
+pub struct DbHealth {
+    degraded: AtomicBool,
+    consecutive_failures: AtomicU32,
+}
+
+impl DbHealth {
+    /// True while the cache is served from memory because database writes are failing
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Database writes that have failed in a row
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
 }
 
 /// Handle to interact with the cache database
 #[derive(Clone)]
 pub struct CacheHandle {
     tx: mpsc::Sender<CacheCommand>,
+    health: Arc<DbHealth>,
+    maintenance_running: Arc<AtomicBool>,
+    /// Connections that answer queries without waiting on the cache thread
+    readers: Option<Arc<ReadPool>>,
+    events: broadcast::Sender<CacheEvent>,
+    /// The database's clock, for timestamps set outside the cache thread
+    clock: Arc<dyn Clock>,
+}
+
+/// Clears the maintenance flag when the cycle ends, however it ends
+struct MaintenanceGuard(Arc<AtomicBool>);
+
+impl Drop for MaintenanceGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
 impl CacheHandle {
     /// Spawn a new cache thread with the given database
-    pub fn spawn(db: CacheDb, hash_tx: watch::Sender<String>) -> Self {
+    pub fn spawn(db: CacheDb, snapshot_tx: watch::Sender<CacheSnapshot>, config: &CacheConfig) -> Self {
         let (tx, mut rx) = mpsc::channel::<CacheCommand>(256);
-
-        // Fix #2: helper to recompute hash only after mutations
-        let recompute_hash = |db: &CacheDb, hash_tx: &watch::Sender<String>| {
-            if let Ok(services) = db.get_all_services() {
-                let new_hash = hash::compute_hash(&services);
-                let _ = hash_tx.send(new_hash);
-            }
-        };
+        let health = Arc::new(DbHealth::default());
+        let clock = db.clock().clone();
+        let mut worker = CacheWorker::new(db, snapshot_tx, health.clone(), config, tx.downgrade());
+        let events = worker.events_tx.clone();
 
         thread::spawn(move || {
             while let Some(cmd) = rx.blocking_recv() {
-                match cmd {
-                    CacheCommand::Upsert(entry, reply) => {
-                        let result = db.upsert_service(&entry);
-                        // Fix #2: only recompute hash when data actually changed
-                        if matches!(&result, Ok(true)) {
-                            recompute_hash(&db, &hash_tx);
-                        }
-                        let _ = reply.send(result);
-                    }
-                    CacheCommand::MarkDead(instance_name, reply) => {
-                        let result = db.mark_dead(&instance_name);
-                        if result.is_ok() {
-                            recompute_hash(&db, &hash_tx);
-                        }
-                        let _ = reply.send(result);
-                    }
-                    CacheCommand::GetAll(reply) => {
-                        let result = db.get_all_services();
-                        let _ = reply.send(result);
-                    }
-                    CacheCommand::GetByType(service_type, reply) => {
-                        let result = db.get_services_by_type(&service_type);
-                        let _ = reply.send(result);
-                    }
-                    CacheCommand::GetOne(instance_name, reply) => {
-                        let result = db.get_service(&instance_name);
-                        let _ = reply.send(result);
-                    }
-                    CacheCommand::Maintenance { stale_after_secs, prune_after_secs, reply } => {
-                        let result = (|| {
-                            db.mark_stale(stale_after_secs)?;
-                            db.prune_stale(prune_after_secs)?;
-                            Ok(())
-                        })();
-                        if result.is_ok() {
-                            recompute_hash(&db, &hash_tx);
-                        }
-                        let _ = reply.send(result);
-                    }
-                    CacheCommand::Shutdown => {
-                        tracing::info!("Cache thread shutting down");
-                        break;
+                let CacheCommand::Shutdown(reply) = cmd else {
+                    worker.execute(cmd);
+                    continue;
+                };
+
+                tracing::info!("Cache thread shutting down");
+
+                // Answer anything queued behind the shutdown so callers aren't left hanging
+                let mut drained_commands = 0;
+                while let Ok(cmd) = rx.try_recv() {
+                    if !matches!(cmd, CacheCommand::Shutdown(_)) {
+                        worker.execute(cmd);
+                        drained_commands += 1;
                     }
                 }
+
+                let services = worker.shutdown();
+                let _ = reply.send(ShutdownReport { services, drained_commands });
+                break;
             }
         });
 
-        Self { tx }
+        Self { tx, health, maintenance_running: Arc::new(AtomicBool::new(false)), readers: None, events, clock }
+    }
+
+    /// The clock the cache stamps and ages services by
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Receive every `CacheEvent` from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<CacheEvent> {
+        self.events.subscribe()
+    }
+
+    /// Answer queries from `readers` rather than the cache thread, except
+    /// while degraded, when only the thread's memory copy is current
+    pub fn with_readers(mut self, readers: ReadPool) -> Self {
+        self.readers = Some(Arc::new(readers));
+        self
+    }
+
+    fn readers(&self) -> Option<&ReadPool> {
+        self.readers.as_deref().filter(|_| !self.health.is_degraded())
+    }
+
+    /// Whether the cache is currently degraded to memory
+    pub fn health(&self) -> &Arc<DbHealth> {
+        &self.health
+    }
+
+    /// True while a maintenance cycle started by `run` is in progress
+    pub fn is_maintenance_running(&self) -> bool {
+        self.maintenance_running.load(Ordering::Relaxed)
+    }
+
+    /// Claim the maintenance flag, or None if a cycle is already running
+    fn begin_maintenance(&self) -> Option<MaintenanceGuard> {
+        if self.maintenance_running.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some(MaintenanceGuard(self.maintenance_running.clone()))
     }
 
     /// Insert or update a service. Returns true if data changed.
@@ -101,6 +474,14 @@ impl CacheHandle {
         rx.await?
     }
 
+    /// Insert or update several services at once, publishing one change.
+    /// Returns whether each changed, or why it was rejected.
+    pub async fn upsert_batch(&self, entries: Vec<ServiceEntry>) -> Result<Vec<Result<bool>>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::UpsertBatch(entries, reply)).await?;
+        rx.await?
+    }
+
     /// Mark a service as dead
     pub async fn mark_dead(&self, instance_name: String) -> Result<()> {
         let (reply, rx) = oneshot::channel();
@@ -108,83 +489,790 @@ impl CacheHandle {
         rx.await?
     }
 
-    /// Get all services
-    pub async fn get_all(&self) -> Result<Vec<ServiceEntry>> {
+    /// Record a liveness probe of a service
+    pub async fn set_reachability(&self, instance_name: String, reachable: bool, latency_ms: Option<u32>) -> Result<bool> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::SetReachability { instance_name, reachable, latency_ms, reply }).await?;
+        rx.await?
+    }
+
+    /// Remove a service from the cache entirely
+    pub async fn purge(&self, instance_name: String) -> Result<bool> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Purge(instance_name, reply)).await?;
+        rx.await?
+    }
+
+    /// Make `entries` the complete set of services from `source`
+    pub async fn replace_source(&self, source: ServiceSource, entries: Vec<ServiceEntry>) -> Result<()> {
         let (reply, rx) = oneshot::channel();
-        self.tx.send(CacheCommand::GetAll(reply)).await?;
+        self.tx.send(CacheCommand::ReplaceSource(source, entries, reply)).await?;
         rx.await?
     }
 
-    /// Get services by type
-    pub async fn get_by_type(&self, service_type: String) -> Result<Vec<ServiceEntry>> {
+    /// Load exported services, merged into the cache or replacing it
+    pub async fn import(&self, services: Vec<ServiceEntry>, replace: bool) -> Result<()> {
         let (reply, rx) = oneshot::channel();
-        self.tx.send(CacheCommand::GetByType(service_type, reply)).await?;
+        self.tx.send(CacheCommand::Import { services, replace, reply }).await?;
         rx.await?
     }
 
     /// Get a single service by instance name
     pub async fn get_one(&self, instance_name: String) -> Result<Option<ServiceEntry>> {
+        if let Some(readers) = self.readers() {
+            return readers.read(move |db| db.get_service(&instance_name)).await;
+        }
         let (reply, rx) = oneshot::channel();
         self.tx.send(CacheCommand::GetOne(instance_name, reply)).await?;
         rx.await?
     }
 
+    /// Check whether a service is cached without fetching it
+    pub async fn exists(&self, instance_name: String, include_dead: bool) -> Result<bool> {
+        if let Some(readers) = self.readers() {
+            return readers.read(move |db| db.service_exists(&instance_name, include_dead)).await;
+        }
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Exists { instance_name, include_dead, reply }).await?;
+        rx.await?
+    }
+
+    /// Whether the cache thread answers within `timeout`
+    pub async fn ping(&self, timeout: std::time::Duration) -> bool {
+        let (reply, rx) = oneshot::channel();
+        let round_trip = async {
+            self.tx.send(CacheCommand::Ping(reply)).await.is_ok() && rx.await.is_ok()
+        };
+        tokio::time::timeout(timeout, round_trip).await.unwrap_or(false)
+    }
+
+    /// Types of alive mDNS services past `unseen`
+    pub async fn unseen_types(&self, unseen: StaleAfter) -> Result<Vec<String>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::UnseenTypes(unseen, reply)).await?;
+        rx.await?
+    }
+
+    /// Alive mDNS services past `unseen`
+    pub async fn unseen_services(&self, unseen: StaleAfter) -> Result<Vec<ServiceEntry>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::UnseenServices(unseen, reply)).await?;
+        rx.await?
+    }
+
+    /// One page of the services matching `filter`
+    pub async fn query(&self, filter: ServiceFilter, page: Page) -> Result<ServicePage> {
+        if let Some(readers) = self.readers() {
+            return readers.read(move |db| db.query_services(&filter, &page)).await;
+        }
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Query { filter, page, reply }).await?;
+        rx.await?
+    }
+
+    /// Full-text search over names, hostnames, and TXT values
+    pub async fn search(&self, query: String, limit: usize) -> Result<Vec<ServiceEntry>> {
+        if let Some(readers) = self.readers() {
+            return readers.read(move |db| db.search(&query, limit)).await;
+        }
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Search { query, limit, reply }).await?;
+        rx.await?
+    }
+
+    /// Persisted changes after sequence number `since`
+    pub async fn changes_since(&self, since: u64) -> Result<ChangeSet> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::ChangesSince(since, reply)).await?;
+        rx.await?
+    }
+
+    /// Recorded state transitions of `instance_name`, oldest first
+    pub async fn history(&self, instance_name: String) -> Result<Vec<ServiceEvent>> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::History(instance_name, reply)).await?;
+        rx.await?
+    }
+
+    /// Write a consistent copy of the database to `path`. Returns the change
+    /// sequence number the copy is at.
+    pub async fn backup(&self, path: PathBuf) -> Result<u64> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Backup(path, reply)).await?;
+        rx.await?
+    }
+
+    /// Latest persisted change sequence number
+    pub async fn seq(&self) -> Result<u64> {
+        // Asking past the end returns just the sequence number
+        Ok(self.changes_since(u64::MAX).await?.seq)
+    }
+
+    /// Apply the retention settings of `config`: per-type overrides,
+    /// tombstones, and history. The rest of `[cache]` is read at startup or
+    /// by `run`.
+    pub async fn reconfigure(&self, config: CacheConfig) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Reconfigure(config, reply)).await?;
+        Ok(rx.await?)
+    }
+
     /// Run maintenance (mark stale, prune old)
-    pub async fn maintenance(&self, stale_after_secs: u64, prune_after_secs: u64) -> Result<()> {
+    pub async fn maintenance(&self, stale: StaleAfter, prune_after_secs: u64) -> Result<()> {
         let (reply, rx) = oneshot::channel();
         self.tx.send(CacheCommand::Maintenance {
-            stale_after_secs,
+            stale,
             prune_after_secs,
             reply,
         }).await?;
         rx.await?
     }
 
-    /// Shutdown the cache thread
-    pub async fn shutdown(&self) -> Result<()> {
-        self.tx.send(CacheCommand::Shutdown).await?;
-        Ok(())
+    /// Shutdown the cache thread, draining any queued commands first
+    pub async fn shutdown(&self) -> Result<ShutdownReport> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(CacheCommand::Shutdown(reply)).await?;
+        Ok(rx.await?)
     }
 }
 
-/// Cache manager event loop - bridges browser events to cache
+/// State owned by the cache thread.
+///
+/// Database writes go through a circuit breaker: after `degrade_after_failures`
+/// consecutive write failures the cache switches to an in-memory copy, so
+/// reads keep working through a full or read-only disk. Every `retry_interval`
+/// (checked as commands arrive) the memory copy is written back to the
+/// database, and on success the database takes over again.
+///
+/// Writes hit the store immediately, but publishing the new hash/snapshot is
+/// limited to once per `hash_notify_interval`; changes inside the window are
+/// published together at its end, so the final state is always announced.
+/// With a `hash_quiet` period, publishing also waits until changes have
+/// stopped for that long, or for `MAX_QUIET_PERIODS` of them during a long
+/// burst, so a burst reloads and hashes the services once rather than per
+/// change.
+struct CacheWorker {
+    db: CacheDb,
+    snapshot_tx: watch::Sender<CacheSnapshot>,
+    health: Arc<DbHealth>,
+    /// 0 disables the fallback; write errors are then only reported
+    degrade_after_failures: u32,
+    retry_interval: Duration,
+    /// Serves the cache while degraded
+    fallback: Option<MemoryStore>,
+    last_retry: std::time::Instant,
+    hash_notify_interval: Duration,
+    last_notify: Option<std::time::Instant>,
+    hash_quiet: Duration,
+    /// When the oldest and the newest unpublished changes were made
+    unpublished: Option<(std::time::Instant, std::time::Instant)>,
+    /// Applied after each maintenance cycle
+    compaction: Compaction,
+    /// A `FlushHash` is on its way for changes not yet published
+    flush_pending: bool,
+    /// Weak so the worker doesn't keep its own command channel open
+    flush_tx: mpsc::WeakSender<CacheCommand>,
+    events_tx: broadcast::Sender<CacheEvent>,
+    /// Instances pruned since the last publication
+    pruned: HashSet<String>,
+}
+
+impl CacheWorker {
+    fn new(
+        db: CacheDb,
+        snapshot_tx: watch::Sender<CacheSnapshot>,
+        health: Arc<DbHealth>,
+        config: &CacheConfig,
+        flush_tx: mpsc::WeakSender<CacheCommand>,
+    ) -> Self {
+        Self {
+            db,
+            snapshot_tx,
+            health,
+            degrade_after_failures: config.degrade_after_failures,
+            retry_interval: Duration::from_secs(config.db_retry_secs),
+            fallback: None,
+            last_retry: std::time::Instant::now(),
+            hash_notify_interval: Duration::from_millis(config.hash_notify_interval_ms),
+            last_notify: None,
+            hash_quiet: Duration::from_millis(config.hash_quiet_ms),
+            unpublished: None,
+            compaction: Compaction {
+                wal_bytes: config.compact_wal_bytes,
+                free_percent: config.vacuum_free_percent,
+            },
+            flush_pending: false,
+            flush_tx,
+            events_tx: broadcast::channel(EVENT_CAPACITY).0,
+            pruned: HashSet::new(),
+        }
+    }
+
+    /// Give disk space back after maintenance. Failures are only logged:
+    /// the data is intact, and the next cycle tries again.
+    fn compact(&self) {
+        if self.fallback.is_some() {
+            return;
+        }
+        match self.db.compact(self.compaction) {
+            Ok(compacted) => {
+                if let Some(bytes) = compacted.wal_bytes {
+                    tracing::info!("Truncated {} byte WAL", bytes);
+                }
+                if compacted.free_pages > 0 {
+                    tracing::info!("Released {} free database pages", compacted.free_pages);
+                }
+            }
+            Err(e) => tracing::warn!("Failed to compact database: {:#}", e),
+        }
+    }
+
+    /// Run a single command against the cache and reply to the caller
+    fn execute(&mut self, cmd: CacheCommand) {
+        self.retry_database(false);
+
+        match cmd {
+            CacheCommand::Upsert(entry, reply) => {
+                let result = self.write(
+                    |db| db.upsert_service(&entry),
+                    |store| store.upsert_service(&entry),
+                );
+                // Fix #2: only recompute hash when data actually changed
+                if matches!(&result, Ok(true)) {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::UpsertBatch(entries, reply) => {
+                let result = self.write(
+                    |db| db.upsert_services(&entries),
+                    |store| Ok(store.upsert_services(&entries)),
+                );
+                if result.as_ref().is_ok_and(|results| results.iter().any(|r| matches!(r, Ok(true)))) {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::MarkDead(instance_name, reply) => {
+                let result = self.write(
+                    |db| db.mark_dead(&instance_name),
+                    |store| {
+                        store.mark_dead(&instance_name);
+                        Ok(())
+                    },
+                );
+                if result.is_ok() {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::SetReachability { instance_name, reachable, latency_ms, reply } => {
+                let result = self.write(
+                    |db| db.set_reachability(&instance_name, reachable, latency_ms),
+                    |store| Ok(store.set_reachability(&instance_name, reachable, latency_ms)),
+                );
+                if matches!(&result, Ok(true)) {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::Purge(instance_name, reply) => {
+                let result = self.write(
+                    |db| db.purge_service(&instance_name),
+                    |store| Ok(store.purge_service(&instance_name)),
+                );
+                if matches!(&result, Ok(true)) {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::Import { services, replace, reply } => {
+                let result = self.write(
+                    |db| if replace { db.restore(&services) } else { db.import(&services) },
+                    |store| {
+                        if replace {
+                            return store.restore(&services);
+                        }
+                        services.iter().try_for_each(|entry| store.upsert_service(entry).map(|_| ()))
+                    },
+                );
+                if result.is_ok() {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::ReplaceSource(source, entries, reply) => {
+                let result = self.write(
+                    |db| db.replace_source(source, &entries),
+                    |store| store.replace_source(source, &entries),
+                );
+                if result.is_ok() {
+                    self.notify_changed();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::Query { filter, page, reply } => {
+                let result = match &self.fallback {
+                    Some(store) => Ok(store.query_services(&filter, &page)),
+                    None => self.db.query_services(&filter, &page),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::Search { query, limit, reply } => {
+                let result = match &self.fallback {
+                    Some(store) => Ok(store.search(&query, limit)),
+                    None => self.db.search(&query, limit),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::GetOne(instance_name, reply) => {
+                let result = match &self.fallback {
+                    Some(store) => Ok(store.get_service(&instance_name)),
+                    None => self.db.get_service(&instance_name),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::Exists { instance_name, include_dead, reply } => {
+                let result = match &self.fallback {
+                    Some(store) => Ok(store.service_exists(&instance_name, include_dead)),
+                    None => self.db.service_exists(&instance_name, include_dead),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::UnseenTypes(unseen, reply) => {
+                let result = match &self.fallback {
+                    Some(store) => Ok(store.unseen_service_types(unseen)),
+                    None => self.db.unseen_service_types(unseen),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::UnseenServices(unseen, reply) => {
+                let result = match &self.fallback {
+                    Some(store) => Ok(store.unseen_services(unseen)),
+                    None => self.db.unseen_services(unseen),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::ChangesSince(since, reply) => {
+                // The change log lives in the database; the memory copy has none
+                let result = match &self.fallback {
+                    Some(_) => Err(anyhow::Error::new(DatabaseDegraded)),
+                    None => self.db.changes_since(since),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::History(instance_name, reply) => {
+                let result = match &self.fallback {
+                    Some(_) => Err(anyhow::Error::new(DatabaseDegraded)),
+                    None => self.db.history(&instance_name),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::Backup(path, reply) => {
+                // The database is behind the memory copy while degraded
+                let result = match &self.fallback {
+                    Some(_) => Err(anyhow::Error::new(DatabaseDegraded)),
+                    None => self.db.backup(&path).and_then(|()| self.db.changes_since(u64::MAX)).map(|c| c.seq),
+                };
+                let _ = reply.send(result);
+            }
+            CacheCommand::Maintenance { stale, prune_after_secs, reply } => {
+                let result = self.write(
+                    |db| {
+                        db.mark_stale(stale)?;
+                        let pruned = db.prune_stale(prune_after_secs)?;
+                        db.prune_history()?;
+                        Ok(pruned)
+                    },
+                    |store| {
+                        store.mark_stale(stale);
+                        Ok(store.prune_stale(prune_after_secs))
+                    },
+                );
+                let result = result.map(|pruned| self.pruned.extend(pruned));
+                if result.is_ok() {
+                    self.notify_changed();
+                    self.compact();
+                }
+                let _ = reply.send(result);
+            }
+            CacheCommand::FlushHash => {
+                self.flush_pending = false;
+                match self.quiet_wait() {
+                    wait if wait.is_zero() => self.publish(),
+                    wait => self.schedule_flush(wait),
+                }
+            }
+            CacheCommand::Reconfigure(config, reply) => {
+                self.db.set_type_retention(config.type_retention());
+                self.db.set_tombstone_retention(config.tombstone_retention());
+                self.db.set_history_retention(config.history_retention());
+                self.db.set_retention_ttl_factor(config.retention_ttl_factor);
+                if let Some(store) = &mut self.fallback {
+                    store.set_retention(config.type_retention(), config.tombstone_retention());
+                }
+                let _ = reply.send(());
+            }
+            CacheCommand::Ping(reply) => {
+                let _ = reply.send(());
+            }
+            // Handled by the cache thread loop, which owns the receiver
+            CacheCommand::Shutdown(_) => {}
+        }
+    }
+
+    /// Apply a write to the database, or to the memory copy while degraded
+    fn write<T>(
+        &mut self,
+        on_db: impl FnOnce(&CacheDb) -> Result<T>,
+        in_memory: impl FnOnce(&mut MemoryStore) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(store) = &mut self.fallback {
+            return in_memory(store);
+        }
+
+        let err = match on_db(&self.db) {
+            Ok(value) => {
+                self.health.consecutive_failures.store(0, Ordering::Relaxed);
+                return Ok(value);
+            }
+            // A rejected write says nothing about the database's health
+            Err(e) if e.is::<TypeConflictError>() => return Err(e),
+            Err(e) => e,
+        };
+
+        let failures = self.health.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.degrade_after_failures == 0 || failures < self.degrade_after_failures {
+            return Err(err);
+        }
+
+        tracing::warn!(
+            "{} consecutive database write failures, serving cache from memory: {:#}",
+            failures, err
+        );
+        // Reads usually still work when the disk is full or read-only
+        let services = self
+            .db
+            .get_all_services()
+            .unwrap_or_else(|_| self.snapshot_tx.borrow().services.to_vec());
+        let mut store = MemoryStore::new(
+            services,
+            self.db.type_conflict_policy(),
+            self.db.type_retention().clone(),
+            self.db.tombstone_retention(),
+            self.db.clock().clone(),
+        );
+        let value = in_memory(&mut store);
+
+        self.fallback = Some(store);
+        self.health.degraded.store(true, Ordering::Relaxed);
+        self.last_retry = std::time::Instant::now();
+        value
+    }
+
+    /// While degraded, try writing the memory copy back to the database once
+    /// the retry interval has passed (or immediately if `force`)
+    fn retry_database(&mut self, force: bool) {
+        let Some(store) = &self.fallback else {
+            return;
+        };
+        if !force && self.last_retry.elapsed() < self.retry_interval {
+            return;
+        }
+        self.last_retry = std::time::Instant::now();
+
+        match self.db.restore(store.services()) {
+            Ok(()) => {
+                tracing::info!(
+                    "Database writable again, restored {} services from memory",
+                    store.services().len()
+                );
+                self.fallback = None;
+                self.health.degraded.store(false, Ordering::Relaxed);
+                self.health.consecutive_failures.store(0, Ordering::Relaxed);
+            }
+            Err(e) => tracing::debug!("Database still unavailable: {:#}", e),
+        }
+    }
+
+    /// Publish a change now, or once the notify interval and quiet period allow
+    fn notify_changed(&mut self) {
+        let now = std::time::Instant::now();
+        self.unpublished = Some((self.unpublished.map_or(now, |(first, _)| first), now));
+        if self.flush_pending {
+            return;
+        }
+        let wait = self
+            .last_notify
+            .map(|at| self.hash_notify_interval.saturating_sub(at.elapsed()))
+            .unwrap_or_default()
+            .max(self.quiet_wait());
+        if wait.is_zero() {
+            self.publish();
+            return;
+        }
+        self.schedule_flush(wait);
+    }
+
+    /// How much longer to wait for changes to stop before publishing
+    fn quiet_wait(&self) -> Duration {
+        let Some((first, last)) = self.unpublished else {
+            return Duration::ZERO;
+        };
+        let quiet = self.hash_quiet.saturating_sub(last.elapsed());
+        let overdue = (self.hash_quiet * MAX_QUIET_PERIODS).saturating_sub(first.elapsed());
+        quiet.min(overdue)
+    }
+
+    /// Send a `FlushHash` after `wait`, or publish now if the channel is gone
+    fn schedule_flush(&mut self, wait: Duration) {
+        let Some(tx) = self.flush_tx.upgrade() else {
+            self.publish();
+            return;
+        };
+        self.flush_pending = true;
+        thread::spawn(move || {
+            thread::sleep(wait);
+            let _ = tx.blocking_send(CacheCommand::FlushHash);
+        });
+    }
+
+    fn publish(&mut self) {
+        self.last_notify = Some(std::time::Instant::now());
+        self.unpublished = None;
+        self.recompute_hash();
+    }
+
+    // Fix #2: helper to recompute hash only after mutations
+    fn recompute_hash(&mut self) {
+        let (services, digests, seq) = match &self.fallback {
+            Some(store) => (store.get_all_services(), None, None),
+            None => match self.db.digested_services() {
+                Ok((services, digests)) => (services, Some(digests), self.db.changes_since(u64::MAX).ok().map(|c| c.seq)),
+                Err(_) => return,
+            },
+        };
+
+        // Only a real content change bumps the generation and wakes receivers.
+        // Events are only worked out while someone is listening.
+        let subscribed = self.events_tx.receiver_count() > 0;
+        let mut events = Vec::new();
+        self.snapshot_tx.send_if_modified(|snapshot| {
+            let previous = subscribed.then(|| snapshot.clone());
+            if let Some(seq) = seq {
+                snapshot.seq = seq;
+            }
+            let changed = match digests {
+                Some(digests) => snapshot.update_digested(services, digests),
+                None => snapshot.update(services),
+            };
+            if let (true, Some(previous)) = (changed, previous) {
+                events = snapshot.events_since(&previous, &self.pruned);
+            }
+            changed
+        });
+        self.pruned.clear();
+        for event in events {
+            let _ = self.events_tx.send(event);
+        }
+    }
+
+    /// Persist anything held in memory one last time and count the services
+    fn shutdown(&mut self) -> usize {
+        if self.flush_pending {
+            self.publish();
+        }
+        self.retry_database(true);
+        if let Some(store) = &self.fallback {
+            tracing::warn!(
+                "Database still unavailable at shutdown; {} services held in memory were not persisted",
+                store.services().len()
+            );
+            return store.services().len();
+        }
+
+        match self.db.get_all_services() {
+            Ok(services) => services.len(),
+            Err(e) => {
+                tracing::error!("Failed to count services at shutdown: {}", e);
+                0
+            }
+        }
+    }
+}
+
+/// However long a burst of changes lasts, it's published after this many
+/// quiet periods
+const MAX_QUIET_PERIODS: u32 = 10;
+
+/// Removals waiting out the grace period before being applied
+#[derive(Default)]
+struct PendingRemovals {
+    deadlines: HashMap<String, Instant>,
+}
+
+impl PendingRemovals {
+    /// Hold a removal until `deadline`. A repeated removal keeps the original deadline.
+    fn insert(&mut self, instance_name: String, deadline: Instant) {
+        self.deadlines.entry(instance_name).or_insert(deadline);
+    }
+
+    /// Drop a held removal because the instance re-resolved. Returns true if one was pending.
+    fn cancel(&mut self, instance_name: &str) -> bool {
+        self.deadlines.remove(instance_name).is_some()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    /// Remove and return every instance whose grace period has elapsed
+    fn take_expired(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .deadlines
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.deadlines.remove(name);
+        }
+        expired
+    }
+
+    fn drain(&mut self) -> Vec<String> {
+        self.deadlines.drain().map(|(name, _)| name).collect()
+    }
+}
+
+/// Cache manager event loop - bridges browser events to cache.
+///
+/// With `probe_tx`, each maintenance cycle first asks the browser to re-browse
+/// the types of services that will go stale before the next cycle, giving
+/// quiet responders a full interval to answer before they're marked dead.
+/// With `confirmer`, services nearing staleness are queried directly, one
+/// attempt per cycle: those that answer are kept fresh, and those that miss
+/// every attempt are marked dead.
+///
+/// Timings are read from `config_rx` again whenever a reload changes it.
 pub async fn run(
     cache: CacheHandle,
     mut rx: mpsc::Receiver<BrowserEvent>,
-    config: CacheConfig,
+    mut config_rx: watch::Receiver<CacheConfig>,
+    probe_tx: Option<mpsc::Sender<BrowserCommand>>,
+    confirmer: Option<Arc<Confirmer>>,
     cancel: CancellationToken,
 ) -> Result<()> {
+    let mut config = config_rx.borrow_and_update().clone();
     // Fix #6: use dedicated maintenance interval instead of browse_interval_secs
     let mut maintenance_interval = tokio::time::interval(
-        std::time::Duration::from_secs(config.maintenance_interval_secs)
+        Duration::from_secs(config.maintenance_interval_secs)
     );
+    let mut removal_grace = Duration::from_millis(config.removal_grace_ms);
+    let mut pending_removals = PendingRemovals::default();
+    let mut batch_window = Duration::from_millis(config.upsert_batch_ms);
+    let mut batch: Vec<ServiceEntry> = Vec::new();
+    let mut batch_deadline: Option<Instant> = None;
+    // Maintenance runs alongside event handling so a slow cycle can't stall it
+    let mut maintenance_task: Option<tokio::task::JoinHandle<()>> = None;
 
     loop {
+        let next_removal = pending_removals.next_deadline();
+
         tokio::select! {
             Some(event) = rx.recv() => {
                 match event {
                     BrowserEvent::Resolved(entry) => {
-                        if let Err(e) = cache.upsert(entry).await {
-                            tracing::error!("Failed to upsert service: {}", e);
+                        let entry = *entry;
+                        if pending_removals.cancel(&entry.instance_name) {
+                            tracing::debug!("{} re-resolved within removal grace period", entry.instance_name);
+                        }
+                        if batch_window.is_zero() {
+                            log_upsert(cache.upsert(entry).await);
+                            continue;
+                        }
+                        batch.push(entry);
+                        batch_deadline.get_or_insert_with(|| Instant::now() + batch_window);
+                        if batch.len() >= MAX_BATCH {
+                            batch_deadline = None;
+                            upsert_batch(&cache, &mut batch).await;
                         }
                     }
                     BrowserEvent::Removed(instance_name) => {
-                        if let Err(e) = cache.mark_dead(instance_name).await {
-                            tracing::error!("Failed to mark service as dead: {}", e);
+                        // A buffered resolve must not land after the removal
+                        batch_deadline = None;
+                        upsert_batch(&cache, &mut batch).await;
+                        if removal_grace.is_zero() {
+                            mark_dead(&cache, instance_name).await;
+                        } else {
+                            pending_removals.insert(instance_name, Instant::now() + removal_grace);
                         }
                     }
                 }
             }
+            _ = tokio::time::sleep_until(batch_deadline.unwrap_or_else(Instant::now)), if batch_deadline.is_some() => {
+                batch_deadline = None;
+                upsert_batch(&cache, &mut batch).await;
+            }
+            _ = tokio::time::sleep_until(next_removal.unwrap_or_else(Instant::now)), if next_removal.is_some() => {
+                for instance_name in pending_removals.take_expired(Instant::now()) {
+                    mark_dead(&cache, instance_name).await;
+                }
+            }
             _ = maintenance_interval.tick() => {
-                if let Err(e) = cache.maintenance(
-                    config.stale_after_secs,
-                    config.prune_after_secs
-                ).await {
-                    tracing::error!("Failed to run maintenance: {}", e);
+                // Don't queue another cycle behind one still stuck on slow storage
+                let Some(guard) = cache.begin_maintenance() else {
+                    tracing::warn!("Previous maintenance cycle still running, skipping this one");
+                    continue;
+                };
+                let cache = cache.clone();
+                let probe_tx = probe_tx.clone();
+                let confirmer = confirmer.clone();
+                let stale = if config.expire_by_ttl {
+                    StaleAfter::Ttl { early_secs: 0 }
+                } else {
+                    StaleAfter::secs(config.stale_after_secs)
+                };
+                let (prune_after_secs, maintenance_interval_secs) =
+                    (config.prune_after_secs, config.maintenance_interval_secs);
+                // Probe a cycle ahead, so answers arrive before the deadline
+                let probe_after = stale.earlier(maintenance_interval_secs);
+                maintenance_task = Some(tokio::spawn(async move {
+                    let _guard = guard;
+                    if let Some(probe_tx) = probe_tx {
+                        probe_unseen_types(&cache, &probe_tx, probe_after).await;
+                    }
+                    if let Some(confirmer) = confirmer {
+                        let cycles = u64::from(confirmer.attempts());
+                        let confirm_after = stale.earlier(cycles * maintenance_interval_secs);
+                        confirm_unseen(&cache, &confirmer, confirm_after).await;
+                    }
+                    if let Err(e) = cache.maintenance(stale, prune_after_secs).await {
+                        tracing::error!("Failed to run maintenance: {}", e);
+                    }
+                }));
+            }
+            Ok(()) = config_rx.changed() => {
+                // Held removals and buffered resolves keep the deadlines they were given
+                let reloaded = config_rx.borrow_and_update().clone();
+                if reloaded.maintenance_interval_secs != config.maintenance_interval_secs {
+                    let period = Duration::from_secs(reloaded.maintenance_interval_secs);
+                    maintenance_interval = tokio::time::interval_at(Instant::now() + period, period);
                 }
+                removal_grace = Duration::from_millis(reloaded.removal_grace_ms);
+                batch_window = Duration::from_millis(reloaded.upsert_batch_ms);
+                config = reloaded;
             }
             _ = cancel.cancelled() => {
                 tracing::info!("Cache manager shutting down");
+                upsert_batch(&cache, &mut batch).await;
+                if let Some(task) = maintenance_task.take() {
+                    let _ = task.await;
+                }
+                // Removals still in their grace period are real as far as we know
+                for instance_name in pending_removals.drain() {
+                    mark_dead(&cache, instance_name).await;
+                }
                 break;
             }
         }
@@ -192,3 +1280,461 @@ pub async fn run(
 
     Ok(())
 }
+
+/// Resolves buffered before a batch is applied regardless of its window
+const MAX_BATCH: usize = 512;
+
+/// Apply the buffered resolves, if any, in one transaction
+async fn upsert_batch(cache: &CacheHandle, batch: &mut Vec<ServiceEntry>) {
+    if batch.is_empty() {
+        return;
+    }
+    match cache.upsert_batch(std::mem::take(batch)).await {
+        Ok(results) => results.into_iter().for_each(log_upsert),
+        Err(e) => tracing::error!("Failed to upsert services: {}", e),
+    }
+}
+
+fn log_upsert(result: Result<bool>) {
+    match result {
+        Ok(_) => {}
+        Err(e) if e.is::<TypeConflictError>() => {
+            tracing::warn!("Ignoring update: {}", e);
+        }
+        Err(e) => tracing::error!("Failed to upsert service: {}", e),
+    }
+}
+
+async fn probe_unseen_types(cache: &CacheHandle, probe_tx: &mpsc::Sender<BrowserCommand>, unseen: StaleAfter) {
+    let types = match cache.unseen_types(unseen).await {
+        Ok(types) => types,
+        Err(e) => {
+            tracing::error!("Failed to find services to probe: {}", e);
+            return;
+        }
+    };
+    for service_type in types {
+        tracing::debug!("Probing {} before its services go stale", service_type);
+        if let Err(e) = probe_tx.try_send(BrowserCommand::Probe(service_type)) {
+            tracing::warn!("Failed to request probe: {}", e);
+        }
+    }
+}
+
+/// Confirmation queries in flight at once, each waiting out its timeout
+const CONFIRM_CONCURRENCY: usize = 16;
+
+/// Query every service past `unseen`, at most `CONFIRM_CONCURRENCY` at once
+async fn confirm_unseen(cache: &CacheHandle, confirmer: &Confirmer, unseen: StaleAfter) {
+    let services = match cache.unseen_services(unseen).await {
+        Ok(services) => services,
+        Err(e) => {
+            tracing::error!("Failed to find services to confirm: {}", e);
+            return;
+        }
+    };
+    confirmer.retain(&services.iter().map(|s| s.instance_name.as_str()).collect());
+    futures::stream::iter(services)
+        .for_each_concurrent(CONFIRM_CONCURRENCY, |service| async move {
+            match confirmer.confirm(&service.instance_name).await {
+                Ok(Outcome::Confirmed) => {
+                    tracing::debug!("Confirmed {}", service.instance_name);
+                    refresh_confirmed(cache, service).await;
+                }
+                Ok(Outcome::Failed(count)) => {
+                    tracing::debug!("{} unconfirmed {} time(s)", service.instance_name, count);
+                }
+                Ok(Outcome::Dead) => {
+                    tracing::info!("{} unconfirmed {} times, marking dead", service.instance_name, confirmer.attempts());
+                    mark_dead(cache, service.instance_name).await;
+                }
+                Err(e) => tracing::warn!("Failed to confirm {}: {:#}", service.instance_name, e),
+            }
+        })
+        .await;
+}
+
+/// Stamp a service that answered its confirmation query as seen now
+async fn refresh_confirmed(cache: &CacheHandle, mut service: ServiceEntry) {
+    service.last_seen = cache.clock().now();
+    if let Err(e) = cache.upsert(service).await {
+        tracing::error!("Failed to refresh confirmed service: {}", e);
+    }
+}
+
+async fn mark_dead(cache: &CacheHandle, instance_name: String) {
+    if let Err(e) = cache.mark_dead(instance_name).await {
+        tracing::error!("Failed to mark service as dead: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+    use chrono::Utc;
+    use shared::types::ServiceSource;
+
+    fn test_entry(instance_name: &str) -> ServiceEntry {
+        ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: "test.local.".to_string(),
+            addresses: vec![Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 1).into()],
+            port: 8080,
+            txt: HashMap::new(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 4500,
+            alive: true,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_tracks_changed_generation() {
+        let a = test_entry("a._http._tcp.local.");
+        let b = test_entry("b._http._tcp.local.");
+        let mut snapshot = CacheSnapshot::new(vec![a.clone(), b.clone()], HashFields::default());
+
+        // Identical content doesn't bump the generation
+        assert!(!snapshot.update(vec![a.clone(), b.clone()]));
+        assert_eq!(snapshot.generation, 0);
+
+        let mut b2 = b.clone();
+        b2.port = 9090;
+        assert!(snapshot.update(vec![a.clone(), b2]));
+        assert_eq!(snapshot.generation, 1);
+
+        let changed: Vec<&str> = snapshot
+            .changed_since(0)
+            .map(|s| s.instance_name.as_str())
+            .collect();
+        assert_eq!(changed, vec!["b._http._tcp.local."]);
+        assert_eq!(snapshot.changed_since(1).count(), 0);
+    }
+
+    #[test]
+    fn test_unhashed_field_change_bumps_generation() {
+        let fields: HashFields = serde_json::from_str(r#"["addresses"]"#).unwrap();
+        let a = test_entry("a._http._tcp.local.");
+        let mut snapshot = CacheSnapshot::new(vec![a.clone()], fields);
+        let (hash, content_hash) = (snapshot.hash.clone(), snapshot.content_hash.clone());
+
+        assert!(snapshot.update(vec![ServiceEntry { port: 9090, ..a }]));
+        assert_eq!(snapshot.generation, 1);
+        assert_eq!(snapshot.services[0].port, 9090);
+        assert_eq!(snapshot.changed_since(0).count(), 1);
+        assert_eq!(snapshot.hash, hash, "the port isn't hashed");
+        assert_ne!(snapshot.content_hash, content_hash);
+    }
+
+    #[test]
+    fn test_type_hashes_ignore_other_types() {
+        let a = test_entry("a._http._tcp.local.");
+        let printer = ServiceEntry {
+            service_type: "_ipp._tcp".to_string(),
+            ..test_entry("printer._ipp._tcp.local.")
+        };
+        let mut snapshot = CacheSnapshot::new(vec![a.clone(), printer.clone()], HashFields::default());
+        assert_eq!(snapshot.type_hash("_http._tcp"), hash::compute_hash(std::slice::from_ref(&a), &HashFields::default()));
+        assert_eq!(snapshot.type_hash("_smb._tcp"), hash::compute_hash(&[], &HashFields::default()));
+
+        let http = snapshot.type_hash("_http._tcp");
+        let ipp = snapshot.type_hash("_ipp._tcp");
+        assert!(snapshot.update(vec![a, ServiceEntry { port: 632, ..printer }]));
+        assert_eq!(snapshot.type_hash("_http._tcp"), http);
+        assert_ne!(snapshot.type_hash("_ipp._tcp"), ipp);
+    }
+
+    #[test]
+    fn test_readd_within_grace_cancels_removal() {
+        let mut pending = PendingRemovals::default();
+        let now = Instant::now();
+
+        pending.insert("nas._smb._tcp.local.".to_string(), now + Duration::from_millis(500));
+        assert!(pending.cancel("nas._smb._tcp.local."), "Re-add should cancel the pending removal");
+
+        assert!(pending.next_deadline().is_none());
+        assert!(pending.take_expired(now + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_removal_applied_after_grace() {
+        let mut pending = PendingRemovals::default();
+        let now = Instant::now();
+
+        pending.insert("nas._smb._tcp.local.".to_string(), now + Duration::from_millis(500));
+        // A second goodbye doesn't extend the grace period
+        pending.insert("nas._smb._tcp.local.".to_string(), now + Duration::from_millis(900));
+
+        assert!(pending.take_expired(now + Duration::from_millis(100)).is_empty());
+        assert_eq!(
+            pending.take_expired(now + Duration::from_millis(500)),
+            vec!["nas._smb._tcp.local.".to_string()]
+        );
+        assert!(!pending.cancel("nas._smb._tcp.local."));
+    }
+
+    fn upsert(worker: &mut CacheWorker, entry: ServiceEntry) -> Result<bool> {
+        let (reply, mut rx) = oneshot::channel();
+        worker.execute(CacheCommand::Upsert(entry, reply));
+        rx.try_recv().unwrap()
+    }
+
+    #[test]
+    fn test_degrades_to_memory_and_recovers() {
+        let (snapshot_tx, snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let health = Arc::new(DbHealth::default());
+        let config = CacheConfig {
+            degrade_after_failures: 2,
+            db_retry_secs: 0,
+            ..CacheConfig::default()
+        };
+        let db = CacheDb::open(":memory:").unwrap();
+        let (flush_tx, _flush_rx) = mpsc::channel(1);
+        let mut worker = CacheWorker::new(db, snapshot_tx, health.clone(), &config, flush_tx.downgrade());
+
+        worker.db.set_read_only(true);
+        assert!(upsert(&mut worker, test_entry("a._http._tcp.local.")).is_err());
+        assert!(!health.is_degraded());
+
+        // The second consecutive failure trips the breaker; the write lands in memory
+        assert!(upsert(&mut worker, test_entry("b._http._tcp.local.")).unwrap());
+        assert!(health.is_degraded());
+        assert_eq!(snapshot_rx.borrow().services.len(), 1);
+
+        // Retrying against a still read-only database keeps serving from memory
+        assert!(upsert(&mut worker, test_entry("c._http._tcp.local.")).unwrap());
+        assert!(health.is_degraded());
+        assert_eq!(snapshot_rx.borrow().services.len(), 2);
+
+        worker.db.set_read_only(false);
+        let (reply, mut rx) = oneshot::channel();
+        worker.execute(CacheCommand::Query { filter: ServiceFilter::default(), page: Page::default(), reply });
+        assert_eq!(rx.try_recv().unwrap().unwrap().services.len(), 2);
+        assert!(!health.is_degraded());
+        assert_eq!(health.consecutive_failures(), 0);
+        assert!(worker.db.get_service("c._http._tcp.local.").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_maintenance_guard_blocks_overlap() {
+        let (snapshot_tx, _snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+
+        let guard = cache.begin_maintenance().expect("first cycle should start");
+        assert!(cache.is_maintenance_running());
+        assert!(cache.begin_maintenance().is_none(), "second cycle should be skipped");
+
+        drop(guard);
+        assert!(!cache.is_maintenance_running());
+        assert!(cache.begin_maintenance().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ping_fails_after_shutdown() {
+        let (snapshot_tx, _snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+
+        assert!(cache.ping(std::time::Duration::from_secs(1)).await);
+        cache.shutdown().await.unwrap();
+        assert!(!cache.ping(std::time::Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_import_merges_or_replaces() {
+        let (snapshot_tx, _snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+        cache.upsert(test_entry("kept._http._tcp.local.")).await.unwrap();
+
+        cache.import(vec![test_entry("a._http._tcp.local.")], false).await.unwrap();
+        assert!(cache.exists("kept._http._tcp.local.".to_string(), true).await.unwrap());
+        assert!(cache.exists("a._http._tcp.local.".to_string(), true).await.unwrap());
+
+        cache.import(vec![test_entry("b._http._tcp.local.")], true).await.unwrap();
+        assert!(!cache.exists("kept._http._tcp.local.".to_string(), true).await.unwrap());
+        assert!(!cache.exists("a._http._tcp.local.".to_string(), true).await.unwrap());
+        assert!(cache.exists("b._http._tcp.local.".to_string(), true).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_upsert_batch_publishes_once() {
+        let (snapshot_tx, snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+
+        let conflicting = ServiceEntry { service_type: "_ipp._tcp".to_string(), ..test_entry("a._http._tcp.local.") };
+        let results = cache
+            .upsert_batch(vec![test_entry("a._http._tcp.local."), test_entry("b._http._tcp.local."), conflicting])
+            .await
+            .unwrap();
+        assert!(matches!(results[..], [Ok(true), Ok(true), Err(_)]), "a rejected entry fails alone");
+        assert!(results[2].as_ref().unwrap_err().is::<TypeConflictError>());
+        assert_eq!(snapshot_rx.borrow().generation, 1);
+        assert_eq!(snapshot_rx.borrow().services.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_events_broadcast() {
+        let (snapshot_tx, _snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+        let mut events = cache.subscribe();
+
+        let a = test_entry("a._http._tcp.local.");
+        cache.upsert(a.clone()).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), CacheEvent::Added(s) if s.instance_name == a.instance_name));
+
+        // Re-announcements change nothing
+        cache.upsert(ServiceEntry { last_seen: Utc::now(), ..a.clone() }).await.unwrap();
+        let moved = ServiceEntry { port: 9090, ..a.clone() };
+        cache.upsert(moved.clone()).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), CacheEvent::Updated(s) if s.port == 9090));
+
+        cache.purge(a.instance_name.clone()).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), CacheEvent::Removed(name) if name == a.instance_name));
+
+        // Only maintenance prunes
+        let previous = CacheSnapshot::new(vec![a.clone(), test_entry("b._http._tcp.local.")], HashFields::default());
+        let current = CacheSnapshot::new(Vec::new(), HashFields::default());
+        let pruned = HashSet::from([a.instance_name.clone()]);
+        assert!(matches!(
+            &current.events_since(&previous, &pruned)[..],
+            [CacheEvent::Pruned(a), CacheEvent::Removed(b)] if a == "a._http._tcp.local." && b == "b._http._tcp.local."
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_service_stamped_by_db_clock() {
+        let a = test_entry("a._http._tcp.local.");
+        let clock = crate::cache::clock::MockClock::new(a.last_seen);
+        let mut db = CacheDb::open(":memory:").unwrap();
+        db.set_clock(clock.clone());
+        let (snapshot_tx, _snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+        cache.upsert(a.clone()).await.unwrap();
+
+        clock.advance(chrono::Duration::seconds(600));
+        refresh_confirmed(&cache, a.clone()).await;
+        let refreshed = cache.get_one(a.instance_name.clone()).await.unwrap().unwrap();
+        assert_eq!(refreshed.last_seen, clock.now());
+    }
+
+    #[test]
+    fn test_removals_remembered_up_to_limit() {
+        let services: Vec<_> = (0..=REMOVALS_KEPT).map(|i| test_entry(&format!("{}._http._tcp.local.", i))).collect();
+        let mut snapshot = CacheSnapshot::new(services.clone(), HashFields::default());
+        assert!(snapshot.update(services[1..].to_vec()));
+        assert_eq!(snapshot.removed_since(0).unwrap().collect::<Vec<_>>(), [("0._http._tcp.local.", "_http._tcp")]);
+
+        // Too many to remember: streams that far behind must resync
+        assert!(snapshot.update(Vec::new()));
+        assert!(snapshot.removed_since(0).is_none());
+        assert!(snapshot.removed_since(2).unwrap().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_joins_mid_stream() {
+        let (snapshot_tx, snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let db = CacheDb::open(":memory:").unwrap();
+        let cache = CacheHandle::spawn(db, snapshot_tx, &CacheConfig::default());
+
+        // Changes with nobody subscribed or reading the hashes
+        let a = test_entry("a._http._tcp.local.");
+        cache.upsert(a.clone()).await.unwrap();
+        cache.upsert(ServiceEntry { port: 9090, ..a.clone() }).await.unwrap();
+
+        let mut events = cache.subscribe();
+        let b = test_entry("b._http._tcp.local.");
+        cache.upsert(b.clone()).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), CacheEvent::Added(s) if s.instance_name == b.instance_name));
+        cache.purge(a.instance_name.clone()).await.unwrap();
+        assert!(matches!(events.recv().await.unwrap(), CacheEvent::Removed(name) if name == a.instance_name));
+
+        // Worked out on first use, and the same as if kept up all along
+        let snapshot = snapshot_rx.borrow().clone();
+        assert!(snapshot.type_hashes.get().is_none() && snapshot.merkle.get().is_none());
+        let fresh = CacheSnapshot::new(snapshot.services.to_vec(), HashFields::default());
+        assert_eq!(snapshot.type_hashes(), fresh.type_hashes());
+        assert_eq!(snapshot.merkle().root(), fresh.merkle().root());
+    }
+
+    #[test]
+    fn test_hash_notifications_coalesced() {
+        let (snapshot_tx, snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let config = CacheConfig { hash_notify_interval_ms: 50, ..CacheConfig::default() };
+        let (flush_tx, mut flush_rx) = mpsc::channel(4);
+        let db = CacheDb::open(":memory:").unwrap();
+        let mut worker = CacheWorker::new(
+            db,
+            snapshot_tx,
+            Arc::new(DbHealth::default()),
+            &config,
+            flush_tx.downgrade(),
+        );
+
+        // The first change is published straight away
+        upsert(&mut worker, test_entry("a._http._tcp.local.")).unwrap();
+        assert_eq!(snapshot_rx.borrow().generation, 1);
+
+        // Changes inside the interval are written but held back
+        upsert(&mut worker, test_entry("b._http._tcp.local.")).unwrap();
+        upsert(&mut worker, test_entry("c._http._tcp.local.")).unwrap();
+        assert_eq!(snapshot_rx.borrow().generation, 1);
+        assert_eq!(worker.db.get_all_services().unwrap().len(), 3);
+
+        // ...then published together once the interval ends
+        let cmd = flush_rx.blocking_recv().unwrap();
+        worker.execute(cmd);
+        assert_eq!(snapshot_rx.borrow().generation, 2);
+        assert_eq!(snapshot_rx.borrow().services.len(), 3);
+    }
+
+    #[test]
+    fn test_hash_published_once_changes_stop() {
+        let (snapshot_tx, snapshot_rx) =
+            watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let config = CacheConfig { hash_quiet_ms: 30, ..CacheConfig::default() };
+        let (flush_tx, mut flush_rx) = mpsc::channel(4);
+        let db = CacheDb::open(":memory:").unwrap();
+        let mut worker = CacheWorker::new(
+            db,
+            snapshot_tx,
+            Arc::new(DbHealth::default()),
+            &config,
+            flush_tx.downgrade(),
+        );
+
+        upsert(&mut worker, test_entry("a._http._tcp.local.")).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        upsert(&mut worker, test_entry("b._http._tcp.local.")).unwrap();
+        assert_eq!(snapshot_rx.borrow().generation, 0);
+
+        // The first flush comes too soon after the second change and is put off
+        let started = std::time::Instant::now();
+        while snapshot_rx.borrow().generation == 0 {
+            let cmd = flush_rx.blocking_recv().unwrap();
+            worker.execute(cmd);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(15));
+        assert_eq!(snapshot_rx.borrow().generation, 1);
+        assert_eq!(snapshot_rx.borrow().services.len(), 2);
+    }
+}
@@ -0,0 +1,83 @@
+//! Command line of the daemon. Options override the config file's values;
+//! subcommands work on the database and exit without starting the daemon.
+
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use crate::config::Overrides;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/subnet-authority/authorityd.toml";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Subnet authority daemon: caches the subnet's DNS-SD services and serves them")]
+pub struct Cli {
+    /// Config file
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Config file, as before `--config` existed
+    #[arg(value_name = "CONFIG", hide = true, conflicts_with = "config")]
+    pub config_positional: Option<PathBuf>,
+    /// Validate the config file without starting anything, then exit,
+    /// non-zero with a list of problems if there are any
+    #[arg(long)]
+    pub check_config: bool,
+    /// Check the database, interface, mDNS, and API listener, then exit
+    #[arg(long, conflicts_with = "check_config")]
+    pub self_test: bool,
+    /// Log filter in `RUST_LOG` syntax, overriding `[logging] level`
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Database to use instead of `[cache] db_path`
+    #[arg(long, value_name = "PATH")]
+    pub db_path: Option<PathBuf>,
+    /// Copy a backup over the database before opening it
+    #[arg(long, value_name = "BACKUP")]
+    pub restore: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Write every cached service to a JSON file, as `/v1/export/json` does
+    Export {
+        path: PathBuf,
+    },
+    /// Merge services from a JSON export into the database
+    Import {
+        path: PathBuf,
+    },
+}
+
+impl Cli {
+    pub fn config_path(&self) -> PathBuf {
+        self.config
+            .clone()
+            .or_else(|| self.config_positional.clone())
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+    }
+
+    pub fn overrides(&self) -> Overrides {
+        Overrides { log_level: self.log_level.clone(), db_path: self.db_path.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cli() {
+        let cli = Cli::try_parse_from(["subnet-authorityd", "--config", "a.toml", "--db-path", "/tmp/x.db", "export", "out.json"]).unwrap();
+        assert_eq!(cli.config_path(), PathBuf::from("a.toml"));
+        assert_eq!(cli.overrides().db_path, Some(PathBuf::from("/tmp/x.db")));
+        assert!(matches!(cli.command, Some(Command::Export { path }) if path.as_os_str() == "out.json"));
+
+        let cli = Cli::try_parse_from(["subnet-authorityd", "b.toml"]).unwrap();
+        assert_eq!(cli.config_path(), PathBuf::from("b.toml"));
+        assert!(cli.command.is_none());
+        assert_eq!(Cli::try_parse_from(["subnet-authorityd"]).unwrap().config_path(), PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        assert!(Cli::try_parse_from(["subnet-authorityd", "--bogus"]).is_err(), "unknown flags are errors");
+        assert!(Cli::try_parse_from(["subnet-authorityd", "--config", "a.toml", "b.toml"]).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Just enough CoAP (RFC 7252) to serve the resource directory: the message
+//! format, the options we read or write, and Block2 (RFC 7959) for bodies
+//! that don't fit in one datagram.
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            MessageType::Confirmable => 0,
+            MessageType::NonConfirmable => 1,
+            MessageType::Acknowledgement => 2,
+            MessageType::Reset => 3,
+        }
+    }
+}
+
+/// Message codes, `class << 5 | detail`: 2.05 is `0x45`
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+    pub const GET: u8 = 0x01;
+    pub const CONTENT: u8 = 0x45;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const NOT_ACCEPTABLE: u8 = 0x86;
+
+    /// Request codes are class 0, apart from the empty message
+    pub fn is_request(code: u8) -> bool {
+        code != EMPTY && code >> 5 == 0
+    }
+}
+
+/// Option numbers
+pub mod option {
+    pub const URI_HOST: u16 = 3;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const BLOCK2: u16 = 23;
+    pub const SIZE2: u16 = 28;
+
+    /// Odd option numbers must be understood or the request rejected
+    pub fn is_critical(number: u16) -> bool {
+        number & 1 == 1
+    }
+}
+
+/// Content-Format of CoRE Link Format (RFC 6690)
+pub const LINK_FORMAT: u32 = 40;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    /// Sorted by option number, repeated options in order
+    options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: MessageType, code: u8, message_id: u16) -> Self {
+        Self { kind, code, message_id, token: Vec::new(), options: Vec::new(), payload: Vec::new() }
+    }
+
+    /// A response to `request` carrying its token: piggybacked on the ACK for
+    /// a confirmable request, otherwise a non-confirmable message with `message_id`
+    pub fn response_to(request: &Message, code: u8, message_id: u16) -> Self {
+        let (kind, message_id) = match request.kind {
+            MessageType::Confirmable => (MessageType::Acknowledgement, request.message_id),
+            _ => (MessageType::NonConfirmable, message_id),
+        };
+        Self { token: request.token.clone(), ..Self::new(kind, code, message_id) }
+    }
+
+    pub fn add_option(&mut self, number: u16, value: Vec<u8>) {
+        let at = self.options.partition_point(|(n, _)| *n <= number);
+        self.options.insert(at, (number, value));
+    }
+
+    pub fn add_uint_option(&mut self, number: u16, value: u32) {
+        self.add_option(number, encode_uint(value));
+    }
+
+    /// Values of every instance of option `number`
+    pub fn options(&self, number: u16) -> impl Iterator<Item = &[u8]> {
+        self.options.iter().filter(move |(n, _)| *n == number).map(|(_, v)| v.as_slice())
+    }
+
+    pub fn option_numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        self.options.iter().map(|(n, _)| *n)
+    }
+
+    /// First instance of option `number` as an unsigned integer, None if
+    /// absent or longer than four bytes
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.options(number).next().and_then(decode_uint)
+    }
+
+    /// Uri-Path segments, joined with `/`
+    pub fn path(&self) -> String {
+        self.options(option::URI_PATH)
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Uri-Query arguments, each `key=value` or a bare `key`
+    pub fn queries(&self) -> Vec<String> {
+        self.options(option::URI_QUERY)
+            .map(|q| String::from_utf8_lossy(q).into_owned())
+            .collect()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        ensure!(buf.len() >= 4, "Message shorter than the CoAP header");
+        ensure!(buf[0] >> 6 == VERSION, "Unknown CoAP version {}", buf[0] >> 6);
+        let token_len = usize::from(buf[0] & 0x0f);
+        ensure!(token_len <= 8, "Token length {} over 8", token_len);
+        ensure!(buf.len() >= 4 + token_len, "Message truncated in the token");
+
+        let mut message = Self::new(
+            MessageType::from_bits(buf[0] >> 4),
+            buf[1],
+            u16::from_be_bytes([buf[2], buf[3]]),
+        );
+        message.token = buf[4..4 + token_len].to_vec();
+
+        let mut rest = &buf[4 + token_len..];
+        let mut number = 0u16;
+        while let Some((&first, tail)) = rest.split_first() {
+            rest = tail;
+            if first == PAYLOAD_MARKER {
+                ensure!(!rest.is_empty(), "Payload marker without a payload");
+                message.payload = rest.to_vec();
+                break;
+            }
+            let delta = extended(first >> 4, &mut rest)?;
+            let len = usize::from(extended(first & 0x0f, &mut rest)?);
+            number = number.checked_add(delta).ok_or_else(|| anyhow!("Option number overflow"))?;
+            ensure!(rest.len() >= len, "Message truncated in option {}", number);
+            message.options.push((number, rest[..len].to_vec()));
+            rest = &rest[len..];
+        }
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.token.len() + self.payload.len() + 16);
+        buf.push(VERSION << 6 | self.kind.bits() << 4 | self.token.len() as u8);
+        buf.push(self.code);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(&self.token);
+
+        let mut previous = 0;
+        for (number, value) in &self.options {
+            let (delta, delta_ext) = nibble(number - previous);
+            let (len, len_ext) = nibble(value.len() as u16);
+            buf.push(delta << 4 | len);
+            buf.extend_from_slice(&delta_ext);
+            buf.extend_from_slice(&len_ext);
+            buf.extend_from_slice(value);
+            previous = *number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(PAYLOAD_MARKER);
+            buf.extend_from_slice(&self.payload);
+        }
+        buf
+    }
+}
+
+/// Read an option delta or length whose 4-bit form is `nibble`
+fn extended(nibble: u8, rest: &mut &[u8]) -> Result<u16> {
+    let take = |rest: &mut &[u8], n: usize| -> Result<Vec<u8>> {
+        ensure!(rest.len() >= n, "Message truncated in an option header");
+        let (bytes, tail) = rest.split_at(n);
+        *rest = tail;
+        Ok(bytes.to_vec())
+    };
+    Ok(match nibble {
+        0..=12 => u16::from(nibble),
+        13 => u16::from(take(rest, 1)?[0]) + 13,
+        14 => {
+            let bytes = take(rest, 2)?;
+            u16::from_be_bytes([bytes[0], bytes[1]])
+                .checked_add(269)
+                .ok_or_else(|| anyhow!("Option value overflow"))?
+        }
+        _ => bail!("Reserved option nibble 15"),
+    })
+}
+
+/// 4-bit form of an option delta or length and its extension bytes
+fn nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// Unsigned option value in as few bytes as possible; zero is empty
+pub fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+pub fn decode_uint(bytes: &[u8]) -> Option<u32> {
+    (bytes.len() <= 4).then(|| bytes.iter().fold(0, |acc, &b| acc << 8 | u32::from(b)))
+}
+
+/// Block2 option value: which slice of a body a response carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Block size exponent: blocks are `16 << szx` bytes
+    pub szx: u8,
+}
+
+impl Block {
+    /// Largest size exponent we use, 1024-byte blocks, so a block and its
+    /// headers fit in the 1280-byte IPv6 minimum MTU
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn decode(value: u32) -> Option<Self> {
+        let szx = (value & 0x07) as u8;
+        // Size exponent 7 is reserved (BERT, over TCP only)
+        (szx != 7).then_some(Self { num: value >> 4, more: value & 0x08 != 0, szx })
+    }
+
+    pub fn encode(self) -> u32 {
+        self.num << 4 | u32::from(self.more) << 3 | u32::from(self.szx)
+    }
+
+    pub fn size(self) -> usize {
+        16 << self.szx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_with_extended_options() {
+        let mut message = Message::new(MessageType::Confirmable, code::GET, 0x1234);
+        message.token = vec![0xde, 0xad];
+        message.add_option(option::URI_QUERY, b"rt=_http._tcp".to_vec());
+        message.add_option(option::URI_PATH, b"rd-lookup".to_vec());
+        message.add_option(option::URI_PATH, b"res".to_vec());
+        // Delta over 268 from Uri-Query needs the two-byte extension
+        message.add_option(2048, vec![0x41; 300]);
+        message.add_uint_option(option::BLOCK2, Block { num: 2, more: false, szx: 6 }.encode());
+        message.payload = b"hello".to_vec();
+
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.path(), "rd-lookup/res");
+        assert_eq!(decoded.queries(), vec!["rt=_http._tcp".to_string()]);
+        let block = Block::decode(decoded.uint_option(option::BLOCK2).unwrap()).unwrap();
+        assert_eq!((block.num, block.size()), (2, 1024));
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        assert!(Message::decode(&[0x40, 0x01]).is_err());
+        // Version 2
+        assert!(Message::decode(&[0x80, 0x01, 0x00, 0x01]).is_err());
+        // Token length 9
+        assert!(Message::decode(&[0x49, 0x01, 0x00, 0x01]).is_err());
+        // Payload marker with nothing after it
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xff]).is_err());
+        // Option claims more bytes than remain
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xb5, b'a']).is_err());
+    }
+}
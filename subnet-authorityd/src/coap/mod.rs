@@ -0,0 +1,403 @@
+//! CoAP front-end for constrained nodes that can't afford HTTP: a read-only
+//! resource directory of the cached services, and the cache hash to observe.
+
+pub mod message;
+pub mod observe;
+pub mod rd;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use crate::addresses::AddressPreference;
+use crate::cache_manager::CacheSnapshot;
+use message::{code, option, Block, Message, MessageType, LINK_FORMAT};
+use observe::Observers;
+
+/// Largest datagram read; requests are small, so anything over is dropped
+const MAX_DATAGRAM: usize = 1500;
+
+/// Resources the server answers for
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Resource {
+    WellKnownCore,
+    Lookup,
+    /// The cache hash, observable
+    Hash,
+}
+
+impl Resource {
+    fn at(path: &str) -> Option<Self> {
+        match path {
+            ".well-known/core" => Some(Resource::WellKnownCore),
+            rd::RESOURCE_LOOKUP_PATH => Some(Resource::Lookup),
+            HASH_PATH => Some(Resource::Hash),
+            _ => None,
+        }
+    }
+
+    fn content_format(self) -> u32 {
+        match self {
+            Resource::Hash => observe::TEXT_PLAIN,
+            _ => LINK_FORMAT,
+        }
+    }
+}
+
+/// Path of the observable cache hash
+pub const HASH_PATH: &str = "hash";
+
+pub struct CoapServer {
+    socket: UdpSocket,
+    snapshot_rx: watch::Receiver<CacheSnapshot>,
+    preference: Arc<AddressPreference>,
+    /// Reported as the `d` (sector) attribute of every link
+    zone: String,
+    next_message_id: u16,
+    observers: Observers,
+}
+
+impl CoapServer {
+    pub async fn bind(
+        listen: &str,
+        snapshot_rx: watch::Receiver<CacheSnapshot>,
+        preference: Arc<AddressPreference>,
+        zone: String,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(listen)
+            .await
+            .with_context(|| format!("Failed to bind CoAP to {}", listen))?;
+        Ok(Self {
+            socket,
+            snapshot_rx,
+            preference,
+            zone,
+            next_message_id: rand_message_id(),
+            observers: Observers::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr().context("Failed to read CoAP socket address")
+    }
+
+    /// Answer requests and notify `/hash` observers until `cancel` fires.
+    /// Malformed datagrams are dropped. Retransmitted confirmable requests
+    /// are simply answered again, which is safe as every resource is read-only.
+    pub async fn run(mut self, cancel: CancellationToken) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        // Keep answering requests if the cache thread goes away
+        let mut watching = true;
+        loop {
+            let retransmit_at = self.observers.next_retransmission();
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                changed = self.snapshot_rx.changed(), if watching => {
+                    if changed.is_err() {
+                        watching = false;
+                        continue;
+                    }
+                    let hash = self.snapshot_rx.borrow_and_update().hash.clone();
+                    let next_id = &mut self.next_message_id;
+                    let datagrams = self.observers.notify(&hash, || {
+                        *next_id = next_id.wrapping_add(1);
+                        *next_id
+                    }, Instant::now());
+                    self.send_all(datagrams).await;
+                }
+                _ = sleep_until(retransmit_at), if retransmit_at.is_some() => {
+                    let datagrams = self.observers.retransmit(Instant::now());
+                    self.send_all(datagrams).await;
+                }
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, peer) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            tracing::warn!("CoAP receive failed: {}", e);
+                            continue;
+                        }
+                    };
+                    let request = match Message::decode(&buf[..len]) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            tracing::debug!("Dropping CoAP datagram from {}: {}", peer, e);
+                            continue;
+                        }
+                    };
+                    if let Some(response) = self.respond(&request, peer) {
+                        self.send_all(vec![(peer, response.encode())]).await;
+                    }
+                }
+            }
+        }
+        tracing::info!("CoAP server stopped");
+    }
+
+    async fn send_all(&self, datagrams: Vec<(SocketAddr, Vec<u8>)>) {
+        for (peer, datagram) in datagrams {
+            if let Err(e) = self.socket.send_to(&datagram, peer).await {
+                tracing::warn!("Failed to send CoAP message to {}: {}", peer, e);
+            }
+        }
+    }
+
+    fn message_id(&mut self) -> u16 {
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.next_message_id
+    }
+
+    /// The reply to `request` from `peer`, if it needs one
+    fn respond(&mut self, request: &Message, peer: SocketAddr) -> Option<Message> {
+        if matches!(request.kind, MessageType::Acknowledgement | MessageType::Reset) {
+            self.observers.reply(peer, request, Instant::now());
+            return None;
+        }
+        if request.code == code::EMPTY {
+            // CoAP ping: a confirmable empty message is answered with a reset
+            return (request.kind == MessageType::Confirmable)
+                .then(|| Message::new(MessageType::Reset, code::EMPTY, request.message_id));
+        }
+        if !code::is_request(request.code) {
+            return None;
+        }
+
+        let message_id = self.message_id();
+        let mut response = Message::response_to(request, code::CONTENT, message_id);
+        let result = self.handle(request).and_then(|(resource, body)| {
+            if resource == Resource::Hash {
+                self.observe(request, peer, &mut response);
+            }
+            set_body(&mut response, resource.content_format(), body, request.uint_option(option::BLOCK2))
+        });
+        if let Err(error) = result {
+            response.code = error;
+        }
+        Some(response)
+    }
+
+    /// Act on the Observe option of a successful GET of `/hash`
+    fn observe(&mut self, request: &Message, peer: SocketAddr, response: &mut Message) {
+        match request.uint_option(option::OBSERVE) {
+            Some(observe::REGISTER) => {
+                if self.observers.register(peer, &request.token, Instant::now()) {
+                    response.add_uint_option(option::OBSERVE, self.observers.sequence());
+                    tracing::debug!("{} observing /hash ({} observers)", peer, self.observers.count());
+                } else {
+                    tracing::warn!("Not adding CoAP observer {}: {} already", peer, observe::MAX_OBSERVERS);
+                }
+            }
+            Some(observe::DEREGISTER) => self.observers.deregister(peer, &request.token),
+            _ => {}
+        }
+    }
+
+    /// The resource `request` asks for and its body, or the error code to answer with
+    fn handle(&self, request: &Message) -> Result<(Resource, Vec<u8>), u8> {
+        let understood = [option::URI_HOST, option::URI_PORT, option::URI_PATH, option::URI_QUERY, option::ACCEPT, option::BLOCK2];
+        if request.option_numbers().any(|n| option::is_critical(n) && !understood.contains(&n)) {
+            return Err(code::BAD_OPTION);
+        }
+        let resource = Resource::at(&request.path()).ok_or(code::NOT_FOUND)?;
+        if request.code != code::GET {
+            return Err(code::METHOD_NOT_ALLOWED);
+        }
+        if request.uint_option(option::ACCEPT).is_some_and(|accept| accept != resource.content_format()) {
+            return Err(code::NOT_ACCEPTABLE);
+        }
+
+        let links = match resource {
+            Resource::WellKnownCore => rd::well_known_core(),
+            Resource::Lookup => {
+                let snapshot = self.snapshot_rx.borrow();
+                rd::service_links(&snapshot.services, &self.preference, &self.zone)
+            }
+            Resource::Hash => return Ok((resource, self.snapshot_rx.borrow().hash.clone().into_bytes())),
+        };
+        Ok((resource, rd::format(&rd::lookup(links, &request.queries())).into_bytes()))
+    }
+}
+
+/// Sleep until `at`, or forever if None
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Put `body` in `response`, split into Block2 blocks if it's larger than
+/// one block or the client asked for a particular block
+fn set_body(response: &mut Message, content_format: u32, body: Vec<u8>, requested: Option<u32>) -> Result<(), u8> {
+    response.add_uint_option(option::CONTENT_FORMAT, content_format);
+    let block = match requested {
+        Some(value) => Block::decode(value).ok_or(code::BAD_OPTION)?,
+        None if body.len() <= Block { num: 0, more: false, szx: Block::MAX_SZX }.size() => {
+            response.payload = body;
+            return Ok(());
+        }
+        None => Block { num: 0, more: false, szx: Block::MAX_SZX },
+    };
+    let start = block.num as usize * block.size();
+    if start > body.len() || (start == body.len() && start > 0) {
+        return Err(code::BAD_OPTION);
+    }
+    let end = (start + block.size()).min(body.len());
+    response.add_uint_option(option::BLOCK2, Block { more: end < body.len(), ..block }.encode());
+    if block.num == 0 {
+        response.add_uint_option(option::SIZE2, body.len() as u32);
+    }
+    response.payload = body[start..end].to_vec();
+    Ok(())
+}
+
+/// Starting message ID; RFC 7252 asks for a randomized one so restarts don't
+/// collide with IDs peers still remember
+fn rand_message_id() -> u16 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use shared::types::ServiceEntry;
+    use crate::addresses::default_preference;
+    use crate::cache::hash::HashFields;
+
+    fn service(n: usize) -> ServiceEntry {
+        ServiceEntry {
+            service_type: "_coap._udp".to_string(),
+            instance_name: format!("sensor-{}._coap._udp.local.", n),
+            hostname: format!("sensor-{}.local.", n),
+            addresses: vec![format!("fd00:1234:5678:1::{:x}", n + 1).parse().unwrap()],
+            port: 5683,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    async fn server(services: Vec<ServiceEntry>) -> (CoapServer, watch::Sender<CacheSnapshot>) {
+        let (tx, snapshot_rx) = watch::channel(CacheSnapshot::new(services, HashFields::default()));
+        let preference = AddressPreference::new(default_preference(), "fd00:1234:5678:1::/64".parse().unwrap());
+        let server = CoapServer::bind("127.0.0.1:0", snapshot_rx, Arc::new(preference), "subnet.example".to_string())
+            .await
+            .unwrap();
+        (server, tx)
+    }
+
+    fn get(path: &str) -> Message {
+        let mut request = Message::new(MessageType::Confirmable, code::GET, 7);
+        request.token = vec![1, 2, 3];
+        for segment in path.split('/') {
+            request.add_option(option::URI_PATH, segment.as_bytes().to_vec());
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_discovery_over_udp() {
+        let (server, _tx) = server(vec![service(1)]).await;
+        let addr = server.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let handle = tokio::spawn(server.run(cancel.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        for (path, expected) in [
+            (".well-known/core", r#"</rd-lookup/res>;rt="core.rd-lookup-res";ct="40""#),
+            ("rd-lookup/res", r#"<coap://[fd00:1234:5678:1::2]:5683>;rt="_coap._udp";ep="sensor-1""#),
+        ] {
+            client.send_to(&get(path).encode(), addr).await.unwrap();
+            let len = client.recv(&mut buf).await.unwrap();
+            let response = Message::decode(&buf[..len]).unwrap();
+            assert_eq!((response.kind, response.message_id, response.code), (MessageType::Acknowledgement, 7, code::CONTENT));
+            assert_eq!(response.token, vec![1, 2, 3]);
+            assert!(String::from_utf8(response.payload).unwrap().starts_with(expected));
+        }
+
+        cancel.cancel();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_lookup_is_blockwise() {
+        let (mut server, _tx) = server((0..40).map(service).collect()).await;
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+
+        let first = server.respond(&get("rd-lookup/res"), peer).unwrap();
+        let block = Block::decode(first.uint_option(option::BLOCK2).unwrap()).unwrap();
+        let total = first.uint_option(option::SIZE2).unwrap() as usize;
+        assert!(block.more && total > 1024);
+        assert_eq!(first.payload.len(), 1024);
+
+        let mut body = first.payload;
+        for num in 1.. {
+            let mut request = get("rd-lookup/res");
+            request.add_uint_option(option::BLOCK2, Block { num, more: false, szx: Block::MAX_SZX }.encode());
+            let response = server.respond(&request, peer).unwrap();
+            body.extend_from_slice(&response.payload);
+            if !Block::decode(response.uint_option(option::BLOCK2).unwrap()).unwrap().more {
+                break;
+            }
+        }
+        assert_eq!(body.len(), total);
+        assert_eq!(String::from_utf8(body).unwrap().matches("<coap://").count(), 40);
+
+        assert_eq!(server.respond(&get("nope"), peer).unwrap().code, code::NOT_FOUND);
+        let mut request = get("rd-lookup/res");
+        request.add_option(1, Vec::new()); // If-Match, critical and unsupported
+        assert_eq!(server.respond(&request, peer).unwrap().code, code::BAD_OPTION);
+        let ping = Message::new(MessageType::Confirmable, code::EMPTY, 9);
+        assert_eq!(server.respond(&ping, peer).unwrap().kind, MessageType::Reset);
+    }
+
+    #[tokio::test]
+    async fn test_hash_observe_over_udp() {
+        let (server, tx) = server(vec![service(1)]).await;
+        let addr = server.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let handle = tokio::spawn(server.run(cancel.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut receive = async || {
+            let len = tokio::time::timeout(std::time::Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .expect("no CoAP message")
+                .unwrap();
+            Message::decode(&buf[..len]).unwrap()
+        };
+
+        let mut request = get(HASH_PATH);
+        request.add_uint_option(option::OBSERVE, observe::REGISTER);
+        client.send_to(&request.encode(), addr).await.unwrap();
+        let registered = receive().await;
+        assert_eq!(registered.uint_option(option::OBSERVE), Some(0));
+        assert_eq!(registered.payload, tx.borrow().hash.as_bytes());
+
+        tx.send_modify(|snapshot| {
+            snapshot.update(vec![service(1), service(2)]);
+        });
+        let notification = receive().await;
+        assert_eq!(notification.kind, MessageType::NonConfirmable);
+        assert_eq!(notification.token, vec![1, 2, 3]);
+        assert_eq!(notification.uint_option(option::OBSERVE), Some(1));
+        assert_eq!(notification.payload, tx.borrow().hash.as_bytes());
+
+        cancel.cancel();
+        handle.await.unwrap();
+    }
+}
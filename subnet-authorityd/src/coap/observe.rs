@@ -0,0 +1,228 @@
+//! Observe (RFC 7641) on `/hash`: registered clients get a notification each
+//! time the cache hash changes, so they needn't poll.
+//!
+//! Notifications are non-confirmable, except that an observer not heard from
+//! for `CONFIRM_INTERVAL` gets a confirmable one, retransmitted per RFC 7252.
+//! An observer that never acknowledges it, or answers any notification with
+//! a reset, is dropped.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use super::message::{code, option, Message, MessageType};
+
+/// Most concurrent observers; registrations beyond this get a plain response
+pub const MAX_OBSERVERS: usize = 256;
+/// How long an observer may go without acknowledging a notification
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// RFC 7252 transmission parameters
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+/// Observe option values in a GET
+pub const REGISTER: u32 = 0;
+pub const DEREGISTER: u32 = 1;
+
+/// Content-Format of the hash
+pub const TEXT_PLAIN: u32 = 0;
+
+struct Observer {
+    peer: SocketAddr,
+    token: Vec<u8>,
+    /// Last time the client acknowledged a notification or registered
+    confirmed_at: Instant,
+    /// Message ID of the latest notification, to match a reset against
+    last_message_id: Option<u16>,
+}
+
+/// A confirmable notification waiting for its ACK
+struct Pending {
+    peer: SocketAddr,
+    datagram: Vec<u8>,
+    attempts: u32,
+    due: Instant,
+}
+
+#[derive(Default)]
+pub struct Observers {
+    observers: Vec<Observer>,
+    /// Last Observe sequence number sent, 24 bits
+    sequence: u32,
+    pending: HashMap<u16, Pending>,
+}
+
+impl Observers {
+    /// Current sequence number, for the Observe option of a registration response
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Add or refresh an observer. False if there is no room for it.
+    pub fn register(&mut self, peer: SocketAddr, token: &[u8], now: Instant) -> bool {
+        if let Some(observer) = self.find(peer, token) {
+            observer.confirmed_at = now;
+            return true;
+        }
+        if self.observers.len() >= MAX_OBSERVERS {
+            return false;
+        }
+        self.observers.push(Observer { peer, token: token.to_vec(), confirmed_at: now, last_message_id: None });
+        true
+    }
+
+    pub fn deregister(&mut self, peer: SocketAddr, token: &[u8]) {
+        self.observers.retain(|o| !(o.peer == peer && o.token == token));
+    }
+
+    pub fn count(&self) -> usize {
+        self.observers.len()
+    }
+
+    fn find(&mut self, peer: SocketAddr, token: &[u8]) -> Option<&mut Observer> {
+        self.observers.iter_mut().find(|o| o.peer == peer && o.token == token)
+    }
+
+    /// Datagrams notifying every observer of `hash`. `message_id` hands out
+    /// fresh message IDs.
+    pub fn notify(
+        &mut self,
+        hash: &str,
+        mut message_id: impl FnMut() -> u16,
+        now: Instant,
+    ) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.sequence = (self.sequence + 1) & 0xff_ffff;
+        let mut datagrams = Vec::with_capacity(self.observers.len());
+        for observer in &mut self.observers {
+            let previous = observer.last_message_id.and_then(|id| self.pending.remove(&id));
+            let confirmable = previous.is_some() || now.duration_since(observer.confirmed_at) >= CONFIRM_INTERVAL;
+            let kind = if confirmable { MessageType::Confirmable } else { MessageType::NonConfirmable };
+
+            let id = message_id();
+            let mut notification = Message::new(kind, code::CONTENT, id);
+            notification.token = observer.token.clone();
+            notification.add_uint_option(option::OBSERVE, self.sequence);
+            notification.add_uint_option(option::CONTENT_FORMAT, TEXT_PLAIN);
+            notification.payload = hash.as_bytes().to_vec();
+            let datagram = notification.encode();
+
+            if confirmable {
+                // A newer state replaces an unacknowledged one, but keeps its
+                // attempt count so a silent client still runs out
+                let attempts = previous.map_or(0, |p| p.attempts);
+                let due = now + ACK_TIMEOUT * 2u32.pow(attempts.min(MAX_RETRANSMIT));
+                self.pending.insert(id, Pending { peer: observer.peer, datagram: datagram.clone(), attempts, due });
+            }
+            observer.last_message_id = Some(id);
+            datagrams.push((observer.peer, datagram));
+        }
+        datagrams
+    }
+
+    /// Handle an ACK or reset from `peer`. A reset to a notification cancels
+    /// the observation.
+    pub fn reply(&mut self, peer: SocketAddr, reply: &Message, now: Instant) {
+        let Some(index) = self
+            .observers
+            .iter()
+            .position(|o| o.peer == peer && o.last_message_id == Some(reply.message_id))
+        else {
+            return;
+        };
+        self.pending.remove(&reply.message_id);
+        match reply.kind {
+            MessageType::Acknowledgement => self.observers[index].confirmed_at = now,
+            MessageType::Reset => {
+                self.observers.swap_remove(index);
+            }
+            _ => {}
+        }
+    }
+
+    /// When the next retransmission is due, if any
+    pub fn next_retransmission(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.due).min()
+    }
+
+    /// Datagrams to retransmit at `now`. Observers that used up their
+    /// retransmissions are dropped.
+    pub fn retransmit(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut datagrams = Vec::new();
+        let mut expired = Vec::new();
+        for (id, pending) in self.pending.iter_mut().filter(|(_, p)| p.due <= now) {
+            if pending.attempts >= MAX_RETRANSMIT {
+                expired.push(*id);
+                continue;
+            }
+            pending.attempts += 1;
+            pending.due = now + ACK_TIMEOUT * 2u32.pow(pending.attempts);
+            datagrams.push((pending.peer, pending.datagram.clone()));
+        }
+        for id in expired {
+            if let Some(pending) = self.pending.remove(&id) {
+                tracing::debug!("CoAP observer {} stopped acknowledging; dropping it", pending.peer);
+            }
+            self.observers.retain(|o| o.last_message_id != Some(id));
+        }
+        datagrams
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unacknowledged_observer_is_dropped() {
+        let peer: SocketAddr = "[fd00::20]:5683".parse().unwrap();
+        let start = Instant::now();
+        let mut observers = Observers::default();
+        assert!(observers.register(peer, &[7], start));
+
+        let mut next_id = 100u16;
+        let mut ids = || {
+            next_id += 1;
+            next_id
+        };
+
+        // Recently registered: a non-confirmable notification
+        let sent = observers.notify("aaaa", &mut ids, start);
+        let first = Message::decode(&sent[0].1).unwrap();
+        assert_eq!((first.kind, first.uint_option(option::OBSERVE)), (MessageType::NonConfirmable, Some(1)));
+        assert_eq!(first.payload, b"aaaa");
+        assert!(observers.next_retransmission().is_none());
+
+        // A day later it must confirm, and is retransmitted until it gives up
+        let later = start + CONFIRM_INTERVAL;
+        let sent = observers.notify("bbbb", &mut ids, later);
+        assert_eq!(Message::decode(&sent[0].1).unwrap().kind, MessageType::Confirmable);
+        for _ in 0..MAX_RETRANSMIT {
+            let due = observers.next_retransmission().unwrap();
+            assert_eq!(observers.retransmit(due).len(), 1);
+        }
+        let due = observers.next_retransmission().unwrap();
+        assert!(observers.retransmit(due).is_empty());
+        assert_eq!(observers.count(), 0);
+    }
+
+    #[test]
+    fn test_reset_cancels_observation() {
+        let peer: SocketAddr = "[fd00::20]:5683".parse().unwrap();
+        let now = Instant::now();
+        let mut observers = Observers::default();
+        observers.register(peer, &[7], now);
+        observers.register(peer, &[8], now);
+
+        let mut id = 0;
+        let sent = observers.notify("aaaa", || {
+            id += 1;
+            id
+        }, now);
+        assert_eq!(sent.len(), 2);
+        let reset = Message::new(MessageType::Reset, code::EMPTY, 1);
+        observers.reply(peer, &reset, now);
+        assert_eq!(observers.count(), 1);
+
+        observers.deregister(peer, &[8]);
+        assert_eq!(observers.count(), 0);
+    }
+}
@@ -0,0 +1,173 @@
+//! Resource directory lookup (RFC 9176) over the cached services. Each alive
+//! service with an address is one resource; there is no registration
+//! interface, since the directory is filled from mDNS.
+
+use std::fmt;
+use std::net::SocketAddr;
+use shared::names::InstanceName;
+use shared::types::ServiceEntry;
+use crate::addresses::AddressPreference;
+
+/// Path of the resource lookup interface
+pub const RESOURCE_LOOKUP_PATH: &str = "rd-lookup/res";
+
+/// A link in CoRE Link Format (RFC 6690)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub target: String,
+    pub attrs: Vec<(&'static str, String)>,
+}
+
+impl Link {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| *k == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the link passes one lookup filter, `name=value` with an
+    /// optional trailing `*` for a prefix match. `href` filters the target.
+    fn matches(&self, filter: &str) -> bool {
+        let (name, wanted) = filter.split_once('=').unwrap_or((filter, "*"));
+        let value = if name == "href" { Some(self.target.as_str()) } else { self.attr(name) };
+        match (value, wanted.strip_suffix('*')) {
+            (Some(value), Some(prefix)) => value.starts_with(prefix),
+            (Some(value), None) => value == wanted,
+            (None, _) => false,
+        }
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}>", self.target)?;
+        for (name, value) in &self.attrs {
+            // Flags such as `obs` have no value
+            if value.is_empty() {
+                write!(f, ";{}", name)?;
+            } else {
+                write!(f, ";{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Links advertised at `/.well-known/core`
+pub fn well_known_core() -> Vec<Link> {
+    vec![
+        Link {
+            target: format!("/{}", RESOURCE_LOOKUP_PATH),
+            attrs: vec![("rt", "core.rd-lookup-res".to_string()), ("ct", "40".to_string())],
+        },
+        Link {
+            target: format!("/{}", super::HASH_PATH),
+            attrs: vec![("obs", String::new()), ("ct", "0".to_string())],
+        },
+    ]
+}
+
+/// One link per alive service, to its preferred address. The target scheme
+/// is taken from the service type (`_coap._udp` gives `coap://`), and a
+/// `path` TXT record is appended as in DNS-SD. Attributes: `rt` is the
+/// service type, `ep` the host the service runs on, `d` the authority's zone,
+/// and `title` the instance name.
+pub fn service_links(services: &[ServiceEntry], preference: &AddressPreference, zone: &str) -> Vec<Link> {
+    services
+        .iter()
+        .filter(|s| s.alive)
+        .filter_map(|s| {
+            let addr = preference.preferred(&s.addresses)?;
+            let scheme = s.service_type.split('.').next()?.trim_start_matches('_');
+            let path = s.txt.get("path").filter(|p| p.starts_with('/')).map_or("", String::as_str);
+            let title = InstanceName::parse(&s.instance_name)
+                .map_or_else(|| s.instance_name.clone(), |name| name.short_name);
+            let endpoint = s.hostname.split('.').next().unwrap_or(&s.hostname);
+            Some(Link {
+                target: format!("{}://{}{}", scheme, SocketAddr::new(addr, s.port), path),
+                attrs: vec![
+                    ("rt", s.service_type.clone()),
+                    ("ep", endpoint.to_string()),
+                    ("d", zone.to_string()),
+                    ("title", title),
+                ],
+            })
+        })
+        .collect()
+}
+
+/// Links passing every filter in `queries`. The RFC 9176 `page` and `count`
+/// parameters page through the result instead of filtering.
+pub fn lookup(links: Vec<Link>, queries: &[String]) -> Vec<Link> {
+    let mut page = 0;
+    let mut count = None;
+    let mut filters = Vec::new();
+    for query in queries {
+        match query.split_once('=') {
+            Some(("page", n)) => page = n.parse().unwrap_or(0),
+            Some(("count", n)) => count = n.parse().ok(),
+            _ => filters.push(query.as_str()),
+        }
+    }
+
+    let matching = links.into_iter().filter(|link| filters.iter().all(|f| link.matches(f)));
+    match count {
+        Some(count) => matching.skip(page * count).take(count).collect(),
+        None => matching.collect(),
+    }
+}
+
+/// Link Format document for `links`
+pub fn format(links: &[Link]) -> String {
+    links.iter().map(Link::to_string).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use crate::addresses::default_preference;
+
+    fn service(instance_name: &str, service_type: &str, hostname: &str, port: u16) -> ServiceEntry {
+        ServiceEntry {
+            service_type: service_type.to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: hostname.to_string(),
+            addresses: vec!["fe80::1".parse().unwrap(), "fd00:1234:5678:1::10".parse().unwrap()],
+            port,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[test]
+    fn test_lookup_filters_service_links() {
+        let preference = AddressPreference::new(default_preference(), "fd00:1234:5678:1::/64".parse().unwrap());
+        let mut web = service("Web UI._http._tcp.local.", "_http._tcp", "nas.local.", 80);
+        web.txt.insert("path".to_string(), "/admin".to_string());
+        let sensor = service("temp._coap._udp.local.", "_coap._udp", "sensor-3.local.", 5683);
+        let mut gone = service("old._coap._udp.local.", "_coap._udp", "sensor-1.local.", 5683);
+        gone.alive = false;
+
+        let links = service_links(&[web, sensor, gone], &preference, "subnet.example");
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            links[0].to_string(),
+            r#"<http://[fd00:1234:5678:1::10]:80/admin>;rt="_http._tcp";ep="nas";d="subnet.example";title="Web UI""#
+        );
+
+        let found = lookup(links.clone(), &["rt=_coap._udp".to_string()]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].target, "coap://[fd00:1234:5678:1::10]:5683");
+        assert_eq!(lookup(links.clone(), &["ep=sensor*".to_string()]).len(), 1);
+        assert_eq!(lookup(links.clone(), &["href=http://*".to_string()]).len(), 1);
+        assert_eq!(lookup(links.clone(), &["count=1".to_string(), "page=1".to_string()]), vec![links[1].clone()]);
+        assert!(lookup(links, &["rt=_ssh._tcp".to_string()]).is_empty());
+    }
+}
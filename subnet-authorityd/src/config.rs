@@ -1,6 +1,16 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv6Addr;
 use std::path::{Path, PathBuf};
-use serde::Deserialize;
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
 use anyhow::{Context, Result};
+use utoipa::ToSchema;
+use crate::addresses::{default_preference, AddressClass};
+use crate::api::auth::AuthConfig;
+use crate::cache::db::{TypeConflictPolicy, TypeRetention, MAX_RETENTION_SECS};
+use crate::cache::hash::HashFields;
+use crate::discovery::SourceKind;
+use crate::dns::tsig::TsigKey;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
@@ -9,6 +19,84 @@ pub struct Config {
     pub cache: CacheConfig,
     #[serde(default)]
     pub api: ApiConfig,
+    #[serde(default)]
+    pub mdns: MdnsConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub browser: BrowserConfig,
+    #[serde(default)]
+    pub reflector: ReflectorConfig,
+    /// Services announced alongside the authority
+    #[serde(default)]
+    pub advertise: Vec<AdvertiseConfig>,
+    #[serde(default)]
+    pub import: ImportConfig,
+    #[serde(default)]
+    pub addresses: AddressConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub liveness: LivenessConfig,
+    #[serde(default)]
+    pub coap: CoapConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Where the values above came from, filled in by `load`
+    #[serde(skip)]
+    pub source: ConfigSource,
+    /// The file as parsed, which a reload is compared against
+    #[serde(skip)]
+    pub table: toml::Table,
+}
+
+/// Values given on the command line, which win over the config file's
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub log_level: Option<String>,
+    pub db_path: Option<PathBuf>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(level) = &self.log_level {
+            config.logging.level = Some(level.clone());
+        }
+        if let Some(path) = &self.db_path {
+            config.cache.db_path = path.clone();
+        }
+    }
+}
+
+/// Keys a reload applies, each covering the keys nested under it. Changing
+/// any other key needs a restart.
+pub const RELOADABLE_KEYS: &[&str] = &[
+    "cache.stale_after_secs",
+    "cache.expire_by_ttl",
+    "cache.prune_after_secs",
+    "cache.tombstone_secs",
+    "cache.maintenance_interval_secs",
+    "cache.removal_grace_ms",
+    "cache.upsert_batch_ms",
+    "cache.history_retention_secs",
+    "cache.retention_ttl_factor",
+    "cache.retention",
+    "browser.include_types",
+    "browser.exclude_types",
+    "logging.level",
+];
+
+/// Provenance of the loaded configuration
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ConfigSource {
+    /// Resolved path of the config file
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    /// Dotted keys set explicitly in the file, e.g. `cache.db_path`.
+    /// Every other field took its default.
+    pub explicit_keys: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -23,25 +111,470 @@ pub struct AuthorityConfig {
 pub struct CacheConfig {
     #[serde(default = "default_db_path")]
     pub db_path: PathBuf,
+    /// SQLCipher key to encrypt the database with. An unencrypted database
+    /// is encrypted when next opened; there's no way back but an export.
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    /// File holding the key instead, so it can be kept out of the config
+    #[serde(default)]
+    pub encryption_key_file: Option<PathBuf>,
+    /// Where `POST /v1/admin/backup` writes database copies
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: PathBuf,
     #[serde(default = "default_stale_after")]
     pub stale_after_secs: u64,
+    /// Mark each mDNS service stale when its own announced TTL runs out,
+    /// instead of after `stale_after_secs`
+    #[serde(default)]
+    pub expire_by_ttl: bool,
     #[serde(default = "default_prune_after")]
     pub prune_after_secs: u64,
+    /// Keep dead mDNS services this long after they die, whatever
+    /// `prune_after_secs` is (0 = prune them with the rest)
+    #[serde(default)]
+    pub tombstone_secs: u64,
     /// Fix #6: separate maintenance interval from browse interval
     #[serde(default = "default_maintenance_interval")]
     pub maintenance_interval_secs: u64,
+    /// Hold mDNS removals this long before marking dead, so a quick re-add
+    /// cancels them (0 = mark dead immediately)
+    #[serde(default)]
+    pub removal_grace_ms: u64,
+    /// Buffer resolved services this long and write them in one transaction,
+    /// publishing one change, so a wake-up storm isn't a write per record
+    /// (0 = write each as it arrives)
+    #[serde(default = "default_upsert_batch")]
+    pub upsert_batch_ms: u64,
+    /// Service fields that contribute to the cache hash. `instance_name` is always included.
+    #[serde(default)]
+    pub hash_fields: HashFields,
+    /// Serve the cache from memory after this many consecutive database write
+    /// failures (0 = never, keep reporting errors)
+    #[serde(default = "default_degrade_after_failures")]
+    pub degrade_after_failures: u32,
+    /// While serving from memory, how often to try writing back to the database
+    #[serde(default = "default_db_retry")]
+    pub db_retry_secs: u64,
+    /// Read-only connections answering queries beside the cache thread, so
+    /// listings don't wait behind writes (0 = the thread answers everything)
+    #[serde(default = "default_read_connections")]
+    pub read_connections: usize,
+    /// What to do when an instance shows up under a different service type
+    #[serde(default)]
+    pub type_conflict: TypeConflictPolicy,
+    /// Publish hash/snapshot changes at most once per this interval; changes
+    /// in between are coalesced (0 = publish every change immediately)
+    #[serde(default)]
+    pub hash_notify_interval_ms: u64,
+    /// Publish hash/snapshot changes only once changes have stopped for
+    /// this long, so a burst is hashed once (0 = don't wait)
+    #[serde(default)]
+    pub hash_quiet_ms: u64,
+    /// After maintenance, checkpoint and truncate the WAL once it's larger
+    /// than this (0 = never)
+    #[serde(default = "default_compact_wal_bytes")]
+    pub compact_wal_bytes: u64,
+    /// After maintenance, release free pages once they're at least this
+    /// percent of the database file (0 = never)
+    #[serde(default = "default_vacuum_free_percent")]
+    pub vacuum_free_percent: u8,
+    /// How long to keep each service's history of state transitions
+    /// (0 = record no history)
+    #[serde(default = "default_history_retention")]
+    pub history_retention_secs: u64,
+    /// Keep history and the change journal at least this many times the
+    /// longest cached TTL. `history_retention_secs` and `prune_after_secs`
+    /// still apply when they're longer (0 = no minimum)
+    #[serde(default = "default_retention_ttl_factor")]
+    pub retention_ttl_factor: u64,
+    /// Staleness and pruning for particular service types, overriding
+    /// `stale_after_secs` and `prune_after_secs`
+    #[serde(default)]
+    pub retention: HashMap<String, RetentionOverride>,
+}
+
+/// `[cache.retention]` entry for one service type, e.g.
+/// `"_ipp._tcp" = { stale_after_secs = 7200 }`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionOverride {
+    pub stale_after_secs: Option<u64>,
+    pub prune_after_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ApiConfig {
     #[serde(default = "default_listen")]
     pub listen: String,
+    /// Accept manually registered services via `POST /v1/services`
+    #[serde(default)]
+    pub allow_registration: bool,
+    /// Reject registered addresses outside `authority.prefix`
+    #[serde(default = "default_strict_prefix")]
+    pub strict_prefix: bool,
+    /// Serve repeated identical `/v1/services` queries from memory for this
+    /// long, unless the cache changes first (0 = disabled)
+    #[serde(default)]
+    pub response_cache_ms: u64,
+    /// Send each SSE subscriber at most one update per this interval.
+    /// Updates in between are collapsed into the latest state.
+    #[serde(default = "default_sse_min_interval")]
+    pub sse_min_interval_ms: u64,
+    /// Gzip or brotli-compress responses for clients that accept it.
+    /// Event streams and small bodies are never compressed.
+    #[serde(default)]
+    pub compression: bool,
+    /// Serve the gRPC `Authority` service on the API listener too
+    #[serde(default)]
+    pub grpc: bool,
+    /// Bearer tokens required by the API (`[api.auth]`)
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// PEM certificate chain; serve the API over TLS when set with `tls_key`
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `tls_cert`
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificates; when set, TLS clients must present a certificate
+    /// signed by one of them
+    #[serde(default)]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+/// Files `ApiConfig::tls_files` found configured
+#[derive(Debug)]
+pub struct TlsFiles<'a> {
+    pub cert: &'a Path,
+    pub key: &'a Path,
+    pub client_ca: Option<&'a Path>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MdnsConfig {
+    /// Lowercase TXT keys before storage (keys are case-insensitive per RFC 6763)
+    #[serde(default)]
+    pub lowercase_txt_keys: bool,
+    /// How long after browsing starts the first discovery cycle is considered complete
+    #[serde(default = "default_warmup_secs")]
+    pub warmup_secs: u64,
+    /// Re-check which interfaces hold in-prefix addresses this often and
+    /// enable/disable mDNS on them accordingly (0 = disabled)
+    #[serde(default)]
+    pub interface_rescan_secs: u64,
+    /// Also re-check when netlink reports a link going up or down or an
+    /// address change, and leave out interfaces whose link is down (Linux)
+    #[serde(default)]
+    pub watch_interfaces: bool,
+    /// How many recently rejected services to keep for `GET /v1/rejected`
+    #[serde(default = "default_rejected_ring_size")]
+    pub rejected_ring_size: usize,
+    /// Before maintenance marks services stale, re-browse their types to
+    /// prompt quiet responders to answer
+    #[serde(default)]
+    pub probe_before_stale: bool,
+    /// Advertise services registered through the API on mDNS, for hosts that
+    /// can't multicast themselves
+    #[serde(default)]
+    pub proxy_registered: bool,
+    /// Query each service directly as it nears staleness, one maintenance
+    /// cycle apart, and mark it dead after this many go unanswered (0 = off)
+    #[serde(default)]
+    pub confirm_attempts: u32,
+    /// How long to wait for each confirmation
+    #[serde(default = "default_confirm_timeout")]
+    pub confirm_timeout_ms: u64,
+    /// Reject services whose full instance name is longer than this many bytes
+    #[serde(default = "default_max_instance_name_len")]
+    pub max_instance_name_len: usize,
+    /// Cache services left with no usable address instead of dropping them,
+    /// so operators can see what is advertising without being reachable
+    #[serde(default)]
+    pub keep_addressless: bool,
+    /// Record services' IPv4 addresses too, so v4-only devices are cached
+    #[serde(default)]
+    pub ipv4: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddressConfig {
+    /// Address classes from most to least preferred, for picking a single
+    /// address for a service. Classes left out rank after all listed ones.
+    #[serde(default = "default_preference")]
+    pub preference: Vec<AddressClass>,
+    /// Store only the IPv6 addresses inside `authority.prefix`, so global
+    /// and VPN addresses stay out of the cache
+    #[serde(default)]
+    pub in_prefix_only: bool,
+    /// Store no link-local (fe80::/10) addresses
+    #[serde(default)]
+    pub exclude_link_local: bool,
+}
+
+impl Default for AddressConfig {
+    fn default() -> Self {
+        Self {
+            preference: default_preference(),
+            in_prefix_only: false,
+            exclude_link_local: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// Export a `subnet_authority_service_up` series per cached service
+    #[serde(default)]
+    pub per_service: bool,
+    /// Most per-service series exported; services beyond this are left out
+    #[serde(default = "default_max_series")]
+    pub max_series: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            per_service: false,
+            max_series: default_max_series(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LivenessConfig {
+    /// How often to try a TCP connect to each alive `_tcp` service (0 = never)
+    #[serde(default)]
+    pub interval_secs: u64,
+    /// How long each connect may take before the service counts as unreachable
+    #[serde(default = "default_liveness_timeout")]
+    pub timeout_ms: u64,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 0,
+            timeout_ms: default_liveness_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CoapConfig {
+    /// Serve the resource directory over CoAP
+    #[serde(default)]
+    pub enabled: bool,
+    /// UDP address for CoAP
+    #[serde(default = "default_coap_listen")]
+    pub listen: String,
+}
+
+impl Default for CoapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: default_coap_listen(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsConfig {
+    /// Serve `authority.zone` over DNS
+    #[serde(default)]
+    pub enabled: bool,
+    /// Address for DNS, over both UDP and TCP
+    #[serde(default = "default_dns_listen")]
+    pub listen: String,
+    /// TTL of every record served, also the negative-caching TTL. Kept short
+    /// since services come and go with mDNS.
+    #[serde(default = "default_dns_ttl")]
+    pub ttl: u32,
+    /// Also serve the `ip6.arpa` zone of `authority.prefix`, so addresses
+    /// resolve back to host names
+    #[serde(default = "default_dns_reverse")]
+    pub reverse: bool,
+    /// TSIG keys secondaries may sign AXFR/IXFR requests with. Empty
+    /// refuses every transfer.
+    #[serde(default)]
+    pub transfer_keys: Vec<TsigKey>,
+    /// TSIG keys hosts may sign DNS UPDATEs with, registering and removing
+    /// services. Empty refuses every update.
+    #[serde(default)]
+    pub update_keys: Vec<TsigKey>,
+    /// Sign the zones with DNSSEC
+    #[serde(default)]
+    pub dnssec: DnssecConfig,
+    /// Also serve DNS over TLS here, e.g. "[::]:853", with the certificate
+    /// from `api.tls_cert` and `api.tls_key`
+    #[serde(default)]
+    pub tls_listen: Option<String>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: default_dns_listen(),
+            ttl: default_dns_ttl(),
+            reverse: default_dns_reverse(),
+            transfer_keys: Vec::new(),
+            update_keys: Vec::new(),
+            tls_listen: None,
+            dnssec: DnssecConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnssecConfig {
+    /// PKCS#8 private key (PEM or DER) the zones are signed with. Unset
+    /// leaves them unsigned.
+    pub key: Option<PathBuf>,
+    /// `ECDSAP256SHA256`, `ECDSAP384SHA384`, or `ED25519`
+    #[serde(default = "default_dnssec_algorithm")]
+    pub algorithm: String,
+    /// How long each signature is valid. Zones are re-signed halfway through.
+    #[serde(default = "default_signature_validity")]
+    pub signature_validity_secs: u64,
+    /// Extra NSEC3 hash iterations; RFC 9276 recommends none
+    #[serde(default)]
+    pub nsec3_iterations: u16,
+    /// NSEC3 salt in hex; RFC 9276 recommends none
+    #[serde(default)]
+    pub nsec3_salt: String,
+}
+
+impl Default for DnssecConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            algorithm: default_dnssec_algorithm(),
+            signature_validity_secs: default_signature_validity(),
+            nsec3_iterations: 0,
+            nsec3_salt: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Protocols services are discovered by
+    #[serde(default = "default_discovery_sources")]
+    pub sources: Vec<SourceKind>,
+}
+
+fn default_discovery_sources() -> Vec<SourceKind> {
+    vec![SourceKind::Mdns]
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self { sources: default_discovery_sources() }
+    }
+}
+
+/// Which service types the browser follows once the meta-query finds them
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BrowserConfig {
+    /// Glob patterns (`_ipp*._tcp`) of the only types to browse; empty
+    /// browses every type
+    #[serde(default)]
+    pub include_types: Vec<String>,
+    /// Glob patterns of types never browsed, even if included
+    #[serde(default)]
+    pub exclude_types: Vec<String>,
+}
+
+/// One `[[advertise]]` entry
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdvertiseConfig {
+    /// e.g. "_dns._udp"; ".local." is implied
+    pub service_type: String,
+    /// Instance label; defaults to the host name
+    pub instance: Option<String>,
+    pub port: AdvertisePort,
+    #[serde(default)]
+    pub txt: HashMap<String, String>,
+}
+
+/// A port number, or the port one of the daemon's listeners bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum AdvertisePort {
+    Number(u16),
+    Listener(Listener),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Listener {
+    Api,
+    Dns,
+    Coap,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LoggingConfig {
+    /// Log filter in `RUST_LOG` syntax, e.g. "subnet_authorityd=debug".
+    /// Unset, `RUST_LOG` applies, or failing that "subnet_authorityd=info".
+    #[serde(default)]
+    pub level: Option<String>,
+}
+
+impl LoggingConfig {
+    pub fn filter(&self) -> Result<tracing_subscriber::EnvFilter> {
+        match &self.level {
+            Some(level) => tracing_subscriber::EnvFilter::try_new(level)
+                .with_context(|| format!("Invalid logging.level: {}", level)),
+            None => Ok(tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("subnet_authorityd=info"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ReflectorConfig {
+    /// Interfaces to reflect mDNS between; empty disables the reflector
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportConfig {
+    /// Directory of Avahi `.service` files loaded as static services at startup
+    pub avahi_dir: Option<PathBuf>,
+    /// Directory of `*.toml` service definitions, loaded at startup and on
+    /// SIGHUP; need not exist
+    #[serde(default = "default_services_dir")]
+    pub services_dir: PathBuf,
+}
+
+fn default_services_dir() -> PathBuf {
+    PathBuf::from("/etc/subnet-authority/services.d")
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        Self {
+            avahi_dir: None,
+            services_dir: default_services_dir(),
+        }
+    }
+}
+
+fn default_max_series() -> usize {
+    1000
 }
 
 fn default_db_path() -> PathBuf {
     PathBuf::from("/var/lib/subnet-authority/services.db")
 }
 
+fn default_backup_dir() -> PathBuf {
+    PathBuf::from("/var/lib/subnet-authority/backups")
+}
+
 fn default_stale_after() -> u64 {
     300
 }
@@ -50,21 +583,124 @@ fn default_prune_after() -> u64 {
     3600
 }
 
+fn default_compact_wal_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_vacuum_free_percent() -> u8 {
+    20
+}
+
+fn default_history_retention() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_retention_ttl_factor() -> u64 {
+    2
+}
+
 fn default_maintenance_interval() -> u64 {
     60
 }
 
+fn default_degrade_after_failures() -> u32 {
+    3
+}
+
+fn default_db_retry() -> u64 {
+    30
+}
+
+fn default_upsert_batch() -> u64 {
+    100
+}
+
+fn default_read_connections() -> usize {
+    4
+}
+
 fn default_listen() -> String {
     "[::]:8053".to_string()
 }
 
+fn default_coap_listen() -> String {
+    "[::]:5683".to_string()
+}
+
+fn default_dns_listen() -> String {
+    "[::]:53".to_string()
+}
+
+fn default_dns_ttl() -> u32 {
+    60
+}
+
+fn default_dns_reverse() -> bool {
+    true
+}
+
+fn default_dnssec_algorithm() -> String {
+    "ECDSAP256SHA256".to_string()
+}
+
+fn default_signature_validity() -> u64 {
+    14 * 86400
+}
+
+fn default_warmup_secs() -> u64 {
+    5
+}
+
+fn default_rejected_ring_size() -> usize {
+    64
+}
+
+fn default_liveness_timeout() -> u64 {
+    1000
+}
+
+fn default_confirm_timeout() -> u64 {
+    2000
+}
+
+fn default_max_instance_name_len() -> usize {
+    255
+}
+
+fn default_strict_prefix() -> bool {
+    true
+}
+
+fn default_sse_min_interval() -> u64 {
+    1000
+}
+
 impl Default for CacheConfig {
     fn default() -> Self {
         Self {
             db_path: default_db_path(),
+            encryption_key: None,
+            encryption_key_file: None,
+            backup_dir: default_backup_dir(),
             stale_after_secs: default_stale_after(),
+            expire_by_ttl: false,
             prune_after_secs: default_prune_after(),
+            tombstone_secs: 0,
             maintenance_interval_secs: default_maintenance_interval(),
+            removal_grace_ms: 0,
+            upsert_batch_ms: default_upsert_batch(),
+            hash_fields: HashFields::default(),
+            degrade_after_failures: default_degrade_after_failures(),
+            db_retry_secs: default_db_retry(),
+            read_connections: default_read_connections(),
+            type_conflict: TypeConflictPolicy::default(),
+            hash_notify_interval_ms: 0,
+            hash_quiet_ms: 0,
+            compact_wal_bytes: default_compact_wal_bytes(),
+            vacuum_free_percent: default_vacuum_free_percent(),
+            history_retention_secs: default_history_retention(),
+            retention_ttl_factor: default_retention_ttl_factor(),
+            retention: HashMap::new(),
         }
     }
 }
@@ -73,6 +709,169 @@ impl Default for ApiConfig {
     fn default() -> Self {
         Self {
             listen: default_listen(),
+            allow_registration: false,
+            strict_prefix: default_strict_prefix(),
+            response_cache_ms: 0,
+            sse_min_interval_ms: default_sse_min_interval(),
+            compression: false,
+            grpc: false,
+            auth: AuthConfig::default(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+        }
+    }
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            lowercase_txt_keys: false,
+            warmup_secs: default_warmup_secs(),
+            interface_rescan_secs: 0,
+            watch_interfaces: false,
+            rejected_ring_size: default_rejected_ring_size(),
+            probe_before_stale: false,
+            proxy_registered: false,
+            confirm_attempts: 0,
+            confirm_timeout_ms: default_confirm_timeout(),
+            max_instance_name_len: default_max_instance_name_len(),
+            keep_addressless: false,
+            ipv4: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Seconds service history is kept, or None if it isn't recorded
+    pub fn history_retention(&self) -> Option<u64> {
+        (self.history_retention_secs > 0).then_some(self.history_retention_secs)
+    }
+
+    /// The database encryption key, if one is configured, read from
+    /// `encryption_key_file` if set there. Setting both is an error, as is
+    /// setting either in a build without the `sqlcipher` feature.
+    pub fn encryption_key(&self) -> Result<Option<String>> {
+        if !cfg!(feature = "sqlcipher") {
+            for (key, set) in [
+                ("cache.encryption_key", self.encryption_key.is_some()),
+                ("cache.encryption_key_file", self.encryption_key_file.is_some()),
+            ] {
+                anyhow::ensure!(!set, "{} needs subnet-authorityd built with the `sqlcipher` feature", key);
+            }
+        }
+        match (&self.encryption_key, &self.encryption_key_file) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("cache.encryption_key and cache.encryption_key_file are mutually exclusive")
+            }
+            (Some(key), None) => Ok(Some(key.clone())),
+            (None, Some(path)) => {
+                let key = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read key file: {}", path.display()))?;
+                let key = key.trim_end_matches(['\r', '\n']);
+                if key.is_empty() {
+                    anyhow::bail!("Key file {} is empty", path.display());
+                }
+                Ok(Some(key.to_string()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Fails if a retention is too long to work out a cutoff for
+    pub fn check_retention(&self) -> Result<()> {
+        for (key, secs) in [
+            ("cache.history_retention_secs", self.history_retention_secs),
+            ("cache.prune_after_secs", self.prune_after_secs),
+        ] {
+            anyhow::ensure!(secs <= MAX_RETENTION_SECS, "{} = {} is more than the maximum {}", key, secs, MAX_RETENTION_SECS);
+        }
+        Ok(())
+    }
+
+    /// Seconds dead services are kept, or None if they're pruned with the rest
+    pub fn tombstone_retention(&self) -> Option<u64> {
+        (self.tombstone_secs > 0).then_some(self.tombstone_secs)
+    }
+
+    /// The `[cache.retention]` overrides, keyed by service type as cached, so
+    /// `_ipp._tcp.local.` configures `_ipp._tcp`
+    pub fn type_retention(&self) -> TypeRetention {
+        let mut retention = TypeRetention::default();
+        for (service_type, entry) in &self.retention {
+            let service_type = service_type.trim_end_matches('.');
+            let service_type = service_type.strip_suffix(".local").unwrap_or(service_type).to_string();
+            if let Some(secs) = entry.stale_after_secs {
+                retention.stale_after_secs.insert(service_type.clone(), secs);
+            }
+            if let Some(secs) = entry.prune_after_secs {
+                retention.prune_after_secs.insert(service_type, secs);
+            }
+        }
+        retention
+    }
+}
+
+impl AuthorityConfig {
+    /// Parse the configured ULA prefix, e.g. "fd00:1234:5678:1::/64"
+    pub fn prefix_net(&self) -> Result<Ipv6Net> {
+        self.prefix
+            .parse::<Ipv6Net>()
+            .with_context(|| format!("Invalid prefix: {}", self.prefix))
+    }
+
+    /// Problems with the values that would otherwise only surface once the
+    /// daemon is running, each naming the key and what it should hold
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let prefix = self.prefix.parse::<Ipv6Net>().ok();
+        if prefix.is_none() {
+            problems.push(format!(
+                "authority.prefix = {:?} is not an IPv6 prefix; write it as address/length, e.g. \"fd00:1234:5678:1::/64\"",
+                self.prefix
+            ));
+        }
+        let address = self
+            .address
+            .parse::<Ipv6Net>()
+            .map(|net| net.addr())
+            .or_else(|_| self.address.parse::<Ipv6Addr>());
+        match (address, prefix) {
+            (Err(_), _) => problems.push(format!(
+                "authority.address = {:?} is not an IPv6 address, e.g. \"fd00:1234:5678:1::1/64\"",
+                self.address
+            )),
+            (Ok(address), Some(prefix)) if !prefix.contains(&address) => problems.push(format!(
+                "authority.address {} is outside authority.prefix {}; the daemon would advertise an address off the subnet",
+                address, prefix
+            )),
+            _ => {}
+        }
+        let zone = self.zone.trim_end_matches('.');
+        if zone.is_empty() {
+            problems.push("authority.zone is empty; set the DNS zone the subnet's names go under, e.g. \"subnet.example\"".to_string());
+        } else if let Err(e) = hickory_proto::rr::Name::from_utf8(zone) {
+            problems.push(format!("authority.zone = {:?} is not a DNS name: {}", self.zone, e));
+        }
+        problems
+    }
+}
+
+impl ApiConfig {
+    /// Certificate and key paths if TLS is configured; setting only one of
+    /// them, or a client CA without them, is an error
+    pub fn tls_files(&self) -> Result<Option<TlsFiles<'_>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsFiles {
+                cert,
+                key,
+                client_ca: self.tls_client_ca.as_deref(),
+            })),
+            (None, None) if self.tls_client_ca.is_some() => {
+                anyhow::bail!("api.tls_client_ca requires api.tls_cert and api.tls_key")
+            }
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("api.tls_cert and api.tls_key must be set together"),
         }
     }
 }
@@ -84,9 +883,258 @@ impl Config {
         let contents = std::fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
 
-        let config: Config = toml::from_str(&contents)
+        let mut config: Config = toml::from_str(&contents)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
 
+        let table: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        let mut explicit_keys = Vec::new();
+        collect_keys(&table, "", &mut explicit_keys);
+
+        config.source = ConfigSource {
+            path: std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            explicit_keys,
+        };
+        config.table = table;
+
         Ok(config)
     }
 }
+
+impl Config {
+    /// Names of the optional behaviours switched on, for the startup summary
+    pub fn enabled_features(&self) -> Vec<&'static str> {
+        [
+            ("registration", self.api.allow_registration),
+            ("response_cache", self.api.response_cache_ms > 0),
+            ("compression", self.api.compression),
+            ("grpc", self.api.grpc),
+            ("coap", self.coap.enabled),
+            ("dns", self.dns.enabled),
+            ("dns_reverse", self.dns.enabled && self.dns.reverse),
+            ("zone_transfer", self.dns.enabled && !self.dns.transfer_keys.is_empty()),
+            ("dns_update", self.dns.enabled && !self.dns.update_keys.is_empty()),
+            ("dns_tls", self.dns.enabled && self.dns.tls_listen.is_some()),
+            ("dnssec", self.dns.enabled && self.dns.dnssec.key.is_some()),
+            ("api_auth", self.api.auth.enabled()),
+            ("tls", self.api.tls_cert.is_some()),
+            ("mutual_tls", self.api.tls_client_ca.is_some()),
+            ("expire_by_ttl", self.cache.expire_by_ttl),
+            ("removal_grace", self.cache.removal_grace_ms > 0),
+            ("memory_fallback", self.cache.degrade_after_failures > 0),
+            ("history", self.cache.history_retention_secs > 0),
+            ("type_retention", !self.cache.retention.is_empty()),
+            ("tombstones", self.cache.tombstone_secs > 0),
+            ("encrypted_db", self.cache.encryption_key.is_some() || self.cache.encryption_key_file.is_some()),
+            ("lowercase_txt_keys", self.mdns.lowercase_txt_keys),
+            ("interface_rescan", self.mdns.interface_rescan_secs > 0),
+            ("watch_interfaces", self.mdns.watch_interfaces),
+            ("probe_before_stale", self.mdns.probe_before_stale),
+            ("confirm", self.mdns.confirm_attempts > 0),
+            ("proxy_registered", self.api.allow_registration && self.mdns.proxy_registered),
+            ("keep_addressless", self.mdns.keep_addressless),
+            ("ipv4", self.mdns.ipv4),
+            ("in_prefix_only", self.addresses.in_prefix_only),
+            ("include_types", !self.browser.include_types.is_empty()),
+            ("exclude_types", !self.browser.exclude_types.is_empty()),
+            ("reflector", !self.reflector.interfaces.is_empty()),
+            ("exclude_link_local", self.addresses.exclude_link_local),
+            ("avahi_import", self.import.avahi_dir.is_some()),
+            ("per_service_metrics", self.metrics.per_service),
+            ("liveness", self.liveness.interval_secs > 0),
+            ("advertise", !self.advertise.is_empty()),
+            ("log_level", self.logging.level.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+    }
+
+    /// Dotted keys whose values differ in `new`, split into those a reload
+    /// applies and those that need a restart
+    pub fn changed_keys(&self, new: &Config) -> (Vec<String>, Vec<String>) {
+        let (mut old_values, mut new_values) = (BTreeMap::new(), BTreeMap::new());
+        collect_values(&self.table, "", &mut old_values);
+        collect_values(&new.table, "", &mut new_values);
+        let mut changed: Vec<String> = old_values
+            .iter()
+            .filter(|(key, value)| new_values.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.extend(new_values.keys().filter(|key| !old_values.contains_key(*key)).cloned());
+        changed.sort();
+        changed.into_iter().partition(|key| {
+            RELOADABLE_KEYS
+                .iter()
+                .any(|hot| key == hot || key.strip_prefix(hot).is_some_and(|rest| rest.starts_with('.')))
+        })
+    }
+}
+
+/// The value at the dotted path of every non-table value in `table`
+fn collect_values<'a>(table: &'a toml::Table, prefix: &str, values: &mut BTreeMap<String, &'a toml::Value>) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(nested) => collect_values(nested, &path, values),
+            _ => {
+                values.insert(path, value);
+            }
+        }
+    }
+}
+
+/// Append the dotted path of every non-table value in `table`
+fn collect_keys(table: &toml::Table, prefix: &str, keys: &mut Vec<String>) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(nested) => collect_keys(nested, &path, keys),
+            _ => keys.push(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_keys_lists_explicit_values() {
+        let table: toml::Table = toml::from_str(
+            r#"
+            [authority]
+            zone = "subnet.example"
+
+            [cache]
+            hash_fields = ["port", "txt"]
+            "#,
+        )
+        .unwrap();
+
+        let mut keys = Vec::new();
+        collect_keys(&table, "", &mut keys);
+        keys.sort();
+        assert_eq!(keys, vec!["authority.zone", "cache.hash_fields"]);
+    }
+
+    #[test]
+    fn test_changed_keys_split_by_reloadability() {
+        let load = |extra: &str| {
+            let contents = format!(
+                "[authority]\ninterface = \"eth0\"\nprefix = \"fd00::/64\"\naddress = \"fd00::1\"\nzone = \"subnet.example\"\n{}",
+                extra
+            );
+            let mut config: Config = toml::from_str(&contents).unwrap();
+            config.table = toml::from_str(&contents).unwrap();
+            config
+        };
+        let old = load("[cache]\nstale_after_secs = 60\n");
+
+        let (hot, restart) = old.changed_keys(&load(
+            "[cache]\nstale_after_secs = 120\nretention = { \"_ipp._tcp\" = { prune_after_secs = 10 } }\n[logging]\nlevel = \"debug\"\n",
+        ));
+        assert_eq!(hot, vec!["cache.retention._ipp._tcp.prune_after_secs", "cache.stale_after_secs", "logging.level"]);
+        assert!(restart.is_empty());
+
+        let (hot, restart) = old.changed_keys(&load("[cache]\nstale_after_secs = 60\ndb_path = \"/tmp/x.db\"\n"));
+        assert!(hot.is_empty());
+        assert_eq!(restart, vec!["cache.db_path"]);
+        assert_eq!(old.changed_keys(&load("[cache]\nstale_after_secs = 60\n")), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_authority_problems() {
+        let authority = |prefix: &str, address: &str, zone: &str| AuthorityConfig {
+            interface: "eth0".to_string(),
+            prefix: prefix.to_string(),
+            address: address.to_string(),
+            zone: zone.to_string(),
+        };
+        assert!(authority("fd00:1::/64", "fd00:1::1/64", "subnet.example").problems().is_empty());
+        assert!(authority("fd00:1::/64", "fd00:1::1", "subnet.example.").problems().is_empty());
+
+        let problems = authority("fd00:1::", "fd00:2::1/64", "").problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("authority.prefix"));
+        assert!(problems[1].starts_with("authority.zone"));
+
+        let problems = authority("fd00:1::/64", "fd00:2::1/64", "bad zone!").problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("outside authority.prefix"));
+        assert!(problems[1].starts_with("authority.zone"));
+        assert!(authority("fd00:1::/64", "10.0.0.1", "subnet.example").problems()[0].starts_with("authority.address"));
+    }
+
+    #[test]
+    fn test_encryption_key_needs_sqlcipher() {
+        assert_eq!(CacheConfig::default().encryption_key().unwrap(), None);
+        let keyed = CacheConfig { encryption_key: Some("secret".to_string()), ..Default::default() };
+        let from_file = CacheConfig { encryption_key_file: Some("/nonexistent/key".into()), ..Default::default() };
+        if cfg!(feature = "sqlcipher") {
+            assert_eq!(keyed.encryption_key().unwrap().as_deref(), Some("secret"));
+        } else {
+            let err = keyed.encryption_key().unwrap_err().to_string();
+            assert!(err.starts_with("cache.encryption_key needs") && err.contains("`sqlcipher`"), "{}", err);
+            let err = from_file.encryption_key().unwrap_err().to_string();
+            assert!(err.starts_with("cache.encryption_key_file needs"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_advertise_ports() {
+        let config: Config = toml::from_str(
+            r#"
+            [authority]
+            interface = "eth0"
+            prefix = "fd00:1234:5678:1::/64"
+            address = "fd00:1234:5678:1::1"
+            zone = "subnet.example"
+
+            [[advertise]]
+            service_type = "_dns._udp"
+            port = "dns"
+
+            [[advertise]]
+            service_type = "_http._tcp"
+            instance = "Status page"
+            port = 8080
+            txt = { path = "/status" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.advertise[0].port, AdvertisePort::Listener(Listener::Dns));
+        assert_eq!(config.advertise[1].port, AdvertisePort::Number(8080));
+        assert_eq!(config.advertise[1].txt["path"], "/status");
+        assert!(toml::from_str::<AdvertiseConfig>("service_type = \"_x._tcp\"\nport = \"ftp\"").is_err());
+    }
+
+    #[test]
+    fn test_tls_files_must_be_paired() {
+        let mut api = ApiConfig::default();
+        assert!(api.tls_files().unwrap().is_none());
+        api.tls_client_ca = Some(PathBuf::from("/etc/subnet-authority/clients.pem"));
+        assert!(api.tls_files().is_err());
+        api.tls_cert = Some(PathBuf::from("/etc/subnet-authority/api.crt"));
+        assert!(api.tls_files().is_err());
+        api.tls_key = Some(PathBuf::from("/etc/subnet-authority/api.key"));
+        let files = api.tls_files().unwrap().unwrap();
+        assert_eq!(files.client_ca, Some(Path::new("/etc/subnet-authority/clients.pem")));
+    }
+
+    #[test]
+    fn test_retention_limits() {
+        let mut cache = CacheConfig::default();
+        assert!(cache.check_retention().is_ok());
+        cache.history_retention_secs = MAX_RETENTION_SECS + 1;
+        assert!(cache.check_retention().unwrap_err().to_string().contains("cache.history_retention_secs"));
+    }
+}
@@ -0,0 +1,71 @@
+//! Where services are discovered. Each source is a stream of
+//! `BrowserEvent`s, and the sources enabled in `[discovery]` are merged into
+//! the one channel the cache manager reads, so a new protocol only has to
+//! implement `DiscoverySource` and be listed here.
+
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use crate::mdns::browser::BrowserEvent;
+
+/// A discovery protocol, as named in `[discovery] sources`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Mdns,
+}
+
+/// Something that finds services and reports them as they come and go
+pub trait DiscoverySource: Send {
+    fn kind(&self) -> SourceKind;
+
+    /// Events until `cancel` fires, after which the stream ends
+    fn events(self: Box<Self>, cancel: CancellationToken) -> BoxStream<'static, BrowserEvent>;
+}
+
+/// Forward the events of every source to `tx` until all of them end
+pub async fn run(sources: Vec<Box<dyn DiscoverySource>>, tx: mpsc::Sender<BrowserEvent>, cancel: CancellationToken) {
+    let streams = sources.into_iter().map(|source| {
+        tracing::info!("Discovering services by {:?}", source.kind());
+        source.events(cancel.clone())
+    });
+    let mut events = stream::select_all(streams);
+    while let Some(event) = events.next().await {
+        if tx.send(event).await.is_err() {
+            tracing::error!("Cache manager stopped taking discovery events");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(Vec<&'static str>);
+
+    impl DiscoverySource for Fixed {
+        fn kind(&self) -> SourceKind {
+            SourceKind::Mdns
+        }
+
+        fn events(self: Box<Self>, _cancel: CancellationToken) -> BoxStream<'static, BrowserEvent> {
+            stream::iter(self.0.into_iter().map(|name| BrowserEvent::Removed(name.to_string()))).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sources_merged() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let sources: Vec<Box<dyn DiscoverySource>> = vec![Box::new(Fixed(vec!["a", "b"])), Box::new(Fixed(vec!["c"]))];
+        run(sources, tx, CancellationToken::new()).await;
+
+        let mut names = Vec::new();
+        while let Some(BrowserEvent::Removed(name)) = rx.recv().await {
+            names.push(name);
+        }
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,385 @@
+//! Online DNSSEC signing (RFC 4034, 4035) of the synthesized zones with one
+//! combined signing key from the config. Denial of existence uses NSEC3
+//! (RFC 5155), rebuilt with every version of the zone, so the names served
+//! can't be walked from the chain.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use data_encoding::BASE32HEX_NOPAD;
+use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, NSEC3, NSEC3PARAM, RRSIG};
+use hickory_proto::rr::dnssec::{tbs, Algorithm, DigestType, KeyFormat, Nsec3HashAlgorithm, SigSigner};
+use hickory_server::proto::rr::{DNSClass, LowerName, Name, RData, Record, RecordType};
+use crate::config::DnssecConfig;
+
+/// Signatures start this far in the past, for validators with slow clocks
+const INCEPTION_SKEW: Duration = Duration::from_secs(3600);
+
+/// Algorithms ring can sign with
+const ALGORITHMS: [Algorithm; 3] = [Algorithm::ECDSAP256SHA256, Algorithm::ECDSAP384SHA384, Algorithm::ED25519];
+
+/// The signing key and NSEC3 parameters from `[dns.dnssec]`, for any zone
+pub struct DnssecKey {
+    algorithm: Algorithm,
+    pkcs8: Vec<u8>,
+    validity: Duration,
+    iterations: u16,
+    salt: Vec<u8>,
+}
+
+impl DnssecKey {
+    /// The configured key, or None if `dns.dnssec.key` isn't set
+    pub fn load(config: &DnssecConfig) -> Result<Option<Self>> {
+        let Some(path) = &config.key else {
+            return Ok(None);
+        };
+        let algorithm = ALGORITHMS
+            .into_iter()
+            .find(|a| a.as_str().eq_ignore_ascii_case(&config.algorithm))
+            .with_context(|| format!("Unsupported DNSSEC algorithm: {}", config.algorithm))?;
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read DNSSEC key: {}", path.display()))?;
+        // PEM as openssl writes it, or DER
+        let pkcs8 = match rustls_pemfile::private_key(&mut bytes.as_slice()) {
+            Ok(Some(tokio_rustls::rustls::pki_types::PrivateKeyDer::Pkcs8(key))) => key.secret_pkcs8_der().to_vec(),
+            Ok(Some(_)) => anyhow::bail!("DNSSEC key {} is not PKCS#8", path.display()),
+            Ok(None) | Err(_) => bytes,
+        };
+        let salt = hex::decode(&config.nsec3_salt)
+            .with_context(|| format!("dns.dnssec.nsec3_salt is not hex: {}", config.nsec3_salt))?;
+        anyhow::ensure!(salt.len() <= 255, "dns.dnssec.nsec3_salt is longer than 255 bytes");
+        anyhow::ensure!(
+            config.signature_validity_secs >= 2 * INCEPTION_SKEW.as_secs(),
+            "dns.dnssec.signature_validity_secs must be at least {}",
+            2 * INCEPTION_SKEW.as_secs()
+        );
+
+        let key = Self {
+            algorithm,
+            pkcs8,
+            validity: Duration::from_secs(config.signature_validity_secs),
+            iterations: config.nsec3_iterations,
+            salt,
+        };
+        // Fail at startup rather than on the first signing
+        key.signer(&Name::root())
+            .with_context(|| format!("Invalid {} key in {}", algorithm, path.display()))?;
+        Ok(Some(key))
+    }
+
+    /// A signer for the zone at `origin`
+    pub fn signer(&self, origin: &Name) -> Result<ZoneSigner> {
+        let pair = KeyFormat::Pkcs8
+            .decode_key(&self.pkcs8, None, self.algorithm)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let public = pair.to_public_bytes().map_err(|e| anyhow::anyhow!("{}", e))?;
+        // Zone key with the SEP flag (257): one key signs everything
+        let dnskey = DNSKEY::new(true, true, false, self.algorithm, public);
+        let key_tag = dnskey.calculate_key_tag()?;
+        Ok(ZoneSigner {
+            signer: SigSigner::dnssec(dnskey.clone(), pair, origin.clone(), self.validity),
+            dnskey,
+            key_tag,
+            origin: origin.clone(),
+            iterations: self.iterations,
+            salt: self.salt.clone(),
+        })
+    }
+}
+
+/// Signs versions of one zone
+pub struct ZoneSigner {
+    signer: SigSigner,
+    dnskey: DNSKEY,
+    key_tag: u16,
+    origin: Name,
+    iterations: u16,
+    salt: Vec<u8>,
+}
+
+impl ZoneSigner {
+    /// How long until a version's signatures should be replaced: halfway
+    /// through their validity
+    pub fn refresh_after(&self) -> Duration {
+        self.signer.sig_duration() / 2
+    }
+
+    /// The DS record for the parent zone, in presentation format
+    pub fn ds(&self) -> Result<String> {
+        let digest = self.dnskey.to_digest(&self.origin, DigestType::SHA256)?;
+        Ok(format!(
+            "{} DS {} {} {} {}",
+            self.origin,
+            self.key_tag,
+            u8::from(self.signer.algorithm()),
+            u8::from(DigestType::SHA256),
+            hex::encode_upper(digest.as_ref())
+        ))
+    }
+
+    /// `records` with the DNSKEY, the NSEC3 chain, and an RRSIG for every
+    /// RRset added, and the chain to answer negative queries from. The TTL of
+    /// the added records is the SOA minimum.
+    pub fn sign(&self, mut records: Vec<Record>) -> Result<(Vec<Record>, Nsec3Chain)> {
+        let ttl = records
+            .iter()
+            .find_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(soa.minimum()),
+                _ => None,
+            })
+            .context("Zone has no SOA")?;
+        let dnssec = |rdata| Record::from_rdata(self.origin.clone(), ttl, RData::DNSSEC(rdata));
+        records.push(dnssec(DNSSECRData::DNSKEY(self.dnskey.clone())));
+        records.push(dnssec(DNSSECRData::NSEC3PARAM(NSEC3PARAM::new(
+            Nsec3HashAlgorithm::SHA1,
+            false,
+            self.iterations,
+            self.salt.clone(),
+        ))));
+        let chain = self.chain(&records)?;
+        records.extend(chain.records(ttl));
+
+        let mut rrsets: BTreeMap<(LowerName, RecordType), Vec<Record>> = BTreeMap::new();
+        for record in records {
+            rrsets
+                .entry((LowerName::new(record.name()), record.record_type()))
+                .or_default()
+                .push(record);
+        }
+        let now = chrono::Utc::now().timestamp() as u32;
+        let inception = now.wrapping_sub(INCEPTION_SKEW.as_secs() as u32);
+        let expiration = now.wrapping_add(self.signer.sig_duration().as_secs() as u32);
+
+        let mut signed = Vec::new();
+        for ((name, record_type), mut rrset) in rrsets {
+            // The zone keeps one copy of repeated records, so sign one
+            rrset.sort();
+            rrset.dedup();
+            let name = Name::from(name);
+            let original_ttl = rrset[0].ttl();
+            let tbs = tbs::rrset_tbs(
+                &name,
+                DNSClass::IN,
+                name.num_labels(),
+                record_type,
+                self.signer.algorithm(),
+                original_ttl,
+                expiration,
+                inception,
+                self.key_tag,
+                &self.origin,
+                &rrset,
+            )?;
+            let signature = self.signer.sign(&tbs)?;
+            let rrsig = RRSIG::new(
+                record_type,
+                self.signer.algorithm(),
+                name.num_labels(),
+                original_ttl,
+                expiration,
+                inception,
+                self.key_tag,
+                self.origin.clone(),
+                signature,
+            );
+            signed.extend(rrset);
+            signed.push(Record::from_rdata(name, original_ttl, RData::DNSSEC(DNSSECRData::RRSIG(rrsig))));
+        }
+        Ok((signed, chain))
+    }
+
+    /// Hash every name in the zone, including empty non-terminals, with the
+    /// types at each
+    fn chain(&self, records: &[Record]) -> Result<Nsec3Chain> {
+        let mut names: BTreeMap<Name, BTreeSet<RecordType>> = BTreeMap::new();
+        for record in records {
+            let name = record.name().to_lowercase();
+            let mut parent = name.base_name();
+            names.entry(name).or_default().insert(record.record_type());
+            while parent.num_labels() > self.origin.num_labels() {
+                names.entry(parent.clone()).or_default();
+                parent = parent.base_name();
+            }
+        }
+        let mut chain = Nsec3Chain {
+            origin: self.origin.clone(),
+            iterations: self.iterations,
+            salt: self.salt.clone(),
+            entries: Vec::with_capacity(names.len()),
+        };
+        for (name, mut types) in names {
+            if !types.is_empty() {
+                types.insert(RecordType::RRSIG);
+            }
+            let hash = chain.hash(&name).with_context(|| format!("Failed to hash {}", name))?;
+            chain.entries.push((hash, types.into_iter().collect()));
+        }
+        chain.entries.sort();
+        Ok(chain)
+    }
+}
+
+/// The hashed names of one version of a zone, in hash order
+pub struct Nsec3Chain {
+    origin: Name,
+    iterations: u16,
+    salt: Vec<u8>,
+    entries: Vec<(Vec<u8>, Vec<RecordType>)>,
+}
+
+impl Nsec3Chain {
+    fn records(&self, ttl: u32) -> Vec<Record> {
+        let next = self.entries.iter().cycle().skip(1);
+        self.entries
+            .iter()
+            .zip(next)
+            .map(|((hash, types), (next, _))| {
+                let nsec3 = NSEC3::new(
+                    Nsec3HashAlgorithm::SHA1,
+                    false,
+                    self.iterations,
+                    self.salt.clone(),
+                    next.clone(),
+                    types.clone(),
+                );
+                Record::from_rdata(self.owner(hash), ttl, RData::DNSSEC(DNSSECRData::NSEC3(nsec3)))
+            })
+            .collect()
+    }
+
+    /// Owner names of the NSEC3 records proving `name` has no records of a
+    /// type (the one matching it), or doesn't exist at all (the closest
+    /// encloser proof of RFC 5155 section 7.2.2)
+    pub fn proof(&self, name: &Name) -> Vec<Name> {
+        if !self.origin.zone_of(name) || self.entries.is_empty() {
+            return Vec::new();
+        }
+        let Some(hash) = self.hash(name) else {
+            return Vec::new();
+        };
+        if self.find(&hash).is_ok() {
+            return vec![self.owner(&hash)];
+        }
+
+        // The closest encloser exists; the apex always does
+        let mut encloser = name.base_name();
+        let mut encloser_hash = self.hash(&encloser);
+        while encloser.num_labels() > self.origin.num_labels()
+            && encloser_hash.as_ref().is_none_or(|h| self.find(h).is_err())
+        {
+            encloser = encloser.base_name();
+            encloser_hash = self.hash(&encloser);
+        }
+        let next_closer = name.trim_to(usize::from(encloser.num_labels()) + 1);
+        let wildcard = Name::from_ascii("*").and_then(|star| star.append_domain(&encloser));
+
+        let mut owners = Vec::new();
+        let hashes = [encloser_hash, self.hash(&next_closer), wildcard.ok().and_then(|w| self.hash(&w))];
+        for hash in hashes.into_iter().flatten() {
+            let owner = self.owner(self.covering(&hash));
+            if !owners.contains(&owner) {
+                owners.push(owner);
+            }
+        }
+        owners
+    }
+
+    fn hash(&self, name: &Name) -> Option<Vec<u8>> {
+        let digest = Nsec3HashAlgorithm::SHA1.hash(&self.salt, name, self.iterations).ok()?;
+        Some(digest.as_ref().to_vec())
+    }
+
+    fn find(&self, hash: &[u8]) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(h, _)| h.as_slice().cmp(hash))
+    }
+
+    /// The entry matching `hash`, or the one before it, wrapping around
+    fn covering(&self, hash: &[u8]) -> &[u8] {
+        let index = match self.find(hash) {
+            Ok(i) => i,
+            Err(i) => (i + self.entries.len() - 1) % self.entries.len(),
+        };
+        &self.entries[index].0
+    }
+
+    fn owner(&self, hash: &[u8]) -> Name {
+        let label = BASE32HEX_NOPAD.encode(hash).to_ascii_lowercase();
+        Name::from_ascii(label)
+            .and_then(|name| name.append_domain(&self.origin))
+            .expect("a 32 character label fits under the zone")
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use hickory_proto::rr::dnssec::{PublicKey, PublicKeyEnum};
+    use hickory_server::proto::rr::rdata::{AAAA, SOA};
+
+    pub fn key() -> DnssecKey {
+        DnssecKey {
+            algorithm: Algorithm::ECDSAP256SHA256,
+            pkcs8: KeyFormat::Pkcs8.generate_and_encode(Algorithm::ECDSAP256SHA256, None).unwrap(),
+            validity: Duration::from_secs(86400),
+            iterations: 0,
+            salt: Vec::new(),
+        }
+    }
+
+    /// A key whose signatures are due for refreshing as soon as they're made
+    pub fn expiring_key() -> DnssecKey {
+        DnssecKey { validity: Duration::ZERO, ..key() }
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+
+    #[test]
+    fn test_signs_and_denies() {
+        let origin = name("subnet.example.");
+        let signer = key().signer(&origin).unwrap();
+        let soa = SOA::new(name("ns.subnet.example."), name("hostmaster.subnet.example."), 1, 0, 0, 0, 60);
+        let host = |label: &str, addr: &str| {
+            Record::from_rdata(name(label), 60, RData::AAAA(AAAA(addr.parse().unwrap())))
+        };
+        let records = vec![
+            Record::from_rdata(origin.clone(), 60, RData::SOA(soa)),
+            host("nas.subnet.example.", "fd00::10"),
+            host("nas.subnet.example.", "fd00::10"),
+            host("web.lab.subnet.example.", "fd00::11"),
+        ];
+        let (signed, chain) = signer.sign(records).unwrap();
+
+        // Every RRset verifies against the zone's DNSKEY
+        let public = PublicKeyEnum::from_public_bytes(signer.dnskey.public_key(), signer.dnskey.algorithm()).unwrap();
+        let rrsigs: Vec<_> = signed
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) => Some((r.name(), sig)),
+                _ => None,
+            })
+            .collect();
+        for (owner, sig) in &rrsigs {
+            let rrset: Vec<Record> = signed
+                .iter()
+                .filter(|r| r.name() == *owner && r.record_type() == sig.type_covered())
+                .cloned()
+                .collect();
+            let tbs = tbs::rrset_tbs_with_sig(owner, DNSClass::IN, sig, &rrset).unwrap();
+            public.verify(sig.algorithm(), tbs.as_ref(), sig.sig()).unwrap();
+        }
+        assert!(rrsigs.iter().any(|(_, sig)| sig.type_covered() == RecordType::NSEC3));
+        // Apex, nas, web.lab, and the empty non-terminal lab
+        assert_eq!(rrsigs.iter().filter(|(_, sig)| sig.type_covered() == RecordType::NSEC3).count(), 4);
+
+        // An existing name is answered by its own NSEC3; a missing one by
+        // the closest encloser, and NSEC3s covering the next closer name and
+        // the wildcard
+        assert_eq!(chain.proof(&name("nas.subnet.example.")).len(), 1);
+        assert_eq!(chain.proof(&name("lab.subnet.example.")).len(), 1);
+        let missing = chain.proof(&name("printer.lab.subnet.example."));
+        assert!((2..=3).contains(&missing.len()));
+        assert_eq!(missing[0], chain.owner(&chain.hash(&name("lab.subnet.example.")).unwrap()));
+        assert!(chain.proof(&name("elsewhere.example.")).is_empty());
+    }
+}
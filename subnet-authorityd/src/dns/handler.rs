@@ -0,0 +1,210 @@
+//! DNS over UDP, TCP and TLS. Transfers and updates are authenticated with TSIG
+//! over the request bytes, which the catalog never sees, so messages are
+//! decoded here: transfers go to `transfer`, updates to `update`, and
+//! everything else to the catalog.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_proto::udp::MAX_RECEIVE_BUFFER_SIZE;
+use hickory_server::authority::{Catalog, MessageRequest, MessageResponse};
+use hickory_server::proto::op::{Message, OpCode};
+use hickory_server::proto::rr::Record;
+use hickory_server::proto::serialize::binary::{BinDecodable, BinEncoder};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use super::update::Updates;
+use super::zone::CacheAuthority;
+use super::{transfer, TCP_TIMEOUT};
+
+pub struct Handler {
+    pub catalog: Catalog,
+    /// Zones that may be transferred
+    pub zones: Vec<Arc<CacheAuthority>>,
+    pub transfer_keys: Vec<TSigner>,
+    /// Where signed updates go; without it the catalog refuses them
+    pub updates: Option<Updates>,
+}
+
+impl Handler {
+    /// Answer datagrams until `cancel` fires, each in its own task
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket, cancel: CancellationToken) {
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+        loop {
+            let (len, peer) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::debug!("DNS UDP receive failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let request = buf[..len].to_vec();
+            let (handler, socket) = (self.clone(), socket.clone());
+            tokio::spawn(async move {
+                for response in handler.respond(&request, peer, Protocol::Udp).await {
+                    if let Err(e) = socket.send_to(&response, peer).await {
+                        tracing::debug!("DNS UDP send to {} failed: {}", peer, e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Accept connections until `cancel` fires, then drop open ones
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener, cancel: CancellationToken) {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        connections.spawn(self.clone().connection(stream, peer, Protocol::Tcp));
+                    }
+                    Err(e) => tracing::warn!("DNS TCP accept failed: {}", e),
+                },
+                // Reap finished connections
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+        connections.shutdown().await;
+    }
+
+    /// Like `serve_tcp`, with a TLS handshake before the first message
+    pub async fn serve_tls(self: Arc<Self>, listener: TcpListener, acceptor: TlsAcceptor, cancel: CancellationToken) {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        let (handler, acceptor) = (self.clone(), acceptor.clone());
+                        connections.spawn(async move {
+                            match tokio::time::timeout(TCP_TIMEOUT, acceptor.accept(stream)).await {
+                                Ok(Ok(stream)) => handler.connection(stream, peer, Protocol::Tls).await,
+                                Ok(Err(e)) => tracing::debug!("DNS TLS handshake with {} failed: {}", peer, e),
+                                Err(_) => tracing::debug!("DNS TLS handshake with {} timed out", peer),
+                            }
+                        });
+                    }
+                    Err(e) => tracing::warn!("DNS TLS accept failed: {}", e),
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+        connections.shutdown().await;
+    }
+
+    /// Answer length-prefixed messages until the client closes or idles out
+    async fn connection<S>(self: Arc<Self>, mut stream: S, peer: SocketAddr, protocol: Protocol)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let request = match tokio::time::timeout(TCP_TIMEOUT, read_message(&mut stream)).await {
+                Ok(Ok(Some(request))) => request,
+                Ok(Ok(None)) | Err(_) => return,
+                Ok(Err(e)) => {
+                    tracing::debug!("DNS {} read from {} failed: {}", protocol, peer, e);
+                    return;
+                }
+            };
+            for response in self.respond(&request, peer, protocol).await {
+                let Ok(len) = u16::try_from(response.len()) else {
+                    tracing::warn!("DNS response to {} over 64 KiB; dropped", peer);
+                    continue;
+                };
+                let sent = async {
+                    stream.write_all(&len.to_be_bytes()).await?;
+                    stream.write_all(&response).await?;
+                    stream.flush().await
+                };
+                if let Err(e) = sent.await {
+                    tracing::debug!("DNS {} write to {} failed: {}", protocol, peer, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn respond(&self, bytes: &[u8], peer: SocketAddr, protocol: Protocol) -> Vec<Vec<u8>> {
+        // Undecodable messages go unanswered
+        let Ok(message) = Message::from_vec(bytes) else {
+            return Vec::new();
+        };
+        // AXFR needs a stream; over UDP the catalog refuses it
+        if matches!(protocol, Protocol::Tcp | Protocol::Tls) && transfer::is_transfer(&message) {
+            return transfer::respond(&self.zones, &self.transfer_keys, &message, bytes);
+        }
+        if let (OpCode::Update, Some(updates)) = (message.op_code(), &self.updates) {
+            return vec![updates.respond(&message, bytes, peer).await];
+        }
+        let Ok(request) = MessageRequest::from_bytes(bytes) else {
+            return Vec::new();
+        };
+        let capture = Capture { protocol, response: Default::default() };
+        self.catalog
+            .handle_request(&Request::new(request, peer, protocol), capture.clone())
+            .await;
+        let response = capture.response.lock().unwrap().take();
+        response.into_iter().collect()
+    }
+}
+
+/// One length-prefixed message, None at a clean end of stream
+async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut message = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+/// Keeps the catalog's response as bytes instead of sending it
+#[derive(Clone)]
+struct Capture {
+    protocol: Protocol,
+    response: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for Capture {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        // Over UDP, truncate to what the client said it can take
+        let max_size = match (self.protocol, response.get_edns()) {
+            (Protocol::Udp, Some(edns)) => edns.max_payload(),
+            (Protocol::Udp, None) => MAX_RECEIVE_BUFFER_SIZE as u16,
+            _ => u16::MAX,
+        };
+        let mut buf = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut buf);
+            encoder.set_max_size(max_size);
+            response.destructive_emit(&mut encoder).map_err(io::Error::other)?
+        };
+        *self.response.lock().unwrap() = Some(buf);
+        Ok(info)
+    }
+}
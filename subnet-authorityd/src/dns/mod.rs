@@ -0,0 +1,225 @@
+//! Authoritative DNS for the authority's zone, answering from the cache so
+//! devices without mDNS can resolve services by name, and for the reverse
+//! zone of the prefix. Both can be DNSSEC-signed, secondaries can transfer
+//! them, hosts can register services with signed updates, and resolvers can
+//! use DNS over TLS.
+
+pub mod dnssec;
+mod handler;
+pub mod transfer;
+pub mod tsig;
+pub mod update;
+pub mod zone;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_server::authority::{Authority, Catalog};
+use hickory_server::proto::op::{Message, ResponseCode};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use crate::cache_manager::CacheSnapshot;
+use dnssec::{DnssecKey, ZoneSigner};
+use handler::Handler;
+use update::Updates;
+use zone::{CacheAuthority, ZoneInfo};
+
+/// How long an idle TCP client may hold its connection
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often signed zones check whether their signatures need replacing
+const RESIGN_CHECK: Duration = Duration::from_secs(600);
+
+pub struct DnsServer {
+    udp: UdpSocket,
+    tcp: TcpListener,
+    info: ZoneInfo,
+    transfer_keys: Vec<TSigner>,
+    updates: Option<Updates>,
+    tls: Option<(TcpListener, TlsAcceptor)>,
+    /// Signers for the forward and reverse zones
+    signers: Option<(ZoneSigner, Option<ZoneSigner>)>,
+}
+
+impl DnsServer {
+    /// Bind UDP and TCP on `listen`. With port 0, TCP takes the port UDP got.
+    /// Secondaries signing with one of `transfer_keys` may transfer the zones;
+    /// updates go to `updates` if set, and are refused otherwise.
+    pub async fn bind(
+        listen: &str,
+        info: ZoneInfo,
+        transfer_keys: Vec<TSigner>,
+        updates: Option<Updates>,
+    ) -> Result<Self> {
+        let udp = UdpSocket::bind(listen)
+            .await
+            .with_context(|| format!("Failed to bind DNS (UDP) to {}", listen))?;
+        let addr = udp.local_addr().context("Failed to read DNS socket address")?;
+        let tcp = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind DNS (TCP) to {}", addr))?;
+        Ok(Self { udp, tcp, info, transfer_keys, updates, tls: None, signers: None })
+    }
+
+    /// Also serve DNS over TLS (RFC 7858) on `listen`
+    pub async fn bind_tls(&mut self, listen: &str, acceptor: TlsAcceptor) -> Result<()> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to bind DNS (TLS) to {}", listen))?;
+        self.tls = Some((listener, acceptor));
+        Ok(())
+    }
+
+    /// Sign the zones with `key`, logging the DS records to publish in the
+    /// parent zones
+    pub fn sign_with(&mut self, key: &DnssecKey) -> Result<()> {
+        let forward = key.signer(&self.info.origin)?;
+        let reverse = match &self.info.prefix {
+            Some(prefix) => Some(key.signer(&zone::reverse_origin(prefix))?),
+            None => None,
+        };
+        for signer in std::iter::once(&forward).chain(&reverse) {
+            tracing::info!("DNSSEC: publish in the parent zone: {}", signer.ds()?);
+        }
+        self.signers = Some((forward, reverse));
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.udp.local_addr().context("Failed to read DNS socket address")
+    }
+
+    /// Serve the zones, rebuilding them whenever the cache changes, until `cancel` fires
+    pub async fn run(self, mut snapshot_rx: watch::Receiver<CacheSnapshot>, cancel: CancellationToken) {
+        let signing = self.signers.is_some();
+        let (forward_signer, reverse_signer) = match self.signers {
+            Some((forward, reverse)) => (Some(forward), reverse),
+            None => (None, None),
+        };
+        let sign = |authority: CacheAuthority, signer: Option<ZoneSigner>| match signer {
+            Some(signer) => authority.signed(signer),
+            None => authority,
+        };
+        let authority = Arc::new(sign(CacheAuthority::new(&self.info.origin), forward_signer));
+        let mut catalog = Catalog::new();
+        catalog.upsert(authority.origin().clone(), Box::new(authority.clone()));
+        let reverse = self.info.prefix.map(|prefix| {
+            let reverse = Arc::new(sign(CacheAuthority::new(&zone::reverse_origin(&prefix)), reverse_signer));
+            catalog.upsert(reverse.origin().clone(), Box::new(reverse.clone()));
+            (prefix, reverse)
+        });
+
+        let handler = Arc::new(Handler {
+            catalog,
+            zones: std::iter::once(authority.clone()).chain(reverse.iter().map(|(_, r)| r.clone())).collect(),
+            transfer_keys: self.transfer_keys,
+            updates: self.updates,
+        });
+        let udp = tokio::spawn(handler.clone().serve_udp(self.udp, cancel.clone()));
+        let tcp = tokio::spawn(handler.clone().serve_tcp(self.tcp, cancel.clone()));
+        let tls = self
+            .tls
+            .map(|(listener, acceptor)| tokio::spawn(handler.serve_tls(listener, acceptor, cancel.clone())));
+
+        let mut resign = tokio::time::interval(RESIGN_CHECK);
+        loop {
+            {
+                let snapshot = snapshot_rx.borrow_and_update();
+                authority.replace(snapshot.seq, |serial| zone::records(&snapshot.services, &self.info, serial));
+                if let Some((prefix, reverse)) = &reverse {
+                    reverse.replace(snapshot.seq, |serial| zone::reverse_records(&snapshot.services, &self.info, prefix, serial));
+                }
+            }
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                changed = snapshot_rx.changed() => {
+                    if changed.is_err() {
+                        // Cache thread gone: keep serving the last zone
+                        cancel.cancelled().await;
+                        break;
+                    }
+                }
+                // Rebuild from the same snapshot; unchanged zones are only
+                // rebuilt when their signatures are due
+                _ = resign.tick(), if signing => {}
+            }
+        }
+
+        let _ = tokio::join!(udp, tcp);
+        if let Some(tls) = tls {
+            let _ = tls.await;
+        }
+        tracing::info!("DNS server stopped");
+    }
+}
+
+/// An unsigned error response to `request`, echoing its question or zone
+fn error_response(request: &Message, code: ResponseCode) -> Vec<u8> {
+    let mut response = Message::error_msg(request.id(), request.op_code(), code);
+    response.add_queries(request.queries().iter().cloned());
+    response.to_vec().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use hickory_server::proto::op::{Message, Query, ResponseCode};
+    use hickory_server::proto::rr::rdata::PTR;
+    use hickory_server::proto::rr::{Name, RData, RecordType};
+    use shared::types::ServiceEntry;
+    use crate::cache::hash::HashFields;
+
+    #[tokio::test]
+    async fn test_answers_over_udp() {
+        let service = ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: "web._http._tcp.local.".to_string(),
+            hostname: "nas.local.".to_string(),
+            addresses: vec!["fd00::10".parse().unwrap()],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+        let (_tx, snapshot_rx) = watch::channel(CacheSnapshot::new(vec![service], HashFields::default()));
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, Some("fd00::/64".parse().unwrap())).unwrap();
+        let server = DnsServer::bind("127.0.0.1:0", info, Vec::new(), None).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let handle = tokio::spawn(server.run(snapshot_rx, cancel.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; 4096];
+        let mut ask = async |name: &str, record_type: RecordType| {
+            let mut query = Message::new();
+            query.add_query(Query::query(Name::from_utf8(name).unwrap(), record_type));
+            client.send_to(&query.to_vec().unwrap(), addr).await.unwrap();
+            let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await.unwrap().unwrap();
+            Message::from_vec(&buf[..len]).unwrap()
+        };
+
+        let found = ask("nas.subnet.example.", RecordType::AAAA).await;
+        assert!(found.authoritative());
+        assert_eq!(found.answers()[0].data(), Some(&RData::AAAA("fd00::10".parse::<std::net::Ipv6Addr>().unwrap().into())));
+        assert_eq!(ask("missing.subnet.example.", RecordType::AAAA).await.response_code(), ResponseCode::NXDomain);
+
+        let reverse = Name::from("fd00::10".parse::<std::net::Ipv6Addr>().unwrap()).to_string();
+        let found = ask(&reverse, RecordType::PTR).await;
+        assert_eq!(found.answers()[0].data(), Some(&RData::PTR(PTR(Name::from_utf8("nas.subnet.example.").unwrap()))));
+
+        cancel.cancel();
+        handle.await.unwrap();
+    }
+}
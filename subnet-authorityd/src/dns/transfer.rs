@@ -0,0 +1,183 @@
+//! Zone transfers to secondary servers: AXFR (RFC 5936) and IXFR (RFC 1995),
+//! over TCP only and authenticated with TSIG (RFC 8945). Requests that aren't
+//! signed with a configured key are refused.
+
+use std::sync::Arc;
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_server::authority::Authority;
+use hickory_server::proto::op::{Message, MessageType, OpCode, ResponseCode};
+use hickory_server::proto::rr::{LowerName, RData, Record, RecordType};
+use hickory_server::proto::serialize::binary::BinEncodable;
+use super::tsig;
+use super::zone::CacheAuthority;
+
+/// Rough answer bytes per message, well under the 64 KiB TCP limit
+const MESSAGE_BUDGET: usize = 16 * 1024;
+
+/// Whether `request` asks for a zone transfer
+pub fn is_transfer(request: &Message) -> bool {
+    request.message_type() == MessageType::Query
+        && request.op_code() == OpCode::Query
+        && request
+            .queries()
+            .first()
+            .is_some_and(|q| matches!(q.query_type(), RecordType::AXFR | RecordType::IXFR))
+}
+
+/// Response messages to a transfer request, already encoded. `bytes` is the
+/// request as received, which its TSIG covers.
+pub fn respond(zones: &[Arc<CacheAuthority>], keys: &[TSigner], request: &Message, bytes: &[u8]) -> Vec<Vec<u8>> {
+    let refuse = |code| vec![super::error_response(request, code)];
+    let (signer, request_mac) = match tsig::verify(keys, request, bytes) {
+        Ok(verified) => verified,
+        Err(code) => return refuse(code),
+    };
+    let query = &request.queries()[0];
+    let Some(zone) = zones.iter().find(|z| *z.origin() == LowerName::new(query.name())) else {
+        return refuse(ResponseCode::NotAuth);
+    };
+
+    // IXFR carries the secondary's SOA in the authority section
+    let since = (query.query_type() == RecordType::IXFR)
+        .then(|| {
+            request.name_servers().iter().find_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            })
+        })
+        .flatten();
+    let answers = zone.transfer(since);
+    if answers.is_empty() {
+        return refuse(ResponseCode::ServFail);
+    }
+    tracing::debug!(
+        "{:?} of {} for key {}: {} records",
+        query.query_type(),
+        query.name(),
+        signer.signer_name(),
+        answers.len()
+    );
+
+    let mut previous_mac = request_mac;
+    let mut messages = Vec::new();
+    for (i, chunk) in chunks(answers).into_iter().enumerate() {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true);
+        if i == 0 {
+            response.add_query(query.clone());
+        }
+        response.add_answers(chunk);
+        match tsig::sign(signer, &mut response, &previous_mac, i == 0) {
+            Ok((encoded, mac)) => {
+                messages.push(encoded);
+                previous_mac = mac;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to sign transfer of {}: {}", query.name(), e);
+                return refuse(ResponseCode::ServFail);
+            }
+        }
+    }
+    messages
+}
+
+/// Split answers into messages of about `MESSAGE_BUDGET` bytes
+fn chunks(answers: Vec<Record>) -> Vec<Vec<Record>> {
+    let mut chunks = vec![Vec::new()];
+    let mut size = 0;
+    for record in answers {
+        let len = record.to_bytes().map_or(MESSAGE_BUDGET, |b| b.len());
+        if size + len > MESSAGE_BUDGET && size > 0 {
+            chunks.push(Vec::new());
+            size = 0;
+        }
+        size += len;
+        chunks.last_mut().unwrap().push(record);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use hickory_server::proto::op::Query;
+    use hickory_server::proto::rr::rdata::SOA;
+    use shared::types::ServiceEntry;
+    use crate::dns::zone::{self, ZoneInfo};
+
+    fn service(hostname: &str, address: &str) -> ServiceEntry {
+        ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: format!("{}._http._tcp.local.", hostname.trim_end_matches(".local.")),
+            hostname: hostname.to_string(),
+            addresses: vec![address.parse().unwrap()],
+            port: 80,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+
+    #[test]
+    fn test_signed_axfr_and_ixfr() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let zone = Arc::new(CacheAuthority::new(&info.origin));
+        let mut services = vec![service("nas.local.", "fd00::10")];
+        zone.replace(1, |serial| zone::records(&services, &info, serial));
+        let old_serial = match zone.transfer(None)[0].data() {
+            Some(RData::SOA(soa)) => soa.serial(),
+            other => panic!("expected SOA, got {:?}", other),
+        };
+        // Enough hosts that the transfer takes several signed messages
+        services.extend((0..300).map(|i| service(&format!("printer-{}.local.", i), &format!("fd00::1:{:x}", i))));
+        zone.replace(2, |serial| zone::records(&services, &info, serial));
+
+        let signer = tsig::tests::key("secondary.subnet.example.").signer().unwrap();
+        let zones = [zone];
+        let ask = |query_type, since: Option<u32>, signed: bool| {
+            let mut request = Message::new();
+            request.set_id(7).add_query(Query::query(info.origin.clone(), query_type));
+            if let Some(serial) = since {
+                let soa = SOA::new(info.nameserver.clone(), info.origin.clone(), serial, 0, 0, 0, 0);
+                request.add_name_server(Record::from_rdata(info.origin.clone(), 0, RData::SOA(soa)));
+            }
+            let mut verifier = signed
+                .then(|| request.finalize(&signer, Utc::now().timestamp() as u32).unwrap().unwrap());
+            let responses = respond(&zones, std::slice::from_ref(&signer), &request, &request.to_vec().unwrap());
+            responses
+                .iter()
+                .flat_map(|bytes| match &mut verifier {
+                    Some(verify) => verify(bytes).unwrap().into_message().take_answers(),
+                    None => Message::from_vec(bytes).unwrap().take_answers(),
+                })
+                .map(|r| r.record_type())
+                .collect::<Vec<_>>()
+        };
+
+        // AXFR: the whole zone between two SOAs
+        let axfr = ask(RecordType::AXFR, None, true);
+        assert_eq!((axfr.first(), axfr.last()), (Some(&RecordType::SOA), Some(&RecordType::SOA)));
+        assert_eq!(axfr.iter().filter(|t| **t == RecordType::AAAA).count(), 302);
+
+        // IXFR from the first version: only the printers' records were added
+        let ixfr = ask(RecordType::IXFR, Some(old_serial), true);
+        assert_eq!(&ixfr[..3], &[RecordType::SOA, RecordType::SOA, RecordType::SOA]);
+        assert_eq!(ixfr.iter().filter(|t| **t == RecordType::AAAA).count(), 300);
+
+        // Unsigned requests get nothing
+        assert!(ask(RecordType::AXFR, None, false).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! TSIG (RFC 8945): keys from the config, verifying signed requests, and
+//! signing responses to them.
+
+use std::fmt;
+use anyhow::{Context, Result};
+use base64::Engine;
+use hickory_proto::rr::dnssec::rdata::tsig::{make_tsig_record, TsigAlgorithm, TSIG};
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_server::proto::op::{Message, ResponseCode};
+use hickory_server::proto::rr::{Name, RecordType};
+use hickory_server::proto::serialize::binary::BinEncoder;
+use serde::Deserialize;
+
+/// Allowed clock difference between client and authority, seconds
+const FUDGE: u16 = 300;
+
+/// A TSIG key clients sign requests with (`[[dns.transfer_keys]]`,
+/// `[[dns.update_keys]]`)
+#[derive(Clone, Deserialize)]
+pub struct TsigKey {
+    /// Key name, the same on the client, e.g. `secondary.subnet.example`
+    pub name: String,
+    /// `hmac-sha256`, `hmac-sha384`, or `hmac-sha512`
+    #[serde(default = "default_algorithm")]
+    pub algorithm: String,
+    /// Base64 key, e.g. from `tsig-keygen` or `keymgr -t`
+    pub secret: String,
+}
+
+// Keep secrets out of logs
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+fn default_algorithm() -> String {
+    "hmac-sha256".to_string()
+}
+
+/// Signers for `keys`, failing on the first bad one
+pub fn signers(keys: &[TsigKey]) -> Result<Vec<TSigner>> {
+    keys.iter().map(TsigKey::signer).collect()
+}
+
+impl TsigKey {
+    pub fn signer(&self) -> Result<TSigner> {
+        let name = Name::from_ascii(&self.name)
+            .with_context(|| format!("Invalid TSIG key name: {}", self.name))?;
+        let algorithm = Name::from_ascii(&self.algorithm)
+            .map(TsigAlgorithm::from_name)
+            .with_context(|| format!("Invalid TSIG algorithm: {}", self.algorithm))?;
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(self.secret.trim())
+            .with_context(|| format!("TSIG key {} is not valid base64", self.name))?;
+        TSigner::new(secret, algorithm, name, FUDGE)
+            .with_context(|| format!("Unsupported TSIG algorithm for key {}: {}", self.name, self.algorithm))
+    }
+}
+
+/// The key that signed `request` and the request's MAC, which the first
+/// response's MAC covers
+pub fn verify<'k>(keys: &'k [TSigner], request: &Message, bytes: &[u8]) -> Result<(&'k TSigner, Vec<u8>), ResponseCode> {
+    let tsig = request
+        .signature()
+        .iter()
+        .find(|r| r.record_type() == RecordType::TSIG)
+        .ok_or(ResponseCode::Refused)?;
+    let signer = keys
+        .iter()
+        .find(|k| k.signer_name() == tsig.name())
+        .ok_or(ResponseCode::NotAuth)?;
+    let (mac, valid, _) = signer
+        .verify_message_byte(None, bytes, true)
+        .map_err(|_| ResponseCode::NotAuth)?;
+    if !valid.contains(&(chrono::Utc::now().timestamp() as u64)) {
+        return Err(ResponseCode::NotAuth);
+    }
+    Ok((signer, mac))
+}
+
+/// Append a TSIG to `response` and encode it. The first message's MAC covers
+/// the request's MAC and every TSIG field; later ones cover the previous
+/// message's MAC and only the timers (RFC 8945 section 5.3.1).
+pub fn sign(signer: &TSigner, response: &mut Message, previous_mac: &[u8], first: bool) -> Result<(Vec<u8>, Vec<u8>)> {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let pre_tsig = TSIG::new(signer.algorithm().clone(), now, signer.fudge(), Vec::new(), response.id(), 0, Vec::new());
+    // The message is encoded on its own, not after the MAC, so its name
+    // compression matches what goes on the wire
+    let mut tbs = Vec::with_capacity(previous_mac.len() + 512);
+    tbs.extend_from_slice(&(previous_mac.len() as u16).to_be_bytes());
+    tbs.extend_from_slice(previous_mac);
+    tbs.extend_from_slice(&response.to_vec()?);
+    if first {
+        let mut variables = Vec::new();
+        pre_tsig.emit_tsig_for_mac(&mut BinEncoder::new(&mut variables), signer.signer_name())?;
+        tbs.extend_from_slice(&variables);
+    } else {
+        tbs.extend_from_slice(&now.to_be_bytes()[2..]);
+        tbs.extend_from_slice(&signer.fudge().to_be_bytes());
+    }
+    let mac = signer.sign(&tbs)?;
+    response.add_tsig(make_tsig_record(signer.signer_name().clone(), pre_tsig.set_mac(mac.clone())));
+    Ok((response.to_vec()?, mac))
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    pub fn key(name: &str) -> TsigKey {
+        TsigKey {
+            name: name.to_string(),
+            algorithm: default_algorithm(),
+            secret: "c2VjcmV0LWtleS1mb3ItdGVzdHMtb25seS0zMmJ5dGVz".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_key_config_errors() {
+        assert!(key("k.").signer().is_ok());
+        assert!(TsigKey { algorithm: "hmac-md5".to_string(), ..key("k.") }.signer().is_err());
+        assert!(TsigKey { secret: "not base64!".to_string(), ..key("k.") }.signer().is_err());
+        assert!(!format!("{:?}", key("k.")).contains("c2Vj"));
+    }
+}
@@ -0,0 +1,365 @@
+//! Dynamic updates (RFC 2136) to the forward zone, signed with TSIG, so hosts
+//! without mDNS can register services with `nsupdate`. An update names
+//! services the way the zone serves them:
+//!
+//! - SRV at `<instance>.<type>.<zone>` registers the instance, or changes its
+//!   host and port
+//! - TXT there replaces its TXT entries (`key=value` strings)
+//! - AAAA at the SRV target gives the host's addresses
+//! - deleting the SRV, or every record at the instance name, removes it
+//!
+//! PTR records are accepted and ignored, since the zone derives them.
+//! Prerequisites are not supported.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use anyhow::Result;
+use chrono::Utc;
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_server::proto::op::{Message, OpCode, ResponseCode};
+use hickory_server::proto::rr::{DNSClass, LowerName, Name, RData, RecordType};
+use ipnet::Ipv6Net;
+use shared::names::InstanceName;
+use shared::types::{ServiceEntry, ServiceSource};
+use crate::cache::db::TypeConflictError;
+use crate::cache_manager::CacheHandle;
+use super::{tsig, zone};
+
+pub struct Updates {
+    pub cache: CacheHandle,
+    /// Keys an update must be signed with
+    pub keys: Vec<TSigner>,
+    /// The forward zone; updates to anything else are refused
+    pub origin: Name,
+    /// Registered addresses must be inside the prefix
+    pub prefix: Ipv6Net,
+}
+
+/// What an update does to one instance, by its cache name
+#[derive(Default)]
+struct Change {
+    service_type: String,
+    /// Port, target host, and TTL
+    srv: Option<(u16, Name, u32)>,
+    txt: Option<HashMap<String, String>>,
+    remove: bool,
+    /// Deleted earlier in the update, so later additions start from scratch
+    fresh: bool,
+}
+
+type Addresses = HashMap<LowerName, Vec<IpAddr>>;
+
+impl Updates {
+    /// The encoded response to an UPDATE, signed with the request's key
+    pub async fn respond(&self, request: &Message, bytes: &[u8], peer: SocketAddr) -> Vec<u8> {
+        let (signer, request_mac) = match tsig::verify(&self.keys, request, bytes) {
+            Ok(verified) => verified,
+            Err(code) => {
+                tracing::info!("Refused DNS update from {}: not signed with a known key", peer);
+                return super::error_response(request, code);
+            }
+        };
+
+        let code = match self.parse(request) {
+            Ok((changes, addresses)) => match self.apply(changes, addresses, signer.signer_name()).await {
+                Ok(code) => code,
+                Err(e) if e.is::<TypeConflictError>() => {
+                    tracing::info!("Refused DNS update from {}: {}", peer, e);
+                    ResponseCode::Refused
+                }
+                Err(e) => {
+                    tracing::error!("Failed to apply DNS update: {}", e);
+                    ResponseCode::ServFail
+                }
+            },
+            Err(code) => code,
+        };
+
+        let mut response = Message::error_msg(request.id(), OpCode::Update, code);
+        response.add_queries(request.queries().iter().cloned());
+        match tsig::sign(signer, &mut response, &request_mac, true) {
+            Ok((encoded, _)) => encoded,
+            Err(e) => {
+                tracing::warn!("Failed to sign DNS update response: {}", e);
+                super::error_response(request, ResponseCode::ServFail)
+            }
+        }
+    }
+
+    /// The changes an update asks for, in order, or the response code
+    /// rejecting it
+    fn parse(&self, request: &Message) -> Result<(BTreeMap<String, Change>, Addresses), ResponseCode> {
+        let [zone] = request.queries() else {
+            return Err(ResponseCode::FormErr);
+        };
+        if LowerName::new(zone.name()) != LowerName::new(&self.origin) {
+            return Err(ResponseCode::NotAuth);
+        }
+        if !request.answers().is_empty() {
+            return Err(ResponseCode::NotImp);
+        }
+
+        let mut changes = BTreeMap::new();
+        let mut addresses = Addresses::new();
+        for record in request.name_servers() {
+            let name = record.name();
+            if !self.origin.zone_of(name) {
+                return Err(ResponseCode::NotZone);
+            }
+            let instance = instance_of(name, &self.origin);
+            match (record.dns_class(), record.data()) {
+                (DNSClass::IN, Some(RData::AAAA(aaaa))) => {
+                    addresses.entry(LowerName::new(name)).or_default().push(aaaa.0.into());
+                }
+                (DNSClass::IN, Some(RData::PTR(_))) => {}
+                (DNSClass::IN, Some(RData::SRV(srv))) => {
+                    if !self.origin.zone_of(srv.target()) || srv.target() == &self.origin {
+                        return Err(ResponseCode::NotZone);
+                    }
+                    let change = change_for(&mut changes, instance)?;
+                    change.srv = Some((srv.port(), srv.target().clone(), record.ttl()));
+                    change.remove = false;
+                }
+                (DNSClass::IN, Some(RData::TXT(txt))) => {
+                    let change = change_for(&mut changes, instance)?;
+                    let entries = change.txt.get_or_insert_with(HashMap::new);
+                    for data in txt.txt_data() {
+                        let data = String::from_utf8_lossy(data);
+                        let (key, value) = data.split_once('=').unwrap_or((&data, ""));
+                        entries.insert(key.to_string(), value.to_string());
+                    }
+                    change.remove = false;
+                }
+                (DNSClass::IN, _) => return Err(ResponseCode::Refused),
+                (DNSClass::ANY | DNSClass::NONE, _) => {
+                    if instance.is_some() && matches!(record.record_type(), RecordType::SRV | RecordType::ANY) {
+                        let change = change_for(&mut changes, instance)?;
+                        *change = Change {
+                            service_type: std::mem::take(&mut change.service_type),
+                            remove: true,
+                            fresh: true,
+                            ..Change::default()
+                        };
+                    }
+                }
+                _ => return Err(ResponseCode::FormErr),
+            }
+        }
+        Ok((changes, addresses))
+    }
+
+    /// Check every change against the cache and the prefix, then apply them
+    /// all. Only a failing cache write leaves an update half done.
+    async fn apply(&self, changes: BTreeMap<String, Change>, addresses: Addresses, key: &Name) -> Result<ResponseCode> {
+        let now = Utc::now();
+        let mut upserts = Vec::new();
+        let mut removals = Vec::new();
+        let mut used_hosts = HashSet::new();
+        for (instance_name, change) in changes {
+            if change.remove {
+                removals.push(instance_name);
+                continue;
+            }
+            let existing = match change.fresh {
+                true => None,
+                false => self.cache.get_one(instance_name.clone()).await?,
+            };
+            let mut entry = match (existing, &change.srv) {
+                (Some(entry), _) => entry,
+                (None, Some(_)) => ServiceEntry {
+                    service_type: change.service_type,
+                    instance_name,
+                    hostname: String::new(),
+                    addresses: Vec::new(),
+                    port: 0,
+                    txt: HashMap::new(),
+                    first_seen: now,
+                    last_seen: now,
+                    ttl: 0,
+                    alive: true,
+                    source: ServiceSource::Dns,
+                    reachable: None,
+                    latency_ms: None,
+                    subtypes: Vec::new(),
+                    iface: None,
+                },
+                // TXT alone can't create a service
+                (None, None) => return Ok(ResponseCode::Refused),
+            };
+
+            if let Some((port, target, ttl)) = change.srv {
+                let hostname = local_host(&target, &self.origin);
+                if hostname != entry.hostname {
+                    entry.addresses.clear();
+                }
+                entry.hostname = hostname;
+                entry.port = port;
+                entry.ttl = ttl;
+            }
+            if let Some(txt) = change.txt {
+                entry.txt = txt;
+            }
+            if let Some(host) = zone::host_name(&entry.hostname, &self.origin).map(|h| LowerName::new(&h)) {
+                if let Some(addrs) = addresses.get(&host) {
+                    entry.addresses = addrs.clone();
+                    used_hosts.insert(host);
+                }
+            }
+            if let Some(addr) = entry.addresses.iter().find(|a| !matches!(a, IpAddr::V6(v6) if self.prefix.contains(v6))) {
+                tracing::info!("Refused DNS update of {}: {} is outside {}", entry.instance_name, addr, self.prefix);
+                return Ok(ResponseCode::Refused);
+            }
+            entry.alive = true;
+            entry.last_seen = now;
+            entry.source = ServiceSource::Dns;
+            upserts.push(entry);
+        }
+        // Addresses only attach to a host through its services
+        if addresses.keys().any(|host| !used_hosts.contains(host)) {
+            return Ok(ResponseCode::Refused);
+        }
+
+        for entry in upserts {
+            tracing::info!("Registering service {} by DNS update with key {}", entry.instance_name, key);
+            self.cache.upsert(entry).await?;
+        }
+        for instance_name in removals {
+            if self.cache.exists(instance_name.clone(), false).await? {
+                tracing::info!("Marked service {} dead by DNS update with key {}", instance_name, key);
+                self.cache.mark_dead(instance_name).await?;
+            }
+        }
+        Ok(ResponseCode::NoError)
+    }
+}
+
+/// Cache instance name and service type of `<instance>._<service>._<proto>.<origin>`,
+/// both under `.local.` as the browser stores them
+fn instance_of(name: &Name, origin: &Name) -> Option<(String, String)> {
+    let labels: Vec<&[u8]> = name.iter().collect();
+    if labels.len() != usize::from(origin.num_labels()) + 3 {
+        return None;
+    }
+    let service = std::str::from_utf8(labels[1]).ok()?;
+    let proto = std::str::from_utf8(labels[2]).ok()?;
+    if !service.starts_with('_') || !matches!(proto, "_tcp" | "_udp") {
+        return None;
+    }
+    let instance = String::from_utf8_lossy(labels[0]);
+    let name = InstanceName::new(&instance, &format!("{}.{}", service, proto), "local");
+    Some((name.to_string(), name.full_type()))
+}
+
+fn change_for(
+    changes: &mut BTreeMap<String, Change>,
+    instance: Option<(String, String)>,
+) -> Result<&mut Change, ResponseCode> {
+    let (instance_name, service_type) = instance.ok_or(ResponseCode::Refused)?;
+    Ok(changes.entry(instance_name).or_insert_with(|| Change { service_type, ..Change::default() }))
+}
+
+/// A host under `origin` by its mDNS name, the inverse of `zone::host_name`
+fn local_host(target: &Name, origin: &Name) -> String {
+    let relative = usize::from(target.num_labels() - origin.num_labels());
+    let labels: Vec<_> = target.iter().take(relative).map(String::from_utf8_lossy).collect();
+    format!("{}.local.", labels.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::op::{MessageType, Query};
+    use hickory_server::proto::rr::rdata::{AAAA, SRV, TXT};
+    use hickory_server::proto::rr::Record;
+    use tokio::sync::watch;
+    use crate::cache::db::CacheDb;
+    use crate::cache::hash::HashFields;
+    use crate::cache_manager::CacheSnapshot;
+    use crate::config::CacheConfig;
+
+    fn name(s: &str) -> Name {
+        Name::from_ascii(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_register_and_remove() {
+        let (snapshot_tx, _snapshot_rx) = watch::channel(CacheSnapshot::new(Vec::new(), HashFields::default()));
+        let cache = CacheHandle::spawn(CacheDb::open(":memory:").unwrap(), snapshot_tx, &CacheConfig::default());
+        let signer = tsig::tests::key("host.subnet.example.").signer().unwrap();
+        let updates = Updates {
+            cache: cache.clone(),
+            keys: vec![signer.clone()],
+            origin: name("subnet.example."),
+            prefix: "fd00::/64".parse().unwrap(),
+        };
+        let peer: SocketAddr = "[fd00::30]:5353".parse().unwrap();
+
+        let update = |records: Vec<Record>, signed: bool| {
+            let mut request = Message::new();
+            request
+                .set_id(9)
+                .set_message_type(MessageType::Query)
+                .set_op_code(OpCode::Update)
+                .add_query(Query::query(name("subnet.example."), RecordType::SOA))
+                .add_name_servers(records);
+            let verifier = signed.then(|| request.finalize(&signer, Utc::now().timestamp() as u32).unwrap().unwrap());
+            (request.to_vec().unwrap(), request, verifier)
+        };
+        let send = async |records: Vec<Record>, signed: bool| {
+            let (bytes, request, verifier) = update(records, signed);
+            let response = updates.respond(&request, &bytes, peer).await;
+            match verifier {
+                Some(mut verify) => verify(&response).unwrap().into_message().response_code(),
+                None => Message::from_vec(&response).unwrap().response_code(),
+            }
+        };
+
+        let instance = Name::from_labels(["Build Box", "_ssh", "_tcp", "subnet", "example"].map(str::as_bytes)).unwrap();
+        let host = name("builder.subnet.example.");
+        let register = vec![
+            Record::from_rdata(instance.clone(), 300, RData::SRV(SRV::new(0, 0, 22, host.clone()))),
+            Record::from_rdata(instance.clone(), 300, RData::TXT(TXT::new(vec!["u=ci".to_string()]))),
+            Record::from_rdata(host.clone(), 300, RData::AAAA(AAAA("fd00::40".parse().unwrap()))),
+        ];
+        assert_eq!(send(register.clone(), false).await, ResponseCode::Refused);
+
+        // Already browsed over mDNS: the same type, so no type conflict
+        let browsed = ServiceEntry {
+            service_type: "_ssh._tcp.local.".to_string(),
+            instance_name: "Build Box._ssh._tcp.local.".to_string(),
+            hostname: "builder.local.".to_string(),
+            addresses: vec!["fd00::40".parse().unwrap()],
+            port: 2222,
+            txt: HashMap::new(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: ServiceSource::Mdns,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+        cache.upsert(browsed).await.unwrap();
+        assert_eq!(send(register, true).await, ResponseCode::NoError);
+
+        let entry = cache.get_one("Build Box._ssh._tcp.local.".to_string()).await.unwrap().unwrap();
+        assert_eq!((entry.hostname.as_str(), entry.port, entry.source), ("builder.local.", 22, ServiceSource::Dns));
+        assert_eq!(entry.addresses, vec!["fd00::40".parse::<IpAddr>().unwrap()]);
+        assert_eq!(entry.txt.get("u").map(String::as_str), Some("ci"));
+        assert_eq!(entry.service_type, "_ssh._tcp.local.", "stored as the browser stores types");
+
+        // Addresses outside the prefix are refused
+        let outside = vec![
+            Record::from_rdata(instance.clone(), 300, RData::SRV(SRV::new(0, 0, 22, host.clone()))),
+            Record::from_rdata(host, 300, RData::AAAA(AAAA("fd99::40".parse().unwrap()))),
+        ];
+        assert_eq!(send(outside, true).await, ResponseCode::Refused);
+
+        let mut delete = Record::with(instance, RecordType::ANY, 0);
+        delete.set_dns_class(DNSClass::ANY);
+        assert_eq!(send(vec![delete], true).await, ResponseCode::NoError);
+        assert!(!cache.exists("Build Box._ssh._tcp.local.".to_string(), false).await.unwrap());
+    }
+}
@@ -0,0 +1,667 @@
+//! The authority's zone, synthesized from the cache: an AAAA (or A) per
+//! host address, and SRV, TXT, and PTR records per service as in DNS-SD, so
+//! clients can browse the zone with unicast DNS-SD (RFC 6763). Also the
+//! reverse zone of the subnet prefix, mapping addresses back to hosts.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use anyhow::{Context, Result};
+use ipnet::Ipv6Net;
+use hickory_server::authority::{
+    AuthLookup, Authority, LookupError, LookupOptions, LookupRecords, MessageRequest, UpdateResult, ZoneType,
+};
+use hickory_server::proto::op::ResponseCode;
+use hickory_server::proto::rr::dnssec::rdata::DNSSECRData;
+use hickory_server::proto::rr::rdata::{A, AAAA, NS, PTR, SOA, SRV, TXT};
+use hickory_server::proto::rr::{LowerName, Name, RData, Record, RecordSet, RecordType, RrKey};
+use hickory_server::server::RequestInfo;
+use hickory_server::store::in_memory::InMemoryAuthority;
+use shared::names::InstanceName;
+use shared::types::ServiceEntry;
+use crate::addresses::AddressPreference;
+use super::dnssec::{Nsec3Chain, ZoneSigner};
+
+/// Service type enumeration, RFC 6763 section 9
+const SERVICES_META: &str = "_services._dns-sd._udp";
+/// Browsing domain queries, RFC 6763 section 11: default browse, browse,
+/// and legacy browse. Each points at the zone itself.
+const BROWSE_DOMAINS: [&str; 3] = ["db._dns-sd._udp", "b._dns-sd._udp", "lb._dns-sd._udp"];
+
+/// SOA timers for secondaries; resolvers only use `minimum`
+const SOA_REFRESH: i32 = 3600;
+const SOA_RETRY: i32 = 600;
+const SOA_EXPIRE: i32 = 86400;
+
+/// Versions of the zone kept for IXFR. A secondary further behind gets the
+/// whole zone.
+const HISTORY_LEN: usize = 32;
+
+/// What goes into the zone besides the services
+#[derive(Debug, Clone)]
+pub struct ZoneInfo {
+    /// e.g. `subnet.example.`
+    pub origin: Name,
+    /// This daemon's host, named by the SOA and NS records
+    pub nameserver: Name,
+    /// AAAA of `nameserver`, if the configured address parses
+    pub nameserver_address: Option<Ipv6Addr>,
+    /// TTL of every record, and the negative-caching TTL
+    pub ttl: u32,
+    /// Prefix to serve the reverse zone of, if any
+    pub prefix: Option<Ipv6Net>,
+    /// Orders each host's AAAA and A records, which otherwise follow the
+    /// advertised order
+    pub address_preference: Option<AddressPreference>,
+}
+
+impl ZoneInfo {
+    pub fn new(zone: &str, hostname: &str, address: &str, ttl: u32, prefix: Option<Ipv6Net>) -> Result<Self> {
+        let mut origin = Name::from_utf8(zone).with_context(|| format!("Invalid DNS zone: {}", zone))?;
+        origin.set_fqdn(true);
+        let label = hostname.split('.').next().unwrap_or(hostname);
+        let nameserver = Name::from_labels([label])
+            .and_then(|name| name.append_domain(&origin))
+            .with_context(|| format!("Hostname {} doesn't fit in zone {}", hostname, zone))?;
+        // `authority.address` may carry a prefix length
+        let nameserver_address = address
+            .split('/')
+            .next()
+            .and_then(|a| a.parse().ok());
+        Ok(Self { origin, nameserver, nameserver_address, ttl, prefix, address_preference: None })
+    }
+
+    /// Answer with each host's addresses in `preference` order, as
+    /// `?single_address` picks from
+    pub fn with_address_preference(self, preference: AddressPreference) -> Self {
+        Self { address_preference: Some(preference), ..self }
+    }
+}
+
+/// `name.local.` as the same name under `origin`
+pub(crate) fn host_name(hostname: &str, origin: &Name) -> Option<Name> {
+    let relative = hostname
+        .trim_end_matches('.')
+        .trim_end_matches(".local");
+    Name::from_utf8(relative).ok()?.append_domain(origin).ok()
+}
+
+/// A name relative to the zone, such as `_services._dns-sd._udp`, under `origin`
+fn type_name(relative: &str, origin: &Name) -> Option<Name> {
+    Name::from_ascii(relative).ok()?.append_domain(origin).ok()
+}
+
+/// Service type under `origin`. Browsed services store the type with its
+/// mDNS domain (`_http._tcp.local.`), which is dropped; a type stored
+/// without one (`_http._tcp`) is taken as it is.
+fn service_type_name(service_type: &str, origin: &Name) -> Option<Name> {
+    match InstanceName::with_type("_", service_type) {
+        Some(name) => type_name(&name.service_type, origin),
+        None => type_name(service_type.trim_end_matches('.'), origin),
+    }
+}
+
+/// Instance name under `origin`. The instance label is taken as raw bytes,
+/// since DNS-SD allows spaces and dots in it.
+fn instance_name(service: &ServiceEntry, origin: &Name) -> Option<Name> {
+    let short_name = InstanceName::parse(&service.instance_name)?.short_name;
+    Name::from_labels([short_name.as_bytes()])
+        .ok()?
+        .append_name(&service_type_name(&service.service_type, origin)?)
+        .ok()
+}
+
+/// SOA and NS of a zone at `origin` served by this host
+fn apex(origin: &Name, info: &ZoneInfo, serial: u32) -> Vec<Record> {
+    let hostmaster = Name::from_ascii("hostmaster")
+        .and_then(|name| name.append_domain(&info.origin))
+        .unwrap_or_else(|_| info.origin.clone());
+    vec![
+        Record::from_rdata(
+            origin.clone(),
+            info.ttl,
+            RData::SOA(SOA::new(
+                info.nameserver.clone(),
+                hostmaster,
+                serial,
+                SOA_REFRESH,
+                SOA_RETRY,
+                SOA_EXPIRE,
+                info.ttl,
+            )),
+        ),
+        Record::from_rdata(origin.clone(), info.ttl, RData::NS(NS(info.nameserver.clone()))),
+    ]
+}
+
+/// Records for the zone. Dead services, and services without an address or
+/// with names that don't fit in DNS, are left out.
+pub fn records(services: &[ServiceEntry], info: &ZoneInfo, serial: u32) -> Vec<Record> {
+    let origin = &info.origin;
+    let ttl = info.ttl;
+    let mut records = apex(origin, info, serial);
+    if let Some(addr) = info.nameserver_address {
+        records.push(Record::from_rdata(info.nameserver.clone(), ttl, RData::AAAA(AAAA(addr))));
+    }
+    for browse in BROWSE_DOMAINS {
+        if let Some(name) = type_name(browse, origin) {
+            records.push(Record::from_rdata(name, ttl, RData::PTR(PTR(origin.clone()))));
+        }
+    }
+    let meta = type_name(SERVICES_META, origin);
+
+    for service in services.iter().filter(|s| s.alive && !s.addresses.is_empty()) {
+        let names = (
+            host_name(&service.hostname, origin),
+            service_type_name(&service.service_type, origin),
+            instance_name(service, origin),
+        );
+        let (Some(host), Some(service_type), Some(instance)) = names else {
+            tracing::debug!("Leaving {} out of the DNS zone: name doesn't fit", service.instance_name);
+            continue;
+        };
+
+        let mut addresses = service.addresses.clone();
+        if let Some(preference) = &info.address_preference {
+            preference.sort(&mut addresses);
+        }
+        for addr in &addresses {
+            let rdata = match addr {
+                IpAddr::V4(v4) => RData::A(A(*v4)),
+                IpAddr::V6(v6) => RData::AAAA(AAAA(*v6)),
+            };
+            records.push(Record::from_rdata(host.clone(), ttl, rdata));
+        }
+        if let Some(meta) = &meta {
+            // Repeats of the same type collapse into one record in the zone
+            records.push(Record::from_rdata(meta.clone(), ttl, RData::PTR(PTR(service_type.clone()))));
+        }
+        records.push(Record::from_rdata(service_type, ttl, RData::PTR(PTR(instance.clone()))));
+        records.push(Record::from_rdata(
+            instance.clone(),
+            ttl,
+            RData::SRV(SRV::new(0, 0, service.port, host)),
+        ));
+        // DNS-SD wants one empty string when there are no TXT entries
+        let mut txt: Vec<String> = service.txt.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        txt.sort();
+        if txt.is_empty() {
+            txt.push(String::new());
+        }
+        records.push(Record::from_rdata(instance, ttl, RData::TXT(TXT::new(txt))));
+    }
+    records
+}
+
+/// The `ip6.arpa` zone covering `prefix`. Zones are cut at nibble
+/// boundaries, so a prefix length that isn't a multiple of 4 is rounded down.
+pub fn reverse_origin(prefix: &Ipv6Net) -> Name {
+    let nibbles = usize::from(prefix.prefix_len() / 4);
+    // The full name has 32 nibble labels, then `ip6` and `arpa`
+    Name::from(prefix.network()).trim_to(nibbles + 2)
+}
+
+/// Records for the reverse zone of `prefix`: a PTR from each address of an
+/// alive service inside the prefix to its host's name in the forward zone
+pub fn reverse_records(services: &[ServiceEntry], info: &ZoneInfo, prefix: &Ipv6Net, serial: u32) -> Vec<Record> {
+    let mut records = apex(&reverse_origin(prefix), info, serial);
+    if let Some(addr) = info.nameserver_address.filter(|a| prefix.contains(a)) {
+        records.push(Record::from_rdata(Name::from(addr), info.ttl, RData::PTR(PTR(info.nameserver.clone()))));
+    }
+    for service in services.iter().filter(|s| s.alive) {
+        let Some(host) = host_name(&service.hostname, &info.origin) else {
+            continue;
+        };
+        let in_prefix = service.addresses.iter().filter_map(|addr| match addr {
+            IpAddr::V6(v6) if prefix.contains(v6) => Some(*v6),
+            _ => None,
+        });
+        for addr in in_prefix {
+            records.push(Record::from_rdata(Name::from(addr), info.ttl, RData::PTR(PTR(host.clone()))));
+        }
+    }
+    records
+}
+
+/// A version's SOA and the rest of its records
+fn split_soa(records: &[Record]) -> (Record, Vec<Record>) {
+    let (soa, rest): (Vec<_>, Vec<_>) = records.iter().cloned().partition(|r| r.record_type() == RecordType::SOA);
+    (soa.into_iter().next().expect("every version has an SOA"), rest)
+}
+
+/// One build of the zone
+struct Version {
+    serial: u32,
+    /// Every record, SOA and signatures included, for transfers
+    records: Arc<Vec<Record>>,
+    zone: InMemoryAuthority,
+    /// When signed, the NSEC3 chain and its RRsets by owner name
+    denial: Option<(Nsec3Chain, HashMap<LowerName, Arc<RecordSet>>)>,
+}
+
+/// Bits of the SOA serial below the change sequence number, counting
+/// rebuilds while it stands still
+const SERIAL_SHIFT: u32 = 4;
+
+/// The serial of a build at change sequence number `seq`, after `last`: the
+/// sequence number shifted up by `SERIAL_SHIFT`, or `last` plus one if that
+/// isn't ahead of it (RFC 1982), as when only the signatures change or the
+/// cache is held in memory.
+fn next_serial(last: u32, seq: u64) -> u32 {
+    let serial = (seq as u32).wrapping_shl(SERIAL_SHIFT);
+    if (serial.wrapping_sub(last) as i32) > 0 {
+        serial
+    } else {
+        last.wrapping_add(1)
+    }
+}
+
+/// A zone rebuilt from each cache snapshot. Lookups go to the latest build.
+pub struct CacheAuthority {
+    origin: LowerName,
+    current: RwLock<Arc<Version>>,
+    /// Earlier versions with different content by serial, newest last
+    history: Mutex<VecDeque<(u32, Arc<Vec<Record>>)>>,
+    signer: Option<ZoneSigner>,
+    /// Unsigned records of the latest build besides its SOA, sorted, and
+    /// when it was built
+    built: Mutex<Option<(Vec<Record>, Instant)>>,
+}
+
+impl CacheAuthority {
+    pub fn new(origin: &Name) -> Self {
+        let zone = InMemoryAuthority::empty(origin.clone(), ZoneType::Primary, false);
+        Self {
+            origin: LowerName::new(origin),
+            current: RwLock::new(Arc::new(Version { serial: 0, records: Arc::new(Vec::new()), zone, denial: None })),
+            history: Mutex::new(VecDeque::with_capacity(HISTORY_LEN)),
+            signer: None,
+            built: Mutex::new(None),
+        }
+    }
+
+    /// Sign every build with `signer`
+    pub fn signed(self, signer: ZoneSigner) -> Self {
+        Self { signer: Some(signer), ..self }
+    }
+
+    /// Replace the zone's records, built at change sequence number `seq`.
+    /// Nothing changes if the records would be the same, unless the
+    /// signatures are due for replacing.
+    ///
+    /// The SOA serial follows `seq` (see `next_serial`), so it carries on
+    /// from the database across restarts and an unchanged cache keeps its
+    /// serial. A signature refresh still needs a new serial for secondaries
+    /// to fetch it; it takes one of the low bits, which a restart forgets,
+    /// so until the next cache change a secondary may hold a higher serial
+    /// than served, for the same content. A version replaced only by its
+    /// refresh isn't kept for IXFR.
+    pub fn replace(&self, seq: u64, records: impl FnOnce(u32) -> Vec<Record>) {
+        let mut built = self.built.lock().unwrap();
+        let last = self.current();
+        let serial = next_serial(last.serial, seq);
+        let records = records(serial);
+
+        let mut content: Vec<Record> = records.iter().filter(|r| r.record_type() != RecordType::SOA).cloned().collect();
+        content.sort();
+        content.dedup();
+        let mut refresh = false;
+        if let Some((previous, at)) = &*built {
+            let fresh = self.signer.as_ref().is_none_or(|s| at.elapsed() < s.refresh_after());
+            if *previous == content {
+                if fresh {
+                    return;
+                }
+                refresh = true;
+            }
+        }
+
+        let (records, chain) = match &self.signer {
+            Some(signer) => match signer.sign(records) {
+                Ok((records, chain)) => (records, Some(chain)),
+                Err(e) => {
+                    tracing::error!("Failed to sign zone {}, serving the previous version: {:#}", self.origin, e);
+                    return;
+                }
+            },
+            None => (records, None),
+        };
+        *built = Some((content, Instant::now()));
+
+        let mut zone = InMemoryAuthority::empty(self.origin.clone().into(), ZoneType::Primary, false);
+        for record in records.iter().filter(|r| r.record_type() != RecordType::RRSIG) {
+            zone.upsert_mut(record.clone(), serial);
+        }
+        // Signatures are kept with the RRsets they cover
+        let rrsets = zone.records_get_mut();
+        for record in records.iter().filter(|r| r.record_type() == RecordType::RRSIG) {
+            let Some(RData::DNSSEC(DNSSECRData::RRSIG(rrsig))) = record.data() else {
+                continue;
+            };
+            if let Some(rrset) = rrsets.get_mut(&RrKey::new(LowerName::new(record.name()), rrsig.type_covered())) {
+                Arc::make_mut(rrset).insert_rrsig(record.clone());
+            }
+        }
+        let denial = chain.map(|chain| {
+            let nsec3 = rrsets
+                .iter()
+                .filter(|(key, _)| key.record_type == RecordType::NSEC3)
+                .map(|(key, rrset)| (key.name.clone(), rrset.clone()))
+                .collect();
+            (chain, nsec3)
+        });
+        *self.current.write().unwrap() = Arc::new(Version { serial, records: Arc::new(records), zone, denial });
+
+        if !refresh && !last.records.is_empty() {
+            let mut history = self.history.lock().unwrap();
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back((last.serial, last.records.clone()));
+        }
+    }
+
+    /// Answer records of a zone transfer. With the serial of a version the
+    /// secondary holds, one IXFR difference sequence (RFC 1995) from that
+    /// version to the current one, or just the SOA if it is current;
+    /// otherwise the whole zone between two SOAs, as in AXFR. Empty before
+    /// the zone is first built.
+    pub fn transfer(&self, since: Option<u32>) -> Vec<Record> {
+        let current = self.current();
+        if current.records.is_empty() {
+            return Vec::new();
+        }
+        let (soa, rest) = split_soa(&current.records);
+        if since == Some(current.serial) {
+            return vec![soa];
+        }
+
+        let mut answers = vec![soa.clone()];
+        let history = self.history.lock().unwrap();
+        match history.iter().find(|(s, _)| Some(*s) == since) {
+            Some((_, old)) => {
+                let (old_soa, old_rest) = split_soa(old);
+                answers.push(old_soa);
+                answers.extend(old_rest.iter().filter(|r| !rest.contains(r)).cloned());
+                answers.push(soa.clone());
+                answers.extend(rest.iter().filter(|r| !old_rest.contains(r)).cloned());
+            }
+            None => answers.extend(rest.iter().cloned()),
+        }
+        answers.push(soa);
+        answers
+    }
+
+    fn current(&self) -> Arc<Version> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Authority for CacheAuthority {
+    type Lookup = AuthLookup;
+
+    fn zone_type(&self) -> ZoneType {
+        ZoneType::Primary
+    }
+
+    fn is_axfr_allowed(&self) -> bool {
+        false
+    }
+
+    /// The zone follows the cache; register services over the API instead
+    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+        Err(ResponseCode::Refused)
+    }
+
+    fn origin(&self) -> &LowerName {
+        &self.origin
+    }
+
+    async fn lookup(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        self.current().zone.lookup(name, rtype, lookup_options).await
+    }
+
+    async fn search(
+        &self,
+        request: RequestInfo<'_>,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        self.current().zone.search(request, lookup_options).await
+    }
+
+    async fn get_nsec_records(
+        &self,
+        name: &LowerName,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        let version = self.current();
+        let Some((chain, nsec3)) = &version.denial else {
+            return version.zone.get_nsec_records(name, lookup_options).await;
+        };
+        let rrsets = chain
+            .proof(&name.into())
+            .iter()
+            .filter_map(|owner| nsec3.get(&LowerName::new(owner)).cloned())
+            .collect();
+        Ok(AuthLookup::answers(LookupRecords::many(lookup_options, rrsets), None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn service(instance_name: &str, hostname: &str, addresses: &[&str]) -> ServiceEntry {
+        ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: hostname.to_string(),
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            port: 8080,
+            txt: [("path".to_string(), "/".to_string())].into(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_utf8(name).unwrap()
+    }
+
+    fn lookup_rdata(authority: &CacheAuthority, name: &Name, rtype: RecordType) -> Vec<RData> {
+        let name = LowerName::new(name);
+        let lookup = futures::executor::block_on(authority.lookup(&name, rtype, LookupOptions::default()));
+        lookup.map_or_else(|_| Vec::new(), |l| l.iter().filter_map(|r| r.data().cloned()).collect())
+    }
+
+    #[test]
+    fn test_zone_follows_services() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1/64", 60, None).unwrap();
+        let authority = CacheAuthority::new(&info.origin);
+        let services = vec![
+            service("Web UI._http._tcp.local.", "nas.local.", &["fd00::10", "fd00::11"]),
+            service("gone._http._tcp.local.", "old.local.", &[]),
+        ];
+        authority.replace(1, |serial| records(&services, &info, serial));
+        let mut instance = Name::from_labels(["Web UI", "_http", "_tcp", "subnet", "example"].map(str::as_bytes)).unwrap();
+        instance.set_fqdn(true);
+
+        assert_eq!(lookup_rdata(&authority, &name("nas.subnet.example."), RecordType::AAAA).len(), 2);
+        assert!(lookup_rdata(&authority, &name("old.subnet.example."), RecordType::AAAA).is_empty());
+        assert_eq!(
+            lookup_rdata(&authority, &name("_http._tcp.subnet.example."), RecordType::PTR),
+            vec![RData::PTR(PTR(instance.clone()))]
+        );
+        let srv = lookup_rdata(&authority, &instance, RecordType::SRV);
+        assert_eq!(srv, vec![RData::SRV(SRV::new(0, 0, 8080, name("nas.subnet.example.")))]);
+        assert_eq!(lookup_rdata(&authority, &name("authority.subnet.example."), RecordType::AAAA).len(), 1);
+        assert_eq!(
+            lookup_rdata(&authority, &name("_services._dns-sd._udp.subnet.example."), RecordType::PTR),
+            vec![RData::PTR(PTR(name("_http._tcp.subnet.example.")))]
+        );
+        assert_eq!(
+            lookup_rdata(&authority, &name("b._dns-sd._udp.subnet.example."), RecordType::PTR),
+            vec![RData::PTR(PTR(name("subnet.example.")))]
+        );
+
+        authority.replace(2, |serial| records(&[], &info, serial));
+        assert!(lookup_rdata(&authority, &name("nas.subnet.example."), RecordType::AAAA).is_empty());
+    }
+
+    #[test]
+    fn test_addresses_in_preference_order() {
+        use crate::addresses::default_preference;
+
+        let prefix: Ipv6Net = "fd00:1234:5678:1::/64".parse().unwrap();
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None)
+            .unwrap()
+            .with_address_preference(AddressPreference::new(default_preference(), prefix));
+        let advertised = ["fe80::1", "2001:db8::1", "fd00:1234:5678:1::7"];
+        let services = vec![service("web._http._tcp.local.", "nas.local.", &advertised)];
+        let authority = CacheAuthority::new(&info.origin);
+        authority.replace(1, |serial| records(&services, &info, serial));
+
+        // The order `?single_address` picks the first of
+        let preferred = ["fd00:1234:5678:1::7", "2001:db8::1", "fe80::1"];
+        assert_eq!(
+            lookup_rdata(&authority, &name("nas.subnet.example."), RecordType::AAAA),
+            preferred.map(|addr| RData::AAAA(AAAA(addr.parse().unwrap())))
+        );
+    }
+
+    #[test]
+    fn test_mdns_type_names_under_zone() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let authority = CacheAuthority::new(&info.origin);
+        let browsed = ServiceEntry {
+            service_type: "_http._tcp.local.".to_string(),
+            ..service("web._http._tcp.local.", "nas.local.", &["fd00::10"])
+        };
+        authority.replace(1, |serial| records(&[browsed], &info, serial));
+
+        assert_eq!(
+            lookup_rdata(&authority, &name("_http._tcp.subnet.example."), RecordType::PTR),
+            vec![RData::PTR(PTR(name("web._http._tcp.subnet.example.")))]
+        );
+        assert_eq!(
+            lookup_rdata(&authority, &name("_services._dns-sd._udp.subnet.example."), RecordType::PTR),
+            vec![RData::PTR(PTR(name("_http._tcp.subnet.example.")))]
+        );
+        assert_eq!(lookup_rdata(&authority, &name("web._http._tcp.subnet.example."), RecordType::SRV).len(), 1);
+        assert!(lookup_rdata(&authority, &name("_http._tcp.local.subnet.example."), RecordType::PTR).is_empty());
+    }
+
+    #[test]
+    fn test_signed_zone() {
+        use hickory_server::proto::rr::dnssec::SupportedAlgorithms;
+
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let signer = crate::dns::dnssec::tests::key().signer(&info.origin).unwrap();
+        let authority = CacheAuthority::new(&info.origin).signed(signer);
+        let services = vec![service("web._http._tcp.local.", "nas.local.", &["fd00::10"])];
+        authority.replace(1, |serial| records(&services, &info, serial));
+        let serial = |authority: &CacheAuthority| authority.transfer(None)[0].clone();
+        let first = serial(&authority);
+        // Same services: same version, signatures and all
+        authority.replace(1, |serial| records(&services, &info, serial));
+        assert_eq!(serial(&authority), first);
+
+        let dnssec = LookupOptions::for_dnssec(true, SupportedAlgorithms::all());
+        let lookup = |name: &Name, rtype| {
+            let lookup = futures::executor::block_on(authority.lookup(&LowerName::new(name), rtype, dnssec));
+            lookup.map_or_else(|_| Vec::new(), |l| l.iter().map(Record::record_type).collect::<Vec<_>>())
+        };
+        assert_eq!(lookup(&name("nas.subnet.example."), RecordType::AAAA), [RecordType::AAAA, RecordType::RRSIG]);
+        assert_eq!(lookup(&info.origin, RecordType::DNSKEY), [RecordType::DNSKEY, RecordType::RRSIG]);
+
+        let missing = LowerName::new(&name("printer.subnet.example."));
+        let denial = futures::executor::block_on(authority.get_nsec_records(&missing, dnssec)).unwrap();
+        let types: Vec<_> = denial.iter().map(Record::record_type).collect();
+        assert!(types.contains(&RecordType::NSEC3) && types.contains(&RecordType::RRSIG));
+    }
+
+    fn soa_serials(answers: &[Record]) -> Vec<u32> {
+        answers
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_serial_follows_seq() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let authority = CacheAuthority::new(&info.origin);
+        let mut services = vec![service("web._http._tcp.local.", "nas.local.", &["fd00::10"])];
+        authority.replace(5, |serial| records(&services, &info, serial));
+        assert_eq!(soa_serials(&authority.transfer(None)), [80, 80]);
+
+        // The cache held in memory changes without moving the sequence number
+        services.push(service("print._ipp._tcp.local.", "printer.local.", &["fd00::20"]));
+        authority.replace(5, |serial| records(&services, &info, serial));
+        assert_eq!(soa_serials(&authority.transfer(None)), [81, 81]);
+
+        services.pop();
+        authority.replace(6, |serial| records(&services, &info, serial));
+        assert_eq!(soa_serials(&authority.transfer(None)), [96, 96]);
+        assert_eq!(soa_serials(&authority.transfer(Some(80))), [96, 80, 96, 96]);
+
+        // A fresh start from the same database serves the same serial
+        let restarted = CacheAuthority::new(&info.origin);
+        restarted.replace(6, |serial| records(&services, &info, serial));
+        assert_eq!(soa_serials(&restarted.transfer(Some(96))), [96]);
+    }
+
+    #[test]
+    fn test_signature_refresh_not_kept() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let signer = crate::dns::dnssec::tests::expiring_key().signer(&info.origin).unwrap();
+        let authority = CacheAuthority::new(&info.origin).signed(signer);
+        let mut services = vec![service("web._http._tcp.local.", "nas.local.", &["fd00::10"])];
+        authority.replace(1, |serial| records(&services, &info, serial));
+        services.push(service("print._ipp._tcp.local.", "printer.local.", &["fd00::20"]));
+        authority.replace(2, |serial| records(&services, &info, serial));
+
+        // Same services, signatures due: a new serial but no new history
+        authority.replace(2, |serial| records(&services, &info, serial));
+        assert_eq!(soa_serials(&authority.transfer(None))[0], 33);
+        assert_eq!(authority.history.lock().unwrap().iter().map(|(s, _)| *s).collect::<Vec<_>>(), [16]);
+        assert_eq!(soa_serials(&authority.transfer(Some(16)))[..2], [33, 16]);
+    }
+
+    #[test]
+    fn test_reverse_zone_covers_prefix() {
+        let prefix: Ipv6Net = "fd00:1234:5678:1::/62".parse().unwrap();
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00:1234:5678:1::1", 60, Some(prefix)).unwrap();
+        assert_eq!(reverse_origin(&prefix), name("0.0.0.8.7.6.5.4.3.2.1.0.0.d.f.ip6.arpa."));
+
+        let authority = CacheAuthority::new(&reverse_origin(&prefix));
+        let services = vec![service("web._http._tcp.local.", "nas.local.", &["fd00:1234:5678:1::10", "fd99::10"])];
+        authority.replace(1, |serial| reverse_records(&services, &info, &prefix, serial));
+
+        let inside: std::net::Ipv6Addr = "fd00:1234:5678:1::10".parse().unwrap();
+        assert_eq!(
+            lookup_rdata(&authority, &Name::from(inside), RecordType::PTR),
+            vec![RData::PTR(PTR(name("nas.subnet.example.")))]
+        );
+        let outside: std::net::Ipv6Addr = "fd99::10".parse().unwrap();
+        assert!(lookup_rdata(&authority, &Name::from(outside), RecordType::PTR).is_empty());
+    }
+}
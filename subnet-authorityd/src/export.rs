@@ -0,0 +1,239 @@
+//! The cache in formats other DNS software reads, so admins can feed the
+//! discovered names into existing infrastructure instead of running the
+//! embedded DNS server.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+use std::net::IpAddr;
+use hickory_server::proto::rr::{Name, RData, Record};
+use shared::types::ServiceEntry;
+use crate::dns::zone::{self, ZoneInfo};
+
+/// The zone the embedded server would serve, as an RFC 1035 master file
+/// (BIND format)
+pub fn zone_file(services: &[ServiceEntry], info: &ZoneInfo, serial: u32) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "; {} exported by subnet-authorityd", info.origin);
+    let _ = writeln!(out, "$ORIGIN {}", presentation_name(&info.origin));
+    let _ = writeln!(out, "$TTL {}", info.ttl);
+    // Repeats collapse into one record in a zone; keep the file free of them
+    let mut seen = HashSet::new();
+    for line in zone::records(services, info, serial).iter().map(presentation) {
+        if seen.insert(line.clone()) {
+            let _ = writeln!(out, "{}", line);
+        }
+    }
+    out
+}
+
+/// `host-record` lines for dnsmasq, which also answers the reverse lookups
+pub fn dnsmasq(services: &[ServiceEntry], info: &ZoneInfo) -> String {
+    let mut out = format!("# {} exported by subnet-authorityd\n", info.origin);
+    for (host, addresses) in hosts(services, info) {
+        let host = presentation_name(&host);
+        for addr in addresses {
+            let _ = writeln!(out, "host-record={},{},{}", host.trim_end_matches('.'), addr, info.ttl);
+        }
+    }
+    out
+}
+
+/// A `server:` clause of `local-data` and `local-data-ptr` for unbound
+pub fn unbound(services: &[ServiceEntry], info: &ZoneInfo) -> String {
+    let mut out = format!("# {} exported by subnet-authorityd\nserver:\n", info.origin);
+    for (host, addresses) in hosts(services, info) {
+        let host = presentation_name(&host);
+        for addr in addresses {
+            let rtype = if addr.is_ipv4() { "A" } else { "AAAA" };
+            let _ = writeln!(out, "    local-data: \"{} {} IN {} {}\"", host, info.ttl, rtype, addr);
+            let _ = writeln!(out, "    local-data-ptr: \"{} {} {}\"", addr, info.ttl, host);
+        }
+    }
+    out
+}
+
+/// `/etc/hosts` lines, one per address of each host
+pub fn hosts_file(services: &[ServiceEntry], info: &ZoneInfo) -> String {
+    let mut out = format!("# {} exported by subnet-authorityd\n", info.origin);
+    for (host, addresses) in hosts(services, info) {
+        let host = presentation_name(&host);
+        for addr in addresses {
+            let _ = writeln!(out, "{}\t{}", addr, host.trim_end_matches('.'));
+        }
+    }
+    out
+}
+
+/// Addresses of each alive service's host under the zone, in order. Hosts
+/// offering several services are listed once.
+fn hosts(services: &[ServiceEntry], info: &ZoneInfo) -> BTreeMap<Name, BTreeSet<IpAddr>> {
+    let mut hosts: BTreeMap<Name, BTreeSet<IpAddr>> = BTreeMap::new();
+    for service in services.iter().filter(|s| s.alive && !s.addresses.is_empty()) {
+        if let Some(host) = zone::host_name(&service.hostname, &info.origin) {
+            hosts.entry(host).or_default().extend(&service.addresses);
+        }
+    }
+    hosts
+}
+
+/// One record as a master file line, names fully qualified
+fn presentation(record: &Record) -> String {
+    let rdata = match record.data() {
+        Some(RData::SOA(soa)) => format!(
+            "{} {} {} {} {} {} {}",
+            presentation_name(soa.mname()),
+            presentation_name(soa.rname()),
+            soa.serial(),
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            soa.minimum()
+        ),
+        Some(RData::NS(ns)) => presentation_name(&ns.0),
+        Some(RData::PTR(ptr)) => presentation_name(&ptr.0),
+        Some(RData::SRV(srv)) => format!(
+            "{} {} {} {}",
+            srv.priority(),
+            srv.weight(),
+            srv.port(),
+            presentation_name(srv.target())
+        ),
+        Some(RData::TXT(txt)) => txt.iter().map(|s| quoted(s)).collect::<Vec<_>>().join(" "),
+        Some(other) => other.to_string(),
+        None => String::new(),
+    };
+    format!(
+        "{} {} {} {} {}",
+        presentation_name(record.name()),
+        record.ttl(),
+        record.dns_class(),
+        record.record_type(),
+        rdata
+    )
+}
+
+/// A name as RFC 1035 section 5.1 writes it. DNS-SD instance labels carry
+/// spaces, dots, and UTF-8, which are escaped.
+fn presentation_name(name: &Name) -> String {
+    let mut out = String::new();
+    for label in name.iter() {
+        out.push_str(&escape(label, b".\\\"();@$"));
+        out.push('.');
+    }
+    if out.is_empty() {
+        out.push('.');
+    }
+    out
+}
+
+/// A TXT string in quotes, with quotes and backslashes escaped
+fn quoted(text: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in text {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(char::from(b));
+            }
+            b' ' => out.push(' '),
+            _ => out.push_str(&escape(&[b], b"")),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Printable ASCII as is, and `special` bytes and anything else as `\DDD`
+/// (decimal), which every parser reads
+fn escape(bytes: &[u8], special: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if (0x21..=0x7e).contains(&b) && !special.contains(&b) {
+            out.push(char::from(b));
+        } else {
+            let _ = write!(out, "\\{:03}", b);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use hickory_server::proto::serialize::txt::Parser;
+
+    fn service(instance_name: &str, hostname: &str, address: &str) -> ServiceEntry {
+        ServiceEntry {
+            service_type: "_http._tcp".to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: hostname.to_string(),
+            addresses: vec![address.parse().unwrap()],
+            port: 8080,
+            txt: [("path".to_string(), "/\"quoted\"".to_string())].into(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[test]
+    fn test_zone_file_parses_back() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let services = vec![
+            service("printer._http._tcp.local.", "printer.local.", "fd00::20"),
+            service("scanner._http._tcp.local.", "printer.local.", "fd00::20"),
+        ];
+        let file = zone_file(&services, &info, 7);
+        let (origin, parsed) = Parser::new(file.as_str(), None, None).parse().unwrap();
+        assert_eq!(origin, info.origin);
+        let mut expected: Vec<_> = zone::records(&services, &info, 7)
+            .iter()
+            .map(|r| (r.name().clone(), r.record_type(), r.data().cloned()))
+            .collect();
+        expected.sort_by_key(|(name, rtype, data)| (name.clone(), *rtype, format!("{:?}", data)));
+        expected.dedup();
+        let mut records: Vec<_> = parsed
+            .values()
+            .flat_map(|set| set.records_without_rrsigs().map(|r| (r.name().clone(), r.record_type(), r.data().cloned())))
+            .collect();
+        records.sort_by_key(|(name, rtype, data)| (name.clone(), *rtype, format!("{:?}", data)));
+        assert_eq!(records, expected);
+        assert!(file.contains(r#"TXT "path=/\"quoted\"""#), "{}", file);
+
+        // DNS-SD instance labels are escaped as RFC 1035 section 5.1 allows
+        let services = [service("Web UI (Living Room)._http._tcp.local.", "nas.local.", "fd00::10")];
+        let file = zone_file(&services, &info, 7);
+        assert!(file.contains("Web\\032UI\\032\\040Living\\032Room\\041._http._tcp.subnet.example."), "{}", file);
+    }
+
+    #[test]
+    fn test_resolver_exports_list_each_host_once() {
+        let info = ZoneInfo::new("subnet.example", "authority", "fd00::1", 60, None).unwrap();
+        let mut dead = service("old._http._tcp.local.", "old.local.", "fd00::30");
+        dead.alive = false;
+        let services = vec![
+            service("printer._http._tcp.local.", "printer.local.", "fd00::20"),
+            service("scanner._http._tcp.local.", "printer.local.", "fd00::20"),
+            dead,
+        ];
+        let lines: Vec<String> = dnsmasq(&services, &info).lines().skip(1).map(String::from).collect();
+        assert_eq!(lines, ["host-record=printer.subnet.example,fd00::20,60"]);
+        let lines: Vec<String> = unbound(&services, &info).lines().skip(2).map(String::from).collect();
+        assert_eq!(
+            lines,
+            [
+                r#"    local-data: "printer.subnet.example. 60 IN AAAA fd00::20""#,
+                r#"    local-data-ptr: "fd00::20 60 printer.subnet.example.""#,
+            ]
+        );
+        let lines: Vec<String> = hosts_file(&services, &info).lines().skip(1).map(String::from).collect();
+        assert_eq!(lines, ["fd00::20\tprinter.subnet.example"]);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use shared::names::InstanceName;
+use shared::types::{ServiceEntry, ServiceSource};
+
+/// Default TTL for imported entries, matching what the browser records
+const IMPORT_TTL: u32 = 4500;
+
+/// The host that Avahi service files describe unless they name another one
+pub struct LocalHost {
+    /// Short hostname, substituted for `%h` in wildcard names
+    pub hostname: String,
+    /// Addresses used for services without an explicit `<host-name>`
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Load every `*.service` file in `dir`. Files that fail to parse are logged
+/// and skipped so one bad file doesn't block the rest.
+pub fn load_dir(dir: &Path, local: &LocalHost) -> Result<Vec<ServiceEntry>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read Avahi service directory: {}", dir.display()))?;
+
+    let mut services = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "service") {
+            continue;
+        }
+
+        let parsed = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .and_then(|xml| parse_service_group(&xml, local));
+
+        match parsed {
+            Ok(group) => {
+                tracing::info!("Imported {} service(s) from {}", group.len(), path.display());
+                services.extend(group);
+            }
+            Err(e) => tracing::warn!("Skipping {}: {:#}", path.display(), e),
+        }
+    }
+
+    Ok(services)
+}
+
+/// Convert an Avahi `<service-group>` document into cache entries, one per
+/// `<service>` element.
+pub fn parse_service_group(xml: &str, local: &LocalHost) -> Result<Vec<ServiceEntry>> {
+    // Avahi service files always carry a DOCTYPE referencing avahi-service.dtd
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(xml, options).context("Invalid XML")?;
+    let group = doc.root_element();
+    if !group.has_tag_name("service-group") {
+        bail!("Expected <service-group>, found <{}>", group.tag_name().name());
+    }
+
+    let name_node = child(group, "name").context("Missing <name>")?;
+    let mut name = name_node.text().unwrap_or_default().trim().to_string();
+    if name_node.attribute("replace-wildcards") == Some("yes") {
+        name = name.replace("%h", &local.hostname);
+    }
+
+    let now = Utc::now();
+    let mut services = Vec::new();
+
+    for service in group.children().filter(|n| n.has_tag_name("service")) {
+        // We only track IPv6; an IPv4-only service has nothing to offer
+        if service.attribute("protocol") == Some("ipv4") {
+            tracing::debug!("Skipping IPv4-only service in group {}", name);
+            continue;
+        }
+
+        let service_type = child_text(service, "type").context("Missing <type>")?;
+        let port = child_text(service, "port")
+            .context("Missing <port>")?
+            .parse::<u16>()
+            .context("Invalid <port>")?;
+        let domain = child_text(service, "domain-name").unwrap_or_else(|| "local".to_string());
+        let domain = domain.trim_end_matches('.');
+
+        let (hostname, addresses) = match child_text(service, "host-name") {
+            Some(host) => (format!("{}.", host.trim_end_matches('.')), Vec::new()),
+            None => (format!("{}.{}.", local.hostname, domain), local.addresses.clone()),
+        };
+
+        let txt: HashMap<String, String> = service
+            .children()
+            .filter(|n| n.has_tag_name("txt-record"))
+            .filter_map(|n| n.text())
+            .map(|record| match record.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                // A key without '=' is a boolean attribute
+                None => (record.to_string(), String::new()),
+            })
+            .collect();
+
+        let instance_name = InstanceName::new(&name, &service_type, domain);
+        services.push(ServiceEntry {
+            service_type: instance_name.full_type(),
+            instance_name: instance_name.to_string(),
+            hostname,
+            addresses,
+            port,
+            txt,
+            first_seen: now,
+            last_seen: now,
+            ttl: IMPORT_TTL,
+            alive: true,
+            source: ServiceSource::Import,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        });
+    }
+
+    Ok(services)
+}
+
+fn child<'a, 'input>(node: roxmltree::Node<'a, 'input>, tag: &str) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(tag))
+}
+
+fn child_text(node: roxmltree::Node, tag: &str) -> Option<String> {
+    child(node, tag)
+        .and_then(|n| n.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SFTP_GROUP: &str = r#"<?xml version="1.0" standalone='no'?>
+<!DOCTYPE service-group SYSTEM "avahi-service.dtd">
+<service-group>
+  <name replace-wildcards="yes">%h files</name>
+  <service>
+    <type>_sftp-ssh._tcp</type>
+    <port>22</port>
+    <txt-record>path=/srv</txt-record>
+    <txt-record>readonly</txt-record>
+  </service>
+  <service protocol="ipv6">
+    <type>_http._tcp</type>
+    <host-name>printer.local</host-name>
+    <port>631</port>
+  </service>
+  <service protocol="ipv4">
+    <type>_ipp._tcp</type>
+    <port>631</port>
+  </service>
+</service-group>
+"#;
+
+    fn local() -> LocalHost {
+        LocalHost {
+            hostname: "nas".to_string(),
+            addresses: vec!["fd00:1234:5678:1::1".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_parse_service_group() {
+        let services = parse_service_group(SFTP_GROUP, &local()).unwrap();
+        assert_eq!(services.len(), 2, "IPv4-only service should be skipped");
+
+        let sftp = &services[0];
+        assert_eq!(sftp.instance_name, "nas files._sftp-ssh._tcp.local.");
+        assert_eq!(sftp.service_type, "_sftp-ssh._tcp.local.");
+        assert_eq!(sftp.hostname, "nas.local.");
+        assert_eq!(sftp.addresses, local().addresses);
+        assert_eq!(sftp.port, 22);
+        assert_eq!(sftp.txt["path"], "/srv");
+        assert_eq!(sftp.txt["readonly"], "");
+        assert_eq!(sftp.source, ServiceSource::Import);
+
+        let http = &services[1];
+        assert_eq!(http.hostname, "printer.local.");
+        assert!(http.addresses.is_empty(), "Remote hosts have no known addresses");
+    }
+
+    #[test]
+    fn test_missing_port_rejected() {
+        let xml = r#"<service-group><name>x</name><service><type>_http._tcp</type></service></service-group>"#;
+        assert!(parse_service_group(xml, &local()).is_err());
+    }
+}
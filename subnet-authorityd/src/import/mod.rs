@@ -0,0 +1,2 @@
+pub mod avahi;
+pub mod services_d;
@@ -0,0 +1,165 @@
+//! Service definitions dropped into a directory, for infrastructure that
+//! should be listed whether or not it is heard on mDNS. Every `*.toml` file
+//! holds `[[service]]` tables; the services are cached with source `file`,
+//! which maintenance never marks stale or prunes. The directory is read at
+//! startup and again on SIGHUP.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use shared::names::InstanceName;
+use shared::types::{ServiceEntry, ServiceSource};
+use tokio_util::sync::CancellationToken;
+use crate::cache_manager::CacheHandle;
+
+/// TTL given to defined services, matching what the browser records
+const FILE_TTL: u32 = 4500;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DefinitionFile {
+    #[serde(default)]
+    service: Vec<Definition>,
+}
+
+/// One `[[service]]` table
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    /// Instance label, e.g. "NAS"
+    name: String,
+    /// e.g. "_smb._tcp"
+    #[serde(rename = "type")]
+    service_type: String,
+    hostname: String,
+    #[serde(default)]
+    addresses: Vec<IpAddr>,
+    port: u16,
+    #[serde(default)]
+    txt: HashMap<String, String>,
+    #[serde(default)]
+    subtypes: Vec<String>,
+}
+
+/// Every service defined in `dir`, in file name order. A missing directory
+/// defines none. Fails if any file doesn't parse, so a typo can't silently
+/// drop the services it defines.
+pub fn load_dir(dir: &Path) -> Result<Vec<ServiceEntry>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "toml"));
+    paths.sort();
+
+    let mut services = Vec::new();
+    for path in paths {
+        let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let defined = parse(&text).with_context(|| format!("Invalid service definitions in {}", path.display()))?;
+        services.extend(defined);
+    }
+    Ok(services)
+}
+
+fn parse(text: &str) -> Result<Vec<ServiceEntry>> {
+    let file: DefinitionFile = toml::from_str(text)?;
+    let now = Utc::now();
+    file.service
+        .into_iter()
+        .map(|definition| {
+            let instance_name = InstanceName::with_type(&definition.name, &format!("{}.local.", definition.service_type))
+                .with_context(|| format!("{} is not a service type", definition.service_type))?;
+            Ok(ServiceEntry {
+                service_type: instance_name.full_type(),
+                instance_name: instance_name.to_string(),
+                hostname: format!("{}.", definition.hostname.trim_end_matches('.')),
+                addresses: definition.addresses,
+                port: definition.port,
+                txt: definition.txt,
+                first_seen: now,
+                last_seen: now,
+                ttl: FILE_TTL,
+                alive: true,
+                source: ServiceSource::File,
+                reachable: None,
+                latency_ms: None,
+                subtypes: definition.subtypes,
+                iface: None,
+            })
+        })
+        .collect()
+}
+
+/// Reload `dir` into the cache on every SIGHUP until `cancel` fires. A
+/// directory that fails to load leaves the cached definitions as they were.
+#[cfg(unix)]
+pub async fn run_reload(dir: PathBuf, cache: CacheHandle, cancel: CancellationToken) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = hangup.recv() => {}
+        }
+        let loaded = match load_dir(&dir) {
+            Ok(services) => services,
+            Err(e) => {
+                tracing::error!("Keeping current service definitions: {:#}", e);
+                continue;
+            }
+        };
+        let count = loaded.len();
+        match cache.replace_source(ServiceSource::File, loaded).await {
+            Ok(()) => tracing::info!("Reloaded {} service definition(s) from {}", count, dir.display()),
+            Err(e) => tracing::error!("Failed to reload service definitions: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_definitions() {
+        let services = parse(
+            r#"
+            [[service]]
+            name = "NAS"
+            type = "_smb._tcp"
+            hostname = "nas.subnet.example"
+            addresses = ["fd00:1234:5678:1::20"]
+            port = 445
+
+            [[service]]
+            name = "Print Server"
+            type = "_ipp._tcp"
+            hostname = "print.subnet.example."
+            port = 631
+            txt = { rp = "printers/office" }
+            subtypes = ["_universal"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].instance_name, "NAS._smb._tcp.local.");
+        assert_eq!(services[0].service_type, "_smb._tcp.local.");
+        assert_eq!(services[0].hostname, "nas.subnet.example.");
+        assert_eq!(services[0].source, ServiceSource::File);
+        assert_eq!(services[1].txt["rp"], "printers/office");
+        assert_eq!(services[1].subtypes, ["_universal"]);
+
+        assert!(parse("[[service]]\nname = \"x\"\ntype = \"_smb\"\nhostname = \"h\"\nport = 1").is_err());
+        assert!(parse("[[service]]\nname = \"x\"\ntype = \"_smb._tcp\"\nhostname = \"h\"\nport = 1\nprot = 2").is_err());
+        assert!(load_dir(Path::new("/nonexistent/services.d")).unwrap().is_empty());
+    }
+}
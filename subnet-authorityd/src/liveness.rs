@@ -0,0 +1,139 @@
+//! TCP liveness probing. A service can keep answering mDNS long after the
+//! program behind its port has stopped accepting connections, so alive `_tcp`
+//! services are connected to on an interval and the outcome is recorded on
+//! the entry as `reachable` and `latency_ms`.
+
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+use shared::names::InstanceName;
+use shared::types::ServiceEntry;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use crate::addresses::{is_link_local, AddressPreference};
+use crate::cache_manager::{CacheHandle, CacheSnapshot};
+use crate::config::LivenessConfig;
+
+/// Connects in flight at once
+const CONCURRENCY: usize = 32;
+
+/// Probe every alive `_tcp` service each `config.interval_secs` until
+/// `cancel` fires. Link-local addresses are reached through `scope_id`.
+pub async fn run(
+    cache: CacheHandle,
+    snapshot_rx: watch::Receiver<CacheSnapshot>,
+    preference: Arc<AddressPreference>,
+    config: LivenessConfig,
+    scope_id: u32,
+    cancel: CancellationToken,
+) {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let services = snapshot_rx.borrow().services.clone();
+        let targets = services.iter().filter_map(|s| Some((s, target(s, &preference, scope_id)?)));
+        let cache = &cache;
+        let probes = futures::stream::iter(targets).for_each_concurrent(CONCURRENCY, |(service, addr)| async move {
+            let latency_ms = probe(addr, timeout).await;
+            if latency_ms.is_none() {
+                tracing::debug!("{} unreachable at {}", service.instance_name, addr);
+            }
+            let recorded = cache
+                .set_reachability(service.instance_name.clone(), latency_ms.is_some(), latency_ms)
+                .await;
+            if let Err(e) = recorded {
+                tracing::error!("Failed to record reachability of {}: {}", service.instance_name, e);
+            }
+        });
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = probes => {}
+        }
+    }
+}
+
+/// Where to connect to `service`, if it is alive, over TCP, and addressable
+fn target(service: &ServiceEntry, preference: &AddressPreference, scope_id: u32) -> Option<SocketAddr> {
+    if !service.alive || !is_tcp(service) || service.port == 0 {
+        return None;
+    }
+    Some(match preference.preferred(&service.addresses)? {
+        IpAddr::V6(v6) if is_link_local(&v6) => SocketAddrV6::new(v6, service.port, 0, scope_id).into(),
+        addr => SocketAddr::new(addr, service.port),
+    })
+}
+
+/// Whether `service` is offered over TCP. The type is read from the
+/// instance name, which always carries it with its domain
+/// (`web._http._tcp.local.`), however `service_type` is stored.
+fn is_tcp(service: &ServiceEntry) -> bool {
+    InstanceName::parse(&service.instance_name).is_some_and(|name| name.service_type.ends_with("._tcp"))
+}
+
+/// Milliseconds a connect to `addr` took, or None if it failed or timed out
+async fn probe(addr: SocketAddr, timeout: Duration) -> Option<u32> {
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Some(started.elapsed().as_millis().try_into().unwrap_or(u32::MAX)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use tokio::net::TcpListener;
+
+    fn service(instance_name: &str, service_type: &str) -> ServiceEntry {
+        ServiceEntry {
+            service_type: service_type.to_string(),
+            instance_name: instance_name.to_string(),
+            hostname: "nas.local.".to_string(),
+            addresses: vec!["fd00::10".parse().unwrap()],
+            port: 8080,
+            txt: Default::default(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 120,
+            alive: true,
+            source: Default::default(),
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        }
+    }
+
+    #[test]
+    fn test_target_tcp_services() {
+        let preference = AddressPreference::new(crate::addresses::default_preference(), "fd00::/64".parse().unwrap());
+        let expected: SocketAddr = "[fd00::10]:8080".parse().unwrap();
+
+        let browsed = service("web._http._tcp.local.", "_http._tcp.local.");
+        assert_eq!(target(&browsed, &preference, 0), Some(expected));
+        let registered = service("web._http._tcp.local.", "_http._tcp");
+        assert_eq!(target(&registered, &preference, 0), Some(expected));
+
+        assert_eq!(target(&service("tv._airplay._udp.local.", "_airplay._udp.local."), &preference, 0), None);
+        let dead = ServiceEntry { alive: false, ..browsed };
+        assert_eq!(target(&dead, &preference, 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(probe(addr, Duration::from_secs(1)).await.is_some());
+
+        drop(listener);
+        assert_eq!(probe(addr, Duration::from_secs(1)).await, None);
+    }
+}
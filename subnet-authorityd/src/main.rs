@@ -1,127 +1,506 @@
+mod addresses;
+mod cli;
 mod config;
 mod cache;
 mod cache_manager;
+mod coap;
+mod discovery;
+mod dns;
+mod export;
+mod liveness;
 mod mdns;
 mod api;
+mod import;
+mod reload;
+mod selftest;
 
+use std::collections::BTreeSet;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::{mpsc, watch};
 use tokio_util::sync::CancellationToken;
-use mdns_sd::ServiceDaemon;
 use anyhow::{Context, Result};
+use clap::Parser;
+use shared::types::{CacheExport, ServiceSource};
 use crate::cache::db::CacheDb;
-use crate::cache_manager::CacheHandle;
+use crate::cache::pool::ReadPool;
+use crate::cache_manager::{CacheHandle, CacheSnapshot};
+use crate::cli::{Cli, Command};
 use crate::config::Config;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("subnet_authorityd=info"))
-        )
-        .init();
+    let started = Instant::now();
+    let cli = Cli::parse();
+
+    // Initialize tracing; the filter is replaced once [logging] is read, and on reload
+    let logging = tracing_subscriber::fmt()
+        .with_env_filter(config::LoggingConfig::default().filter()?)
+        .with_filter_reloading();
+    let log_filter = logging.reload_handle();
+    logging.init();
 
     tracing::info!("Starting subnet-authorityd");
 
     // Load config
-    let config_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "/etc/subnet-authority/authorityd.toml".to_string());
+    let config_path = cli.config_path();
+    let overrides = cli.overrides();
+    let mut config = Config::load(&config_path)
+        .with_context(|| format!("Failed to load config from {}", config_path.display()))?;
+    overrides.apply(&mut config);
+
+    log_filter.reload(config.logging.filter()?).context("Failed to set the log filter")?;
+    tracing::info!("Loaded config from {}", config_path.display());
+    if cli.check_config {
+        let problems = selftest::check_config(&config);
+        if problems.is_empty() {
+            println!("{} is valid", config_path.display());
+            return Ok(());
+        }
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        eprintln!("{}: {} problem(s) found", config_path.display(), problems.len());
+        std::process::exit(1);
+    }
+    // One structured line summarising what this deployment will actually do
+    tracing::info!(
+        interface = %config.authority.interface,
+        prefix = %config.authority.prefix,
+        zone = %config.authority.zone,
+        listen = %config.api.listen,
+        db_path = %config.cache.db_path.display(),
+        features = ?config.enabled_features(),
+        "Effective configuration"
+    );
 
-    let config = Config::load(&config_path)
-        .with_context(|| format!("Failed to load config from {}", config_path))?;
+    if cli.self_test {
+        let passed = selftest::run(&config).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    tracing::info!("Loaded config from {}", config_path);
+    config.cache.check_retention()?;
+    let db_key = config.cache.encryption_key()?;
+    if let Some(from) = &cli.restore {
+        CacheDb::restore_backup(&config.cache.db_path, from, db_key.as_deref())?;
+        tracing::info!("Restored {:?} from {}", config.cache.db_path, from.display());
+    }
 
     // Open SQLite database
-    let db = CacheDb::open(&config.cache.db_path)?;
+    let mut db = CacheDb::open_with_key(&config.cache.db_path, db_key.as_deref())?;
+    db.set_type_conflict_policy(config.cache.type_conflict);
+    db.set_history_retention(config.cache.history_retention());
+    db.set_retention_ttl_factor(config.cache.retention_ttl_factor);
+    db.set_type_retention(config.cache.type_retention());
+    db.set_tombstone_retention(config.cache.tombstone_retention());
+    db.set_hash_fields(config.cache.hash_fields.clone())?;
     tracing::info!("Opened database at {:?}", config.cache.db_path);
 
+    match &cli.command {
+        Some(Command::Export { path }) => {
+            let export = CacheExport { exported_at: chrono::Utc::now(), services: db.get_all_services()? };
+            let json = serde_json::to_vec_pretty(&export).context("Failed to serialize services")?;
+            std::fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))?;
+            tracing::info!("Exported {} service(s) to {}", export.services.len(), path.display());
+            return Ok(());
+        }
+        Some(Command::Import { path }) => {
+            let json = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            let export: CacheExport = serde_json::from_slice(&json)
+                .with_context(|| format!("{} is not a JSON export", path.display()))?;
+            db.import(&export.services)?;
+            tracing::info!("Imported {} service(s) from {}", export.services.len(), path.display());
+            return Ok(());
+        }
+        None => {}
+    }
+
+    // Load static services migrated from Avahi
+    if let Some(dir) = &config.import.avahi_dir {
+        let local = import::avahi::LocalHost {
+            hostname: hostname::get()
+                .context("Failed to get system hostname")?
+                .to_string_lossy()
+                .to_string(),
+            addresses: config.authority.address
+                .parse::<ipnet::Ipv6Net>()
+                .map(|net| vec![net.addr().into()])
+                .unwrap_or_default(),
+        };
+        let imported = import::avahi::load_dir(dir, &local)?;
+        db.replace_source(ServiceSource::Import, &imported)?;
+        tracing::info!("Imported {} Avahi service(s) from {}", imported.len(), dir.display());
+    }
+
+    // Load services pinned by drop-in definitions
+    match import::services_d::load_dir(&config.import.services_dir) {
+        Ok(defined) => {
+            db.replace_source(ServiceSource::File, &defined)?;
+            if !defined.is_empty() {
+                tracing::info!("Loaded {} service definition(s) from {}", defined.len(), config.import.services_dir.display());
+            }
+        }
+        Err(e) => tracing::error!("Keeping cached service definitions: {:#}", e),
+    }
+
     // Compute initial hash
-    let initial_services = db.get_all_services()?;
-    let initial_hash = cache::hash::compute_hash(&initial_services);
-    tracing::info!("Initial cache hash: {}", initial_hash);
+    let mut initial_snapshot = CacheSnapshot::new(
+        db.get_all_services()?,
+        config.cache.hash_fields.clone(),
+    );
+    initial_snapshot.seq = db.changes_since(u64::MAX)?.seq;
+    tracing::info!("Initial cache hash: {}", initial_snapshot.hash);
 
-    // Create hash watch channel
-    let (hash_tx, hash_rx) = watch::channel(initial_hash);
+    // Create snapshot watch channel
+    let (snapshot_tx, snapshot_rx) = watch::channel(initial_snapshot);
 
     // Start cache manager thread
-    let cache_handle = CacheHandle::spawn(db, hash_tx);
+    let mut cache_handle = CacheHandle::spawn(db, snapshot_tx, &config.cache);
+    if config.cache.read_connections > 0 {
+        let readers = ReadPool::open(&config.cache.db_path, db_key.as_deref(), config.cache.read_connections)?;
+        cache_handle = cache_handle.with_readers(readers);
+    }
 
     // Create mDNS daemon bound to configured interface
-    let mdns_daemon = ServiceDaemon::new()
-        .context("Failed to create mDNS daemon")?;
-    mdns_daemon
-        .disable_interface(mdns_sd::IfKind::All)
-        .context("Failed to disable default interfaces")?;
-    mdns_daemon
-        .enable_interface(config.authority.interface.as_str())
-        .with_context(|| format!("Failed to enable interface {}", config.authority.interface))?;
+    let mdns_daemon = mdns::interfaces::create_daemon(&config.authority.interface)?;
 
     // Extract port from listen address
+    if config.api.listen.starts_with(api::server::UNIX_PREFIX) {
+        tracing::warn!("API only listens on {}; the advertised port is unreachable", config.api.listen);
+    }
     let api_port = config.api.listen
-        .split(':')
-        .last()
+        .rsplit(':')
+        .next()
         .and_then(|s| s.parse::<u16>().ok())
         .unwrap_or(8053);
 
-    // Register self-advertisement
-    let service_info = mdns::advertise::register_authority(
+    let address_preference = Arc::new(addresses::AddressPreference::new(
+        config.addresses.preference.clone(),
+        config.authority.prefix_net()?,
+    ));
+
+    // Bind CoAP and DNS before advertising so the advertised ports are the bound ones
+    let coap_server = if config.coap.enabled {
+        Some(coap::CoapServer::bind(
+            &config.coap.listen,
+            snapshot_rx.clone(),
+            address_preference.clone(),
+            config.authority.zone.clone(),
+        ).await?)
+    } else {
+        None
+    };
+    let coap_port = match &coap_server {
+        Some(server) => Some(server.local_addr()?.port()),
+        None => None,
+    };
+
+    // The zone is also exported over the API when DNS is off
+    let zone_info = {
+        let hostname = hostname::get()
+            .context("Failed to get system hostname")?
+            .to_string_lossy()
+            .to_string();
+        dns::zone::ZoneInfo::new(
+            &config.authority.zone,
+            &hostname,
+            &config.authority.address,
+            config.dns.ttl,
+            if config.dns.reverse { Some(config.authority.prefix_net()?) } else { None },
+        )?
+        .with_address_preference((*address_preference).clone())
+    };
+    let dns_server = if config.dns.enabled {
+        let info = zone_info.clone();
+        let transfer_keys = dns::tsig::signers(&config.dns.transfer_keys)?;
+        let updates = if config.dns.update_keys.is_empty() {
+            None
+        } else {
+            Some(dns::update::Updates {
+                cache: cache_handle.clone(),
+                keys: dns::tsig::signers(&config.dns.update_keys)?,
+                origin: info.origin.clone(),
+                prefix: config.authority.prefix_net()?,
+            })
+        };
+        let mut server = dns::DnsServer::bind(&config.dns.listen, info, transfer_keys, updates).await?;
+        if let Some(key) = dns::dnssec::DnssecKey::load(&config.dns.dnssec)? {
+            server.sign_with(&key)?;
+        }
+        if let Some(listen) = &config.dns.tls_listen {
+            // Same certificate as the API, but resolvers don't present one
+            let files = config
+                .api
+                .tls_files()?
+                .context("dns.tls_listen requires api.tls_cert and api.tls_key")?;
+            let files = config::TlsFiles { client_ca: None, ..files };
+            server.bind_tls(listen, api::server::tls_acceptor(&files, &[b"dot"])?).await?;
+        }
+        Some(server)
+    } else {
+        None
+    };
+    let dns_port = match &dns_server {
+        Some(server) => Some(server.local_addr()?.port()),
+        None => None,
+    };
+
+    // Register self-advertisement, then any extra services
+    let initial_hash = snapshot_rx.borrow().hash.clone();
+    let mut advertisements = vec![mdns::advertise::register_authority(
         &mdns_daemon,
         &config.authority,
         api_port,
-    )?;
+        coap_port,
+        dns_port,
+        &initial_hash,
+        cache_handle.seq().await.ok(),
+    )?];
+    for advertise in &config.advertise {
+        advertisements.push(mdns::advertise::register_advertised(
+            &mdns_daemon,
+            &config.authority,
+            advertise,
+            api_port,
+            coap_port,
+            dns_port,
+        )?);
+    }
+    let advertisements = Arc::new(mdns::advertise::Advertisements::new(advertisements));
 
     // Create cancellation token for graceful shutdown
     let cancel = CancellationToken::new();
 
-    // Spawn mDNS browser task
+    // Discovery sources feed the cache manager
     let (browser_tx, browser_rx) = mpsc::channel(256);
-    let browser_cancel = cancel.clone();
-    let browser_daemon = mdns_daemon.clone();
-    let browser_handle = tokio::spawn(async move {
-        if let Err(e) = mdns::browser::run_browser(browser_daemon, browser_tx, browser_cancel).await {
-            tracing::error!("mDNS browser error: {}", e);
-        }
+    let (browser_command_tx, browser_command_rx) = mpsc::channel(64);
+    let (warm_tx, warm_rx) = watch::channel(false);
+    let rejected = Arc::new(mdns::rejected::RejectedRing::new(config.mdns.rejected_ring_size));
+    // Interfaces mDNS is browsed on: the primary, and with rescanning any
+    // others holding in-prefix addresses
+    let (browsed_tx, browsed_rx) = watch::channel(BTreeSet::from([config.authority.interface.clone()]));
+    // Announced TTLs, subtypes and interfaces, heard on each browsed interface
+    let announcements = Arc::new(mdns::announced::Announcements::default());
+    let announced_handle = tokio::spawn(mdns::announced::run_listeners(
+        announcements.clone(),
+        browsed_rx.clone(),
+        cancel.clone(),
+    ));
+    // Keep the authority's TXT records in step with the cache
+    let sync_txt_handle = tokio::spawn(mdns::advertise::run_sync_txt(
+        mdns_daemon.clone(),
+        advertisements.clone(),
+        cache_handle.clone(),
+        snapshot_rx.clone(),
+        cancel.clone(),
+    ));
+    // Registered services advertised by proxy, which the browser leaves alone
+    let proxied = Arc::new(mdns::proxy::ProxiedNames::default());
+    let proxy_handle = config.mdns.proxy_registered.then(|| {
+        tokio::spawn(mdns::proxy::run_proxy(
+            mdns_daemon.clone(),
+            snapshot_rx.clone(),
+            proxied.clone(),
+            cancel.clone(),
+        ))
     });
+    let browser_settings = mdns::browser::BrowserSettings {
+        mdns: config.mdns.clone(),
+        addresses: addresses::AddressFilter::new(&config.addresses, config.authority.prefix_net()?),
+        types: mdns::browser::TypeFilter::new(&config.browser)?,
+        announcements,
+        proxied,
+    };
+    let mdns_enabled = config.discovery.sources.contains(&discovery::SourceKind::Mdns);
+    let mut sources: Vec<Box<dyn discovery::DiscoverySource>> = Vec::new();
+    if mdns_enabled {
+        sources.push(Box::new(mdns::browser::MdnsSource {
+            daemon: mdns_daemon.clone(),
+            commands: browser_command_rx,
+            settings: browser_settings,
+            warm_tx,
+            rejected: rejected.clone(),
+        }));
+    } else {
+        // Only mDNS has a discovery cycle to wait out, or takes browse requests
+        warm_tx.send_replace(true);
+        drop(browser_command_rx);
+    }
+    let discovery_handle = tokio::spawn(discovery::run(sources, browser_tx, cancel.clone()));
+
+    // Spawn interface rescan task
+    let rescan_handle = if config.mdns.interface_rescan_secs > 0 || config.mdns.watch_interfaces {
+        let prefix = config.authority.prefix_net()?;
+        let rescan_daemon = mdns_daemon.clone();
+        let rescan_primary = config.authority.interface.clone();
+        let rescan_interval = (config.mdns.interface_rescan_secs > 0)
+            .then(|| std::time::Duration::from_secs(config.mdns.interface_rescan_secs));
+        let rescan_watch = if config.mdns.watch_interfaces {
+            Some(mdns::interfaces::LinkWatch::new()?)
+        } else {
+            None
+        };
+        let rescan_advertisements = advertisements.clone();
+        let rescan_cancel = cancel.clone();
+        Some(tokio::spawn(async move {
+            if let Err(e) = mdns::interfaces::run_rescan(
+                rescan_daemon,
+                prefix,
+                rescan_primary,
+                rescan_interval,
+                rescan_watch,
+                rescan_advertisements,
+                browsed_tx,
+                rescan_cancel,
+            ).await {
+                tracing::error!("Interface rescan error: {}", e);
+            }
+        }))
+    } else {
+        None
+    };
+
+    // Spawn mDNS reflector
+    let reflector_handle = if config.reflector.interfaces.is_empty() {
+        None
+    } else {
+        let reflector = mdns::reflector::Reflector::open(&config.reflector.interfaces)?;
+        tracing::info!("Reflecting mDNS between {}", config.reflector.interfaces.join(", "));
+        Some(tokio::spawn(reflector.run(cancel.clone())))
+    };
 
     // Spawn cache manager task
     let mgr_cancel = cancel.clone();
-    let mgr_config = config.cache.clone();
+    // Replaced by a reload
+    let (cache_config_tx, mgr_config) = watch::channel(config.cache.clone());
     let mgr_cache = cache_handle.clone();
+    let mgr_probe_tx = (mdns_enabled && config.mdns.probe_before_stale).then(|| browser_command_tx.clone());
+    let mgr_confirmer = if config.mdns.confirm_attempts > 0 {
+        Some(Arc::new(mdns::confirm::Confirmer::new(
+            &config.authority.interface,
+            config.mdns.confirm_attempts,
+            std::time::Duration::from_millis(config.mdns.confirm_timeout_ms),
+        )?))
+    } else {
+        None
+    };
     let mgr_handle = tokio::spawn(async move {
-        if let Err(e) = cache_manager::run(mgr_cache, browser_rx, mgr_config, mgr_cancel).await {
+        if let Err(e) = cache_manager::run(mgr_cache, browser_rx, mgr_config, mgr_probe_tx, mgr_confirmer, mgr_cancel).await {
             tracing::error!("Cache manager error: {}", e);
         }
     });
 
+    // Reload drop-in service definitions on SIGHUP
+    #[cfg(unix)]
+    let reload_handle = {
+        let dir = config.import.services_dir.clone();
+        let cache = cache_handle.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = import::services_d::run_reload(dir, cache, cancel).await {
+                tracing::error!("Service definitions won't be reloaded: {:#}", e);
+            }
+        })
+    };
+
+    // Apply config file changes on SIGHUP and POST /v1/admin/reload
+    let reloader = Arc::new(reload::Reloader::new(
+        config.clone(),
+        overrides,
+        cache_handle.clone(),
+        cache_config_tx,
+        browser_command_tx.clone(),
+        Box::new(move |filter| log_filter.reload(filter).context("Failed to change the log filter")),
+    ));
+    #[cfg(unix)]
+    let config_reload_handle = {
+        let reloader = reloader.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reload::run_on_hangup(reloader, cancel).await {
+                tracing::error!("Config won't be reloaded: {:#}", e);
+            }
+        })
+    };
+
+    // Spawn TCP liveness probing
+    let liveness_handle = if config.liveness.interval_secs > 0 {
+        let scope_id = mdns::interfaces::interface_index(&config.authority.interface).unwrap_or_else(|e| {
+            tracing::warn!("Link-local addresses won't be probed: {:#}", e);
+            0
+        });
+        Some(tokio::spawn(liveness::run(
+            cache_handle.clone(),
+            snapshot_rx.clone(),
+            address_preference.clone(),
+            config.liveness.clone(),
+            scope_id,
+            cancel.clone(),
+        )))
+    } else {
+        None
+    };
+
     // Build API router
     let app_state = api::routes::AppState {
         cache: cache_handle.clone(),
-        hash_rx,
+        snapshot_rx: snapshot_rx.clone(),
+        warm_rx,
+        rejected,
+        response_cache: Arc::new(api::response_cache::ResponseCache::new(
+            std::time::Duration::from_millis(config.api.response_cache_ms),
+        )),
         config: Arc::new(config.authority.clone()),
+        api_config: Arc::new(config.api.clone()),
+        reloader,
+        backup_dir: Arc::new(config.cache.backup_dir.clone()),
+        address_preference,
+        zone: Arc::new(zone_info),
+        metrics: Arc::new(api::metrics::Metrics::new(config.metrics.clone())),
+        mdns_daemon: mdns_daemon.clone(),
+        browser: browser_command_tx,
+        browsed_rx,
+        shutdown: cancel.clone(),
         api_port, // Fix #1: pass pre-computed port to AppState
     };
     let app = api::routes::router(app_state);
 
+    // Load TLS material before binding so a bad certificate fails startup
+    let tls = match config.api.tls_files()? {
+        Some(files) => Some(api::server::tls_acceptor(&files, &[b"h2", b"http/1.1"])?),
+        None => None,
+    };
+
     // Bind HTTP server
-    let listener = tokio::net::TcpListener::bind(&config.api.listen)
-        .await
-        .with_context(|| format!("Failed to bind to {}", config.api.listen))?;
+    let listener = api::server::ApiListener::bind(&config.api.listen).await?;
 
-    tracing::info!("API listening on {}", config.api.listen);
+    tracing::info!(
+        "API listening on {}{}",
+        config.api.listen,
+        match (&tls, &config.api.tls_client_ca) {
+            (Some(_), Some(_)) => " (mutual TLS)",
+            (Some(_), None) => " (TLS)",
+            _ => "",
+        }
+    );
 
     // Run server with graceful shutdown
     let server_cancel = cancel.clone();
-    let server_handle = tokio::spawn(async move {
-        if let Err(e) = axum::serve(listener, app)
-            .with_graceful_shutdown(async move { server_cancel.cancelled().await })
-            .await
-        {
-            tracing::error!("Server error: {}", e);
+    let server_handle = tokio::spawn(api::server::serve(listener, tls, app, server_cancel));
+
+    // Spawn CoAP resource directory
+    let coap_handle = coap_server.map(|server| {
+        tracing::info!("CoAP resource directory listening on {}", config.coap.listen);
+        tokio::spawn(server.run(cancel.clone()))
+    });
+
+    // Spawn DNS server
+    let dns_handle = dns_server.map(|server| {
+        tracing::info!("DNS for {} listening on {}", config.authority.zone, config.dns.listen);
+        if let Some(listen) = &config.dns.tls_listen {
+            tracing::info!("DNS over TLS listening on {}", listen);
         }
+        tokio::spawn(server.run(snapshot_rx.clone(), cancel.clone()))
     });
 
     // Wait for shutdown signal
@@ -135,23 +514,60 @@ async fn main() -> Result<()> {
     cancel.cancel();
 
     // Wait for all tasks to complete
-    let _ = tokio::join!(browser_handle, mgr_handle, server_handle);
+    let _ = tokio::join!(discovery_handle, announced_handle, sync_txt_handle, mgr_handle, server_handle);
+    if let Some(handle) = rescan_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = proxy_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = reflector_handle {
+        let _ = handle.await;
+    }
+    #[cfg(unix)]
+    let _ = reload_handle.await;
+    #[cfg(unix)]
+    let _ = config_reload_handle.await;
+    if let Some(handle) = liveness_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = coap_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = dns_handle {
+        let _ = handle.await;
+    }
 
-    // Unregister mDNS service
-    if let Err(e) = mdns::advertise::unregister_authority(&mdns_daemon, &service_info) {
-        tracing::error!("Failed to unregister mDNS service: {}", e);
+    // Unregister mDNS services
+    let mut mdns_unregistered = true;
+    for service_info in advertisements.read().unwrap().iter() {
+        if let Err(e) = mdns::advertise::unregister(&mdns_daemon, service_info) {
+            tracing::error!("Failed to unregister mDNS service: {}", e);
+            mdns_unregistered = false;
+        }
     }
 
     // Shutdown cache thread
-    if let Err(e) = cache_handle.shutdown().await {
-        tracing::error!("Failed to shutdown cache: {}", e);
-    }
+    let cache_report = match cache_handle.shutdown().await {
+        Ok(report) => Some(report),
+        Err(e) => {
+            tracing::error!("Failed to shutdown cache: {}", e);
+            None
+        }
+    };
 
     // Shutdown mDNS daemon
     if let Err(e) = mdns_daemon.shutdown() {
         tracing::error!("Failed to shutdown mDNS daemon: {}", e);
     }
 
-    tracing::info!("Shutdown complete");
+    tracing::info!(
+        services = cache_report.map(|r| r.services),
+        drained_commands = cache_report.map(|r| r.drained_commands),
+        mdns_unregistered,
+        uptime_secs = started.elapsed().as_secs(),
+        "Shutdown complete"
+    );
     Ok(())
 }
+
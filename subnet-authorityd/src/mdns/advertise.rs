@@ -1,52 +1,198 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use mdns_sd::{ServiceDaemon, ServiceInfo};
 use anyhow::{Context, Result};
-use shared::protocol::{AUTHORITY_SERVICE_TYPE, TXT_ZONE, TXT_PREFIX};
-use crate::config::AuthorityConfig;
+use shared::names::InstanceName;
+use shared::protocol::{AUTHORITY_SERVICE_TYPE, TXT_COAP_PORT, TXT_DNS_PORT, TXT_HASH, TXT_SEQ, TXT_ZONE, TXT_PREFIX};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use crate::cache_manager::{CacheHandle, CacheSnapshot};
+use crate::config::{AdvertiseConfig, AdvertisePort, AuthorityConfig, Listener};
 
+/// The services the daemon advertises, the authority first. Shared so that
+/// re-announcements carry the current TXT records.
+pub type Advertisements = RwLock<Vec<ServiceInfo>>;
+
+/// Least time between TXT updates, since RFC 6762 section 6 asks that a
+/// record be multicast at most once a second
+const TXT_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Register the authority, with the cache's `hash` and `seq` in its TXT
+/// records (no `seq` when the change log is unavailable)
 pub fn register_authority(
     daemon: &ServiceDaemon,
     config: &AuthorityConfig,
     api_port: u16,
+    coap_port: Option<u16>,
+    dns_port: Option<u16>,
+    hash: &str,
+    seq: Option<u64>,
 ) -> Result<ServiceInfo> {
-    let hostname = hostname::get()
-        .context("Failed to get system hostname")?
-        .to_string_lossy()
-        .to_string();
-
-    let instance_name = format!("subnet-authority-{}", hostname);
+    let hostname = system_hostname()?;
+    let instance_name = InstanceName::authority(&hostname);
 
     // Create TXT records with zone and prefix info
-    let txt_records = HashMap::from([
+    let mut txt_records = HashMap::from([
         (TXT_ZONE.to_string(), config.zone.clone()),
         (TXT_PREFIX.to_string(), config.prefix.clone()),
     ]);
+    if let Some(port) = coap_port {
+        txt_records.insert(TXT_COAP_PORT.to_string(), port.to_string());
+    }
+    if let Some(port) = dns_port {
+        txt_records.insert(TXT_DNS_PORT.to_string(), port.to_string());
+    }
+    set_sync_txt(&mut txt_records, hash, seq);
 
-    let service_info = ServiceInfo::new(
+    register(
+        daemon,
         AUTHORITY_SERVICE_TYPE,
-        &instance_name,
+        &instance_name.short_name,
         &hostname,
         &config.address,
         api_port,
         txt_records,
     )
-    .context("Failed to create ServiceInfo")?;
+}
+
+/// Register one `[[advertise]]` entry at the authority's address. Listener
+/// ports resolve to the ports bound, and fail if that listener is disabled.
+pub fn register_advertised(
+    daemon: &ServiceDaemon,
+    authority: &AuthorityConfig,
+    config: &AdvertiseConfig,
+    api_port: u16,
+    coap_port: Option<u16>,
+    dns_port: Option<u16>,
+) -> Result<ServiceInfo> {
+    let port = match config.port {
+        AdvertisePort::Number(port) => Some(port),
+        AdvertisePort::Listener(Listener::Api) => Some(api_port),
+        AdvertisePort::Listener(Listener::Coap) => coap_port,
+        AdvertisePort::Listener(Listener::Dns) => dns_port,
+    }
+    .with_context(|| format!("Cannot advertise {}: its listener is disabled", config.service_type))?;
+
+    let hostname = system_hostname()?;
+    let service_type = format!("{}.local.", config.service_type.trim_end_matches('.').trim_end_matches(".local"));
+    register(
+        daemon,
+        &service_type,
+        config.instance.as_deref().unwrap_or(&hostname),
+        &hostname,
+        &authority.address,
+        port,
+        config.txt.clone(),
+    )
+}
+
+/// Re-register the authority whenever the cache hash changes, so its TXT
+/// records follow the cache, until `cancel` fires
+pub async fn run_sync_txt(
+    daemon: ServiceDaemon,
+    advertisements: Arc<Advertisements>,
+    cache: CacheHandle,
+    mut snapshot_rx: watch::Receiver<CacheSnapshot>,
+    cancel: CancellationToken,
+) {
+    loop {
+        let hash = snapshot_rx.borrow_and_update().hash.clone();
+        let authority = advertisements.read().unwrap()[0].clone();
+        if authority.get_property_val_str(TXT_HASH) != Some(hash.as_str()) {
+            let seq = cache.seq().await.ok();
+            let updated = with_sync_txt(&authority, &hash, seq).and_then(|info| {
+                daemon.register(info.clone()).context("Failed to re-register mDNS service")?;
+                Ok(info)
+            });
+            match updated {
+                Ok(info) => {
+                    tracing::debug!("Announced cache hash {} at seq {:?}", hash, seq);
+                    advertisements.write().unwrap()[0] = info;
+                }
+                Err(e) => tracing::warn!("Failed to update authority TXT records: {:#}", e),
+            }
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(TXT_UPDATE_INTERVAL) => {}
+            }
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            changed = snapshot_rx.changed() => if changed.is_err() { return },
+        }
+    }
+}
+
+/// `info` with its hash and seq TXT entries replaced
+fn with_sync_txt(info: &ServiceInfo, hash: &str, seq: Option<u64>) -> Result<ServiceInfo> {
+    let mut txt_records: HashMap<String, String> = info
+        .get_properties()
+        .iter()
+        .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
+        .collect();
+    set_sync_txt(&mut txt_records, hash, seq);
+    let short_name = info
+        .get_fullname()
+        .strip_suffix(info.get_type())
+        .and_then(|name| name.strip_suffix('.'))
+        .context("Service name doesn't end in its type")?;
+    let addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    ServiceInfo::new(
+        info.get_type(),
+        short_name,
+        info.get_hostname(),
+        addresses.as_slice(),
+        info.get_port(),
+        txt_records,
+    )
+    .context("Failed to create ServiceInfo")
+}
+
+fn set_sync_txt(txt_records: &mut HashMap<String, String>, hash: &str, seq: Option<u64>) {
+    txt_records.insert(TXT_HASH.to_string(), hash.to_string());
+    match seq {
+        Some(seq) => txt_records.insert(TXT_SEQ.to_string(), seq.to_string()),
+        None => txt_records.remove(TXT_SEQ),
+    };
+}
+
+fn register(
+    daemon: &ServiceDaemon,
+    service_type: &str,
+    short_name: &str,
+    hostname: &str,
+    address: &str,
+    port: u16,
+    txt_records: HashMap<String, String>,
+) -> Result<ServiceInfo> {
+    let service_info = ServiceInfo::new(service_type, short_name, hostname, address, port, txt_records)
+        .with_context(|| format!("Failed to create ServiceInfo for {}", service_type))?;
 
     daemon
         .register(service_info.clone())
-        .context("Failed to register mDNS service")?;
+        .with_context(|| format!("Failed to register mDNS service {}", service_type))?;
 
     tracing::info!(
         "Registered {} as {} on port {}",
-        AUTHORITY_SERVICE_TYPE,
-        instance_name,
-        api_port
+        service_type,
+        service_info.get_fullname(),
+        port
     );
 
     Ok(service_info)
 }
 
-pub fn unregister_authority(daemon: &ServiceDaemon, service_info: &ServiceInfo) -> Result<()> {
+fn system_hostname() -> Result<String> {
+    Ok(hostname::get()
+        .context("Failed to get system hostname")?
+        .to_string_lossy()
+        .to_string())
+}
+
+pub fn unregister(daemon: &ServiceDaemon, service_info: &ServiceInfo) -> Result<()> {
     daemon
         .unregister(service_info.get_fullname())
         .context("Failed to unregister mDNS service")?;
@@ -54,3 +200,25 @@ pub fn unregister_authority(daemon: &ServiceDaemon, service_info: &ServiceInfo)
     tracing::info!("Unregistered {}", service_info.get_fullname());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_txt_replaced() {
+        let txt = HashMap::from([(TXT_ZONE.to_string(), "subnet.example".to_string())]);
+        let info = ServiceInfo::new(AUTHORITY_SERVICE_TYPE, "Authority on host", "host.local.", "fd00::1", 8053, txt)
+            .unwrap();
+
+        let updated = with_sync_txt(&info, "abc", Some(7)).unwrap();
+        assert_eq!(updated.get_fullname(), info.get_fullname());
+        assert_eq!(updated.get_property_val_str(TXT_ZONE), Some("subnet.example"));
+        assert_eq!(updated.get_property_val_str(TXT_HASH), Some("abc"));
+        assert_eq!(updated.get_property_val_str(TXT_SEQ), Some("7"));
+
+        let updated = with_sync_txt(&updated, "def", None).unwrap();
+        assert_eq!(updated.get_property_val_str(TXT_HASH), Some("def"));
+        assert_eq!(updated.get_property_val_str(TXT_SEQ), None);
+    }
+}
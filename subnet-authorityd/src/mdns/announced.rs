@@ -0,0 +1,275 @@
+//! What services announce about themselves that mdns-sd doesn't pass on.
+//! mdns-sd resolves services with its own default TTLs rather than the
+//! received ones, and drops subtype PTR records (RFC 6763 section 7.1)
+//! unless the subtype itself is browsed. So responses are also read here, on
+//! a second socket bound to the mDNS group, and the PTR record TTL and
+//! subtypes of each instance are kept for the browser to stamp on its
+//! entries, along with the interface the announcement was heard on, which
+//! mdns-sd doesn't report either. There's a socket per browsed interface,
+//! each bound to its interface. A service resolved before its announcement
+//! is read here keeps the defaults until it next resolves.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv6Addr, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use hickory_proto::op::{Message, MessageType};
+use hickory_proto::rr::{Name, RData};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use super::browser::canonical_name;
+
+const MDNS_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_PORT: u16 = 5353;
+
+/// Beyond this many instances, expired ones are dropped on the next record
+const MAX_INSTANCES: usize = 4096;
+
+/// What was last announced for one instance
+#[derive(Debug)]
+struct Announced {
+    ttl: Option<u32>,
+    subtypes: BTreeSet<String>,
+    /// Where the instance's last announcement was heard
+    iface: String,
+    expires: Instant,
+}
+
+/// The last announced TTL, subtypes and interface of each instance, by
+/// canonical instance name
+#[derive(Debug, Default)]
+pub struct Announcements {
+    instances: Mutex<HashMap<String, Announced>>,
+}
+
+impl Announcements {
+    pub fn ttl(&self, instance_name: &str) -> Option<u32> {
+        self.instances.lock().unwrap().get(instance_name).and_then(|a| a.ttl)
+    }
+
+    /// Subtype labels, such as `_printer`, in order
+    pub fn subtypes(&self, instance_name: &str) -> Vec<String> {
+        self.instances
+            .lock()
+            .unwrap()
+            .get(instance_name)
+            .map(|a| a.subtypes.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The interface the instance was last heard announcing on
+    pub fn iface(&self, instance_name: &str) -> Option<String> {
+        self.instances.lock().unwrap().get(instance_name).map(|a| a.iface.clone())
+    }
+
+    /// Keep the TTLs and subtypes of the PTR records in `message`, heard on
+    /// `iface`. A TTL of zero is a goodbye: for the service type it forgets
+    /// the instance, and for a subtype just that subtype.
+    pub fn record(&self, message: &Message, iface: &str) {
+        let now = Instant::now();
+        let mut instances = self.instances.lock().unwrap();
+        for record in message.answers().iter().chain(message.additionals()) {
+            let Some(RData::PTR(ptr)) = record.data() else {
+                continue;
+            };
+            let instance_name = canonical_name(&fullname(&ptr.0));
+            let subtype = subtype(record.name());
+            let ttl = record.ttl();
+            if ttl == 0 {
+                match subtype {
+                    Some(subtype) => {
+                        if let Some(announced) = instances.get_mut(&instance_name) {
+                            announced.subtypes.remove(&subtype);
+                        }
+                    }
+                    None => {
+                        instances.remove(&instance_name);
+                    }
+                }
+                continue;
+            }
+            let expires = now + Duration::from_secs(u64::from(ttl));
+            let announced = instances.entry(instance_name).or_insert_with(|| Announced {
+                ttl: None,
+                subtypes: BTreeSet::new(),
+                iface: iface.to_string(),
+                expires,
+            });
+            announced.expires = announced.expires.max(expires);
+            iface.clone_into(&mut announced.iface);
+            match subtype {
+                Some(subtype) => {
+                    announced.subtypes.insert(subtype);
+                }
+                None => announced.ttl = Some(ttl),
+            }
+        }
+        if instances.len() > MAX_INSTANCES {
+            instances.retain(|_, announced| announced.expires > now);
+        }
+    }
+}
+
+/// Record the announcements heard on each browsed interface until `cancel`
+/// fires, starting and stopping a listener as `browsed` changes
+pub async fn run_listeners(
+    announcements: Arc<Announcements>,
+    mut browsed: watch::Receiver<BTreeSet<String>>,
+    cancel: CancellationToken,
+) {
+    let mut listeners: HashMap<String, CancellationToken> = HashMap::new();
+    let mut tasks = JoinSet::new();
+    loop {
+        let wanted = browsed.borrow_and_update().clone();
+        listeners.retain(|name, listener| {
+            let keep = wanted.contains(name);
+            if !keep {
+                listener.cancel();
+            }
+            keep
+        });
+        for name in wanted {
+            if listeners.contains_key(&name) {
+                continue;
+            }
+            let listener = cancel.child_token();
+            listeners.insert(name.clone(), listener.clone());
+            let announcements = announcements.clone();
+            tasks.spawn(async move {
+                if let Err(e) = run_listener(announcements, name.clone(), listener).await {
+                    tracing::warn!("Not recording announcements on {}: {:#}", name, e);
+                }
+            });
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            changed = browsed.changed() => {
+                if changed.is_err() {
+                    // Nothing changes the set any more; keep what's running
+                    cancel.cancelled().await;
+                    break;
+                }
+            }
+            Some(_) = tasks.join_next() => {}
+        }
+    }
+    tasks.shutdown().await;
+}
+
+/// Record the announcements heard on `interface` until `cancel` fires
+async fn run_listener(announcements: Arc<Announcements>, interface: String, cancel: CancellationToken) -> Result<()> {
+    let index = super::interfaces::interface_index(&interface)?;
+    let socket = bind(index).with_context(|| format!("Failed to listen for mDNS on {}", interface))?;
+    let mut buf = vec![0u8; 9000];
+    loop {
+        let len = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    tracing::debug!("mDNS announcement receive failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        match Message::from_vec(&buf[..len]) {
+            Ok(message) if message.message_type() == MessageType::Response => {
+                announcements.record(&message, &interface)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A socket sharing port 5353 with mdns-sd. Bound to the group address, it
+/// gets multicast responses only, so unicast replies meant for mdns-sd are
+/// never shared with it.
+fn bind(interface_index: u32) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddrV6::new(MDNS_GROUP, MDNS_PORT, 0, interface_index).into())?;
+    socket.join_multicast_v6(&MDNS_GROUP, interface_index)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// The subtype label of a `<subtype>._sub.<service type>` PTR owner name
+fn subtype(owner: &Name) -> Option<String> {
+    let mut labels = owner.iter();
+    let subtype = labels.next()?;
+    (labels.next()? == b"_sub").then(|| String::from_utf8_lossy(subtype).into_owned())
+}
+
+/// A name as mdns-sd spells it: raw labels joined by dots, with the root dot
+fn fullname(name: &Name) -> String {
+    let mut out = String::new();
+    for label in name.iter() {
+        out.push_str(&String::from_utf8_lossy(label));
+        out.push('.');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::PTR;
+    use hickory_proto::rr::Record;
+
+    fn announcement(owner: &str, instance: &str, ttl: u32) -> Message {
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        let ptr = PTR(Name::from_labels(instance.split('.').map(str::as_bytes)).unwrap());
+        message.add_answer(Record::from_rdata(Name::from_ascii(owner).unwrap(), ttl, RData::PTR(ptr)));
+        message
+    }
+
+    #[test]
+    fn test_record_ttls() {
+        let announcements = Announcements::default();
+        announcements.record(&announcement("_http._tcp.local.", "Living Room._http._tcp.local", 120), "eth0");
+        assert_eq!(announcements.ttl("Living Room._http._tcp.local."), Some(120));
+        assert_eq!(announcements.ttl("Kitchen._http._tcp.local."), None);
+
+        // A goodbye forgets the instance
+        announcements.record(&announcement("_http._tcp.local.", "Living Room._http._tcp.local", 0), "eth0");
+        assert_eq!(announcements.ttl("Living Room._http._tcp.local."), None);
+    }
+
+    #[test]
+    fn test_record_subtypes() {
+        let announcements = Announcements::default();
+        let instance = "Office._http._tcp.local";
+        announcements.record(&announcement("_printer._sub._http._tcp.local.", instance, 4500), "eth0");
+        announcements.record(&announcement("_scanner._sub._http._tcp.local.", instance, 4500), "eth0");
+        announcements.record(&announcement("_http._tcp.local.", instance, 120), "eth0");
+        assert_eq!(announcements.subtypes("Office._http._tcp.local."), ["_printer", "_scanner"]);
+        assert_eq!(announcements.ttl("Office._http._tcp.local."), Some(120), "subtype TTLs aren't the instance's");
+
+        // A subtype goodbye drops only that subtype
+        announcements.record(&announcement("_scanner._sub._http._tcp.local.", instance, 0), "eth0");
+        assert_eq!(announcements.subtypes("Office._http._tcp.local."), ["_printer"]);
+        assert_eq!(announcements.ttl("Office._http._tcp.local."), Some(120));
+    }
+
+    #[test]
+    fn test_record_iface() {
+        let announcements = Announcements::default();
+        let instance = "Office._http._tcp.local";
+        announcements.record(&announcement("_http._tcp.local.", instance, 120), "eth0");
+        assert_eq!(announcements.iface("Office._http._tcp.local.").as_deref(), Some("eth0"));
+
+        // Heard on another segment, e.g. after moving
+        announcements.record(&announcement("_http._tcp.local.", instance, 120), "eth1");
+        assert_eq!(announcements.iface("Office._http._tcp.local.").as_deref(), Some("eth1"));
+        assert_eq!(announcements.iface("Kitchen._http._tcp.local."), None);
+    }
+}
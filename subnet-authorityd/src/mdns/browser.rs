@@ -1,24 +1,48 @@
 use std::collections::HashSet;
-use std::net::Ipv6Addr;
+use std::net::IpAddr;
 use std::pin::Pin;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio_util::sync::CancellationToken;
 use mdns_sd::{ServiceDaemon, ServiceEvent};
-use futures::stream::{FuturesUnordered, StreamExt};
+use futures::stream::{self, BoxStream, FuturesUnordered, StreamExt};
 use futures::Future;
 use anyhow::{Context, Result};
 use chrono::Utc;
-use shared::types::ServiceEntry;
+use shared::names::InstanceName;
+use shared::types::{ServiceEntry, ServiceSource};
 use std::collections::HashMap;
+use crate::addresses::AddressFilter;
+use crate::config::{BrowserConfig, MdnsConfig};
+use crate::discovery::{DiscoverySource, SourceKind};
+use crate::mdns::proxy::ProxiedNames;
+use crate::mdns::rejected::{RejectReason, RejectedRing};
+use crate::mdns::announced::Announcements;
 
 const META_QUERY_TYPE: &str = "_services._dns-sd._udp.local.";
 
 /// Fix #5: BrowserEvent belongs in the browser module, not cache_manager
 pub enum BrowserEvent {
-    Resolved(ServiceEntry),
+    /// Boxed, as entries are many times the size of a removal
+    Resolved(Box<ServiceEntry>),
     Removed(String),
 }
 
+/// Requests to the browser from the rest of the daemon
+pub enum BrowserCommand {
+    /// Browse a type afresh, which re-sends the PTR query for it. Ignored
+    /// unless the type is already browsed.
+    Probe(String),
+    /// Browse a fully-qualified type even if the meta-query hasn't surfaced
+    /// it. Replies false, and probes it, if it was already browsed.
+    Browse(String, oneshot::Sender<bool>),
+    /// Replace the `[browser]` patterns, browsing discovered types they now
+    /// allow and stopping those they now exclude. Types asked for by name
+    /// stay browsed. Replies, if asked, with every type browsed afterwards.
+    SetTypes(TypeFilter, Option<oneshot::Sender<Vec<String>>>),
+}
+
 type RecvResult = (usize, flume::Receiver<ServiceEvent>, std::result::Result<ServiceEvent, flume::RecvError>);
 type RecvFuture = Pin<Box<dyn Future<Output = RecvResult> + Send>>;
 
@@ -31,19 +55,106 @@ fn make_recv_future(idx: usize, rx: flume::Receiver<ServiceEvent>) -> RecvFuture
     })
 }
 
-pub async fn run_browser(
+/// What the browser is configured by
+#[derive(Debug, Clone)]
+pub struct BrowserSettings {
+    pub mdns: MdnsConfig,
+    /// Which advertised addresses are stored
+    pub addresses: AddressFilter,
+    /// Which discovered service types are browsed
+    pub types: TypeFilter,
+    /// TTLs, subtypes and interfaces heard in responses, which mdns-sd
+    /// doesn't pass on
+    pub announcements: Arc<Announcements>,
+    /// Services this daemon advertises by proxy, not to be cached as mDNS ones
+    pub proxied: Arc<ProxiedNames>,
+}
+
+/// `[browser]` include and exclude patterns, compiled
+#[derive(Debug, Clone, Default)]
+pub struct TypeFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl TypeFilter {
+    pub fn new(config: &BrowserConfig) -> Result<Self> {
+        let compile = |patterns: &[String], key: &str| {
+            patterns
+                .iter()
+                .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid browser.{} pattern: {}", key, p)))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            include: compile(&config.include_types, "include_types")?,
+            exclude: compile(&config.exclude_types, "exclude_types")?,
+        })
+    }
+
+    /// Whether to browse `service_type`, matched without its `.local.` domain
+    pub fn allows(&self, service_type: &str) -> bool {
+        let short = service_type.trim_end_matches('.').trim_end_matches(".local");
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(short)))
+            && !self.exclude.iter().any(|p| p.matches(short))
+    }
+}
+
+/// mDNS as a discovery source: `run_browser` behind a stream
+pub struct MdnsSource {
+    pub daemon: ServiceDaemon,
+    pub commands: mpsc::Receiver<BrowserCommand>,
+    pub settings: BrowserSettings,
+    pub warm_tx: watch::Sender<bool>,
+    pub rejected: Arc<RejectedRing>,
+}
+
+impl DiscoverySource for MdnsSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Mdns
+    }
+
+    fn events(self: Box<Self>, cancel: CancellationToken) -> BoxStream<'static, BrowserEvent> {
+        let (tx, rx) = mpsc::channel(256);
+        tokio::spawn(async move {
+            let Self { daemon, commands, settings, warm_tx, rejected } = *self;
+            if let Err(e) = run_browser(daemon, tx, commands, settings, warm_tx, rejected, cancel).await {
+                tracing::error!("mDNS browser error: {}", e);
+            }
+        });
+        // Ends once the browser returns and drops `tx`
+        stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|event| (event, rx)) }).boxed()
+    }
+}
+
+/// Browse all service types, sending results to `tx` and taking requests on
+/// `commands`
+async fn run_browser(
     daemon: ServiceDaemon,
     tx: mpsc::Sender<BrowserEvent>,
+    mut commands: mpsc::Receiver<BrowserCommand>,
+    mut settings: BrowserSettings,
+    warm_tx: watch::Sender<bool>,
+    rejected: Arc<RejectedRing>,
     cancel: CancellationToken,
 ) -> Result<()> {
     tracing::info!("Starting mDNS browser");
 
+    // Responders answer the initial queries within the warmup window; once it
+    // passes, the cache reflects a full discovery cycle
+    let warmup = tokio::time::sleep(Duration::from_secs(settings.mdns.warmup_secs));
+    tokio::pin!(warmup);
+    let mut warmed_up = false;
+
     // Start browsing the meta-query to discover all service types
     let meta_receiver = daemon
         .browse(META_QUERY_TYPE)
         .context("Failed to start meta-query browse")?;
 
     let mut browsed_types = HashSet::new();
+    // Types browsed by `BrowserCommand::Browse`, which patterns don't apply to
+    let mut requested: HashSet<String> = HashSet::new();
+    // Receiver index currently serving each browsed type; a re-browse replaces it
+    let mut type_receivers: HashMap<String, usize> = HashMap::new();
     let mut next_idx = 0usize;
     // Fix #4: use FuturesUnordered for async event-driven reception instead of
     // try_recv + sleep polling. Each future yields (receiver_index, receiver, result).
@@ -58,17 +169,14 @@ pub async fn run_browser(
                         let service_type = info.get_type();
 
                         if !browsed_types.contains(service_type) {
-                            tracing::info!("Discovered new service type: {}", service_type);
                             browsed_types.insert(service_type.to_string());
 
-                            match daemon.browse(service_type) {
-                                Ok(receiver) => {
-                                    let idx = next_idx;
-                                    next_idx += 1;
-                                    type_futures.push(make_recv_future(idx, receiver));
-                                }
-                                Err(e) => {
-                                    tracing::error!("Failed to browse {}: {}", service_type, e);
+                            if !settings.types.allows(service_type) {
+                                tracing::info!("Discovered service type {}, excluded by [browser]", service_type);
+                            } else {
+                                tracing::info!("Discovered new service type: {}", service_type);
+                                if let Some(idx) = browse_type(&daemon, service_type, &mut next_idx, &mut type_futures) {
+                                    type_receivers.insert(service_type.to_string(), idx);
                                 }
                             }
                         }
@@ -85,17 +193,37 @@ pub async fn run_browser(
             Some((idx, rx, result)) = type_futures.next() => {
                 match result {
                     Ok(ServiceEvent::ServiceResolved(info)) => {
-                        if let Some(entry) = convert_service_info(&info) {
-                            tracing::debug!("Resolved service: {}", entry.instance_name);
-                            if let Err(e) = tx.send(BrowserEvent::Resolved(entry)).await {
-                                tracing::error!("Failed to send resolved event: {}", e);
+                        match convert_service_info(&info, &settings.mdns, &settings.addresses) {
+                            Ok(_) if settings.proxied.read().unwrap().contains(&canonical_name(info.get_fullname())) => {
+                                tracing::debug!("Ignoring {}, advertised by proxy", info.get_fullname());
+                            }
+                            Ok(mut entry) => {
+                                tracing::debug!("Resolved service: {}", entry.instance_name);
+                                if let Some(ttl) = settings.announcements.ttl(&entry.instance_name) {
+                                    entry.ttl = ttl;
+                                }
+                                entry.subtypes = settings.announcements.subtypes(&entry.instance_name);
+                                entry.iface = settings.announcements.iface(&entry.instance_name);
+                                if entry.addresses.is_empty() {
+                                    rejected.push(&entry.instance_name, RejectReason::NoIpv6Addresses, true);
+                                }
+                                if let Err(e) = tx.send(BrowserEvent::Resolved(Box::new(entry))).await {
+                                    tracing::error!("Failed to send resolved event: {}", e);
+                                }
+                            }
+                            Err(reason) => {
+                                tracing::debug!("Skipping service {} - {}", info.get_fullname(), reason);
+                                rejected.push(info.get_fullname(), reason, false);
                             }
                         }
                         type_futures.push(make_recv_future(idx, rx));
                     }
                     Ok(ServiceEvent::ServiceRemoved(_typ, fullname)) => {
                         tracing::debug!("Service removed: {}", fullname);
-                        if let Err(e) = tx.send(BrowserEvent::Removed(fullname)).await {
+                        let instance_name = canonical_name(&fullname);
+                        if settings.proxied.read().unwrap().contains(&instance_name) {
+                            tracing::debug!("Ignoring removal of {}, advertised by proxy", fullname);
+                        } else if let Err(e) = tx.send(BrowserEvent::Removed(instance_name)).await {
                             tracing::error!("Failed to send removed event: {}", e);
                         }
                         type_futures.push(make_recv_future(idx, rx));
@@ -104,11 +232,71 @@ pub async fn run_browser(
                         type_futures.push(make_recv_future(idx, rx));
                     }
                     Err(e) => {
-                        tracing::warn!("Receiver {} disconnected: {}", idx, e);
+                        if type_receivers.values().any(|&current| current == idx) {
+                            tracing::warn!("Receiver {} disconnected: {}", idx, e);
+                        } else {
+                            tracing::debug!("Receiver {} replaced by a probe", idx);
+                        }
+                    }
+                }
+            }
+
+            Some(command) = commands.recv() => {
+                let (service_type, reply) = match command {
+                    BrowserCommand::Probe(service_type) => (service_type, None),
+                    BrowserCommand::Browse(service_type, reply) => (service_type, Some(reply)),
+                    BrowserCommand::SetTypes(types, reply) => {
+                        for service_type in browsed_types.difference(&requested) {
+                            let browsing = type_receivers.contains_key(service_type);
+                            if types.allows(service_type) && !browsing {
+                                tracing::info!("Browsing {}, now allowed by [browser]", service_type);
+                                if let Some(idx) = browse_type(&daemon, service_type, &mut next_idx, &mut type_futures) {
+                                    type_receivers.insert(service_type.clone(), idx);
+                                }
+                            } else if !types.allows(service_type) && browsing {
+                                tracing::info!("No longer browsing {}, now excluded by [browser]", service_type);
+                                if let Err(e) = daemon.stop_browse(service_type) {
+                                    tracing::warn!("Failed to stop browsing {}: {}", service_type, e);
+                                }
+                                type_receivers.remove(service_type);
+                            }
+                        }
+                        settings.types = types;
+                        if let Some(reply) = reply {
+                            let mut active: Vec<String> = type_receivers.keys().cloned().collect();
+                            active.sort();
+                            let _ = reply.send(active);
+                        }
+                        continue;
+                    }
+                };
+                // Browsing a type again replaces the daemon's listener for it,
+                // so the old receiver disconnects once drained
+                if let Some(current) = type_receivers.get_mut(&service_type) {
+                    if let Some(idx) = browse_type(&daemon, &service_type, &mut next_idx, &mut type_futures) {
+                        *current = idx;
+                    }
+                    if let Some(reply) = reply {
+                        let _ = reply.send(false);
                     }
+                } else if let Some(reply) = reply {
+                    // Asked for by name, so [browser] patterns don't apply
+                    tracing::info!("Browsing {} on request", service_type);
+                    browsed_types.insert(service_type.clone());
+                    requested.insert(service_type.clone());
+                    if let Some(idx) = browse_type(&daemon, &service_type, &mut next_idx, &mut type_futures) {
+                        type_receivers.insert(service_type, idx);
+                    }
+                    let _ = reply.send(true);
                 }
             }
 
+            _ = &mut warmup, if !warmed_up => {
+                warmed_up = true;
+                tracing::info!("Initial discovery complete ({} service types)", browsed_types.len());
+                warm_tx.send_replace(true);
+            }
+
             _ = cancel.cancelled() => {
                 tracing::info!("mDNS browser shutting down");
                 break;
@@ -119,46 +307,181 @@ pub async fn run_browser(
     Ok(())
 }
 
-/// Convert an mdns-sd ServiceInfo to our ServiceEntry
-fn convert_service_info(info: &mdns_sd::ServiceInfo) -> Option<ServiceEntry> {
+/// Start browsing `service_type` and queue its first receive. Returns the receiver index.
+fn browse_type(
+    daemon: &ServiceDaemon,
+    service_type: &str,
+    next_idx: &mut usize,
+    type_futures: &mut FuturesUnordered<RecvFuture>,
+) -> Option<usize> {
+    match daemon.browse(service_type) {
+        Ok(receiver) => {
+            let idx = *next_idx;
+            *next_idx += 1;
+            type_futures.push(make_recv_future(idx, receiver));
+            Some(idx)
+        }
+        Err(e) => {
+            tracing::error!("Failed to browse {}: {}", service_type, e);
+            None
+        }
+    }
+}
+
+/// Convert an mdns-sd ServiceInfo to our ServiceEntry, or say why it can't be cached
+fn convert_service_info(
+    info: &mdns_sd::ServiceInfo,
+    config: &MdnsConfig,
+    filter: &AddressFilter,
+) -> Result<ServiceEntry, RejectReason> {
     let now = Utc::now();
 
-    // Extract IPv6 addresses only
-    let addresses: Vec<Ipv6Addr> = info
+    // Fuzzers emit kilobyte names; keep them out of the primary key and responses
+    if info.get_fullname().len() > config.max_instance_name_len {
+        tracing::warn!(
+            "Instance name of {} bytes exceeds the {} byte limit: {:.64}...",
+            info.get_fullname().len(),
+            config.max_instance_name_len,
+            info.get_fullname()
+        );
+        return Err(RejectReason::InstanceNameTooLong);
+    }
+
+    // IPv6 addresses, and IPv4 ones if configured, that pass the filter
+    let addresses: Vec<IpAddr> = info
         .get_addresses()
         .iter()
-        .filter_map(|addr| match addr {
-            std::net::IpAddr::V6(ipv6) => Some(*ipv6),
-            _ => None,
-        })
+        .filter(|addr| (addr.is_ipv6() || config.ipv4) && filter.keeps(addr))
+        .copied()
         .collect();
 
-    if addresses.is_empty() {
-        tracing::debug!("Skipping service {} - no IPv6 addresses", info.get_fullname());
-        return None;
+    if addresses.is_empty() && !config.keep_addressless {
+        return Err(RejectReason::NoIpv6Addresses);
     }
 
     // Extract TXT records
-    let txt: HashMap<String, String> = info
-        .get_properties()
-        .iter()
-        .map(|prop| {
-            let key = prop.key().to_string();
-            let value = prop.val_str().to_string();
-            (key, value)
-        })
-        .collect();
+    let txt = collect_txt(
+        info.get_properties()
+            .iter()
+            .map(|prop| (prop.key().to_string(), prop.val_str().to_string())),
+        config.lowercase_txt_keys,
+    );
 
-    Some(ServiceEntry {
+    Ok(ServiceEntry {
         service_type: info.get_type().to_string(),
-        instance_name: info.get_fullname().to_string(),
+        instance_name: canonical_name(info.get_fullname()),
         hostname: info.get_hostname().to_string(),
         addresses,
         port: info.get_port(),
         txt,
         first_seen: now,
         last_seen: now,
-        ttl: 4500, // Default TTL - mdns-sd doesn't expose this
+        // mdns-sd's default; the browser swaps in the announced TTL if heard
+        ttl: info.get_other_ttl(),
         alive: true,
+        source: ServiceSource::Mdns,
+        reachable: None,
+        latency_ms: None,
+        subtypes: Vec::new(),
+        iface: None,
     })
 }
+
+/// Key the cache by the canonical instance name, so resolve and remove events
+/// agree. Names that don't parse as DNS-SD instances are kept verbatim.
+pub(super) fn canonical_name(fullname: &str) -> String {
+    InstanceName::parse(fullname)
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| fullname.to_string())
+}
+
+/// Build the TXT map, optionally lowercasing keys. When lowercasing merges two
+/// keys the first one wins, as RFC 6763 section 6.4 prescribes for repeats.
+fn collect_txt(
+    pairs: impl IntoIterator<Item = (String, String)>,
+    lowercase_keys: bool,
+) -> HashMap<String, String> {
+    if !lowercase_keys {
+        return pairs.into_iter().collect();
+    }
+
+    let mut txt = HashMap::new();
+    for (key, value) in pairs {
+        txt.entry(key.to_ascii_lowercase()).or_insert(value);
+    }
+    txt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_txt_keys_preserved_by_default() {
+        let txt = collect_txt(pairs(&[("Path", "/a"), ("path", "/b")]), false);
+        assert_eq!(txt.len(), 2);
+        assert_eq!(txt["Path"], "/a");
+        assert_eq!(txt["path"], "/b");
+    }
+
+    #[test]
+    fn test_txt_keys_lowercased() {
+        let txt = collect_txt(pairs(&[("Path", "/a"), ("VERSION", "2"), ("path", "/b")]), true);
+        assert_eq!(txt.len(), 2);
+        assert_eq!(txt["path"], "/a", "First occurrence should win");
+        assert_eq!(txt["version"], "2");
+    }
+
+    fn service_info(instance: &str) -> mdns_sd::ServiceInfo {
+        mdns_sd::ServiceInfo::new("_http._tcp.local.", instance, "host.local.", "fd00::1", 80, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_overlong_instance_name_rejected() {
+        let config = MdnsConfig::default();
+
+        let entry = convert_service_info(&service_info("printer"), &config, &AddressFilter::default()).unwrap();
+        assert_eq!(entry.instance_name, "printer._http._tcp.local.");
+
+        let result = convert_service_info(&service_info(&"x".repeat(300)), &config, &AddressFilter::default());
+        assert_eq!(result.unwrap_err(), RejectReason::InstanceNameTooLong);
+    }
+
+    #[test]
+    fn test_type_filter() {
+        let config = BrowserConfig {
+            include_types: vec!["_ipp*._tcp".to_string(), "_googlecast._tcp".to_string()],
+            exclude_types: vec!["_googlecast.*".to_string()],
+        };
+        let filter = TypeFilter::new(&config).unwrap();
+        assert!(filter.allows("_ipps._tcp.local."));
+        assert!(!filter.allows("_googlecast._tcp.local."));
+        assert!(!filter.allows("_http._tcp.local."));
+        assert!(TypeFilter::default().allows("_http._tcp.local."));
+
+        let config = BrowserConfig { include_types: vec!["[".to_string()], ..BrowserConfig::default() };
+        assert!(TypeFilter::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_addressless_kept_when_configured() {
+        let info = mdns_sd::ServiceInfo::new("_http._tcp.local.", "legacy", "host.local.", "10.0.0.1", 80, None)
+            .unwrap();
+
+        let result = convert_service_info(&info, &MdnsConfig::default(), &AddressFilter::default());
+        assert_eq!(result.unwrap_err(), RejectReason::NoIpv6Addresses);
+
+        let config = MdnsConfig { keep_addressless: true, ..MdnsConfig::default() };
+        let entry = convert_service_info(&info, &config, &AddressFilter::default()).unwrap();
+        assert!(entry.addresses.is_empty());
+
+        let config = MdnsConfig { ipv4: true, ..MdnsConfig::default() };
+        let entry = convert_service_info(&info, &config, &AddressFilter::default()).unwrap();
+        assert_eq!(entry.addresses, ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+    }
+}
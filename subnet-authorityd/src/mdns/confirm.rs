@@ -0,0 +1,162 @@
+//! Re-confirmation of services nearing staleness. Healthy devices can stay
+//! quiet longer than the cache waits, so each one is asked for its SRV record
+//! directly. The query is a one-shot query (RFC 6762 section 5.1) sent from
+//! its own port, which responders answer by unicast, so mdns-sd's socket is
+//! never involved.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv6Addr, SocketAddrV6};
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use hickory_proto::op::{Message, MessageType, Query};
+use hickory_proto::rr::{Name, RecordType};
+use shared::names::InstanceName;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+const MDNS_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_PORT: u16 = 5353;
+
+/// What a confirmation round found out about a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Confirmed,
+    /// Unanswered this many times in a row
+    Failed(u32),
+    /// Unanswered as many times as allowed
+    Dead,
+}
+
+/// Asks services whether they're still there, counting failures per instance
+pub struct Confirmer {
+    interface_index: u32,
+    attempts: u32,
+    timeout: Duration,
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+impl Confirmer {
+    pub fn new(interface: &str, attempts: u32, timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            interface_index: super::interfaces::interface_index(interface)?,
+            attempts,
+            timeout,
+            failures: Mutex::default(),
+        })
+    }
+
+    /// Unanswered confirmations after which a service is dead
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Query `instance_name` once and count the result. Fails, without
+    /// counting, if the query couldn't be sent.
+    pub async fn confirm(&self, instance_name: &str) -> Result<Outcome> {
+        let name = query_name(instance_name)
+            .with_context(|| format!("{} is not a DNS-SD instance name", instance_name))?;
+        let answered = self.query(&name).await?;
+        Ok(self.count(instance_name, answered))
+    }
+
+    /// Forget the failures of instances not in `instance_names`, which were
+    /// seen again or went away
+    pub fn retain(&self, instance_names: &HashSet<&str>) {
+        self.failures.lock().unwrap().retain(|name, _| instance_names.contains(name.as_str()));
+    }
+
+    fn count(&self, instance_name: &str, answered: bool) -> Outcome {
+        let mut failures = self.failures.lock().unwrap();
+        if answered {
+            failures.remove(instance_name);
+            return Outcome::Confirmed;
+        }
+        let count = failures.entry(instance_name.to_string()).or_default();
+        *count += 1;
+        if *count < self.attempts {
+            return Outcome::Failed(*count);
+        }
+        failures.remove(instance_name);
+        Outcome::Dead
+    }
+
+    /// Whether a response for `name`'s SRV record arrives within the timeout
+    async fn query(&self, name: &Name) -> Result<bool> {
+        let socket = bind(self.interface_index).context("Failed to open confirmation socket")?;
+        let mut message = Message::new();
+        message.add_query(Query::query(name.clone(), RecordType::SRV));
+        let group = SocketAddrV6::new(MDNS_GROUP, MDNS_PORT, 0, self.interface_index);
+        socket.send_to(&message.to_vec()?, group).await.context("Failed to send confirmation query")?;
+
+        let mut buf = vec![0u8; 9000];
+        let answered = async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if Message::from_vec(&buf[..len]).is_ok_and(|response| answers(&response, name)) {
+                    return Ok::<_, std::io::Error>(true);
+                }
+            }
+        };
+        match tokio::time::timeout(self.timeout, answered).await {
+            Ok(result) => result.context("Failed to receive confirmation"),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// Whether `response` carries an SRV record for `name`
+fn answers(response: &Message, name: &Name) -> bool {
+    response.message_type() == MessageType::Response
+        && response
+            .answers()
+            .iter()
+            .any(|r| r.record_type() == RecordType::SRV && r.name() == name)
+}
+
+/// `instance_name` as wire labels; the instance label may contain dots
+fn query_name(instance_name: &str) -> Option<Name> {
+    let instance = InstanceName::parse(instance_name)?;
+    let labels = std::iter::once(instance.short_name.as_str())
+        .chain(instance.service_type.split('.'))
+        .chain(instance.domain.split('.'));
+    let mut name = Name::from_labels(labels.map(str::as_bytes)).ok()?;
+    name.set_fqdn(true);
+    Some(name)
+}
+
+/// An ephemeral-port socket sending to the mDNS group on `interface_index`
+fn bind(interface_index: u32) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_multicast_if_v6(interface_index)?;
+    socket.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::SRV;
+    use hickory_proto::rr::{RData, Record};
+
+    #[test]
+    fn test_failures_counted_until_dead() {
+        let confirmer = Confirmer { interface_index: 0, attempts: 2, timeout: Duration::ZERO, failures: Mutex::default() };
+        assert_eq!(confirmer.count("a._http._tcp.local.", false), Outcome::Failed(1));
+        assert_eq!(confirmer.count("a._http._tcp.local.", true), Outcome::Confirmed);
+        assert_eq!(confirmer.count("a._http._tcp.local.", false), Outcome::Failed(1));
+        assert_eq!(confirmer.count("a._http._tcp.local.", false), Outcome::Dead);
+
+        // Dotted instance labels stay one label
+        let name = query_name("Mr. Printer._ipp._tcp.local.").unwrap();
+        assert_eq!(name.num_labels(), 4);
+        let mut response = Message::new();
+        response.set_message_type(MessageType::Response);
+        let srv = SRV::new(0, 0, 631, Name::from_ascii("printer.local.").unwrap());
+        response.add_answer(Record::from_rdata(name.clone(), 120, RData::SRV(srv)));
+        assert!(answers(&response, &name));
+        assert!(!answers(&response, &query_name("Other._ipp._tcp.local.").unwrap()));
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use ipnet::Ipv6Net;
+use mdns_sd::ServiceDaemon;
+use tokio_util::sync::CancellationToken;
+use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use futures::{stream::BoxStream, FutureExt, StreamExt, TryStreamExt};
+#[cfg(target_os = "linux")]
+use netlink_packet_route::{link::nlas::Nla, IFF_RUNNING};
+
+/// How long after a netlink change to wait for the rest of its burst
+#[cfg(target_os = "linux")]
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Create the mDNS daemon with only `interface` enabled
+pub fn create_daemon(interface: &str) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()
+        .context("Failed to create mDNS daemon")?;
+    daemon
+        .disable_interface(mdns_sd::IfKind::All)
+        .context("Failed to disable default interfaces")?;
+    daemon
+        .enable_interface(interface)
+        .with_context(|| format!("Failed to enable interface {}", interface))?;
+    Ok(daemon)
+}
+
+/// Whether the daemon's thread reports itself running within `timeout`
+pub async fn daemon_alive(daemon: &ServiceDaemon, timeout: std::time::Duration) -> bool {
+    let Ok(status) = daemon.status() else {
+        return false;
+    };
+    matches!(
+        tokio::time::timeout(timeout, status.recv_async()).await,
+        Ok(Ok(mdns_sd::DaemonStatus::Running))
+    )
+}
+
+/// Addresses of every interface, as (name, address) pairs
+pub fn list_addresses() -> Result<Vec<(String, IpAddr)>> {
+    let interfaces = if_addrs::get_if_addrs().context("Failed to list interfaces")?;
+    Ok(interfaces.into_iter().map(|i| (i.name.clone(), i.ip())).collect())
+}
+
+/// The index of `interface`, for scoping link-local sockets to it
+pub fn interface_index(interface: &str) -> Result<u32> {
+    if_addrs::get_if_addrs()
+        .context("Failed to list interfaces")?
+        .into_iter()
+        .find(|i| i.name == interface)
+        .and_then(|i| i.index)
+        .with_context(|| format!("No index for interface {}", interface))
+}
+
+/// Enable mDNS on every interface holding an address inside the subnet
+/// prefix, and disable it on interfaces that lose theirs, checking every
+/// `interval` and, with `watch`, whenever netlink reports a change. The
+/// configured primary interface is always left enabled. The advertised
+/// services are re-announced whenever interfaces are added, so they hear of
+/// them too. The interfaces browsed after each pass are sent on `browsed`.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_rescan(
+    daemon: ServiceDaemon,
+    prefix: Ipv6Net,
+    primary: String,
+    interval: Option<Duration>,
+    mut watch: Option<LinkWatch>,
+    advertisements: Arc<super::advertise::Advertisements>,
+    browsed: tokio::sync::watch::Sender<BTreeSet<String>>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    match interval {
+        Some(interval) => tracing::info!("Rescanning interfaces for {} every {:?}", prefix, interval),
+        None => tracing::info!("Rescanning interfaces for {} on link changes", prefix),
+    }
+
+    let mut enabled: BTreeSet<String> = BTreeSet::from([primary.clone()]);
+    let mut ticker = interval.map(tokio::time::interval);
+
+    loop {
+        tokio::select! {
+            _ = tick(&mut ticker) => {}
+            _ = changed(&mut watch) => tracing::debug!("Interfaces changed, rescanning"),
+            _ = cancel.cancelled() => {
+                tracing::info!("Interface rescan shutting down");
+                break;
+            }
+        }
+
+        let addrs = match list_addresses() {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                tracing::warn!("{:#}", e);
+                continue;
+            }
+        };
+        let links_up = match &mut watch {
+            Some(watch) => match watch.links_up().await {
+                Ok(up) => Some(up),
+                Err(e) => {
+                    tracing::warn!("{:#}", e);
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let wanted = wanted_interfaces(&addrs, links_up.as_ref(), &prefix, &primary);
+
+        let mut added = false;
+        for name in wanted.difference(&enabled) {
+            match daemon.enable_interface(name.as_str()) {
+                Ok(()) => {
+                    tracing::info!("Interface {} gained an in-prefix address, browsing it", name);
+                    added = true;
+                }
+                Err(e) => tracing::error!("Failed to enable interface {}: {}", name, e),
+            }
+        }
+        for name in enabled.difference(&wanted) {
+            match daemon.disable_interface(name.as_str()) {
+                Ok(()) => tracing::info!("Interface {} lost its in-prefix address or link, no longer browsing it", name),
+                Err(e) => tracing::error!("Failed to disable interface {}: {}", name, e),
+            }
+        }
+        if added {
+            let advertisements = advertisements.read().unwrap().clone();
+            for advertisement in &advertisements {
+                if let Err(e) = daemon.register(advertisement.clone()) {
+                    tracing::error!("Failed to re-announce {}: {}", advertisement.get_fullname(), e);
+                }
+            }
+        }
+
+        browsed.send_if_modified(|browsed| {
+            let changed = *browsed != wanted;
+            browsed.clone_from(&wanted);
+            changed
+        });
+        enabled = wanted;
+    }
+
+    Ok(())
+}
+
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn changed(watch: &mut Option<LinkWatch>) {
+    match watch {
+        Some(watch) => watch.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Interfaces to browse: those with an in-prefix address and, if link states
+/// are known, a link that is up, plus the primary regardless
+pub fn wanted_interfaces(
+    addrs: &[(String, IpAddr)],
+    links_up: Option<&BTreeSet<String>>,
+    prefix: &Ipv6Net,
+    primary: &str,
+) -> BTreeSet<String> {
+    let mut wanted = in_prefix_interfaces(addrs, prefix);
+    if let Some(up) = links_up {
+        wanted.retain(|name| up.contains(name));
+    }
+    wanted.insert(primary.to_string());
+    wanted
+}
+
+/// Link and address changes from netlink, and link states
+#[cfg(target_os = "linux")]
+pub struct LinkWatch {
+    handle: rtnetlink::Handle,
+    changes: BoxStream<'static, ()>,
+}
+
+#[cfg(target_os = "linux")]
+impl LinkWatch {
+    /// Subscribe to link and address changes. Must be called in the runtime,
+    /// which drives the netlink connection.
+    pub fn new() -> Result<Self> {
+        use netlink_sys::AsyncSocket;
+        use rtnetlink::constants::{RTMGRP_IPV4_IFADDR, RTMGRP_IPV6_IFADDR, RTMGRP_LINK};
+
+        let (mut connection, handle, changes) =
+            rtnetlink::new_connection().context("Failed to open netlink socket")?;
+        let groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&netlink_sys::SocketAddr::new(0, groups))
+            .context("Failed to subscribe to netlink link and address changes")?;
+        tokio::spawn(connection);
+        Ok(Self { handle, changes: changes.map(|_| ()).boxed() })
+    }
+
+    /// Wait for a change, then for the rest of its burst (an address per
+    /// prefix, duplicate address detection finishing, and so on)
+    async fn changed(&mut self) {
+        if self.changes.next().await.is_none() {
+            // The connection is gone; keep to the interval
+            return std::future::pending().await;
+        }
+        tokio::time::sleep(SETTLE).await;
+        while let Some(Some(_)) = self.changes.next().now_or_never() {}
+    }
+
+    /// Names of interfaces whose link is up and running
+    async fn links_up(&mut self) -> Result<BTreeSet<String>> {
+        let mut links = self.handle.clone().link().get().execute();
+        let mut up = BTreeSet::new();
+        while let Some(link) = links.try_next().await.context("Failed to list links")? {
+            if link.header.flags & IFF_RUNNING != 0 {
+                up.extend(link.nlas.into_iter().filter_map(|nla| match nla {
+                    Nla::IfName(name) => Some(name),
+                    _ => None,
+                }));
+            }
+        }
+        Ok(up)
+    }
+}
+
+/// Netlink is Linux's; elsewhere the interval is all there is
+#[cfg(not(target_os = "linux"))]
+pub struct LinkWatch;
+
+#[cfg(not(target_os = "linux"))]
+impl LinkWatch {
+    pub fn new() -> Result<Self> {
+        anyhow::bail!("mdns.watch_interfaces needs netlink, which only Linux has")
+    }
+
+    async fn changed(&mut self) {
+        std::future::pending().await
+    }
+
+    async fn links_up(&mut self) -> Result<BTreeSet<String>> {
+        Ok(BTreeSet::new())
+    }
+}
+
+/// Names of interfaces with at least one address inside `prefix`
+pub fn in_prefix_interfaces(addrs: &[(String, IpAddr)], prefix: &Ipv6Net) -> BTreeSet<String> {
+    addrs
+        .iter()
+        .filter(|(_, addr)| matches!(addr, IpAddr::V6(v6) if prefix.contains(v6)))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_prefix_interfaces() {
+        let prefix: Ipv6Net = "fd00:1234:5678:1::/64".parse().unwrap();
+        let addrs = vec![
+            ("eth0".to_string(), "fd00:1234:5678:1::1".parse().unwrap()),
+            ("eth0".to_string(), "fe80::1".parse().unwrap()),
+            ("wg0".to_string(), "fd00:1234:5678:2::1".parse().unwrap()),
+            ("usb0".to_string(), "192.168.1.2".parse().unwrap()),
+            ("usb1".to_string(), "fd00:1234:5678:1::42".parse().unwrap()),
+        ];
+
+        let names: Vec<String> = in_prefix_interfaces(&addrs, &prefix).into_iter().collect();
+        assert_eq!(names, vec!["eth0".to_string(), "usb1".to_string()]);
+
+        // With link states known, a down link is left out, but never the primary
+        let up = BTreeSet::from(["eth0".to_string()]);
+        let names: Vec<String> = wanted_interfaces(&addrs, Some(&up), &prefix, "lo").into_iter().collect();
+        assert_eq!(names, vec!["eth0".to_string(), "lo".to_string()]);
+    }
+}
@@ -1,2 +1,8 @@
 pub mod browser;
+pub mod confirm;
 pub mod advertise;
+pub mod announced;
+pub mod interfaces;
+pub mod proxy;
+pub mod reflector;
+pub mod rejected;
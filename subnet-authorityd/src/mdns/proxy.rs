@@ -0,0 +1,145 @@
+//! Advertising of statically registered services on mDNS, on behalf of hosts
+//! that can't multicast. Each alive `static` service is registered with the
+//! daemon under its own instance name, host, and addresses, so mDNS clients
+//! find it as if the host announced it itself.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use shared::names::InstanceName;
+use shared::types::{ServiceEntry, ServiceSource};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use crate::cache_manager::CacheSnapshot;
+
+/// Instance names currently advertised by proxy, which the browser ignores
+/// when it hears them back
+pub type ProxiedNames = RwLock<HashSet<String>>;
+
+/// Keep the daemon's proxy registrations in line with the cache until
+/// `cancel` fires, then withdraw them
+pub async fn run_proxy(
+    daemon: ServiceDaemon,
+    mut snapshot_rx: watch::Receiver<CacheSnapshot>,
+    proxied: Arc<ProxiedNames>,
+    cancel: CancellationToken,
+) {
+    let mut registered: HashMap<String, ServiceEntry> = HashMap::new();
+    loop {
+        let wanted: HashMap<String, ServiceEntry> = snapshot_rx
+            .borrow_and_update()
+            .services
+            .iter()
+            .filter(|s| s.alive && s.source == ServiceSource::Static)
+            .map(|s| (s.instance_name.clone(), s.clone()))
+            .collect();
+
+        let gone: Vec<String> = registered
+            .iter()
+            .filter(|(name, entry)| !wanted.get(*name).is_some_and(|w| same_records(w, entry)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in gone {
+            if let Some(entry) = registered.remove(&name) {
+                unregister(&daemon, &entry);
+            }
+            if !wanted.contains_key(&name) {
+                proxied.write().unwrap().remove(&name);
+            }
+        }
+        for (name, entry) in wanted {
+            if registered.contains_key(&name) {
+                continue;
+            }
+            proxied.write().unwrap().insert(name.clone());
+            match service_info(&entry).and_then(|info| daemon.register(info).context("Failed to register")) {
+                Ok(()) => {
+                    tracing::info!("Advertising {} by proxy", name);
+                    registered.insert(name, entry);
+                }
+                Err(e) => tracing::warn!("Not advertising {}: {:#}", name, e),
+            }
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            changed = snapshot_rx.changed() => if changed.is_err() { break },
+        }
+    }
+    for entry in registered.values() {
+        unregister(&daemon, entry);
+    }
+}
+
+fn unregister(daemon: &ServiceDaemon, entry: &ServiceEntry) {
+    let Ok(info) = service_info(entry) else {
+        return;
+    };
+    match daemon.unregister(info.get_fullname()) {
+        Ok(_) => tracing::info!("Stopped advertising {} by proxy", entry.instance_name),
+        Err(e) => tracing::warn!("Failed to stop advertising {}: {}", entry.instance_name, e),
+    }
+}
+
+/// Whether two entries would be advertised with the same records
+fn same_records(a: &ServiceEntry, b: &ServiceEntry) -> bool {
+    a.service_type == b.service_type
+        && a.hostname == b.hostname
+        && a.addresses == b.addresses
+        && a.port == b.port
+        && a.txt == b.txt
+}
+
+/// The registration for `entry`. Hosts outside `.local.` are advertised
+/// under their first label there, since mDNS answers only for `.local.`.
+fn service_info(entry: &ServiceEntry) -> Result<ServiceInfo> {
+    let instance = InstanceName::parse(&entry.instance_name)
+        .with_context(|| format!("{} is not a DNS-SD instance name", entry.instance_name))?;
+    let hostname = entry.hostname.trim_end_matches('.');
+    let host = match hostname.strip_suffix(".local") {
+        Some(_) => format!("{}.", hostname),
+        None => format!("{}.local.", hostname.split('.').next().unwrap_or(hostname)),
+    };
+    ServiceInfo::new(
+        &format!("{}.local.", instance.service_type),
+        &instance.short_name,
+        &host,
+        entry.addresses.as_slice(),
+        entry.port,
+        entry.txt.clone(),
+    )
+    .context("Failed to create ServiceInfo")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_service_info() {
+        let entry = ServiceEntry {
+            service_type: "_ipp._tcp".to_string(),
+            instance_name: "Office Printer._ipp._tcp.local.".to_string(),
+            hostname: "printer.example.com.".to_string(),
+            addresses: vec!["fd00::20".parse().unwrap()],
+            port: 631,
+            txt: [("rp".to_string(), "ipp/print".to_string())].into(),
+            first_seen: Utc::now(),
+            last_seen: Utc::now(),
+            ttl: 0,
+            alive: true,
+            source: ServiceSource::Static,
+            reachable: None,
+            latency_ms: None,
+            subtypes: Vec::new(),
+            iface: None,
+        };
+        let info = service_info(&entry).unwrap();
+        assert_eq!(info.get_fullname(), "Office Printer._ipp._tcp.local.");
+        assert_eq!(info.get_hostname(), "printer.local.");
+        assert_eq!(info.get_port(), 631);
+        assert_eq!(info.get_property_val_str("rp"), Some("ipp/print"));
+    }
+}
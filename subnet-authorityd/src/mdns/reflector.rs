@@ -0,0 +1,176 @@
+//! mDNS reflection between interfaces, for subnets split into segments that
+//! multicast doesn't cross. As Avahi's reflector does, every multicast query
+//! and response heard on one configured interface is sent on to the others,
+//! so services on each segment are found from all of them. IPv6 only.
+//!
+//! Two guards keep reflected packets from looping: packets from the host's
+//! own addresses are ignored, and a packet identical to one forwarded within
+//! the last `DUPLICATE_WINDOW` is dropped, which catches copies coming back
+//! through another reflector.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+const MDNS_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const MDNS_PORT: u16 = 5353;
+
+/// How long a forwarded packet is remembered. mDNS repeats announcements
+/// at least a second apart, so this never swallows a real repeat.
+const DUPLICATE_WINDOW: Duration = Duration::from_millis(500);
+
+/// One reflected interface
+struct Link {
+    name: String,
+    index: u32,
+    socket: Arc<UdpSocket>,
+}
+
+pub struct Reflector {
+    links: Vec<Link>,
+    guard: LoopGuard,
+}
+
+impl Reflector {
+    /// Bind a socket on each of `interfaces`, of which there must be at least two
+    pub fn open(interfaces: &[String]) -> Result<Self> {
+        if interfaces.len() < 2 {
+            anyhow::bail!("reflector.interfaces needs at least two interfaces");
+        }
+        let links = interfaces
+            .iter()
+            .map(|name| {
+                let index = super::interfaces::interface_index(name)?;
+                let socket = bind(index).with_context(|| format!("Failed to reflect mDNS on {}", name))?;
+                Ok(Link { name: name.clone(), index, socket: Arc::new(socket) })
+            })
+            .collect::<Result<_>>()?;
+        let own = super::interfaces::list_addresses()?.into_iter().map(|(_, addr)| addr).collect();
+        Ok(Self { links, guard: LoopGuard::new(own) })
+    }
+
+    /// Forward packets between the interfaces until `cancel` fires
+    pub async fn run(mut self, cancel: CancellationToken) {
+        let (tx, mut rx) = mpsc::channel(256);
+        let mut receivers = JoinSet::new();
+        for (i, link) in self.links.iter().enumerate() {
+            receivers.spawn(receive(i, link.socket.clone(), tx.clone()));
+        }
+        drop(tx);
+
+        loop {
+            let (from, packet, source) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                received = rx.recv() => match received {
+                    Some(received) => received,
+                    None => break,
+                },
+            };
+            if !self.guard.admit(&packet, source, Instant::now()) {
+                continue;
+            }
+            for link in self.links.iter().enumerate().filter(|&(i, _)| i != from).map(|(_, link)| link) {
+                let group = SocketAddrV6::new(MDNS_GROUP, MDNS_PORT, 0, link.index);
+                if let Err(e) = link.socket.send_to(&packet, group).await {
+                    tracing::debug!("Failed to reflect mDNS from {} to {}: {}", self.links[from].name, link.name, e);
+                }
+            }
+        }
+        receivers.shutdown().await;
+    }
+}
+
+/// Pass each datagram heard on a link to the forwarding loop, tagged with it
+async fn receive(link: usize, socket: Arc<UdpSocket>, tx: mpsc::Sender<(usize, Vec<u8>, SocketAddr)>) {
+    let mut buf = vec![0u8; 9000];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, source)) => {
+                if tx.send((link, buf[..len].to_vec(), source)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => tracing::debug!("mDNS reflector receive failed: {}", e),
+        }
+    }
+}
+
+/// Decides which received packets are forwarded
+struct LoopGuard {
+    own: HashSet<IpAddr>,
+    /// Hash of each recently forwarded packet, and when it was forwarded
+    recent: HashMap<u64, Instant>,
+}
+
+impl LoopGuard {
+    fn new(own: HashSet<IpAddr>) -> Self {
+        Self { own, recent: HashMap::new() }
+    }
+
+    /// Whether to forward `packet`, remembering it if so. Only traffic from
+    /// port 5353 is reflected: answers to one-shot queries from other ports
+    /// would have to come back to the querier's segment by unicast.
+    fn admit(&mut self, packet: &[u8], source: SocketAddr, now: Instant) -> bool {
+        if source.port() != MDNS_PORT || self.own.contains(&source.ip()) {
+            return false;
+        }
+        self.recent.retain(|_, &mut at| now.duration_since(at) < DUPLICATE_WINDOW);
+        let mut hasher = DefaultHasher::new();
+        packet.hash(&mut hasher);
+        match self.recent.entry(hasher.finish()) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+/// A socket on port 5353 of one interface, shared with mdns-sd. Bound to the
+/// group address it hears only multicast, and its own sends aren't looped
+/// back to it.
+fn bind(interface_index: u32) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddrV6::new(MDNS_GROUP, MDNS_PORT, 0, interface_index).into())?;
+    socket.join_multicast_v6(&MDNS_GROUP, interface_index)?;
+    socket.set_multicast_if_v6(interface_index)?;
+    socket.set_multicast_loop_v6(false)?;
+    // Receivers may check that mDNS wasn't routed (RFC 6762 section 11)
+    socket.set_multicast_hops_v6(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_guard() {
+        let own: IpAddr = "fe80::1".parse().unwrap();
+        let mut guard = LoopGuard::new(HashSet::from([own]));
+        let peer: SocketAddr = "[fe80::2]:5353".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(!guard.admit(b"query", SocketAddr::new(own, MDNS_PORT), start), "own packets aren't reflected");
+        assert!(!guard.admit(b"query", "[fe80::2]:40000".parse().unwrap(), start), "one-shot queries aren't reflected");
+        assert!(guard.admit(b"query", peer, start));
+        assert!(!guard.admit(b"query", peer, start + Duration::from_millis(10)), "a copy coming back is dropped");
+        assert!(guard.admit(b"answer", peer, start + Duration::from_millis(10)));
+        assert!(guard.admit(b"query", peer, start + DUPLICATE_WINDOW), "a real repeat is reflected");
+    }
+}
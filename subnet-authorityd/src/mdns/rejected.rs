@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Why the browser dropped a resolved service, or cached it only as addressless
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    /// The service resolved with no IPv6 addresses
+    NoIpv6Addresses,
+    /// The instance name exceeds `[mdns] max_instance_name_len`
+    InstanceNameTooLong,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::NoIpv6Addresses => write!(f, "no IPv6 addresses"),
+            RejectReason::InstanceNameTooLong => write!(f, "instance name too long"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RejectedService {
+    pub instance_name: String,
+    pub reason: RejectReason,
+    /// Cached anyway with no addresses (`[mdns] keep_addressless`)
+    pub kept: bool,
+    pub rejected_at: DateTime<Utc>,
+}
+
+/// Bounded record of the most recently rejected services, oldest evicted first
+pub struct RejectedRing {
+    capacity: usize,
+    entries: Mutex<VecDeque<RejectedService>>,
+}
+
+impl RejectedRing {
+    /// A capacity of 0 disables recording
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, instance_name: &str, reason: RejectReason, kept: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(RejectedService {
+            instance_name: instance_name.to_string(),
+            reason,
+            kept,
+            rejected_at: Utc::now(),
+        });
+    }
+
+    /// Current contents, newest first
+    pub fn recent(&self) -> Vec<RejectedService> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_evicts_oldest() {
+        let ring = RejectedRing::new(2);
+        ring.push("a._http._tcp.local.", RejectReason::NoIpv6Addresses, false);
+        ring.push("b._http._tcp.local.", RejectReason::NoIpv6Addresses, false);
+        ring.push("c._http._tcp.local.", RejectReason::NoIpv6Addresses, false);
+
+        let names: Vec<String> = ring.recent().into_iter().map(|r| r.instance_name).collect();
+        assert_eq!(names, vec!["c._http._tcp.local.", "b._http._tcp.local."]);
+    }
+}
@@ -0,0 +1,147 @@
+//! Applying config file changes without a restart, on SIGHUP or
+//! `POST /v1/admin/reload`. The file is loaded again and compared key by key
+//! with the config in effect. If only keys in `config::RELOADABLE_KEYS`
+//! changed, each change is handed to whatever reads it: the cache thread,
+//! the cache manager loop, the mDNS browser, or the log filter. Everything
+//! else is bound into sockets, the open database, or advertisements at
+//! startup, so a change to any other key rejects the reload as a whole.
+
+use std::sync::RwLock;
+use anyhow::{Context, Result};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::EnvFilter;
+use crate::cache_manager::CacheHandle;
+use crate::config::{CacheConfig, Config, ConfigSource, Overrides};
+use crate::mdns::browser::{BrowserCommand, TypeFilter};
+
+/// Swaps the log filter of the installed subscriber
+pub type SetLogFilter = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+/// A reload refused because keys changed that only a restart applies
+#[derive(Debug)]
+pub struct RestartRequired(pub Vec<String>);
+
+impl std::fmt::Display for RestartRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} changed; restart the daemon to apply (nothing was reloaded)", self.0.join(", "))
+    }
+}
+
+impl std::error::Error for RestartRequired {}
+
+pub struct Reloader {
+    current: RwLock<Config>,
+    /// Applied to each reloaded config, as at startup
+    overrides: Overrides,
+    /// Held for a whole reload, so two can't interleave
+    reloading: Mutex<()>,
+    cache: CacheHandle,
+    /// Read by `cache_manager::run`
+    cache_config: watch::Sender<CacheConfig>,
+    browser: mpsc::Sender<BrowserCommand>,
+    set_log_filter: SetLogFilter,
+}
+
+impl Reloader {
+    pub fn new(
+        config: Config,
+        overrides: Overrides,
+        cache: CacheHandle,
+        cache_config: watch::Sender<CacheConfig>,
+        browser: mpsc::Sender<BrowserCommand>,
+        set_log_filter: SetLogFilter,
+    ) -> Self {
+        Self {
+            current: RwLock::new(config),
+            overrides,
+            reloading: Mutex::new(()),
+            cache,
+            cache_config,
+            browser,
+            set_log_filter,
+        }
+    }
+
+    /// Provenance of the config in effect, as of the last successful reload
+    pub fn source(&self) -> ConfigSource {
+        self.current.read().unwrap().source.clone()
+    }
+
+    /// Load the config file again and apply what changed, returning the
+    /// dotted keys applied. Fails without applying anything if the file
+    /// doesn't load, a new value is invalid, or a key changed that needs a
+    /// restart (`RestartRequired`).
+    pub async fn reload(&self) -> Result<Vec<String>> {
+        let _reloading = self.reloading.lock().await;
+        let path = self.current.read().unwrap().source.path.clone();
+        let mut new = Config::load(&path).with_context(|| format!("Failed to load config from {}", path.display()))?;
+        self.overrides.apply(&mut new);
+        let (changed, restart) = self.current.read().unwrap().changed_keys(&new);
+        if !restart.is_empty() {
+            return Err(RestartRequired(restart).into());
+        }
+        let types = TypeFilter::new(&new.browser)?;
+        let log_filter = new.logging.filter()?;
+        new.cache.check_retention()?;
+
+        let section_changed = |section: &str| changed.iter().any(|key| key.starts_with(section));
+        if section_changed("cache.") {
+            self.cache.reconfigure(new.cache.clone()).await?;
+            self.cache_config.send_replace(new.cache.clone());
+        }
+        if section_changed("browser.") && self.browser.send(BrowserCommand::SetTypes(types, None)).await.is_err() {
+            tracing::debug!("mDNS discovery is off; [browser] has nothing to apply to");
+        }
+        if section_changed("logging.") {
+            (self.set_log_filter)(log_filter)?;
+        }
+        *self.current.write().unwrap() = new;
+
+        if changed.is_empty() {
+            tracing::info!("Reloaded {}; nothing changed", path.display());
+        } else {
+            tracing::info!("Reloaded {}, applying {}", path.display(), changed.join(", "));
+        }
+        Ok(changed)
+    }
+
+    /// Load the config file again for its `[browser]` section alone, and
+    /// make that section current, returning its compiled patterns for the
+    /// browser. Changes anywhere else in the file are left for a full reload.
+    /// Fails without changing anything if the file doesn't load or a
+    /// pattern is invalid.
+    pub async fn reload_browser(&self) -> Result<TypeFilter> {
+        let _reloading = self.reloading.lock().await;
+        let path = self.current.read().unwrap().source.path.clone();
+        let new = Config::load(&path).with_context(|| format!("Failed to load config from {}", path.display()))?;
+        let types = TypeFilter::new(&new.browser)?;
+
+        // Keep the compared table in step, so a full reload doesn't apply it again
+        let mut current = self.current.write().unwrap();
+        match new.table.get("browser") {
+            Some(browser) => current.table.insert("browser".to_string(), browser.clone()),
+            None => current.table.remove("browser"),
+        };
+        current.browser = new.browser;
+        tracing::info!("Reloaded [browser] from {}", path.display());
+        Ok(types)
+    }
+}
+
+/// Reload the config on every SIGHUP until `cancel` fires
+#[cfg(unix)]
+pub async fn run_on_hangup(reloader: std::sync::Arc<Reloader>, cancel: CancellationToken) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = hangup.recv() => {}
+        }
+        if let Err(e) = reloader.reload().await {
+            tracing::error!("Keeping current config: {:#}", e);
+        }
+    }
+}
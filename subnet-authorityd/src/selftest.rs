@@ -0,0 +1,144 @@
+use std::net::IpAddr;
+use anyhow::{bail, Context, Result};
+use crate::api::server::ApiListener;
+use crate::cache::db::CacheDb;
+use crate::config::Config;
+use crate::mdns::browser::TypeFilter;
+use crate::mdns::interfaces;
+
+/// Check that `config` would let the daemon start: open the database, find
+/// the interface and an in-prefix address on it, start mDNS, and bind the API
+/// listener. Prints a pass/fail line per check and returns true if all pass.
+/// Nothing is left running; the database file is created if missing, as the
+/// daemon itself would.
+pub async fn run(config: &Config) -> bool {
+    let results = [
+        ("database", check_database(config)),
+        ("interface", check_interface(config)),
+        ("mdns", check_mdns(config)),
+        ("listen", check_listen(config).await),
+    ];
+
+    let mut passed = true;
+    for (name, result) in &results {
+        match result {
+            Ok(detail) => println!("PASS  {:<10} {}", name, detail),
+            Err(e) => {
+                println!("FAIL  {:<10} {:#}", name, e);
+                passed = false;
+            }
+        }
+    }
+    println!("{}", if passed { "Self-test passed" } else { "Self-test FAILED" });
+    passed
+}
+
+/// Check `config` without opening the database or binding anything: the
+/// `[authority]` values parse and agree, the interface exists, and the
+/// other values checked only at startup are valid. Returns each problem
+/// found, worded to say what to change.
+pub fn check_config(config: &Config) -> Vec<String> {
+    let mut problems = config.authority.problems();
+    match interfaces::list_addresses() {
+        Ok(addrs) if !addrs.iter().any(|(iface, _)| *iface == config.authority.interface) => {
+            let mut names: Vec<&str> = addrs.iter().map(|(iface, _)| iface.as_str()).collect();
+            names.sort_unstable();
+            names.dedup();
+            problems.push(format!(
+                "authority.interface = {:?} doesn't exist on this host; it has {}",
+                config.authority.interface,
+                names.join(", ")
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("Can't check authority.interface: {:#}", e)),
+    }
+    let startup_checks = [
+        TypeFilter::new(&config.browser).map(drop),
+        config.api.tls_files().map(drop),
+        config.cache.encryption_key().map(drop),
+        config.cache.check_retention(),
+    ];
+    problems.extend(startup_checks.into_iter().filter_map(Result::err).map(|e| format!("{:#}", e)));
+    problems
+}
+
+fn check_database(config: &Config) -> Result<String> {
+    let db = CacheDb::open_with_key(&config.cache.db_path, config.cache.encryption_key()?.as_deref())?;
+    let services = db.get_all_services()?;
+    Ok(format!("{} ({} services)", config.cache.db_path.display(), services.len()))
+}
+
+fn check_interface(config: &Config) -> Result<String> {
+    let name = &config.authority.interface;
+    let prefix = config.authority.prefix_net()?;
+    let addrs = interfaces::list_addresses()?;
+
+    if !addrs.iter().any(|(iface, _)| iface == name) {
+        bail!("Interface {} not found", name);
+    }
+    if !interfaces::in_prefix_interfaces(&addrs, &prefix).contains(name) {
+        bail!("Interface {} has no address in {}", name, prefix);
+    }
+
+    let in_prefix: Vec<String> = addrs
+        .iter()
+        .filter(|(iface, addr)| iface == name && matches!(addr, IpAddr::V6(v6) if prefix.contains(v6)))
+        .map(|(_, addr)| addr.to_string())
+        .collect();
+    Ok(format!("{} has {}", name, in_prefix.join(", ")))
+}
+
+fn check_mdns(config: &Config) -> Result<String> {
+    let daemon = interfaces::create_daemon(&config.authority.interface)?;
+    daemon.shutdown().context("Failed to shut down mDNS daemon")?;
+    Ok(format!("daemon started on {}", config.authority.interface))
+}
+
+/// Bind as the daemon would, a unix socket included. Dropping the listener
+/// removes the socket's file again. A socket a running daemon listens on is
+/// reported as in use, and left alone.
+async fn check_listen(config: &Config) -> Result<String> {
+    match &ApiListener::bind(&config.api.listen).await? {
+        ApiListener::Tcp(listener) => Ok(format!("bound {}", listener.local_addr()?)),
+        ApiListener::Unix { path, .. } => Ok(format!("bound unix socket {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_listen_check_binds_unix_socket() {
+        let path = std::env::temp_dir().join(format!("subnet-authority-selftest-{}.sock", std::process::id()));
+        let config: Config = toml::from_str(&format!(
+            "[authority]\ninterface = \"eth0\"\nprefix = \"fd00::/64\"\naddress = \"fd00::1\"\nzone = \"subnet.example\"\n\
+             [api]\nlisten = \"unix:{}\"\n",
+            path.display()
+        ))
+        .unwrap();
+
+        let detail = check_listen(&config).await.unwrap();
+        assert!(detail.contains(&path.display().to_string()), "{}", detail);
+        assert!(!path.exists(), "the check should leave no socket behind");
+    }
+
+    #[tokio::test]
+    async fn test_listen_check_leaves_running_daemon_socket() {
+        let path = std::env::temp_dir().join(format!("subnet-authority-selftest-{}-live.sock", std::process::id()));
+        let config: Config = toml::from_str(&format!(
+            "[authority]\ninterface = \"eth0\"\nprefix = \"fd00::/64\"\naddress = \"fd00::1\"\nzone = \"subnet.example\"\n\
+             [api]\nlisten = \"unix:{}\"\n",
+            path.display()
+        ))
+        .unwrap();
+        let daemon = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let err = check_listen(&config).await.unwrap_err();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+        drop(daemon);
+        std::fs::remove_file(&path).unwrap();
+    }
+}